@@ -0,0 +1,73 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use minigrep::matcher;
+
+// Compares the matcher engines against the plain contains() loop search used to have.
+// Run with: cargo bench
+// Each case searches the same generated corpus and prints the best time of a few runs.
+
+const LINES: usize = 50_000;
+const RUNS: usize = 5;
+
+// A deterministic corpus of word salad, so every run searches the same text.
+// A small linear congruential generator is enough, we only need it to look varied.
+fn corpus() -> String {
+    let words = [
+        "rust", "safe", "fast", "productive", "pick", "three", "duct", "tape", "trust", "me",
+        "nobody", "somebody", "dreary", "public", "frog", "admiring", "bog", "livelong", "day",
+    ];
+    let mut seed: u64 = 42;
+    let mut contents = String::new();
+    for _ in 0..LINES {
+        for _ in 0..12 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            contents.push_str(words[(seed >> 33) as usize % words.len()]);
+            contents.push(' ');
+        }
+        contents.push('\n');
+    }
+    contents
+}
+
+fn best_of<F: FnMut() -> usize>(mut run: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        count = black_box(run());
+        best = best.min(start.elapsed());
+    }
+    (best, count)
+}
+
+fn compare(name: &str, contents: &str, patterns: &[&str]) {
+    let owned: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+    let engine = matcher::build(&owned, false);
+
+    let (naive, expected) = best_of(|| {
+        contents
+            .lines()
+            .filter(|line| patterns.iter().any(|pattern| line.contains(pattern)))
+            .count()
+    });
+    let (fast, count) = best_of(|| contents.lines().filter(|line| engine.is_match(line)).count());
+    assert_eq!(expected, count, "{name}: engines disagree");
+
+    println!(
+        "{name:<28} contains loop {:>10.2?}   matcher {:>10.2?}   ({count} matching lines)",
+        naive, fast
+    );
+}
+
+fn main() {
+    let contents = corpus();
+    let many: Vec<String> = (0..40).map(|i| format!("word{i}")).collect();
+    let mut many: Vec<&str> = many.iter().map(String::as_str).collect();
+    many.extend(["livelong day", "admiring bog"]);
+
+    compare("1 short pattern", &contents, &["frog"]);
+    compare("1 long pattern", &contents, &["livelong day livelong"]);
+    compare("5 patterns", &contents, &["frog", "trust me", "duct tape", "pick three", "xyz"]);
+    compare("42 patterns", &contents, &many);
+}
@@ -0,0 +1 @@
+cat poem.txt | cargo run -- to -
\ No newline at end of file
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+// Fuzzy matching for --fuzzy. Both modes give a line a cost: 0 is a perfect match and lower is
+// better, so results can be ranked the same way whichever mode found them.
+
+/// How --fuzzy compares the query to a line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FuzzyMode {
+    /// The query appears in the line with at most max_errors inserted, deleted or changed chars.
+    /// The cost is the number of errors.
+    EditDistance { max_errors: u32 },
+    /// The chars of the query appear in the line in order, like fzf.
+    /// The cost is the number of chars skipped between the first and last matched char,
+    /// plus one when the match doesn't start at the beginning of a word.
+    Subsequence,
+}
+
+impl Default for FuzzyMode {
+    fn default() -> FuzzyMode {
+        FuzzyMode::EditDistance { max_errors: 2 }
+    }
+}
+
+/// A query compiled for one FuzzyMode, so searching many lines doesn't redo the work
+#[derive(Debug, Clone)]
+pub struct FuzzyMatcher {
+    mode: FuzzyMode,
+    // Lowercased for Subsequence
+    query: Vec<char>,
+    // For Bitap, bit i of masks[c] is set where query[i] == c
+    masks: HashMap<char, u64>,
+}
+
+impl FuzzyMatcher {
+    pub fn new(mode: FuzzyMode, query: &str) -> FuzzyMatcher {
+        let query: Vec<char> = match mode {
+            FuzzyMode::EditDistance { .. } => query.chars().collect(),
+            FuzzyMode::Subsequence => query.chars().flat_map(char::to_lowercase).collect(),
+        };
+        let mut masks: HashMap<char, u64> = HashMap::new();
+        if matches!(mode, FuzzyMode::EditDistance { .. }) && query.len() <= 64 {
+            for (index, c) in query.iter().enumerate() {
+                *masks.entry(*c).or_insert(0) |= 1 << index;
+            }
+        }
+        FuzzyMatcher { mode, query, masks }
+    }
+
+    /// The cost of the best match of the query in line, or None if it doesn't match at all
+    pub fn cost(&self, line: &str) -> Option<u32> {
+        match self.mode {
+            // Queries of up to 64 chars use Bitap, which keeps the state of every prefix of the
+            // query in the bits of a u64. Longer ones fall back to the dynamic programming table.
+            FuzzyMode::EditDistance { max_errors } if self.query.len() <= 64 => bitap(&self.query, &self.masks, line, max_errors),
+            FuzzyMode::EditDistance { max_errors } => sellers(&self.query, line, max_errors),
+            FuzzyMode::Subsequence => subsequence(&self.query, line),
+        }
+    }
+}
+
+/// The fewest edits needed for query to appear somewhere in line, if that is at most max_errors
+pub fn edit_distance(query: &str, line: &str, max_errors: u32) -> Option<u32> {
+    FuzzyMatcher::new(FuzzyMode::EditDistance { max_errors }, query).cost(line)
+}
+
+// Bitap (shift-and) with errors, after Wu and Manber.
+// Bit i of states[d] is set when the first i + 1 chars of the query match the text ending at the
+// current char with at most d errors. Each text char updates every row with a few shifts and ors.
+fn bitap(query: &[char], masks: &HashMap<char, u64>, line: &str, max_errors: u32) -> Option<u32> {
+    if query.is_empty() {
+        return Some(0);
+    }
+    let length = query.len();
+    let max_errors = (max_errors as usize).min(length);
+    let done = 1u64 << (length - 1);
+
+    // With d errors allowed, the first d chars of the query can be deleted before the text
+    // starts. d can be 64, so the low d bits are set without shifting by d.
+    let mut states: Vec<u64> = (0..=max_errors).map(|d| if d == 0 { 0 } else { u64::MAX >> (64 - d) }).collect();
+    // Deleting the whole query is a match, though the line may have a better one
+    let mut best = states.iter().position(|state| state & done != 0);
+    for c in line.chars() {
+        let mask = *masks.get(&c).unwrap_or(&0);
+        let mut previous = states[0];
+        states[0] = ((states[0] << 1) | 1) & mask;
+        for d in 1..=max_errors {
+            let old = states[d];
+            states[d] = (((old << 1) | 1) & mask)  // the char matches
+                | previous                          // the text char is an insertion
+                | ((previous << 1) | 1)             // the text char replaces a query char
+                | ((states[d - 1] << 1) | 1);       // a query char is deleted
+            previous = old;
+        }
+        if let Some(d) = states.iter().position(|state| state & done != 0) {
+            best = Some(best.map_or(d, |best: usize| best.min(d)));
+            if d == 0 {
+                break;
+            }
+        }
+    }
+    best.map(|d| d as u32)
+}
+
+// Sellers' algorithm: Levenshtein distance where the match may start anywhere in the line.
+// Only one column of the table is kept at a time.
+fn sellers(query: &[char], line: &str, max_errors: u32) -> Option<u32> {
+    let mut column: Vec<u32> = (0..=query.len() as u32).collect();
+    let mut best = column[query.len()];
+    for c in line.chars() {
+        // Row 0 stays 0: a match can start at any char of the line
+        let mut diagonal = column[0];
+        for index in 1..=query.len() {
+            let substitution = diagonal + (query[index - 1] != c) as u32;
+            diagonal = column[index];
+            column[index] = substitution.min(column[index] + 1).min(column[index - 1] + 1);
+        }
+        best = best.min(column[query.len()]);
+    }
+    (best <= max_errors).then_some(best)
+}
+
+/// The cost of the tightest match of query's chars, in order, in line.
+/// Case is ignored, like fzf does for an all-lowercase query.
+pub fn subsequence_cost(query: &str, line: &str) -> Option<u32> {
+    FuzzyMatcher::new(FuzzyMode::Subsequence, query).cost(line)
+}
+
+// subsequence_cost for a query that is already lowercase
+fn subsequence(query: &[char], line: &str) -> Option<u32> {
+    let Some(first) = query.first() else {
+        return Some(0);
+    };
+    let chars: Vec<char> = line.chars().collect();
+    // Lowercased the same way as the query, where one char can become several. origins has the
+    // index in chars each lowercase char came from.
+    let (origins, lower): (Vec<usize>, Vec<char>) = chars
+        .iter()
+        .enumerate()
+        .flat_map(|(index, c)| c.to_lowercase().map(move |lower| (index, lower)))
+        .unzip();
+
+    // Try every place the first char matches and take the greedy match from there
+    let mut best = None;
+    for start in (0..lower.len()).filter(|start| lower[*start] == *first) {
+        let mut next = 1;
+        let mut end = start;
+        for (index, c) in lower.iter().enumerate().skip(start + 1) {
+            if next == query.len() {
+                break;
+            }
+            if *c == query[next] {
+                next += 1;
+                end = index;
+            }
+        }
+        if next < query.len() {
+            // No later start can find the whole query if this one couldn't
+            break;
+        }
+        let gaps = (end - start + 1 - query.len()) as u32;
+        let origin = origins[start];
+        let word_start = origin == 0 || !chars[origin - 1].is_alphanumeric();
+        let cost = gaps + (!word_start) as u32;
+        best = Some(best.map_or(cost, |best: u32| best.min(cost)));
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance_counts_errors() {
+        assert_eq!(Some(0), edit_distance("frog", "How public, like a frog", 2));
+        assert_eq!(Some(1), edit_distance("frog", "How public, like a fog", 2));
+        assert_eq!(Some(1), edit_distance("frog", "How public, like a frag", 2));
+        assert_eq!(Some(1), edit_distance("frog", "How public, like a froog", 2));
+        assert_eq!(Some(2), edit_distance("frog", "How public, like a dog", 2));
+        assert_eq!(None, edit_distance("frog", "To an admiring bog!", 1));
+    }
+
+    #[test]
+    fn bitap_agrees_with_sellers() {
+        let lines = ["I'm nobody! Who are you?", "Are you nobody, too?", "How dreary to be somebody!"];
+        for query in ["nobody", "somebdy", "dreery to", "you", "xyz"] {
+            let chars: Vec<char> = query.chars().collect();
+            let masks = FuzzyMatcher::new(FuzzyMode::default(), query).masks;
+            for line in lines {
+                for max_errors in 0..3 {
+                    assert_eq!(
+                        sellers(&chars, line, max_errors),
+                        bitap(&chars, &masks, line, max_errors),
+                        "{query} in {line} with {max_errors}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn subsequence_prefers_tight_matches() {
+        assert_eq!(Some(0), subsequence_cost("frog", "like a frog"));
+        assert_eq!(Some(1), subsequence_cost("frog", "like afrog"));
+        assert_eq!(Some(4), subsequence_cost("hpb", "How public"));
+        assert_eq!(Some(0), subsequence_cost("ADMIR", "an admiring bog"));
+        assert_eq!(None, subsequence_cost("gorf", "like a frog"));
+        // İ lowercases to two chars, both in the query and in the line
+        assert_eq!(Some(0), subsequence_cost("İstanbul", "to İstanbul"));
+        assert_eq!(Some(0), subsequence_cost("i̇st", "İSTANBUL"));
+    }
+
+    #[test]
+    fn bitap_takes_64_errors() {
+        let query = "x".repeat(64);
+        assert_eq!(Some(64), edit_distance(&query, "no match here", 64));
+        assert_eq!(Some(0), edit_distance(&query, &query, 64));
+        assert_eq!(None, edit_distance(&query, "y", 63));
+    }
+}
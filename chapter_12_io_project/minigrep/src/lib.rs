@@ -1,6 +1,17 @@
 use std::error::Error;
-use std::fs;
+use std::fs::File;
 use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::fs;
+use std::ops::Range;
+
+pub mod fuzzy;
+pub mod matcher;
+pub mod output;
+
+use fuzzy::{FuzzyMatcher, FuzzyMode};
+use matcher::{Contains, IgnoreCase, Matcher};
+use output::{ColorChoice, Context, Emit};
 
 //This function borrows query and contents and returns a vector of &str types in contents' lifetime
 // The conntents of each &str is a slice of the original &str, so no data is duplicated.
@@ -8,24 +19,25 @@ pub fn search<'a>(
     query: &str,
     contents: &'a str
 ) -> Vec<&'a str> {
-    let mut results: Vec<&'a str> = Vec::new();
-    for line in contents.lines() {
-        if line.contains(query) {
-            results.push(line);
-        }
-    }
-
-    results
+    search_with(&Contains::new(query), contents)
 }
 
 pub fn search_case_insensitive<'a>(
     query: &str,
     contents: &'a str
 ) -> Vec<&'a str> {
-    let query = query.to_lowercase(); // Shadowed query with let, did not overwrite it. Once returned will be old value
+    search_with(&IgnoreCase::new(query), contents)
+}
+
+// search and search_case_insensitive only differ in the Matcher they use.
+// Any engine from the matcher module can be passed in here, see matcher::build.
+pub fn search_with<'a>(
+    matcher: &dyn Matcher,
+    contents: &'a str
+) -> Vec<&'a str> {
     let mut results: Vec<&'a str> = Vec::new();
     for line in contents.lines() {
-        if line.to_lowercase().contains(&query) { // Because query is a String type, need to lend its contents to contains()
+        if matcher.is_match(line) {
             results.push(line);
         }
     }
@@ -33,42 +45,276 @@ pub fn search_case_insensitive<'a>(
     results
 }
 
+// A line found by a fuzzy search, with how closely it matched.
+// score is the cost from fuzzy::FuzzyMatcher::cost, 0 for a perfect match and lower is better.
+// line is a &str for search_fuzzy and an owned String for search_reader_fuzzy.
+#[derive(Debug, PartialEq)]
+pub struct Ranked<T> {
+    pub score: u32,
+    pub number: usize,
+    pub line: T,
+}
+
+// Every line that fuzzily matches query, best first.
+// Lines with the same score keep the order they have in contents.
+pub fn search_fuzzy<'a>(
+    query: &str,
+    contents: &'a str,
+    mode: FuzzyMode
+) -> Vec<Ranked<&'a str>> {
+    let matcher = FuzzyMatcher::new(mode, query);
+    let mut results: Vec<Ranked<&'a str>> = contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let score = matcher.cost(line)?;
+            Some(Ranked { score, number: index + 1, line })
+        })
+        .collect();
+    results.sort_by_key(|ranked| ranked.score);
+    results
+}
+
+// search_fuzzy for a reader. The results can only be ranked once the whole input has been read,
+// so unlike search_reader this collects them, though only the matching lines are kept.
+pub fn search_reader_fuzzy<R: BufRead>(
+    query: &str,
+    reader: R,
+    mode: FuzzyMode
+) -> io::Result<Vec<Ranked<String>>> {
+    let matcher = FuzzyMatcher::new(mode, query);
+    let mut results = Vec::new();
+    // The empty query matches every line, so this reads them all
+    for line in read_lines_with(Box::new(Contains::new("")), reader) {
+        let line = line?;
+        if let Some(score) = matcher.cost(&line.text) {
+            results.push(Ranked { score, number: line.number, line: line.text });
+        }
+    }
+    results.sort_by_key(|ranked| ranked.score);
+    Ok(results)
+}
+
+// A line read from the input, numbered from 1.
+// The text is an owned String because it is read from a stream rather than borrowed from a file in memory.
+// spans holds the byte ranges of the query in text, and is empty when the line didn't match.
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    pub number: usize,
+    pub text: String,
+    pub spans: Vec<Range<usize>>,
+}
+
+impl Line {
+    pub fn is_match(&self) -> bool {
+        !self.spans.is_empty()
+    }
+}
+
+// Lines is the iterator returned by read_lines, it yields every line of the input with its spans.
+// It reads one line at a time into buf, so memory use depends on the longest line, not the file size.
+pub struct Lines<R> {
+    reader: R,
+    matcher: Box<dyn Matcher + Send + Sync>,
+    buf: Vec<u8>,
+    number: usize,
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = io::Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.clear();
+        match self.reader.read_until(b'\n', &mut self.buf) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(err) => return Some(Err(err)),
+        }
+        self.number += 1;
+        // Strip the line ending the same way str::lines() does
+        if self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+            if self.buf.last() == Some(&b'\r') {
+                self.buf.pop();
+            }
+        }
+        // Invalid UTF-8 becomes U+FFFD instead of failing the whole search
+        let text = String::from_utf8_lossy(&self.buf).into_owned();
+        let spans = self.matcher.find_spans(&text);
+        Some(Ok(Line { number: self.number, text, spans }))
+    }
+}
+
+// read_lines is what run uses when it needs the lines around the matches too
+pub fn read_lines<R: BufRead>(query: &str, reader: R, ignore_case: bool) -> Lines<R> {
+    read_lines_with(matcher::build(&[query.to_string()], ignore_case), reader)
+}
+
+// read_lines with any Matcher, e.g. one built from the patterns in a -f file
+pub fn read_lines_with<R: BufRead>(matcher: Box<dyn Matcher + Send + Sync>, reader: R) -> Lines<R> {
+    Lines { reader, matcher, buf: Vec::new(), number: 0 }
+}
+
+// Matches is the iterator returned by search_reader, Lines with the non-matching lines filtered out
+pub struct Matches<R> {
+    lines: Lines<R>,
+}
+
+impl<R: BufRead> Iterator for Matches<R> {
+    type Item = io::Result<Line>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lines.find(|line| line.as_ref().map_or(true, Line::is_match))
+    }
+}
+
+// search_reader is the streaming version of search/search_case_insensitive.
+// Nothing is read until the iterator is polled, so it works on pipes and files bigger than memory.
+pub fn search_reader<R: BufRead>(query: &str, reader: R, ignore_case: bool) -> Matches<R> {
+    Matches { lines: read_lines(query, reader, ignore_case) }
+}
+
+// Opens the input named by file_path, where "-" means standard input like most unix tools
+fn open(file_path: &str) -> io::Result<Box<dyn BufRead>> {
+    if file_path == "-" {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(file_path)?)))
+    }
+}
+
 // run takes config, takes ownership of it, and returns a Result
 // By setting E=Box<dyn Error> we are allowing the error to take on any object
 // that implements the std::error::Error trait
 // This makes it easier to handle any error that might arise.
 pub fn run(config: Config) -> Result<(), Box<dyn Error>>{
-    let contents = fs::read_to_string(config.file_path)?;
-    //println!("With text:\n{contents}");
-    let results = if config.ignore_case {
-        search_case_insensitive(&config.query,&contents)
+    let reader = open(&config.file_path)?;
+    let mut stdout = io::stdout().lock();
+    print_matches(&config, reader, &mut stdout)
+}
+
+// Prints the matches in reader with their context lines.
+// This is run without the stdout handle, so tests can pass in a Vec<u8> instead.
+pub fn print_matches<R: BufRead, W: Write>(
+    config: &Config,
+    reader: R,
+    out: &mut W
+) -> Result<(), Box<dyn Error>> {
+    if let Some(mode) = config.fuzzy {
+        // Each line is printed after its score, ranked rather than in file order
+        for ranked in search_reader_fuzzy(&config.patterns[0], reader, mode)? {
+            writeln!(out, "{}\t{}", ranked.score, ranked.line)?;
+        }
+        return Ok(());
     }
-    else{
-        search(&config.query, &contents)
-    };
-    for line in results {
-        println!("{line}");
+    let color = config.color.enabled();
+    let mut context = Context::new(config.before, config.after);
+    let matcher = matcher::build(&config.patterns, config.ignore_case);
+    for line in read_lines_with(matcher, reader) {
+        let line = line?;
+        let matched = line.is_match();
+        // emit can't return an error, so the first write error is kept here and returned after the line
+        let mut result = Ok(());
+        context.push(line.number, line, matched, |emit| {
+            let written = match emit {
+                Emit::Separator => writeln!(out, "{}", output::separator(color)),
+                Emit::Context(_, line) => writeln!(out, "{}", line.text),
+                Emit::Match(_, line) if color => {
+                    writeln!(out, "{}", output::highlight(&line.text, &line.spans))
+                }
+                Emit::Match(_, line) => writeln!(out, "{}", line.text),
+            };
+            if result.is_ok() {
+                result = written;
+            }
+        });
+        result?;
     }
     Ok(())
 }
 
 pub struct Config {
-    query: String,
+    patterns: Vec<String>,
     file_path: String,
-    ignore_case: bool
+    ignore_case: bool,
+    before: usize,
+    after: usize,
+    color: ColorChoice,
+    fuzzy: Option<FuzzyMode>,
 }
 
 impl Config {
+    // Usage: minigrep [-A N] [-B N] [-C N] [--color=WHEN] query file_path
+    //        minigrep [options] -f patterns_file file_path
+    //        minigrep --fuzzy[=MAX_ERRORS|=subsequence] query file_path
+    // Options can go anywhere. Without -f the first two other arguments are the query and the file path.
     pub fn build(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 3 {
-            return Err("Not enough arguments.");
+        let mut before = 0;
+        let mut after = 0;
+        let mut color = ColorChoice::default();
+        let mut patterns_file = None;
+        let mut fuzzy = None;
+        let mut positional = Vec::new();
+        let mut rest = args.iter().skip(1);
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "-A" => after = parse_count(rest.next())?,
+                "-B" => before = parse_count(rest.next())?,
+                "-C" => {
+                    after = parse_count(rest.next())?;
+                    before = after;
+                }
+                "-f" => patterns_file = Some(rest.next().ok_or("-f needs a file of patterns.")?),
+                "--fuzzy" => fuzzy = Some(FuzzyMode::default()),
+                _ if arg.starts_with("--fuzzy=") => fuzzy = Some(parse_fuzzy(&arg[8..])?),
+                _ if arg.starts_with("--color=") => color = ColorChoice::parse(&arg[8..])?,
+                _ => positional.push(arg),
+            }
         }
 
-        let query = args[1].clone();
-        let file_path = args[2].clone();
+        if fuzzy.is_some() && patterns_file.is_some() {
+            return Err("--fuzzy takes a single query, not -f.");
+        }
+        let patterns = match patterns_file {
+            Some(path) => read_patterns(path)?,
+            None if positional.len() >= 2 => vec![positional.remove(0).clone()],
+            None => return Err("Not enough arguments."),
+        };
+        let file_path = match positional.as_slice() {
+            [path] => path.to_string(),
+            [] => return Err("Not enough arguments."),
+            _ if patterns_file.is_some() => return Err("With -f the only other argument is the file path."),
+            [path, ..] => path.to_string(),
+        };
         let ignore_case = env::var("IGNORE_CASE").is_ok();
 
-        Ok(Config {query, file_path, ignore_case})
+        Ok(Config {patterns, file_path, ignore_case, before, after, color, fuzzy})
+    }
+}
+
+// One literal pattern per line. Blank lines are skipped, otherwise they would match everything.
+fn read_patterns(path: &str) -> Result<Vec<String>, &'static str> {
+    let contents = fs::read_to_string(path).map_err(|_| "Couldn't read the patterns file.")?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+// The number of context lines after -A, -B or -C
+fn parse_count(arg: Option<&String>) -> Result<usize, &'static str> {
+    arg.and_then(|arg| arg.parse().ok()).ok_or("Context options need a number of lines.")
+}
+
+// --fuzzy=subsequence, or --fuzzy=N for an edit distance of at most N
+fn parse_fuzzy(arg: &str) -> Result<FuzzyMode, &'static str> {
+    match arg {
+        "subsequence" => Ok(FuzzyMode::Subsequence),
+        _ => arg
+            .parse()
+            .map(|max_errors| FuzzyMode::EditDistance { max_errors })
+            .map_err(|_| "--fuzzy needs a number of errors or 'subsequence'."),
     }
 }
 
@@ -120,4 +366,95 @@ Trust me.";
             search_case_insensitive(query, contents)
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn streams_from_reader() {
+        let contents = "Rust:\r\nsafe, fast, productive.\nPick three.\nTrust me.";
+
+        let matches: Vec<Line> = search_reader("rUsT", contents.as_bytes(), true)
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                Line { number: 1, text: "Rust:".to_string(), spans: vec![Range { start: 0, end: 4 }] },
+                Line { number: 4, text: "Trust me.".to_string(), spans: vec![Range { start: 1, end: 5 }] },
+            ],
+            matches
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_is_lossy() {
+        let contents: &[u8] = b"caf\xe9 duct\nplain";
+
+        let line = search_reader("duct", contents, false).next().unwrap().unwrap();
+        assert_eq!("caf\u{FFFD} duct", line.text);
+    }
+
+    #[test]
+    fn spans_keep_original_offsets() {
+        assert_eq!(vec![0..2, 13..15], Contains::new("to").find_spans("to be or not to be"));
+        // 'İ' lowercases to two chars, the span must still cover its two bytes in the line
+        assert_eq!(vec![Range { start: 3, end: 7 }], IgnoreCase::new("i̇st").find_spans("xx İST"));
+        assert_eq!(vec![Range { start: 0, end: 0 }], Contains::new("").find_spans("anything"));
+    }
+
+    #[test]
+    fn prints_context() {
+        let args: Vec<String> = ["minigrep", "-C", "1", "--color=never", "body", "poem.txt"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let config = Config::build(&args).unwrap();
+        let poem = "I'm nobody! Who are you?\nAre you nobody, too?\nThen there's a pair of us - don't tell!\nThey'd banish us, you know.\n\nHow dreary to be somebody!\nHow public, like a frog\nTo tell your name the livelong day\nTo an admiring bog!\n";
+
+        let mut out = Vec::new();
+        print_matches(&config, poem.as_bytes(), &mut out).unwrap();
+        assert_eq!(
+            "I'm nobody! Who are you?\nAre you nobody, too?\nThen there's a pair of us - don't tell!\n--\n\nHow dreary to be somebody!\nHow public, like a frog\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn patterns_from_file() {
+        let patterns = env::temp_dir().join(format!("minigrep-patterns-{}", std::process::id()));
+        fs::write(&patterns, "frog\n\nbog\n").unwrap();
+        let args: Vec<String> = ["minigrep", "-f", patterns.to_str().unwrap(), "poem.txt"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let config = Config::build(&args).unwrap();
+        let mut extra = args.clone();
+        extra.push("sonnet.txt".to_string());
+        assert_eq!(Some("With -f the only other argument is the file path."), Config::build(&extra).err());
+        fs::remove_file(patterns).unwrap();
+
+        let mut out = Vec::new();
+        print_matches(&config, "How public, like a frog\nTo an admiring bog!\nfog\n".as_bytes(), &mut out).unwrap();
+        assert_eq!("How public, like a frog\nTo an admiring bog!\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn fuzzy_ranks_lines() {
+        let poem = "How public, like a frog\nTo an admiring bog!\nfog\nfrog";
+
+        assert_eq!(
+            vec![
+                Ranked { score: 0, number: 1, line: "How public, like a frog" },
+                Ranked { score: 0, number: 4, line: "frog" },
+                Ranked { score: 1, number: 3, line: "fog" },
+            ],
+            search_fuzzy("frog", poem, FuzzyMode::EditDistance { max_errors: 1 })
+        );
+
+        let args: Vec<String> = ["minigrep", "--fuzzy=subsequence", "hpb", "poem.txt"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let config = Config::build(&args).unwrap();
+        let mut out = Vec::new();
+        print_matches(&config, "How public\nhpb\nno\n".as_bytes(), &mut out).unwrap();
+        assert_eq!("0\thpb\n4\tHow public\n", String::from_utf8(out).unwrap());
+    }
+}
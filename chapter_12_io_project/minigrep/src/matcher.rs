@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+
+// A Matcher is a search engine for one or more literal patterns.
+// search, read_lines and run all go through this trait, so engines can be swapped without
+// touching the code that reads and prints lines. build() picks the engine for a set of patterns.
+
+/// Finds literal patterns in a line
+pub trait Matcher {
+    /// Byte ranges of the leftmost, non-overlapping matches in line, in order.
+    /// Where several patterns match at the same position the longest one wins.
+    fn find_spans(&self, line: &str) -> Vec<Range<usize>>;
+
+    fn is_match(&self, line: &str) -> bool {
+        !self.find_spans(line).is_empty()
+    }
+}
+
+/// Patterns at least this long are searched with Boyer-Moore-Horspool.
+/// Below this the skip table doesn't buy enough to beat str::contains.
+pub const HORSPOOL_MIN_LEN: usize = 8;
+
+/// With fewer patterns than this, running str::contains once per pattern is still faster than
+/// Aho-Corasick's table lookups (see benches/matchers.rs)
+pub const AHO_CORASICK_MIN_PATTERNS: usize = 8;
+
+/// Picks the engine for a set of patterns:
+/// one short pattern uses str::contains, one long one Horspool, many use Aho-Corasick.
+/// Ignoring case, patterns are always tried one at a time with IgnoreCase. Aho-Corasick only
+/// folds ASCII, so even for ASCII patterns it would miss lines like "\u{212A}" (KELVIN SIGN),
+/// which lowercases to 'k'.
+pub fn build(patterns: &[String], ignore_case: bool) -> Box<dyn Matcher + Send + Sync> {
+    match (patterns, ignore_case) {
+        ([pattern], false) if pattern.len() >= HORSPOOL_MIN_LEN => Box::new(Horspool::new(pattern)),
+        ([pattern], false) => Box::new(Contains::new(pattern)),
+        ([pattern], true) => Box::new(IgnoreCase::new(pattern)),
+        (patterns, false) if patterns.len() >= AHO_CORASICK_MIN_PATTERNS => Box::new(AhoCorasick::new(patterns, false)),
+        (patterns, true) => Box::new(AnyOf(
+            patterns.iter().map(|pattern| IgnoreCase::new(pattern)).collect(),
+        )),
+        (patterns, false) => Box::new(AnyOf(
+            patterns.iter().map(|pattern| Contains::new(pattern)).collect(),
+        )),
+    }
+}
+
+// An empty pattern matches every line, with a single empty span at the start
+fn empty_span() -> Vec<Range<usize>> {
+    vec![Range { start: 0, end: 0 }]
+}
+
+/// One pattern, found with str::contains and str::match_indices. This is what search always did.
+pub struct Contains {
+    pattern: String,
+}
+
+impl Contains {
+    pub fn new(pattern: &str) -> Contains {
+        Contains { pattern: pattern.to_string() }
+    }
+}
+
+impl Matcher for Contains {
+    fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        if self.pattern.is_empty() {
+            return empty_span();
+        }
+        line.match_indices(self.pattern.as_str())
+            .map(|(start, found)| start..start + found.len())
+            .collect()
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        line.contains(&self.pattern)
+    }
+}
+
+/// One pattern, ignoring case.
+/// Lines are compared char by char rather than lowercasing the line, because lowercasing can
+/// change a char's length in bytes and then the offsets would no longer point into the original line.
+pub struct IgnoreCase {
+    lowercase: String,
+}
+
+impl IgnoreCase {
+    pub fn new(pattern: &str) -> IgnoreCase {
+        IgnoreCase { lowercase: pattern.to_lowercase() }
+    }
+
+    // If text starts with the pattern ignoring case, returns the length of the match in text
+    fn match_at(&self, text: &str) -> Option<usize> {
+        let mut pattern = self.lowercase.chars().peekable();
+        for (offset, c) in text.char_indices() {
+            for lower in c.to_lowercase() {
+                if pattern.next() != Some(lower) {
+                    return None;
+                }
+            }
+            if pattern.peek().is_none() {
+                return Some(offset + c.len_utf8());
+            }
+        }
+        None
+    }
+}
+
+impl Matcher for IgnoreCase {
+    fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        if self.lowercase.is_empty() {
+            return empty_span();
+        }
+        let mut spans = Vec::new();
+        let mut from = 0;
+        for (start, _) in line.char_indices() {
+            if start < from {
+                continue;
+            }
+            if let Some(end) = self.match_at(&line[start..]) {
+                spans.push(start..start + end);
+                from = start + end;
+            }
+        }
+        spans
+    }
+}
+
+/// One pattern, found with Boyer-Moore-Horspool.
+/// The pattern is compared from its last byte backwards, and on a mismatch the window jumps ahead
+/// by how far the byte under its end is from the end of the pattern, often the whole pattern length.
+pub struct Horspool {
+    pattern: Vec<u8>,
+    shift: [usize; 256],
+}
+
+impl Horspool {
+    pub fn new(pattern: &str) -> Horspool {
+        let pattern = pattern.as_bytes().to_vec();
+        let mut shift = [pattern.len().max(1); 256];
+        // The last byte is left out: if it matched we would shift by zero
+        for (index, byte) in pattern.iter().enumerate().take(pattern.len().saturating_sub(1)) {
+            shift[*byte as usize] = pattern.len() - 1 - index;
+        }
+        Horspool { pattern, shift }
+    }
+
+    // The start of the first match at or after from. The pattern is never empty here.
+    fn find_from(&self, haystack: &[u8], from: usize) -> Option<usize> {
+        let length = self.pattern.len();
+        let last = self.pattern[length - 1];
+        let mut start = from;
+        while start + length <= haystack.len() {
+            let end = haystack[start + length - 1];
+            // Checking the last byte first rules out most windows without comparing the rest
+            if end == last && haystack[start..start + length - 1] == self.pattern[..length - 1] {
+                return Some(start);
+            }
+            start += self.shift[end as usize];
+        }
+        None
+    }
+}
+
+impl Matcher for Horspool {
+    fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        if self.pattern.is_empty() {
+            return empty_span();
+        }
+        let mut spans = Vec::new();
+        let mut from = 0;
+        while let Some(start) = self.find_from(line.as_bytes(), from) {
+            from = start + self.pattern.len();
+            spans.push(start..from);
+        }
+        spans
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        self.pattern.is_empty() || self.find_from(line.as_bytes(), 0).is_some()
+    }
+}
+
+/// Many patterns at once with Aho-Corasick.
+/// ascii_case_insensitive folds ASCII letters only, unlike IgnoreCase.
+/// The patterns are put in a trie, and every node gets a failure link to the longest suffix of
+/// its path that is also in the trie. Filling in the missing transitions from the failure links
+/// turns the trie into a DFA, so each byte of the line costs a single table lookup however many
+/// patterns there are.
+pub struct AhoCorasick {
+    // transitions[state][byte] is the next state
+    transitions: Vec<[u32; 256]>,
+    // The patterns that end at each state, including those reached through failure links
+    outputs: Vec<Vec<usize>>,
+    lengths: Vec<usize>,
+    ascii_case_insensitive: bool,
+    has_empty: bool,
+}
+
+impl AhoCorasick {
+    pub fn new(patterns: &[String], ascii_case_insensitive: bool) -> AhoCorasick {
+        let fold = |byte: u8| if ascii_case_insensitive { byte.to_ascii_lowercase() } else { byte };
+        let mut transitions = vec![[0u32; 256]];
+        let mut outputs = vec![Vec::new()];
+        // 0 means "no child yet" while building, which is fine because nothing points back at the root
+        for (index, pattern) in patterns.iter().enumerate() {
+            let mut state = 0;
+            for byte in pattern.bytes().map(fold) {
+                if transitions[state][byte as usize] == 0 {
+                    transitions.push([0; 256]);
+                    outputs.push(Vec::new());
+                    transitions[state][byte as usize] = (transitions.len() - 1) as u32;
+                }
+                state = transitions[state][byte as usize] as usize;
+            }
+            outputs[state].push(index);
+        }
+
+        // Breadth first, so a node's failure state is always finished before the node itself
+        let mut fail = vec![0usize; transitions.len()];
+        let mut queue: VecDeque<usize> = transitions[0]
+            .iter()
+            .filter(|child| **child != 0)
+            .map(|child| *child as usize)
+            .collect();
+        while let Some(state) = queue.pop_front() {
+            let inherited = outputs[fail[state]].clone();
+            outputs[state].extend(inherited);
+            let fail_row = transitions[fail[state]];
+            for (next, through_fail) in transitions[state].iter_mut().zip(fail_row) {
+                if *next == 0 {
+                    *next = through_fail;
+                } else {
+                    fail[*next as usize] = through_fail as usize;
+                    queue.push_back(*next as usize);
+                }
+            }
+        }
+
+        AhoCorasick {
+            transitions,
+            outputs,
+            lengths: patterns.iter().map(String::len).collect(),
+            ascii_case_insensitive,
+            has_empty: patterns.iter().any(String::is_empty),
+        }
+    }
+
+    fn fold(&self, byte: u8) -> u8 {
+        if self.ascii_case_insensitive { byte.to_ascii_lowercase() } else { byte }
+    }
+}
+
+impl Matcher for AhoCorasick {
+    fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        if self.has_empty {
+            return empty_span();
+        }
+        // Every occurrence of every pattern, then keep the leftmost longest ones that don't overlap
+        let mut found = Vec::new();
+        let mut state = 0;
+        for (index, byte) in line.bytes().enumerate() {
+            state = self.transitions[state][self.fold(byte) as usize] as usize;
+            for pattern in &self.outputs[state] {
+                found.push(index + 1 - self.lengths[*pattern]..index + 1);
+            }
+        }
+        found.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+        let mut spans: Vec<Range<usize>> = Vec::new();
+        for span in found {
+            if spans.last().is_none_or(|last| span.start >= last.end) {
+                spans.push(span);
+            }
+        }
+        spans
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        if self.has_empty {
+            return true;
+        }
+        let mut state = 0;
+        line.bytes().any(|byte| {
+            state = self.transitions[state][self.fold(byte) as usize] as usize;
+            !self.outputs[state].is_empty()
+        })
+    }
+}
+
+/// Several matchers tried one after the other, for pattern sets Aho-Corasick can't handle
+pub struct AnyOf<M>(pub Vec<M>);
+
+impl<M: Matcher> Matcher for AnyOf<M> {
+    fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        let mut found: Vec<Range<usize>> =
+            self.0.iter().flat_map(|matcher| matcher.find_spans(line)).collect();
+        found.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+        let mut spans: Vec<Range<usize>> = Vec::new();
+        for span in found {
+            if spans.last().is_none_or(|last| span.start >= last.end) {
+                spans.push(span);
+            }
+        }
+        spans
+    }
+
+    fn is_match(&self, line: &str) -> bool {
+        self.0.iter().any(|matcher| matcher.is_match(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn horspool_finds_every_match() {
+        let matcher = Horspool::new("needle in");
+        assert_eq!(vec![4..13, 22..31], matcher.find_spans("hay needle in hay and needle in a stack"));
+        assert!(!matcher.is_match("needle i"));
+        assert_eq!(vec![4..13], Horspool::new("ĥaystack").find_spans("ĥa ĥaystack"));
+    }
+
+    #[test]
+    fn aho_corasick_leftmost_longest() {
+        let matcher = AhoCorasick::new(&patterns(&["he", "she", "his", "hers"]), false);
+        assert_eq!(vec![1..4], matcher.find_spans("ushers"));
+        assert_eq!(vec![0..3, 7..9], matcher.find_spans("his is he"));
+        assert!(!matcher.is_match("no match"));
+
+        let ignore_case = AhoCorasick::new(&patterns(&["rust", "trust"]), true);
+        assert_eq!(vec![0..4, 6..11], ignore_case.find_spans("Rust: TRUST me"));
+    }
+
+    #[test]
+    fn build_agrees_with_contains() {
+        let lines = ["safe, fast, productive.", "Pick three.", "Duct tape.", "Trust me."];
+        let many = vec!["duct", "Pick", "me", "a", "b", "c", "d", "e", "f"];
+        let sets = [vec!["duct"], vec!["productive."], vec!["duct", "Pick", "me"], vec!["e."], many];
+        for set in sets {
+            let matcher = build(&patterns(&set), false);
+            for line in lines {
+                let expected = set.iter().any(|pattern| line.contains(pattern));
+                assert_eq!(expected, matcher.is_match(line), "{set:?} in {line:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal};
+use std::ops::Range;
+
+// ANSI escape codes: bold red for matches, cyan for the -- separator, then reset
+const MATCH_COLOR: &str = "\x1b[1;31m";
+const SEPARATOR_COLOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// When to colour matches, set with --color=auto|always|never
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColorChoice {
+    /// Only when stdout is a terminal, so piping to a file gives plain text
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(value: &str) -> Result<ColorChoice, &'static str> {
+        match value {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err("--color must be auto, always or never"),
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => io::stdout().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Wraps every span of line in colour codes. Spans are byte ranges, sorted and non-overlapping.
+pub fn highlight(line: &str, spans: &[Range<usize>]) -> String {
+    let mut colored = String::with_capacity(line.len() + spans.len() * 11);
+    let mut end = 0;
+    for span in spans.iter().filter(|span| !span.is_empty()) {
+        colored.push_str(&line[end..span.start]);
+        colored.push_str(MATCH_COLOR);
+        colored.push_str(&line[span.clone()]);
+        colored.push_str(RESET);
+        end = span.end;
+    }
+    colored.push_str(&line[end..]);
+    colored
+}
+
+/// The line printed between groups of context, coloured like grep's
+pub fn separator(color: bool) -> String {
+    if color {
+        format!("{SEPARATOR_COLOR}--{RESET}")
+    } else {
+        "--".to_string()
+    }
+}
+
+/// What Context decided should be printed next
+#[derive(Debug, PartialEq)]
+pub enum Emit<T> {
+    /// "--" between two groups of lines that are not next to each other
+    Separator,
+    /// A line printed because it is near a match
+    Context(usize, T),
+    /// The matching line that was just pushed
+    Match(usize, T),
+}
+
+/// Decides which lines to print for -A/-B/-C.
+/// Lines are pushed one at a time, so it works on a stream: only the last `before` lines are kept.
+pub struct Context<T> {
+    before: usize,
+    after: usize,
+    buffer: VecDeque<(usize, T)>,
+    after_left: usize,
+    last_printed: Option<usize>,
+}
+
+impl<T> Context<T> {
+    pub fn new(before: usize, after: usize) -> Context<T> {
+        Context { before, after, buffer: VecDeque::new(), after_left: 0, last_printed: None }
+    }
+
+    /// Feeds the next line to the context window and calls emit for each line to print, in order
+    pub fn push(&mut self, number: usize, line: T, matched: bool, mut emit: impl FnMut(Emit<T>)) {
+        if matched {
+            // Without any context lines grep doesn't print separators, and neither do we
+            let first = self.buffer.front().map_or(number, |(first, _)| *first);
+            let has_context = self.before > 0 || self.after > 0;
+            if has_context && self.last_printed.is_some_and(|last| first > last + 1) {
+                emit(Emit::Separator);
+            }
+            for (number, line) in self.buffer.drain(..) {
+                emit(Emit::Context(number, line));
+            }
+            emit(Emit::Match(number, line));
+            self.after_left = self.after;
+            self.last_printed = Some(number);
+        } else if self.after_left > 0 {
+            emit(Emit::Context(number, line));
+            self.after_left -= 1;
+            self.last_printed = Some(number);
+        } else if self.before > 0 {
+            if self.buffer.len() == self.before {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back((number, line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_groups() {
+        let lines = ["a", "match", "b", "c", "d", "e", "match", "f"];
+        let mut context = Context::new(1, 1);
+        let mut printed = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            context.push(index + 1, *line, *line == "match", |emit| printed.push(emit));
+        }
+
+        assert_eq!(
+            vec![
+                Emit::Context(1, "a"),
+                Emit::Match(2, "match"),
+                Emit::Context(3, "b"),
+                Emit::Separator,
+                Emit::Context(6, "e"),
+                Emit::Match(7, "match"),
+                Emit::Context(8, "f"),
+            ],
+            printed
+        );
+    }
+
+    #[test]
+    fn highlights_spans() {
+        assert_eq!(
+            "\x1b[1;31mto\x1b[0m be or not \x1b[1;31mto\x1b[0m be",
+            highlight("to be or not to be", &[0..2, 13..15])
+        );
+    }
+}
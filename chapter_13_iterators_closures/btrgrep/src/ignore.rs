@@ -0,0 +1,219 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The names of the ignore files we read in every directory we walk into.
+// .gitignore is what most repos carry, .ignore lets users hide files from btrgrep only.
+pub const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+// A single line of an ignore file, e.g. "target/", "*.log" or "!keep.log"
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Rule {
+    // Returns None for blank lines and comments
+    fn parse(line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        // A leading backslash escapes a literal '#' or '!'
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        // A slash anywhere but the end ties the pattern to the directory of the ignore file,
+        // otherwise the pattern matches a file name at any depth.
+        let anchored = line.contains('/');
+        let pattern = line.strip_prefix('/').unwrap_or(line).to_string();
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(Rule { pattern, negated, dir_only, anchored })
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(self.pattern.as_bytes(), relative.as_bytes())
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            glob_match(self.pattern.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+/// The rules read from the ignore files of one directory.
+/// Patterns are matched against paths relative to that directory.
+#[derive(Debug, Clone)]
+pub struct IgnoreFile {
+    base: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl IgnoreFile {
+    /// Parses the contents of a .gitignore style file living in base
+    pub fn parse(base: &Path, contents: &str) -> IgnoreFile {
+        IgnoreFile {
+            base: base.to_path_buf(),
+            rules: contents.lines().filter_map(Rule::parse).collect(),
+        }
+    }
+
+    /// Reads every ignore file in dir and merges their rules.
+    /// Returns None when the directory has no rules so the walker doesn't carry empty sets around.
+    pub fn from_dir(dir: &Path) -> Option<IgnoreFile> {
+        let mut ignore = IgnoreFile { base: dir.to_path_buf(), rules: Vec::new() };
+        for name in IGNORE_FILES {
+            if let Ok(contents) = fs::read_to_string(dir.join(name)) {
+                ignore.rules.extend(contents.lines().filter_map(Rule::parse));
+            }
+        }
+        if ignore.rules.is_empty() { None } else { Some(ignore) }
+    }
+
+    /// Some(true) if the last matching rule ignores the path, Some(false) if it re-includes it
+    /// with a "!" rule, and None when no rule applies to it.
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?;
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(&relative, is_dir))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Checks a path against a stack of ignore files, outermost directory first.
+/// Deeper files take precedence, just like git.
+pub fn is_ignored(stack: &[IgnoreFile], path: &Path, is_dir: bool) -> bool {
+    stack
+        .iter()
+        .rev()
+        .find_map(|ignore| ignore.matched(path, is_dir))
+        .unwrap_or(false)
+}
+
+/// Matches text against a glob pattern.
+/// '*' and '?' never match a '/', "**" matches across directories and "[a-z]" / "[!abc]" are classes.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            match rest.strip_prefix(b"/") {
+                // "**/" matches zero or more whole directories
+                Some(rest) => {
+                    glob_match(rest, text)
+                        || text
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, byte)| **byte == b'/')
+                            .any(|(i, _)| glob_match(rest, &text[i + 1..]))
+                }
+                None => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+            }
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            let limit = text.iter().position(|byte| *byte == b'/').unwrap_or(text.len());
+            (0..=limit).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(b'?') => match text.first() {
+            Some(byte) if *byte != b'/' => glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => match (text.first(), class_end(pattern)) {
+            (Some(byte), Some(end)) => {
+                *byte != b'/'
+                    && class_matches(&pattern[1..end], *byte)
+                    && glob_match(&pattern[end + 1..], &text[1..])
+            }
+            // An unterminated class is just a literal '['
+            (Some(b'['), None) => glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..])
+        }
+        Some(literal) => text.first() == Some(literal) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+// Index of the ']' closing the class that starts at pattern[0]
+fn class_end(pattern: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    if matches!(pattern.get(i), Some(b'!') | Some(b'^')) {
+        i += 1;
+    }
+    // A ']' straight after the opening bracket is part of the class
+    if pattern.get(i) == Some(&b']') {
+        i += 1;
+    }
+    pattern[i..].iter().position(|byte| *byte == b']').map(|pos| pos + i)
+}
+
+fn class_matches(class: &[u8], byte: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!') | Some(b'^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            found |= class[i] <= byte && byte <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == byte;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match(b"*.log", b"error.log"));
+        assert!(!glob_match(b"*.log", b"logs/error.log"));
+        assert!(glob_match(b"**/*.log", b"error.log"));
+        assert!(glob_match(b"**/*.log", b"logs/2024/error.log"));
+        assert!(glob_match(b"logs/**", b"logs/2024/error.log"));
+        assert!(glob_match(b"file?.[a-c]", b"file1.b"));
+        assert!(!glob_match(b"file?.[!a-c]", b"file1.b"));
+    }
+
+    #[test]
+    fn last_rule_wins() {
+        let ignore = IgnoreFile::parse(Path::new("repo"), "\
+# build output
+*.log
+!keep.log
+/target/
+docs/*.tmp");
+
+        let check = |path: &str, is_dir| ignore.matched(&Path::new("repo").join(path), is_dir);
+        assert_eq!(check("a/error.log", false), Some(true));
+        assert_eq!(check("a/keep.log", false), Some(false));
+        assert_eq!(check("target", true), Some(true));
+        assert_eq!(check("target", false), None);
+        assert_eq!(check("a/target", true), None);
+        assert_eq!(check("docs/notes.tmp", false), Some(true));
+        assert_eq!(check("src/main.rs", false), None);
+    }
+}
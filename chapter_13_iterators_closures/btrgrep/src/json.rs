@@ -0,0 +1,134 @@
+use std::fmt;
+use std::time::Duration;
+
+// A small JSON serialiser, just enough for --json output.
+// Objects keep their keys in insertion order so the output matches ripgrep's field order.
+
+/// A JSON value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    /// Arbitrary bytes as ripgrep encodes them: {"text": "..."} when they are valid UTF-8,
+    /// otherwise {"bytes": "<base64>"} so nothing is lost or mangled
+    pub fn data(bytes: &[u8]) -> Json {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Json::Object(vec![("text", Json::String(text.to_string()))]),
+            Err(_) => Json::Object(vec![("bytes", Json::String(base64(bytes)))]),
+        }
+    }
+
+    /// {"secs": .., "nanos": .., "human": ".."}
+    pub fn duration(duration: Duration) -> Json {
+        Json::Object(vec![
+            ("secs", Json::Number(duration.as_secs())),
+            ("nanos", Json::Number(duration.subsec_nanos() as u64)),
+            ("human", Json::String(format!("{:.6}s", duration.as_secs_f64()))),
+        ])
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(value) => write!(f, "{value}"),
+            Json::Number(value) => write!(f, "{value}"),
+            Json::String(value) => write_string(f, value),
+            Json::Array(values) => {
+                f.write_str("[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+// Writes a quoted JSON string, escaping quotes, backslashes and control characters
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding (RFC 4648)
+pub fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for index in 0..4 {
+            if index <= chunk.len() {
+                let sextet = (triple >> (18 - 6 * index)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialises() {
+        let value = Json::Object(vec![
+            ("type", Json::String("match".to_string())),
+            ("text", Json::String("say \"hi\"\\\n\u{1}".to_string())),
+            ("list", Json::Array(vec![Json::Number(1), Json::Null, Json::Bool(true)])),
+        ]);
+        assert_eq!(
+            r#"{"type":"match","text":"say \"hi\"\\\n\u0001","list":[1,null,true]}"#,
+            value.to_string()
+        );
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9v", base64(b"foo"));
+        assert_eq!(r#"{"bytes":"/3RvCg=="}"#, Json::data(b"\xffto\n").to_string());
+    }
+}
@@ -1,6 +1,31 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
 use std::env;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+pub mod ignore;
+pub mod json;
+pub mod matcher;
+pub mod pool;
+pub mod replace;
+pub mod signal;
+pub mod walk;
+
+use json::Json;
+use matcher::Matcher;
+use output::{ColorChoice, Context, Emit};
+use pool::ThreadPool;
+use walk::WalkOptions;
+
+// Context and color work the same as in minigrep
+pub use minigrep::output;
 
 //This function borrows query and contents and returns a vector of &str types in contents' lifetime
 // The conntents of each &str is a slice of the original &str, so no data is duplicated.
@@ -11,68 +36,693 @@ pub fn search<'a>(
     query: &str,
     contents: &'a str
 ) -> Vec<&'a str> {
+    let matcher = Matcher::new(query, false);
     contents
         .lines()
-        .filter(|line| line.contains(query))
+        .filter(|line| matcher.is_match(line))
         .collect()
 }
 
 
-// Here we use filter() in conjunction with to_lowercase on both self/argument
+// Here we use filter() with a Matcher that folds the query once,
+// instead of calling to_lowercase on both the line and the query for every line
 pub fn search_case_insensitive<'a>(
     query: &str,
     contents: &'a str
 ) -> Vec<&'a str> {
+    let matcher = Matcher::new(query, true);
     contents
         .lines()
-        .filter(|line| line.to_lowercase().contains(&query.to_lowercase()))
+        .filter(|line| matcher.is_match(line))
         .collect()
 
 }
 
+// Same filter as search/search_case_insensitive, but enumerate() keeps the line numbers around
+// so run can print path:lineno: in front of each match. Line numbers start at 1 like grep's.
+pub fn search_numbered<'a>(
+    query: &str,
+    contents: &'a str,
+    ignore_case: bool
+) -> Vec<(usize, &'a str)> {
+    matching_lines(&Matcher::new(query, ignore_case), contents).collect()
+}
+
+// The lazy version of search_numbered. -l and -q only need to know there is a first match,
+// so they can stop reading the file there.
+fn matching_lines<'a>(
+    matcher: &Matcher,
+    contents: &'a str
+) -> impl Iterator<Item = (usize, &'a str)> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(move |(_, line)| matcher.is_match(line))
+        .map(|(index, line)| (index + 1, line))
+}
+
+// Matcher::find_spans for a line that may not be valid UTF-8.
+// Each valid run of text is searched on its own, so a stray invalid byte only stops
+// matches that would cross it. Offsets are into the raw bytes of line.
+pub fn find_spans_bytes(matcher: &Matcher, line: &[u8]) -> Vec<Range<usize>> {
+    if let Ok(text) = std::str::from_utf8(line) {
+        return matcher.find_spans(text);
+    }
+    let mut spans = Vec::new();
+    let mut offset = 0;
+    for chunk in line.utf8_chunks() {
+        let valid = chunk.valid();
+        spans.extend(
+            matcher
+                .find_spans(valid)
+                .into_iter()
+                .map(|span| span.start + offset..span.end + offset),
+        );
+        offset += valid.len() + chunk.invalid().len();
+    }
+    spans
+}
+
+// One line of a file with everything --json reports about it.
+// line still has its line terminator, absolute_offset is where it starts in the file
+// and spans are byte offsets into line. spans is empty when the line didn't match.
+#[derive(Debug, PartialEq)]
+pub struct SearchLine<'a> {
+    pub line_number: usize,
+    pub absolute_offset: usize,
+    pub line: &'a [u8],
+    pub spans: Vec<Range<usize>>,
+}
+
+// Splits raw file contents into lines and searches each one.
+// Unlike search this never converts the file to a String, so byte offsets stay exact.
+pub fn search_bytes<'a>(
+    query: &str,
+    contents: &'a [u8],
+    ignore_case: bool
+) -> impl Iterator<Item = SearchLine<'a>> {
+    search_bytes_with(Matcher::new(query, ignore_case), contents)
+}
+
+// search_bytes with a Matcher that has already been built, e.g. a regex
+pub fn search_bytes_with(
+    matcher: Matcher,
+    contents: &[u8]
+) -> impl Iterator<Item = SearchLine<'_>> {
+    let mut absolute_offset = 0;
+    contents
+        .split_inclusive(|byte| *byte == b'\n')
+        .enumerate()
+        .map(move |(index, line)| {
+            let text = line.strip_suffix(b"\n").unwrap_or(line);
+            let text = text.strip_suffix(b"\r").unwrap_or(text);
+            let spans = find_spans_bytes(&matcher, text);
+            let found = SearchLine { line_number: index + 1, absolute_offset, line, spans };
+            absolute_offset += line.len();
+            found
+        })
+}
+
+// Files with a NUL byte in them are treated as binary and skipped, the same heuristic grep uses
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0)
+}
+
 // run takes config, takes ownership of it, and returns a Result
 // By setting E=Box<dyn Error> we are allowing the error to take on any object
 // that implements the std::error::Error trait
 // This makes it easier to handle any error that might arise.
-pub fn run(config: Config) -> Result<(), Box<dyn Error>>{
-    let contents = fs::read_to_string(config.file_path)?;
-    //println!("With text:\n{contents}");
-    let results = if config.ignore_case {
-        search_case_insensitive(&config.query,&contents)
-    }
-    else{
-        search(&config.query, &contents)
+// On success the bool says whether anything matched, so main can exit with 1 like grep when nothing did.
+pub fn run(config: Config) -> Result<bool, Box<dyn Error>>{
+    let stdout = io::stdout();
+    search_paths(&config, &mut stdout.lock())
+}
+
+// What is printed for each file that matches
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputMode {
+    // Every matching line
+    #[default]
+    Lines,
+    // -l: only the path of the file
+    FilesWithMatches,
+    // -q: nothing at all, only the exit status
+    Quiet,
+    // --json: one JSON object per line describing each event, like ripgrep's --json
+    Json,
+}
+
+// Counters reported in --json end and summary messages
+#[derive(Debug, Clone, Copy, Default)]
+struct Stats {
+    elapsed: Duration,
+    searches: u64,
+    searches_with_match: u64,
+    bytes_searched: u64,
+    bytes_printed: u64,
+    matched_lines: u64,
+    matches: u64,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.elapsed += other.elapsed;
+        self.searches += other.searches;
+        self.searches_with_match += other.searches_with_match;
+        self.bytes_searched += other.bytes_searched;
+        self.bytes_printed += other.bytes_printed;
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+    }
+
+    fn to_json(self) -> Json {
+        Json::Object(vec![
+            ("elapsed", Json::duration(self.elapsed)),
+            ("searches", Json::Number(self.searches)),
+            ("searches_with_match", Json::Number(self.searches_with_match)),
+            ("bytes_searched", Json::Number(self.bytes_searched)),
+            ("bytes_printed", Json::Number(self.bytes_printed)),
+            ("matched_lines", Json::Number(self.matched_lines)),
+            ("matches", Json::Number(self.matches)),
+        ])
+    }
+}
+
+// The text one file contributes to the output and whether it matched at all
+struct FileOutput {
+    text: String,
+    matched: bool,
+    stats: Stats,
+}
+
+// Searches a single file and renders its part of the output.
+// This is what each worker runs in parallel mode, the main thread only prints.
+fn search_file(config: &Config, file: &Path, with_path: bool) -> io::Result<FileOutput> {
+    let bytes = fs::read(file)?;
+    let mut output = FileOutput { text: String::new(), matched: false, stats: Stats::default() };
+    // Binary files are skipped whole, which is also why --json end messages have no
+    // binary_offset like ripgrep's
+    if is_binary(&bytes) {
+        return Ok(output);
+    }
+    let rewrite = config.replace.as_ref().filter(|replace| replace.rewrites());
+    match (config.mode, rewrite) {
+        (OutputMode::Json, _) => render_json(config, file, &bytes, &mut output),
+        (_, Some(replace)) => rewrite_file(config, replace, file, &bytes, &mut output)?,
+        (OutputMode::Lines, None) => {
+            render_lines(config, file, with_path, &String::from_utf8_lossy(&bytes), &mut output)
+        }
+        (OutputMode::FilesWithMatches, None) => {
+            if matching_lines(&config.matcher, &String::from_utf8_lossy(&bytes)).next().is_some() {
+                output.matched = true;
+                output.text = format!("{}\n", file.display());
+            }
+        }
+        (OutputMode::Quiet, None) => {
+            output.matched = matching_lines(&config.matcher, &String::from_utf8_lossy(&bytes)).next().is_some();
+        }
+    }
+    Ok(output)
+}
+
+// What to do with --replace
+#[derive(Debug, Clone, Default)]
+pub struct Replace {
+    template: String,
+    // --in-place: rewrite the files instead of printing the replaced lines
+    in_place: bool,
+    // --dry-run: print a diff of what --in-place would change and leave the files alone
+    dry_run: bool,
+    // --backup: keep the original of every rewritten file as file.bak
+    backup: bool,
+}
+
+impl Replace {
+    fn rewrites(&self) -> bool {
+        self.in_place || self.dry_run
+    }
+}
+
+// Makes the replacement in every matching line of one file, keeping each line's own line ending,
+// then either writes the file back or renders the change as a diff for --dry-run.
+// Files that aren't valid UTF-8 are left alone, rewriting them lossily would corrupt them.
+fn rewrite_file(
+    config: &Config,
+    replace: &Replace,
+    file: &Path,
+    bytes: &[u8],
+    output: &mut FileOutput,
+) -> io::Result<()> {
+    let Ok(contents) = std::str::from_utf8(bytes) else {
+        eprintln!("btrgrep: {}: not valid UTF-8, skipping", file.display());
+        return Ok(());
     };
-    for line in results {
-        println!("{line}");
+    // Each line and what replaces it, kept apart for the diff
+    let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+    let mut replaced = Vec::with_capacity(lines.len());
+    for line in &lines {
+        let text = line.strip_suffix('\n').unwrap_or(line);
+        let text = text.strip_suffix('\r').unwrap_or(text);
+        match config.matcher.replace(text, &replace.template) {
+            Some(new) => {
+                output.matched = true;
+                replaced.push(new + &line[text.len()..]);
+            }
+            None => replaced.push(line.to_string()),
+        }
+    }
+    if lines.iter().zip(&replaced).all(|(line, new)| line == new) {
+        return Ok(());
+    }
+    if replace.dry_run {
+        output.text = replace::unified_diff(file, &lines, &replaced);
+        Ok(())
+    } else {
+        replace::write_atomic(file, replaced.concat().as_bytes(), replace.backup)
+    }
+}
+
+// Renders the matching lines of one file and the -A/-B/-C lines around them.
+// With a path, matches are printed as path:lineno:line and context lines as path-lineno-line like grep.
+// With --replace, matching lines are printed with the replacement made instead.
+fn render_lines(config: &Config, file: &Path, with_path: bool, contents: &str, output: &mut FileOutput) {
+    let template = config.replace.as_ref().map(|replace| replace.template.as_str());
+    let mut context = Context::new(config.before, config.after);
+    for (index, line) in contents.lines().enumerate() {
+        let spans = config.matcher.find_spans(line);
+        let matched = !spans.is_empty();
+        output.matched |= matched;
+        context.push(index + 1, (line, spans), matched, |emit| {
+            let (number, separator, text) = match emit {
+                Emit::Separator => {
+                    output.text.push_str(&output::separator(config.color));
+                    output.text.push('\n');
+                    return;
+                }
+                Emit::Context(number, (line, _)) => (number, '-', line.to_string()),
+                Emit::Match(number, (line, _)) if template.is_some() => {
+                    let replaced = config.matcher.replace(line, template.unwrap_or_default());
+                    (number, ':', replaced.unwrap_or_else(|| line.to_string()))
+                }
+                Emit::Match(number, (line, spans)) if config.color => {
+                    (number, ':', output::highlight(line, &spans))
+                }
+                Emit::Match(number, (line, _)) => (number, ':', line.to_string()),
+            };
+            if with_path {
+                output.text.push_str(&format!("{}{separator}{number}{separator}", file.display()));
+            }
+            output.text.push_str(&text);
+            output.text.push('\n');
+        });
+    }
+}
+
+// The path as it appears in JSON messages
+fn path_json(path: &Path) -> Json {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Json::data(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        Json::data(path.to_string_lossy().as_bytes())
+    }
+}
+
+// Renders one file as --json messages: begin, then match and context lines, then end with the
+// file's stats. Like ripgrep, files without a match print nothing, they only count in the summary.
+fn render_json(config: &Config, file: &Path, bytes: &[u8], output: &mut FileOutput) {
+    let start = Instant::now();
+    let path = path_json(file);
+    let mut context = Context::new(config.before, config.after);
+    let mut events = Vec::new();
+    for line in search_bytes_with(config.matcher.clone(), bytes) {
+        let matched = !line.spans.is_empty();
+        if matched {
+            output.stats.matched_lines += 1;
+            output.stats.matches += line.spans.len() as u64;
+        }
+        context.push(line.line_number, line, matched, |emit| {
+            let (kind, line) = match emit {
+                Emit::Separator => return,
+                Emit::Context(_, line) => ("context", line),
+                Emit::Match(_, line) => ("match", line),
+            };
+            let submatches = line
+                .spans
+                .iter()
+                .map(|span| {
+                    Json::Object(vec![
+                        ("match", Json::data(&line.line[span.clone()])),
+                        ("start", Json::Number(span.start as u64)),
+                        ("end", Json::Number(span.end as u64)),
+                    ])
+                })
+                .collect();
+            events.push(message(kind, vec![
+                ("path", path.clone()),
+                ("lines", Json::data(line.line)),
+                ("line_number", Json::Number(line.line_number as u64)),
+                ("absolute_offset", Json::Number(line.absolute_offset as u64)),
+                ("submatches", Json::Array(submatches)),
+            ]));
+        });
+    }
+
+    output.matched = output.stats.matched_lines > 0;
+    output.stats.searches = 1;
+    output.stats.searches_with_match = output.matched as u64;
+    output.stats.bytes_searched = bytes.len() as u64;
+    if output.matched {
+        output.text.push_str(&message("begin", vec![("path", path.clone())]));
+        for event in events {
+            output.text.push_str(&event);
+        }
+        output.stats.bytes_printed = output.text.len() as u64;
+        output.stats.elapsed = start.elapsed();
+        output.text.push_str(&message("end", vec![
+            ("path", path),
+            ("stats", output.stats.to_json()),
+        ]));
+    } else {
+        output.stats.elapsed = start.elapsed();
+    }
+}
+
+// One --json line: {"type": kind, "data": {...}}
+fn message(kind: &'static str, data: Vec<(&'static str, Json)>) -> String {
+    let message = Json::Object(vec![
+        ("type", Json::String(kind.to_string())),
+        ("data", Json::Object(data)),
+    ]);
+    format!("{message}\n")
+}
+
+// search_paths does the work of run but writes to any Write, so tests can capture the output.
+// When more than one file could match each line is prefixed with path:lineno:
+pub fn search_paths<W: Write>(config: &Config, out: &mut W) -> Result<bool, Box<dyn Error>> {
+    let files = walk::files(&config.paths, config.walk);
+    let with_path = config.paths.len() > 1
+        || config.paths.iter().any(|path| Path::new(path).is_dir());
+
+    if config.threads > 1 {
+        return search_parallel(config, files, with_path, out);
+    }
+    let mut printer = Printer::new(config, out);
+    for file in &files {
+        if signal::interrupted() {
+            return Err(INTERRUPTED.into());
+        }
+        printer.report(search_file(config, file, with_path), file)?;
+        if printer.matched && config.mode == OutputMode::Quiet {
+            break;
+        }
+    }
+    printer.finish()
+}
+
+// The error a search stopped by Ctrl-C returns
+const INTERRUPTED: &str = "interrupted";
+
+// The error a search returns when some file couldn't be searched, each already reported
+const UNSEARCHED: &str = "some files couldn't be searched";
+
+// Fans the files out over a ThreadPool of config.threads workers.
+// Workers send back (index, result) pairs in whatever order they finish, and the main thread
+// holds results in a BTreeMap until every earlier file has been printed, so the output is
+// identical to a sequential run.
+fn search_parallel<W: Write>(
+    config: &Config,
+    files: Vec<PathBuf>,
+    with_path: bool,
+    out: &mut W,
+) -> Result<bool, Box<dyn Error>> {
+    let shared = Arc::new(config.clone());
+    // Set once the answer is known (a worker found a match for -q) or printing failed.
+    // Queued jobs check it, and Ctrl-C, first and return without reading their file.
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let pool = ThreadPool::new(config.threads);
+    let (sender, receiver) = mpsc::channel();
+
+    for (index, file) in files.iter().enumerate() {
+        let (config, cancelled, sender) = (Arc::clone(&shared), Arc::clone(&cancelled), sender.clone());
+        let file = file.clone();
+        pool.execute(move || {
+            if cancelled.load(Ordering::Relaxed) || signal::interrupted() {
+                return;
+            }
+            // A panic is that file's error, so its place in the output isn't left waiting forever
+            let result = panic::catch_unwind(AssertUnwindSafe(|| search_file(&config, &file, with_path)))
+                .unwrap_or_else(|_| Err(io::Error::other("btrgrep panicked searching it")));
+            // -q needs no more than one match, whichever file it is in
+            if config.mode == OutputMode::Quiet && result.as_ref().is_ok_and(|output| output.matched) {
+                cancelled.store(true, Ordering::Relaxed);
+            }
+            // The receiver is gone if the main thread already returned, nothing left to do then
+            let _ = sender.send((index, result));
+        })?;
     }
-    Ok(())
+    // Drop our own sender so the loop below ends once every job has finished
+    drop(sender);
+
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    let mut printer = Printer::new(config, out);
+    for (index, result) in receiver {
+        // Files skipped since a match was found never arrive, so -q can't wait for them in order
+        if config.mode == OutputMode::Quiet && result.as_ref().is_ok_and(|output| output.matched) {
+            // Dropping the pool waits for the running jobs, the rest see cancelled and skip
+            drop(pool);
+            return Ok(true);
+        }
+        pending.insert(index, result);
+        while let Some(result) = pending.remove(&next) {
+            let printed = printer.report(result, &files[next]);
+            next += 1;
+            if printed.is_err() {
+                cancelled.store(true, Ordering::Relaxed);
+                drop(pool);
+                return printed.map(|_| printer.matched);
+            }
+        }
+    }
+    if signal::interrupted() {
+        return Err(INTERRUPTED.into());
+    }
+    printer.finish()
 }
 
+// Writes the output of each file in turn, keeping track of what has been printed so far
+struct Printer<'w, W> {
+    out: &'w mut W,
+    // With context lines on, the output of two files is separated by -- like two groups in one file
+    separator: Option<String>,
+    printed: bool,
+    matched: bool,
+    // Whether a file couldn't be searched, which makes the search fail once it's done
+    failed: bool,
+    // -q, where a match is all that counts even if some file failed, like grep
+    quiet: bool,
+    // Some when printing --json, to add up the stats of every file for the summary
+    stats: Option<Stats>,
+    started: Instant,
+}
+
+impl<'w, W: Write> Printer<'w, W> {
+    fn new(config: &Config, out: &'w mut W) -> Printer<'w, W> {
+        let context = config.mode == OutputMode::Lines && (config.before > 0 || config.after > 0);
+        let separator = context.then(|| output::separator(config.color));
+        let stats = (config.mode == OutputMode::Json).then(Stats::default);
+        Printer {
+            out,
+            separator,
+            printed: false,
+            matched: false,
+            failed: false,
+            quiet: config.mode == OutputMode::Quiet,
+            stats,
+            started: Instant::now(),
+        }
+    }
+
+    // Prints the --json summary, if there is one, and returns whether anything matched.
+    // Fails if a file couldn't be searched, after everything else has been.
+    fn finish(self) -> Result<bool, Box<dyn Error>> {
+        if let Some(stats) = self.stats {
+            let summary = message("summary", vec![
+                ("elapsed_total", Json::duration(self.started.elapsed())),
+                ("stats", stats.to_json()),
+            ]);
+            self.out.write_all(summary.as_bytes())?;
+        }
+        if self.failed && !(self.quiet && self.matched) {
+            return Err(UNSEARCHED.into());
+        }
+        Ok(self.matched)
+    }
+
+    // Prints the output of one file.
+    // A file that couldn't be read, whether named on the command line and missing or removed
+    // mid-walk, is reported with its path like walk_dir does, and the rest are still searched.
+    fn report(&mut self, result: io::Result<FileOutput>, file: &Path) -> Result<(), Box<dyn Error>> {
+        match result {
+            Ok(output) => {
+                self.matched |= output.matched;
+                if let Some(stats) = &mut self.stats {
+                    stats.add(&output.stats);
+                }
+                if output.text.is_empty() {
+                    return Ok(());
+                }
+                if let (Some(separator), true) = (&self.separator, self.printed) {
+                    writeln!(self.out, "{separator}")?;
+                }
+                self.out.write_all(output.text.as_bytes())?;
+                self.printed = true;
+            }
+            Err(err) => {
+                eprintln!("btrgrep: {}: {err}", file.display());
+                self.failed = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
-    query: String,
-    file_path: String,
-    ignore_case: bool
+    // Built from the query once, so a regex is only compiled here
+    matcher: Matcher,
+    paths: Vec<String>,
+    walk: WalkOptions,
+    mode: OutputMode,
+    threads: usize,
+    before: usize,
+    after: usize,
+    // Resolved from --color when the config is built, so workers don't each check for a terminal
+    color: bool,
+    replace: Option<Replace>,
 }
 
 impl Config {
     // We modify this to take a mut args iterator from env::args()
+    // Usage: btrgrep [-i|-S] [-E] [-l|-q|--json] [-j N] [-A N] [-B N] [-C N] [--color=WHEN]
+    //                [-r TEMPLATE [--in-place [--backup]] [--dry-run]]
+    //                [-L|--follow] [--no-ignore] [--] query path...
     pub fn build(
         mut args: impl Iterator<Item = String>
     ) -> Result<Config, &'static str> {
         args.next(); // Skip this value because its just the name of the binary
-        // Use matching on the Option<String> values to store the query, file_path vars
-        let query = match args.next(){
+        let mut ignore_case = env::var("IGNORE_CASE").is_ok();
+        let mut smart_case = false;
+        let mut walk = WalkOptions::default();
+        let mut mode = OutputMode::default();
+        let mut threads = 1;
+        let mut before = 0;
+        let mut after = 0;
+        let mut color = ColorChoice::default();
+        let mut regex = false;
+        let mut template = None;
+        let mut replace = Replace::default();
+        let mut positional = Vec::new();
+        let mut options_done = false;
+        while let Some(arg) = args.next() {
+            if options_done {
+                positional.push(arg);
+                continue;
+            }
+            match arg.as_str() {
+                "-i" | "--ignore-case" => ignore_case = true,
+                "-S" | "--smart-case" => smart_case = true,
+                "-L" | "--follow" => walk.follow_links = true,
+                "--no-follow" => walk.follow_links = false,
+                "--no-ignore" => walk.no_ignore = true,
+                "-l" | "--files-with-matches" => mode = OutputMode::FilesWithMatches,
+                "-q" | "--quiet" => mode = OutputMode::Quiet,
+                "--json" => mode = OutputMode::Json,
+                "-j" | "--threads" => threads = parse_threads(args.next())?,
+                _ if arg.starts_with("-j") => threads = parse_threads(Some(arg[2..].to_string()))?,
+                "-A" => after = parse_count(args.next())?,
+                "-B" => before = parse_count(args.next())?,
+                "-C" => {
+                    after = parse_count(args.next())?;
+                    before = after;
+                }
+                _ if arg.starts_with("--color=") => color = ColorChoice::parse(&arg[8..])?,
+                "-E" | "--regex" => regex = true,
+                "-r" | "--replace" => {
+                    template = Some(args.next().ok_or("--replace needs a replacement template!")?)
+                }
+                "--in-place" => replace.in_place = true,
+                "--dry-run" => replace.dry_run = true,
+                "--backup" => replace.backup = true,
+                // Everything after -- is a query or path, even if it starts with a dash
+                "--" => options_done = true,
+                _ if arg.starts_with('-') && arg != "-" => return Err("Unknown option!"),
+                _ => positional.push(arg),
+            }
+        }
+        let mut positional = positional.into_iter();
+        // Use matching on the Option<String> values to store the query, paths vars
+        let query = match positional.next(){
             Some(arg) => arg,
             None => return Err("Didn't receive a query string!")
         };
-        let file_path = match args.next(){
-            Some(arg) => arg,
-            None => return Err("Didn't get a file path!")
+        let paths: Vec<String> = positional.collect();
+        if paths.is_empty() {
+            return Err("Didn't get a file path!");
+        }
+
+        // -S turns case folding on only for all-lowercase queries, like ripgrep's --smart-case
+        let ignore_case = ignore_case || (smart_case && matcher::smart_case(&query));
+        let matcher = build_matcher(&query, ignore_case, regex)?;
+        let replace = match template {
+            Some(template) if !regex && !matcher::whole_match_only(&template) => {
+                return Err("Only $0 can be used in --replace without -E, there are no groups!")
+            }
+            // --json only reports matches, it has no message for a rewritten file
+            Some(_) if mode == OutputMode::Json && replace.rewrites() => {
+                return Err("--json can't be used with --in-place or --dry-run!")
+            }
+            Some(template) => Some(Replace { template, ..replace }),
+            None if replace.rewrites() || replace.backup => {
+                return Err("--in-place, --dry-run and --backup need --replace!")
+            }
+            None => None,
         };
-        let ignore_case = env::var("IGNORE_CASE").is_ok();
+        let color = color.enabled();
+        Ok(Config {matcher, paths, walk, mode, threads, before, after, color, replace})
+    }
+}
+
+// -E compiles the query as a regex, which needs btrgrep built with --features regex
+fn build_matcher(query: &str, ignore_case: bool, regex: bool) -> Result<Matcher, &'static str> {
+    if !regex {
+        return Ok(Matcher::new(query, ignore_case));
+    }
+    #[cfg(feature = "regex")]
+    {
+        Matcher::regex(query, ignore_case).map_err(|_| "The query isn't a valid regex!")
+    }
+    #[cfg(not(feature = "regex"))]
+    {
+        Err("-E needs btrgrep built with the regex feature!")
+    }
+}
 
-        Ok(Config {query, file_path, ignore_case})
+// The number of context lines after -A, -B or -C
+fn parse_count(arg: Option<String>) -> Result<usize, &'static str> {
+    arg.and_then(|arg| arg.parse().ok()).ok_or("Context options need a number of lines!")
+}
+
+// -j takes a thread count, -j 0 means one thread per CPU
+fn parse_threads(arg: Option<String>) -> Result<usize, &'static str> {
+    match arg.map(|arg| arg.parse::<usize>()) {
+        Some(Ok(0)) => Ok(std::thread::available_parallelism().map_or(1, |n| n.get())),
+        Some(Ok(threads)) => Ok(threads),
+        _ => Err("-j needs a number of threads!"),
     }
 }
 
@@ -124,4 +774,264 @@ Trust me.";
             search_case_insensitive(query, contents)
         );
     }
-}
\ No newline at end of file
+
+    // Builds a throwaway directory tree under the system temp dir for the walking tests
+    fn tree(name: &str, files: &[(&str, &[u8])]) -> std::path::PathBuf {
+        let root = env::temp_dir().join(format!("btrgrep-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        root
+    }
+
+    fn output(args: &[&str]) -> String {
+        let args = std::iter::once("btrgrep").chain(args.iter().copied()).map(String::from);
+        let config = Config::build(args).unwrap();
+        let mut out = Vec::new();
+        search_paths(&config, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn recursive_search() {
+        let root = tree("recursive", &[
+            ("b.txt", b"to be\nor not to be"),
+            ("a/z.txt", b"nothing here"),
+            ("a/c.txt", b"tomorrow"),
+            ("a/blob.bin", b"to\0be"),
+            ("build/out.txt", b"to be ignored"),
+            ("notes.log", b"to be ignored too"),
+            (".gitignore", b"build/\n*.log"),
+        ]);
+        let dir = root.display().to_string();
+
+        assert_eq!(
+            format!("{dir}/a/c.txt:1:tomorrow\n{dir}/b.txt:1:to be\n{dir}/b.txt:2:or not to be\n"),
+            output(&["to", &dir])
+        );
+        assert_eq!(
+            format!("{dir}/build/out.txt:1:to be ignored\n{dir}/notes.log:1:to be ignored too\n"),
+            output(&["--no-ignore", "ignored", &dir])
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_need_follow() {
+        let root = tree("symlinks", &[("real/poem.txt", b"to be")]);
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+        let dir = root.display().to_string();
+
+        assert_eq!(format!("{dir}/real/poem.txt:1:to be\n"), output(&["to", &dir]));
+        assert_eq!(
+            format!("{dir}/link/poem.txt:1:to be\n{dir}/real/poem.txt:1:to be\n"),
+            output(&["-L", "to", &dir])
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let names: Vec<String> = (0..40).map(|i| format!("dir{}/file{i:02}.txt", i % 3)).collect();
+        let files: Vec<(&str, &[u8])> = names
+            .iter()
+            .map(|name| (name.as_str(), b"to be\nnot this\nor not to be".as_slice()))
+            .collect();
+        let root = tree("parallel", &files);
+        let dir = root.display().to_string();
+
+        let sequential = output(&["to", &dir]);
+        assert_eq!(sequential.lines().count(), 80);
+        assert_eq!(sequential, output(&["-j", "4", "to", &dir]));
+        assert_eq!(output(&["-l", "to", &dir]), output(&["-l", "-j4", "to", &dir]));
+        assert_eq!(output(&["-l", "to", &dir]).lines().count(), 40);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn quiet_stops_at_first_match() {
+        let root = tree("quiet", &[("a.txt", b"to be"), ("b.txt", b"nothing")]);
+        let dir = root.display().to_string();
+        let config = |args: &[&str]| {
+            Config::build(["btrgrep"].iter().chain(args).map(|arg| arg.to_string())).unwrap()
+        };
+
+        let mut out = Vec::new();
+        assert!(search_paths(&config(&["-q", "-j", "2", "to", &dir]), &mut out).unwrap());
+        assert!(!search_paths(&config(&["-q", "missing", &dir]), &mut out).unwrap());
+        // Whichever worker finds a match answers, even with files before it not searched yet
+        assert!(search_paths(&config(&["-q", "-j", "2", "not", &dir]), &mut out).unwrap());
+        assert!(search_paths(&config(&["-q", "-j", "2", "be", &dir]), &mut out).unwrap());
+        assert!(out.is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn missing_paths_dont_stop_the_search() {
+        let root = tree("missing", &[("a.txt", b"to be")]);
+        let (missing, file) = (root.join("missing.txt").display().to_string(), root.join("a.txt").display().to_string());
+        let config = |args: &[&str]| {
+            Config::build(["btrgrep"].iter().chain(args).map(|arg| arg.to_string())).unwrap()
+        };
+
+        // The other paths are searched and printed, then the search fails
+        for threads in ["1", "2"] {
+            let mut out = Vec::new();
+            let result = search_paths(&config(&["-j", threads, "to", &missing, &file]), &mut out);
+            assert_eq!(Some(UNSEARCHED.to_string()), result.err().map(|err| err.to_string()));
+            assert_eq!(format!("{file}:1:to be\n"), String::from_utf8(out).unwrap());
+        }
+        assert!(search_paths(&config(&["to", &missing]), &mut Vec::new()).is_err());
+        // Like grep, -q only cares that something matched
+        assert!(search_paths(&config(&["-q", "to", &missing, &file]), &mut Vec::new()).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn context_and_color() {
+        let root = tree("context", &[
+            ("a.txt", b"one\ntwo\nthree\nfour\nfive\nTWO again\nseven"),
+            ("b.txt", b"zero\ntwo"),
+        ]);
+        let dir = root.display().to_string();
+
+        assert_eq!(
+            format!("\
+{dir}/a.txt-1-one
+{dir}/a.txt:2:two
+{dir}/a.txt-3-three
+--
+{dir}/a.txt-5-five
+{dir}/a.txt:6:TWO again
+{dir}/a.txt-7-seven
+--
+{dir}/b.txt-1-zero
+{dir}/b.txt:2:two
+"),
+            output(&["-C", "1", "--color=never", "-j", "2", "-i", "tWo", &dir])
+        );
+        assert_eq!(
+            "t\x1b[1;31mwo\x1b[0m\n",
+            output(&["--color=always", "wo", &format!("{dir}/b.txt")])
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn json_messages() {
+        let root = tree("json", &[("poem.txt", b"I'm nobody!\nAre you nobody, too?\n\xff nobody\n")]);
+        let file = format!("{}/poem.txt", root.display());
+        let path = format!(r#"{{"text":"{file}"}}"#);
+
+        let lines: Vec<String> = output(&["--json", "-A", "1", "you", &file])
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(5, lines.len());
+        assert_eq!(format!(r#"{{"type":"begin","data":{{"path":{path}}}}}"#), lines[0]);
+        assert_eq!(
+            format!(concat!(
+                r#"{{"type":"match","data":{{"path":{path},"lines":{{"text":"Are you nobody, too?\n"}},"#,
+                r#""line_number":2,"absolute_offset":12,"submatches":[{{"match":{{"text":"you"}},"start":4,"end":7}}]}}}}"#,
+            ), path = path),
+            lines[1]
+        );
+        assert_eq!(
+            format!(concat!(
+                r#"{{"type":"context","data":{{"path":{path},"lines":{{"bytes":"/yBub2JvZHkK"}},"#,
+                r#""line_number":3,"absolute_offset":33,"submatches":[]}}}}"#,
+            ), path = path),
+            lines[2]
+        );
+        assert!(lines[3].starts_with(r#"{"type":"end","#));
+        assert!(lines[3].contains(r#""searches":1,"searches_with_match":1,"bytes_searched":42,"#));
+        assert!(lines[4].starts_with(r#"{"type":"summary","#));
+        assert!(lines[4].ends_with(r#""matched_lines":1,"matches":1}}}"#));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn case_insensitive_non_ascii() {
+        let contents = "\
+Die Straße ist lang.
+DIE STRAẞE IST LANG.
+Die Strasse ist lang.
+ΟΔΟΣ οδος";
+
+        assert_eq!(
+            vec!["Die Straße ist lang.", "DIE STRAẞE IST LANG."],
+            search_case_insensitive("STRAßE", contents)
+        );
+        assert_eq!(vec!["ΟΔΟΣ οδος"], search_case_insensitive("οδοσ", contents));
+    }
+
+    #[test]
+    fn modes_agree_on_what_matches() {
+        let root = tree("agree", &[("a.txt", "İSTANBUL".as_bytes()), ("b.txt", "i̇stanbul".as_bytes()), ("c.txt", "ıstanbul".as_bytes())]);
+        let dir = root.display().to_string();
+        for query in ["İstanbul", "i̇stanbul", "istanbul", "ıstanbul"] {
+            let lines = output(&["-i", query, &dir]);
+            let mut files: Vec<&str> = lines.lines().filter_map(|line| line.split_once(':')).map(|(file, _)| file).collect();
+            files.sort();
+            let listed = output(&["-i", "-l", query, &dir]);
+            let mut listed: Vec<&str> = listed.lines().collect();
+            listed.sort();
+            assert_eq!(files, listed, "{query}");
+            let quiet = Config::build(["btrgrep", "-i", "-q", query, &dir].iter().map(|arg| arg.to_string())).unwrap();
+            assert_eq!(!files.is_empty(), search_paths(&quiet, &mut Vec::new()).unwrap());
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn smart_case() {
+        let root = tree("smart", &[("a.txt", b"Rust\nrust")]);
+        let file = format!("{}/a.txt", root.display());
+
+        assert_eq!("Rust\nrust\n", output(&["-S", "rust", &file]));
+        assert_eq!("Rust\n", output(&["-S", "Rust", &file]));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn replace_lines_and_files() {
+        let root = tree("replace", &[("a.txt", b"to be\r\nor not\nto be\n"), ("b.txt", b"nothing")]);
+        let dir = root.display().to_string();
+        let file = format!("{dir}/a.txt");
+
+        assert_eq!("[to] be\n[to] be\n", output(&["-r", "[$0]", "to", &file]));
+        assert_eq!(
+            format!("--- {file}\n+++ {file}\n@@ -1,3 +1,3 @@\n-to be\r\n+2 be\r\n or not\n-to be\n+2 be\n"),
+            output(&["-r", "2", "--dry-run", "to", &dir])
+        );
+        assert_eq!(b"to be\r\nor not\nto be\n".as_slice(), fs::read(&file).unwrap());
+
+        output(&["-r", "2", "--in-place", "--backup", "to", &dir]);
+        assert_eq!(b"2 be\r\nor not\n2 be\n".as_slice(), fs::read(&file).unwrap());
+        assert_eq!(b"to be\r\nor not\nto be\n".as_slice(), fs::read(format!("{file}.bak")).unwrap());
+        assert_eq!(b"nothing".as_slice(), fs::read(format!("{dir}/b.txt")).unwrap());
+        assert!(!Path::new(&format!("{dir}/b.txt.bak")).exists());
+
+        // A template with a newline in it adds lines
+        fs::write(&file, "one\ntwo\nthree").unwrap();
+        assert_eq!(
+            format!("--- {file}\n+++ {file}\n@@ -1,3 +1,4 @@\n one\n-two\n+X\n+Y\n three\n\\ No newline at end of file\n"),
+            output(&["-r", "X\nY", "--dry-run", "two", &file])
+        );
+        output(&["-r", "X\nY", "--in-place", "two", &file]);
+        assert_eq!("one\nX\nY\nthree", fs::read_to_string(&file).unwrap());
+        fs::remove_dir_all(root).unwrap();
+
+        let build = |args: &[&str]| Config::build(["btrgrep"].iter().chain(args).map(|arg| arg.to_string())).err();
+        assert_eq!(None, build(&["-r", "[$0] ${0} $$1", "to", "a.txt"]));
+        assert!(build(&["-r", "$1", "to", "a.txt"]).is_some());
+        assert!(build(&["-r", "${name}", "to", "a.txt"]).is_some());
+        assert!(build(&["--json", "-r", "x", "--in-place", "to", "a.txt"]).is_some());
+        assert!(build(&["-r", "x", "--dry-run", "--json", "to", "a.txt"]).is_some());
+        assert_eq!(None, build(&["--json", "-r", "x", "to", "a.txt"]));
+    }
+}
@@ -13,12 +13,20 @@ fn main() {
             }
         );
 
-    // Because run returns a () on success, which is the default output of main()
-    // We do not need to unwrap it on success. Instead we can just use the following to raise an error
 
-    if let Err(e) = btrgrep::run(config) {
-        eprintln!("Application error: {e}");
-        process::exit(1);
+    // Ctrl-C stops the search cleanly, the way a shell expects from a process it interrupted
+    btrgrep::signal::on_interrupt();
+
+    // Like grep we exit with 1 when nothing matched and 2 when something went wrong,
+    // which is what scripts checking -q rely on
+    match btrgrep::run(config) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(_) if btrgrep::signal::interrupted() => process::exit(130),
+        Err(e) => {
+            eprintln!("Application error: {e}");
+            process::exit(2);
+        }
     }
 
 }
@@ -0,0 +1,275 @@
+use std::ops::Range;
+
+// Case-insensitive search used to lowercase the query and every line with to_lowercase().
+// That allocates two Strings per line, and lowercasing isn't the right comparison anyway:
+// 'İ' lowercases to two chars ("i̇"), so byte offsets drift, and some chars that should be
+// equal ignoring case, like 'ς' and 'σ', have different lowercase forms.
+//
+// Instead the query is folded once when the Matcher is built, and each char of the line is
+// folded on the fly while comparing, which needs no allocation at all.
+
+/// Simple case folding: maps a char to the single char it is compared as when case is ignored.
+/// Unlike full folding this never turns one char into several, so 'ß' does not match "ss",
+/// 'ẞ' matches 'ß', and the Turkish dotted 'İ' and dotless 'ı' only match themselves.
+pub fn fold(c: char) -> char {
+    if c.is_ascii() {
+        return c.to_ascii_lowercase();
+    }
+    // Chars whose case folding is not their lowercase form, from Unicode's CaseFolding.txt
+    match c {
+        'µ' => return 'μ',
+        'ſ' => return 's',
+        'ς' => return 'σ',
+        'ϐ' => return 'β',
+        'ϑ' => return 'θ',
+        'ϕ' => return 'φ',
+        'ϖ' => return 'π',
+        'ϰ' => return 'κ',
+        'ϱ' => return 'ρ',
+        'ϵ' => return 'ε',
+        'ẛ' => return 'ṡ',
+        // GREEK PROSGEGRAMMENI folds to a plain iota
+        '\u{1FBE}' => return 'ι',
+        _ => {}
+    }
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(folded), None) => folded,
+        // Lowercases to more than one char (only 'İ'), which simple folding leaves alone
+        _ => c,
+    }
+}
+
+/// Smart case: ignore case only if the query has no uppercase letters in it
+pub fn smart_case(query: &str) -> bool {
+    !query.chars().any(char::is_uppercase)
+}
+
+/// Finds a literal query in lines, optionally ignoring case.
+/// With the regex feature it can also hold a regular expression, see Matcher::regex.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    query: String,
+    // The folded chars of the query when case is ignored
+    folded: Option<Vec<char>>,
+    #[cfg(feature = "regex")]
+    regex: Option<regex::Regex>,
+}
+
+impl Matcher {
+    pub fn new(query: &str, ignore_case: bool) -> Matcher {
+        let folded = ignore_case.then(|| query.chars().map(fold).collect());
+        Matcher {
+            query: query.to_string(),
+            folded,
+            #[cfg(feature = "regex")]
+            regex: None,
+        }
+    }
+
+    /// Treats the query as a regular expression, compiled once here.
+    /// The regex crate applies Unicode simple case folding itself when ignoring case.
+    #[cfg(feature = "regex")]
+    pub fn regex(pattern: &str, ignore_case: bool) -> Result<Matcher, regex::Error> {
+        let regex = regex::RegexBuilder::new(pattern).case_insensitive(ignore_case).build()?;
+        Ok(Matcher { query: pattern.to_string(), folded: None, regex: Some(regex) })
+    }
+
+    /// The line with every match replaced by template, or None if the line doesn't match.
+    /// $0 (or ${0}) in the template is the matched text and $$ is a literal $.
+    /// For a regex, $1 or ${name} are its capture groups as well; a literal query has no others.
+    pub fn replace(&self, line: &str, template: &str) -> Option<String> {
+        #[cfg(feature = "regex")]
+        if let Some(regex) = &self.regex {
+            return regex
+                .is_match(line)
+                .then(|| regex.replace_all(line, template).into_owned());
+        }
+        let spans = self.find_spans(line);
+        if spans.is_empty() {
+            return None;
+        }
+        let mut replaced = String::with_capacity(line.len());
+        let mut end = 0;
+        for span in spans {
+            replaced.push_str(&line[end..span.start]);
+            expand(template, &line[span.clone()], &mut replaced);
+            end = span.end;
+        }
+        replaced.push_str(&line[end..]);
+        Some(replaced)
+    }
+
+    /// Whether find_spans would find anything in line, without looking past the first match
+    pub fn is_match(&self, line: &str) -> bool {
+        self.find(line, 0).is_some()
+    }
+
+    /// Byte ranges of every non-overlapping occurrence of the query in line.
+    /// An empty query matches every line, with a single empty span at the start.
+    pub fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        #[cfg(feature = "regex")]
+        if let Some(regex) = &self.regex {
+            return regex.find_iter(line).map(|found| found.range()).collect();
+        }
+        if self.query.is_empty() {
+            return vec![Range { start: 0, end: 0 }];
+        }
+        let mut spans = Vec::new();
+        let mut from = 0;
+        while let Some(span) = self.find(line, from) {
+            from = span.end;
+            spans.push(span);
+        }
+        spans
+    }
+
+    // The first match starting at or after byte offset from, which every search goes through
+    // so is_match and find_spans can't disagree
+    fn find(&self, line: &str, from: usize) -> Option<Range<usize>> {
+        #[cfg(feature = "regex")]
+        if let Some(regex) = &self.regex {
+            return regex.find_at(line, from).map(|found| found.range());
+        }
+        match &self.folded {
+            None => line[from..]
+                .find(self.query.as_str())
+                .map(|start| from + start..from + start + self.query.len()),
+            Some(folded) if folded.is_empty() => Some(from..from),
+            Some(folded) => self.find_folded(folded, line, from),
+        }
+    }
+
+    // The first case-insensitive match starting at or after byte offset from
+    fn find_folded(&self, folded: &[char], line: &str, from: usize) -> Option<Range<usize>> {
+        let first = *folded.first()?;
+        line[from..]
+            .char_indices()
+            .filter(|(_, c)| fold(*c) == first)
+            .find_map(|(start, _)| {
+                let start = from + start;
+                let mut chars = line[start..].char_indices();
+                for expected in folded {
+                    match chars.next() {
+                        Some((_, c)) if fold(c) == *expected => {}
+                        _ => return None,
+                    }
+                }
+                let end = chars.next().map_or(line.len(), |(offset, _)| start + offset);
+                Some(start..end)
+            })
+    }
+}
+
+// A piece of a --replace template
+enum Piece<'a> {
+    Text(&'a str),
+    // $name or ${name}
+    Group(&'a str),
+}
+
+// Splits template at its $names. $$ is a literal $, and so is a $ with no name after it.
+fn pieces(template: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        pieces.push(Piece::Text(&rest[..dollar]));
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            pieces.push(Piece::Text("$"));
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{').and_then(|inner| inner.split_once('}')) {
+            Some((name, after)) => (name, after),
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        pieces.push(if name.is_empty() { Piece::Text("$") } else { Piece::Group(name) });
+        rest = after;
+    }
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+/// Whether template names no group but $0, the only one a literal query has
+pub fn whole_match_only(template: &str) -> bool {
+    pieces(template).iter().all(|piece| matches!(piece, Piece::Text(_) | Piece::Group("0")))
+}
+
+// Appends template to dst, with $0 and ${0} standing for the matched text.
+// Config::build turns away templates naming any other group.
+fn expand(template: &str, matched: &str, dst: &mut String) {
+    for piece in pieces(template) {
+        match piece {
+            Piece::Text(text) => dst.push_str(text),
+            Piece::Group("0") => dst.push_str(matched),
+            Piece::Group(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_non_ascii() {
+        let matcher = Matcher::new("straße", true);
+        assert_eq!(vec![4..12, 21..28], matcher.find_spans("die STRAẞE und die Straße"));
+        assert!(!matcher.is_match("STRASSE"));
+
+        let sigma = Matcher::new("ΟΔΟΣ", true);
+        assert!(sigma.is_match("οδος"));
+        assert!(sigma.is_match("οδοσ"));
+
+        // Turkish dotted and dotless i don't fold to the ASCII i
+        let turkish = Matcher::new("istanbul", true);
+        assert!(turkish.is_match("ISTANBUL"));
+        assert!(!turkish.is_match("İSTANBUL"));
+        assert!(!turkish.is_match("ıstanbul"));
+        assert_eq!(vec![3..9], Matcher::new("İzmir", true).find_spans("to İZMIR"));
+
+        // Every way of asking agrees
+        for (query, ignore_case) in [("İ", true), ("i̇", true), ("i", true), ("İ", false), ("", true)] {
+            let matcher = Matcher::new(query, ignore_case);
+            for line in ["İSTANBUL", "i̇stanbul", "istanbul", "ıstanbul", ""] {
+                assert_eq!(!matcher.find_spans(line).is_empty(), matcher.is_match(line), "{query} in {line}");
+            }
+        }
+    }
+
+    #[test]
+    fn smart_case_only_for_lowercase_queries() {
+        assert!(smart_case("rust"));
+        assert!(!smart_case("Rust"));
+        assert!(smart_case("ß"));
+        assert!(!smart_case("ẞ"));
+    }
+
+    #[test]
+    fn literal_replacement() {
+        let matcher = Matcher::new("rust", true);
+        assert_eq!(
+            Some("[Rust] and t[rust] cost $5".to_string()),
+            matcher.replace("Rust and trust cost $5", "[$0]")
+        );
+        assert_eq!(Some("x$y".to_string()), matcher.replace("rust", "x$$y"));
+        assert_eq!(None, matcher.replace("nothing here", "$0"));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_replacement() {
+        let matcher = Matcher::regex(r"(?P<key>\w+)=(\d+)", false).unwrap();
+        assert_eq!(
+            Some("set a to 1, b to 22".to_string()),
+            matcher.replace("set a=1, b=22", "${key} to $2")
+        );
+        assert_eq!(vec![4..7, 9..13], matcher.find_spans("set a=1, b=22"));
+    }
+}
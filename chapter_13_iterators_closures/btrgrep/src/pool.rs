@@ -0,0 +1,101 @@
+use std::{
+    error, fmt,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+// This is the same worker pool as hello::ThreadPool from chapter 20:
+// workers share one receiver behind a Mutex and pull boxed closures off it.
+// btrgrep hands each worker one file at a time.
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct Worker {
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+        let thread = thread::spawn(move || loop {
+            // The lock guard is dropped at the end of this statement, so other
+            // workers can take the next job while this one runs
+            let message = receiver.lock().unwrap().recv();
+            match message {
+                Ok(job) => job(),
+                Err(_) => break,
+            }
+        });
+        Worker { thread: Some(thread) }
+    }
+}
+
+/// A fixed size pool of threads that runs the closures given to execute
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    /// Creates a pool with size threads. Panics if size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0);
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size).map(|_| Worker::new(Arc::clone(&receiver))).collect();
+        ThreadPool { workers, sender: Some(sender) }
+    }
+
+    /// Queues f to run on a worker. Fails once every worker has died from a panicking job,
+    /// which drops the receiver, since then nothing would ever run it.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sender = self.sender.as_ref().ok_or(ExecuteError)?;
+        sender.send(Box::new(f)).map_err(|_| ExecuteError)
+    }
+}
+
+/// Why ThreadPool::execute couldn't queue a job: no worker is left to run it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExecuteError;
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("every worker in the thread pool has died")
+    }
+}
+
+impl error::Error for ExecuteError {}
+
+impl Drop for ThreadPool {
+    // Closing the channel lets the workers finish what is queued and exit
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                // A worker whose job panicked has already had its panic printed, and
+                // panicking again here, maybe while unwinding already, would abort
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("a bad file")).unwrap();
+        // Once the only worker is gone, nothing is left to run jobs
+        while pool.execute(|| {}).is_ok() {
+            thread::yield_now();
+        }
+        assert_eq!(Err(ExecuteError), pool.execute(|| {}));
+        drop(pool);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Helpers for --replace with --in-place and --dry-run.
+// Replacements are made one line at a time, so the diff is made from each old line and what
+// replaced it, which can be several lines when the template has a newline in it.
+
+/// Lines of unchanged text shown around each change, the same as diff -u
+pub const DIFF_CONTEXT: usize = 3;
+
+/// The path a --backup copy of path is written to
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+/// Replaces the file at path with contents so readers only ever see the old or the new file.
+/// The new contents go to a temporary file next to it, which is then renamed over the original,
+/// since a rename within one directory is atomic. A symlink is followed and its target rewritten.
+pub fn write_atomic(path: &Path, contents: &[u8], backup: bool) -> io::Result<()> {
+    let path = fs::canonicalize(path)?;
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file"));
+    };
+    let temp = dir.join(format!(".{}.btrgrep-{}", name.to_string_lossy(), process::id()));
+
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::set_permissions(&temp, fs::metadata(&path)?.permissions())?;
+        if backup {
+            fs::copy(&path, backup_path(&path))?;
+        }
+        fs::rename(&temp, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// A unified diff (diff -u) of a file made by replacing each of the old lines with the text
+/// at the same index in new, which can be any number of lines, none included.
+/// Returns an empty String when nothing changed.
+pub fn unified_diff(path: &Path, old: &[&str], new: &[String]) -> String {
+    let changed: Vec<usize> = (0..old.len()).filter(|index| old[*index] != new[*index]).collect();
+    if changed.is_empty() {
+        return String::new();
+    }
+    let new: Vec<Vec<&str>> = new.iter().map(|text| text.split_inclusive('\n').collect()).collect();
+
+    let mut diff = format!("--- {0}\n+++ {0}\n", path.display());
+    let mut index = 0;
+    while index < changed.len() {
+        // Grow the hunk while the next change is close enough for the context to overlap
+        let first = changed[index];
+        let mut last = first;
+        while index + 1 < changed.len() && changed[index + 1] <= last + 2 * DIFF_CONTEXT + 1 {
+            index += 1;
+            last = changed[index];
+        }
+        index += 1;
+
+        let start = first.saturating_sub(DIFF_CONTEXT);
+        let end = (last + DIFF_CONTEXT + 1).min(old.len());
+        // The new file's lines are counted through the replacements, which can add or remove some
+        let new_start: usize = new[..start].iter().map(Vec::len).sum();
+        let new_count: usize = new[start..end].iter().map(Vec::len).sum();
+        diff.push_str(&format!("@@ -{} +{} @@\n", range(start, end - start), range(new_start, new_count)));
+        let mut line = start;
+        while line < end {
+            if old[line] == new[line].concat() {
+                push_line(&mut diff, ' ', old[line]);
+                line += 1;
+                continue;
+            }
+            // A run of changed lines is shown as all the removals followed by all the additions
+            let run_end = (line..end).find(|next| old[*next] == new[*next].concat()).unwrap_or(end);
+            for removed in &old[line..run_end] {
+                push_line(&mut diff, '-', removed);
+            }
+            for added in new[line..run_end].iter().flatten() {
+                push_line(&mut diff, '+', added);
+            }
+            line = run_end;
+        }
+    }
+    diff
+}
+
+// A hunk's start and count, from the index of its first line. Like diff -u, an empty range
+// starts at the line before it and the count is left out when it's 1.
+fn range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+fn push_line(diff: &mut String, marker: char, line: &str) {
+    diff.push(marker);
+    diff.push_str(line);
+    if !line.ends_with('\n') {
+        diff.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_hunks() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\nold";
+        let new = "1\nTWO\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\nnew";
+
+        assert_eq!(
+            "\
+--- f.txt
++++ f.txt
+@@ -1,5 +1,5 @@
+ 1
+-2
++TWO
+ 3
+ 4
+ 5
+@@ -11,4 +11,4 @@
+ 11
+ 12
+ 13
+-old
+\\ No newline at end of file
++new
+\\ No newline at end of file
+",
+            diff(old, new)
+        );
+        assert_eq!("", diff(old, old));
+    }
+
+    // Diffs two files with the same number of lines
+    fn diff(old: &str, new: &str) -> String {
+        let old: Vec<&str> = old.split_inclusive('\n').collect();
+        let new: Vec<String> = new.split_inclusive('\n').map(String::from).collect();
+        unified_diff(Path::new("f.txt"), &old, &new)
+    }
+
+    #[test]
+    fn diff_replacements_that_add_and_remove_lines() {
+        let old = ["one\n", "two\n", "three\n"];
+        let new = ["one\n".to_string(), "X\nY\n".to_string(), "three\n".to_string()];
+        assert_eq!(
+            "--- f.txt\n+++ f.txt\n@@ -1,3 +1,4 @@\n one\n-two\n+X\n+Y\n three\n",
+            unified_diff(Path::new("f.txt"), &old, &new)
+        );
+
+        let new = ["".to_string(), "two\n".to_string(), "three\n".to_string()];
+        assert_eq!(
+            "--- f.txt\n+++ f.txt\n@@ -1,3 +1,2 @@\n-one\n two\n three\n",
+            unified_diff(Path::new("f.txt"), &old, &new)
+        );
+        let old = ["only"];
+        let new = ["".to_string()];
+        assert_eq!(
+            "--- f.txt\n+++ f.txt\n@@ -1 +0,0 @@\n-only\n\\ No newline at end of file\n",
+            unified_diff(Path::new("f.txt"), &old, &new)
+        );
+    }
+}
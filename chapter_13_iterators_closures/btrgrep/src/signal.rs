@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Ctrl-C handling without any dependencies.
+// A signal handler may only do async-signal-safe things, so it just sets a flag that searches
+// check between files. A second Ctrl-C exits at once, for when a file takes too long.
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::c_int;
+
+    pub const SIGINT: c_int = 2;
+
+    unsafe extern "C" {
+        // sighandler_t is a pointer-sized function pointer
+        pub fn signal(signum: c_int, handler: usize) -> usize;
+        pub fn _exit(status: c_int) -> !;
+    }
+
+    pub extern "C" fn handle(signum: c_int) {
+        if super::INTERRUPTED.swap(true, super::Ordering::SeqCst) {
+            // 128 + the signal number is what shells report for a process killed by it
+            unsafe { _exit(128 + signum) }
+        }
+    }
+}
+
+/// Makes SIGINT (Ctrl-C) stop the search after the files being searched, see interrupted.
+/// Only unix signals are supported; elsewhere Ctrl-C kills the process as usual.
+pub fn on_interrupt() {
+    #[cfg(unix)]
+    {
+        let handler = sys::handle as extern "C" fn(std::ffi::c_int) as usize;
+        // Safe because the handler only touches an atomic and calls _exit
+        unsafe {
+            sys::signal(sys::SIGINT, handler);
+        }
+    }
+}
+
+/// Whether Ctrl-C has been pressed since on_interrupt
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
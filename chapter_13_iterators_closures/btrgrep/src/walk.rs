@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ignore::{self, IgnoreFile};
+
+/// Options controlling how directories are walked
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WalkOptions {
+    /// Descend into symlinked directories and search symlinked files
+    pub follow_links: bool,
+    /// Skip reading .gitignore/.ignore files
+    pub no_ignore: bool,
+}
+
+/// Expands the paths given on the command line into the list of files to search.
+/// Files are returned in command line order, and the contents of each directory
+/// in sorted order, so the output is the same on every run.
+/// Paths named explicitly are always searched, even if they are symlinks or ignored.
+/// One that doesn't exist is kept too, so reading it fails and is reported in its place.
+pub fn files(paths: &[String], options: WalkOptions) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for path in paths {
+        let path = PathBuf::from(path);
+        // fs::metadata follows symlinks, which is what we want for explicit paths
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+            let mut ancestors = HashSet::new();
+            let mut stack = Vec::new();
+            walk_dir(&path, options, &mut stack, &mut ancestors, &mut found);
+        } else {
+            found.push(path);
+        }
+    }
+    found
+}
+
+// Recursively pushes every searchable file under dir onto found.
+// stack holds the ignore files of dir and its parents, ancestors the canonical paths of
+// the directories we are inside, so symlink loops can't send us round forever.
+fn walk_dir(
+    dir: &Path,
+    options: WalkOptions,
+    stack: &mut Vec<IgnoreFile>,
+    ancestors: &mut HashSet<PathBuf>,
+    found: &mut Vec<PathBuf>,
+) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(err) => {
+            eprintln!("btrgrep: {}: {err}", dir.display());
+            return;
+        }
+    };
+    // Only directories we are currently inside count, so two links to the same
+    // directory are both searched but a link back up to a parent is not.
+    let canonical = fs::canonicalize(dir).ok();
+    if let Some(canonical) = &canonical
+        && !ancestors.insert(canonical.clone())
+    {
+        return;
+    }
+    entries.sort();
+
+    let pushed = match (options.no_ignore, IgnoreFile::from_dir(dir)) {
+        (false, Some(ignore)) => {
+            stack.push(ignore);
+            true
+        }
+        _ => false,
+    };
+
+    for path in entries {
+        let Ok(link_meta) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        let is_link = link_meta.file_type().is_symlink();
+        if is_link && !options.follow_links {
+            continue;
+        }
+        // For a followed link we want to know what it points at. Dangling links are skipped.
+        let Ok(meta) = (if is_link { fs::metadata(&path) } else { Ok(link_meta) }) else {
+            continue;
+        };
+        if path.file_name().is_some_and(|name| name == ".git") {
+            continue;
+        }
+        if ignore::is_ignored(stack, &path, meta.is_dir()) {
+            continue;
+        }
+        if meta.is_dir() {
+            walk_dir(&path, options, stack, ancestors, found);
+        } else if meta.is_file() {
+            found.push(path);
+        }
+    }
+
+    if pushed {
+        stack.pop();
+    }
+    if let Some(canonical) = canonical {
+        ancestors.remove(&canonical);
+    }
+}
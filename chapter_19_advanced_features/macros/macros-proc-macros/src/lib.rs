@@ -1,6 +1,9 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, FnArg, Ident, ItemFn, LitStr, Token,
+};
 
 
 /// This is an example of a proc macro for deriving a trait from metadata about the struct
@@ -26,19 +29,67 @@ fn impl_hello_macro(ast: &syn::DeriveInput) -> TokenStream {
 }
 
 /// This is an example of an attribute-like macro
+/// #[route(GET, "/users/:id")] on fn show(request: &Request) -> Response keeps the function
+/// and adds a constant next to it that a router can register:
+/// pub const SHOW_ROUTE: (&str, &str, fn(&Request) -> Response) = ("GET", "/users/:id", show);
+/// The macro doesn't know about any router, so the function can have any signature.
 #[proc_macro_attribute]
 pub fn route(
     attr: TokenStream, // This is for the parameters GET and "/"
     item: TokenStream, // This is for the item under 
 ) -> TokenStream
 {
-    println!("attr: \"{attr}\"");
-    println!("item: \"{item}\"");
-    item
+    let RouteArgs { method, path } = parse_macro_input!(attr as RouteArgs);
+    let function = parse_macro_input!(item as ItemFn);
+
+    let name = &function.sig.ident;
+    let vis = &function.vis;
+    let constant = format_ident!("{}_ROUTE", name.to_string().to_uppercase());
+    let method = method.to_string();
+    let mut inputs = Vec::new();
+    for input in &function.sig.inputs {
+        match input {
+            FnArg::Typed(input) => inputs.push(&input.ty),
+            FnArg::Receiver(receiver) => {
+                return syn::Error::new_spanned(receiver, "a route can't be a method")
+                    .to_compile_error()
+                    .into();
+            }
+        }
+    }
+    let output = &function.sig.output;
+
+    let gen_ = quote! {
+        #function
+
+        #vis const #constant: (&str, &str, fn(#(#inputs),*) #output) = (#method, #path, #name);
+    };
+    gen_.into()
 }
 
-/// This is an example of a function like macro
+// The arguments of #[route]: a method name and a path pattern
+struct RouteArgs {
+    method: Ident,
+    path: LitStr,
+}
 
+impl Parse for RouteArgs {
+    fn parse(input: ParseStream) -> syn::Result<RouteArgs> {
+        let method: Ident = input.parse()?;
+        const METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "TRACE", "CONNECT"];
+        if !METHODS.contains(&method.to_string().as_str()) {
+            return Err(syn::Error::new(method.span(), "expected an HTTP method such as GET or POST"));
+        }
+        input.parse::<Token![,]>()?;
+        let path: LitStr = input.parse()?;
+        if !path.value().starts_with('/') {
+            return Err(syn::Error::new(path.span(), "a route path starts with '/'"));
+        }
+        Ok(RouteArgs { method, path })
+    }
+}
+
+/// This is an example of a function like macro
 #[proc_macro]
 pub fn sql(input: TokenStream) -> TokenStream {
     println!("Your SQL query: {input}");
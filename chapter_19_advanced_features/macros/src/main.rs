@@ -50,6 +50,10 @@ fn main() {
     println!("{:?}", v);
     Pancakes::hello_macro();
     macros::index(); // At compile time, the macro on this function will run
+    // The route macro also generated INDEX_ROUTE, which is what a router would register
+    let (method, path, handler) = macros::INDEX_ROUTE;
+    println!("{method} {path} is handled by macros::index");
+    handler();
 
     macros_proc_macros::sql!("SELECT * FROM FOO WHERE BAR"); // At compile time this will run
 }
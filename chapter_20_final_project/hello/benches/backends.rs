@@ -0,0 +1,147 @@
+// Compares the two ways a Server can serve its connections: a ThreadPool worker for each
+// open connection (Server::run) and one epoll loop handing requests to the pool
+// (Server::event_loop), under the same router, pool size and ConnectionOptions.
+//
+//     cargo bench --bench backends --features event-loop
+//
+// Clients are threads on the same machine making keep-alive requests one after another.
+// The thread per connection backend does well while there are no more connections than
+// workers; past that, connections wait for a worker, and idle ones hold workers until
+// they time out, which is where the event loop should pull ahead.
+
+use std::{
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::http::{Request, Response};
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server, ShutdownHandle};
+use hello::ThreadPool;
+
+const WORKERS: usize = 8;
+const REQUESTS: usize = 20_000;
+// (clients making requests, idle connections left open alongside them)
+const SCENARIOS: [(usize, usize); 4] = [(1, 0), (8, 0), (32, 0), (4, 32)];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Backend {
+    Threads,
+    EventLoop,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Threads => "thread per conn",
+            Backend::EventLoop => "event loop",
+        }
+    }
+}
+
+fn start(backend: Backend) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<()>) {
+    let mut router = Router::new();
+    router.get("/", |_: &Request| Response::new(200).body("Hello, world!"));
+    // Idle connections are let go after a second rather than five, so the thread per
+    // connection scenarios with idle connections finish in a reasonable time
+    let options = ConnectionOptions { idle_timeout: Duration::from_secs(1), ..ConnectionOptions::default() };
+    let pool = ThreadPool::builder().threads(WORKERS).build().unwrap();
+    let server = Server::bind_with_pool("127.0.0.1:0", router, pool, options).unwrap();
+    let address = server.local_addr();
+    let handle = server.shutdown_handle();
+    let running = match backend {
+        Backend::Threads => thread::spawn(move || drop(server.run(Duration::from_secs(5)))),
+        Backend::EventLoop => {
+            let server = server.event_loop().unwrap();
+            thread::spawn(move || drop(server.run(Duration::from_secs(5))))
+        }
+    };
+    (address, handle, running)
+}
+
+// A keep-alive connection that reconnects whenever the server closes it
+struct Client {
+    address: SocketAddr,
+    connection: Option<(TcpStream, BufReader<TcpStream>)>,
+}
+
+impl Client {
+    fn get(&mut self) {
+        let (stream, reader) = self.connection.get_or_insert_with(|| {
+            let stream = TcpStream::connect(self.address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            (stream, reader)
+        });
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n").unwrap();
+        let (mut length, mut close) = (0, false);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            let lower = line.to_ascii_lowercase();
+            if let Some(value) = lower.strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            close |= lower.starts_with("connection:") && lower.contains("close");
+            if line == "\r\n" {
+                break;
+            }
+        }
+        reader.read_exact(&mut vec![0; length]).unwrap();
+        if close {
+            self.connection = None;
+        }
+    }
+}
+
+// Requests per second and the slowest request
+fn measure(backend: Backend, clients: usize, idle: usize) -> (f64, Duration) {
+    let (address, handle, running) = start(backend);
+    let idle: Vec<TcpStream> = (0..idle).map(|_| TcpStream::connect(address).unwrap()).collect();
+    // Let the idle connections be accepted before timing starts
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    let slowest = thread::scope(|scope| {
+        let clients: Vec<_> = (0..clients)
+            .map(|_| {
+                scope.spawn(move || {
+                    let mut client = Client { address, connection: None };
+                    let mut slowest = Duration::ZERO;
+                    for _ in 0..REQUESTS / clients {
+                        let sent = Instant::now();
+                        client.get();
+                        slowest = slowest.max(sent.elapsed());
+                    }
+                    slowest
+                })
+            })
+            .collect();
+        clients.into_iter().map(|client| client.join().unwrap()).max().unwrap()
+    });
+    let rate = (REQUESTS / clients * clients) as f64 / start.elapsed().as_secs_f64();
+
+    drop(idle);
+    handle.shutdown();
+    running.join().unwrap();
+    (rate, slowest)
+}
+
+fn main() {
+    let mut rows = Vec::new();
+    for (clients, idle) in SCENARIOS {
+        for backend in [Backend::Threads, Backend::EventLoop] {
+            let (rate, slowest) = measure(backend, clients, idle);
+            rows.push((backend.name(), clients, idle, rate, slowest));
+        }
+    }
+
+    println!();
+    println!("{:<16} {:>7} {:>5} {:>12} {:>12}", "backend", "clients", "idle", "requests/s", "slowest");
+    for (backend, clients, idle, rate, slowest) in rows {
+        println!("{backend:<16} {clients:>7} {idle:>5} {rate:>12.0} {slowest:>12?}");
+    }
+}
@@ -0,0 +1,221 @@
+// Compares ThreadPool's work-stealing scheduler with the design it replaced, where every
+// worker waited on one Arc<Mutex<mpsc::Receiver<Job>>>, at several pool sizes.
+//
+//     cargo bench --bench pool
+//
+// There is no dependency on a benchmarking crate, so each scenario is just timed a few times
+// and the best run is kept. The pools print as their workers stop, and the results come in
+// one table at the end.
+
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::ThreadPool;
+
+const POOL_SIZES: [usize; 5] = [1, 2, 4, 8, 16];
+const RUNS: usize = 3;
+const THROUGHPUT_JOBS: usize = 200_000;
+const PRODUCERS: usize = 4;
+const LATENCY_JOBS: usize = 20_000;
+
+trait Pool: Sync {
+    const NAME: &'static str;
+    fn new(size: usize) -> Self;
+    fn run<F: FnOnce() + Send + 'static>(&self, f: F);
+}
+
+impl Pool for ThreadPool {
+    const NAME: &'static str = "work-stealing";
+
+    fn new(size: usize) -> ThreadPool {
+        ThreadPool::new(size)
+    }
+
+    fn run<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.execute(f).unwrap();
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// The old ThreadPool, less its panic handling, which doesn't change how jobs are queued
+struct ChannelPool {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Pool for ChannelPool {
+    const NAME: &'static str = "shared receiver";
+
+    fn new(size: usize) -> ChannelPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || {
+                    loop {
+                        let message = receiver.lock().unwrap().recv();
+                        match message {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    }
+                })
+            })
+            .collect();
+        ChannelPool { sender: Some(sender), workers }
+    }
+
+    fn run<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+// Counts jobs down to zero, so a scenario can wait for all of them
+struct Countdown {
+    remaining: AtomicUsize,
+    done: Mutex<bool>,
+    finished: Condvar,
+}
+
+impl Countdown {
+    fn new(jobs: usize) -> Arc<Countdown> {
+        Arc::new(Countdown { remaining: AtomicUsize::new(jobs), done: Mutex::new(false), finished: Condvar::new() })
+    }
+
+    fn count(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            *self.done.lock().unwrap() = true;
+            self.finished.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let _done = self.finished.wait_while(self.done.lock().unwrap(), |done| !*done).unwrap();
+    }
+}
+
+// A little work for a job to do, around a microsecond's worth
+fn work() {
+    let mut x = 0u64;
+    for i in 0..200 {
+        x = black_box(x.wrapping_mul(31).wrapping_add(i));
+    }
+    black_box(x);
+}
+
+// Jobs per second with every job queued from one thread
+fn throughput<P: Pool>(pool: &P) -> f64 {
+    let countdown = Countdown::new(THROUGHPUT_JOBS);
+    let start = Instant::now();
+    for _ in 0..THROUGHPUT_JOBS {
+        let countdown = Arc::clone(&countdown);
+        pool.run(move || {
+            work();
+            countdown.count();
+        });
+    }
+    countdown.wait();
+    THROUGHPUT_JOBS as f64 / start.elapsed().as_secs_f64()
+}
+
+// Jobs per second with PRODUCERS threads queuing at once, the way connections arrive
+fn contended<P: Pool>(pool: &P) -> f64 {
+    let countdown = Countdown::new(THROUGHPUT_JOBS);
+    let start = Instant::now();
+    thread::scope(|producers| {
+        for _ in 0..PRODUCERS {
+            producers.spawn(|| {
+                for _ in 0..THROUGHPUT_JOBS / PRODUCERS {
+                    let countdown = Arc::clone(&countdown);
+                    pool.run(move || {
+                        work();
+                        countdown.count();
+                    });
+                }
+            });
+        }
+    });
+    countdown.wait();
+    THROUGHPUT_JOBS as f64 / start.elapsed().as_secs_f64()
+}
+
+// The median and 99th percentile of how long jobs waited between being queued and starting,
+// for jobs queued a few microseconds apart
+fn latency<P: Pool>(pool: &P) -> (Duration, Duration) {
+    let countdown = Countdown::new(LATENCY_JOBS);
+    let waits: Arc<Vec<AtomicU64>> = Arc::new((0..LATENCY_JOBS).map(|_| AtomicU64::new(0)).collect());
+    for index in 0..LATENCY_JOBS {
+        let (countdown, waits) = (Arc::clone(&countdown), Arc::clone(&waits));
+        let queued = Instant::now();
+        pool.run(move || {
+            waits[index].store(queued.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            work();
+            countdown.count();
+        });
+        let pace = Instant::now();
+        while pace.elapsed() < Duration::from_micros(2) {
+            std::hint::spin_loop();
+        }
+    }
+    countdown.wait();
+    let mut waits: Vec<u64> = waits.iter().map(|wait| wait.load(Ordering::Relaxed)).collect();
+    waits.sort_unstable();
+    let percentile = |p: usize| Duration::from_nanos(waits[(waits.len() - 1) * p / 100]);
+    (percentile(50), percentile(99))
+}
+
+struct Row {
+    design: &'static str,
+    size: usize,
+    throughput: f64,
+    contended: f64,
+    p50: Duration,
+    p99: Duration,
+}
+
+fn measure<P: Pool>(size: usize) -> Row {
+    let pool = P::new(size);
+    let best = |scenario: fn(&P) -> f64| (0..RUNS).map(|_| scenario(&pool)).fold(0.0, f64::max);
+    let throughput = best(throughput);
+    let contended = best(contended);
+    let (p50, p99) = (0..RUNS).map(|_| latency(&pool)).min_by_key(|&(p50, _)| p50).unwrap();
+    Row { design: P::NAME, size, throughput, contended, p50, p99 }
+}
+
+fn main() {
+    let mut rows = Vec::new();
+    for size in POOL_SIZES {
+        rows.push(measure::<ChannelPool>(size));
+        rows.push(measure::<ThreadPool>(size));
+    }
+
+    println!();
+    println!(
+        "{:<16} {:>7} {:>14} {:>20} {:>12} {:>12}",
+        "design", "workers", "jobs/s", "jobs/s, 4 producers", "wait p50", "wait p99"
+    );
+    for row in rows {
+        println!(
+            "{:<16} {:>7} {:>14.0} {:>20.0} {:>12?} {:>12?}",
+            row.design, row.size, row.throughput, row.contended, row.p50, row.p99
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::scheduler::Scheduler;
+use crate::{spawn_worker, EventHook, PoolEvent, QueuePolicy, Shared, ThreadPool, Worker};
+
+/// Sets up a ThreadPool that starts min_threads workers and adds more, up to max_threads,
+/// while jobs are queued faster than the workers it has can take them. A worker above
+/// min_threads that goes keep_alive without a job stops again.
+///
+/// ```
+/// use std::time::Duration;
+/// use hello::{Builder, QueuePolicy};
+///
+/// let pool = Builder::new()
+///     .min_threads(2)
+///     .max_threads(16)
+///     .keep_alive(Duration::from_secs(30))
+///     .name_prefix("http")
+///     .capacity(1024)
+///     .policy(QueuePolicy::Reject)
+///     .build()
+///     .unwrap();
+/// pool.execute(|| println!("on a thread named http-0")).unwrap();
+/// ```
+pub struct Builder {
+    min_threads: usize,
+    max_threads: usize,
+    keep_alive: Duration,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    capacity: usize,
+    policy: QueuePolicy,
+    on_event: Option<EventHook>,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl Builder {
+    /// One thread growing to one per CPU, which stop after a minute idle, named worker-N,
+    /// with the default stack size and no limit on the queue
+    pub fn new() -> Builder {
+        Builder {
+            min_threads: 1,
+            max_threads: thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+            keep_alive: Duration::from_secs(60),
+            name_prefix: "worker".to_string(),
+            stack_size: None,
+            capacity: usize::MAX,
+            policy: QueuePolicy::Block,
+            on_event: None,
+        }
+    }
+
+    /// A fixed number of threads, both min_threads and max_threads
+    pub fn threads(self, threads: usize) -> Builder {
+        self.min_threads(threads).max_threads(threads)
+    }
+
+    /// Workers started straight away, which never retire. May be 0.
+    pub fn min_threads(mut self, min_threads: usize) -> Builder {
+        self.min_threads = min_threads;
+        self
+    }
+
+    pub fn max_threads(mut self, max_threads: usize) -> Builder {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// How long a worker above min_threads waits for a job before it stops
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Builder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Worker threads are named prefix-N, N being the worker's id
+    pub fn name_prefix(mut self, prefix: impl Into<String>) -> Builder {
+        self.name_prefix = prefix.into();
+        self
+    }
+
+    /// The stack size of each worker thread, in bytes
+    pub fn stack_size(mut self, stack_size: usize) -> Builder {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Most jobs waiting for a worker, past which policy applies
+    pub fn capacity(mut self, capacity: usize) -> Builder {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn policy(mut self, policy: QueuePolicy) -> Builder {
+        self.policy = policy;
+        self
+    }
+
+    /// Calls hook, on the worker's own thread, for whatever happens to a worker,
+    /// instead of printing it
+    pub fn on_event<F>(mut self, hook: F) -> Builder
+    where F: Fn(&PoolEvent) + Send + Sync + 'static
+    {
+        self.on_event = Some(Box::new(hook));
+        self
+    }
+
+    /// Starts min_threads workers. Fails with InvalidInput if max_threads or capacity is 0
+    /// or min_threads is more than max_threads, and with the error from the OS if a thread
+    /// can't be started.
+    pub fn build(self) -> io::Result<ThreadPool> {
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        if self.max_threads == 0 {
+            return invalid("max_threads must be at least 1");
+        }
+        if self.min_threads > self.max_threads {
+            return invalid("min_threads can't be more than max_threads");
+        }
+        if self.capacity == 0 {
+            return invalid("capacity must be at least 1");
+        }
+
+        let workers = (0..self.max_threads)
+            .map(|id| Worker { id, thread: Mutex::new(None), taken: AtomicBool::new(id < self.min_threads) })
+            .collect();
+        let shared = Arc::new(Shared {
+            scheduler: Scheduler::new(self.max_threads, self.capacity),
+            workers,
+            live: AtomicUsize::new(self.min_threads),
+            min_threads: self.min_threads,
+            keep_alive: self.keep_alive,
+            name_prefix: self.name_prefix,
+            stack_size: self.stack_size,
+            events: self.on_event,
+            policy: self.policy,
+            active: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            panics: AtomicUsize::new(0),
+            panic_hook: RwLock::new(None),
+            died: Mutex::new(Vec::new()),
+        });
+        // Built before starting any thread, so dropping it on an error stops the ones started
+        let pool = ThreadPool { shared };
+        for id in 0..self.min_threads {
+            spawn_worker(&pool.shared, id)?;
+        }
+        Ok(pool)
+    }
+}
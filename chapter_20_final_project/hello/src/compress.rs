@@ -0,0 +1,418 @@
+use crate::deflate::{self, Deflater};
+use crate::http::{Response, Version};
+
+// Compressed responses (RFC 9110 8.4): the gzip and deflate content codings, choosing one
+// from a request's Accept-Encoding, and which responses are worth compressing.
+//
+// Both codings are DEFLATE with a header and a checksum around it: gzip (RFC 1952) and,
+// despite its name, zlib (RFC 1950) for deflate. A large body is compressed a piece at a
+// time as it's written and sent chunked, since its compressed length isn't known until
+// the end; see Response::compress.
+
+/// A content coding for response bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The name in Content-Encoding and Accept-Encoding
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Compresses the whole of data
+    pub fn encode(self, data: &[u8]) -> Vec<u8> {
+        Encoder::new(self).finish(data)
+    }
+
+    /// Decompresses data, None if it isn't a valid stream of this coding
+    pub fn decode(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Encoding::Gzip => decode_gzip(data),
+            Encoding::Deflate => decode_zlib(data),
+        }
+    }
+}
+
+/// Compresses a body a piece at a time, each piece's output ready to send before the
+/// next is compressed
+pub struct Encoder {
+    encoding: Encoding,
+    deflater: Deflater,
+    started: bool,
+    // The running CRC-32 for gzip, or Adler-32 for deflate
+    checksum: u32,
+    length: u32,
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Encoder {
+        let checksum = match encoding {
+            Encoding::Gzip => 0,
+            Encoding::Deflate => 1,
+        };
+        Encoder { encoding, deflater: Deflater::new(), started: false, checksum, length: 0 }
+    }
+
+    /// The output for data, which follows whatever was written before
+    pub fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = self.start(data);
+        output.extend(self.deflater.write(data));
+        output
+    }
+
+    /// The output for the rest of the data and the end of the stream
+    pub fn finish(mut self, data: &[u8]) -> Vec<u8> {
+        let mut output = self.start(data);
+        output.extend(self.deflater.finish(data));
+        match self.encoding {
+            Encoding::Gzip => {
+                output.extend_from_slice(&self.checksum.to_le_bytes());
+                output.extend_from_slice(&self.length.to_le_bytes());
+            }
+            Encoding::Deflate => output.extend_from_slice(&self.checksum.to_be_bytes()),
+        }
+        output
+    }
+
+    // The header if nothing has been written yet, and data counted in the checksum
+    fn start(&mut self, data: &[u8]) -> Vec<u8> {
+        let header: &[u8] = match (self.started, self.encoding) {
+            (true, _) => &[],
+            // No file name or time, and an unknown operating system
+            (false, Encoding::Gzip) => &[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff],
+            // DEFLATE with a 32 KiB window, and a check that makes the two bytes a multiple of 31
+            (false, Encoding::Deflate) => &[0x78, 0x9c],
+        };
+        self.started = true;
+        self.checksum = match self.encoding {
+            Encoding::Gzip => crc32(self.checksum, data),
+            Encoding::Deflate => adler32(self.checksum, data),
+        };
+        // gzip keeps the length modulo 2^32
+        self.length = self.length.wrapping_add(data.len() as u32);
+        header.to_vec()
+    }
+}
+
+fn decode_gzip(data: &[u8]) -> Option<Vec<u8>> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    let header = data.get(..10)?;
+    if header[..3] != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    let flags = header[3];
+    let mut at = 10;
+    if flags & FEXTRA != 0 {
+        let length = u16::from_le_bytes([*data.get(at)?, *data.get(at + 1)?]);
+        at += 2 + length as usize;
+    }
+    // A file name and a comment, each ended with a zero byte
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            at += data.get(at..)?.iter().position(|&byte| byte == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        at += 2;
+    }
+    let (decoded, used) = deflate::inflate(data.get(at..)?)?;
+    let trailer = data.get(at + used..at + used + 8)?;
+    let checksum = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let length = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    (checksum == crc32(0, &decoded) && length == decoded.len() as u32).then_some(decoded)
+}
+
+fn decode_zlib(data: &[u8]) -> Option<Vec<u8>> {
+    const FDICT: u8 = 0x20;
+    let (method, flags) = (*data.first()?, *data.get(1)?);
+    if method & 0x0f != 8 || u16::from_be_bytes([method, flags]) % 31 != 0 || flags & FDICT != 0 {
+        return None;
+    }
+    let (decoded, used) = deflate::inflate(&data[2..])?;
+    let trailer = data.get(2 + used..2 + used + 4)?;
+    (u32::from_be_bytes(trailer.try_into().unwrap()) == adler32(1, &decoded)).then_some(decoded)
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+// The CRC-32 gzip uses, carried on from crc over more data, starting from 0
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!crc, |crc, &byte| CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8));
+    !crc
+}
+
+// Adler-32 for zlib, carried on from adler over more data, starting from 1
+fn adler32(adler: u32, data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    // The sums can't overflow a u32 in this many bytes before being reduced
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        (a, b) = (a % MOD, b % MOD);
+    }
+    b << 16 | a
+}
+
+/// The coding to answer with for an Accept-Encoding field: the one the client gives the
+/// highest weight, with gzip before deflate when they tie, or None if it accepts neither
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    // Weights in thousandths, by coding, with * for the codings not named
+    let mut weights: Vec<(String, u16)> = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let weight = parts.find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("q").then(|| q_value(value.trim()))
+        });
+        match weight.unwrap_or(Some(1000)) {
+            Some(weight) if !coding.is_empty() => weights.push((coding, weight)),
+            _ => {}
+        }
+    }
+    let weight_of = |encoding: Encoding| {
+        // x-gzip is an old name for gzip that's still treated as the same (RFC 9110 8.4.1.3)
+        let named = weights.iter().find(|(coding, _)| {
+            coding == encoding.name() || (encoding == Encoding::Gzip && coding == "x-gzip")
+        });
+        named.or_else(|| weights.iter().find(|(coding, _)| coding == "*")).map_or(0, |&(_, weight)| weight)
+    };
+    let mut best = None;
+    for encoding in [Encoding::Gzip, Encoding::Deflate] {
+        let weight = weight_of(encoding);
+        if weight > 0 && best.is_none_or(|(_, best)| weight > best) {
+            best = Some((encoding, weight));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+// A q value, 0 to 1 with up to three decimals, in thousandths
+fn q_value(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths: u16 = format!("{fraction:0<3}").parse().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Types compressed by default. Images, audio, video and archives other than SVG are
+/// already compressed.
+pub const DEFAULT_TYPES: [&str; 6] =
+    ["text/*", "application/javascript", "application/json", "application/xml", "application/wasm", "image/svg+xml"];
+
+/// Bodies larger than this are compressed as they're written and sent chunked, rather
+/// than all at once before the response goes out
+pub const STREAM_SIZE: usize = 64 * 1024;
+
+/// Which responses to compress: bodies of at least min_size bytes whose Content-Type is
+/// one of types, where a type may be written like text/* to take all of them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compression {
+    min_size: usize,
+    types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    /// Compresses bodies of 1 KiB or more with DEFAULT_TYPES. Smaller ones barely shrink
+    /// once the gzip header and checksum are added.
+    pub fn new() -> Compression {
+        Compression { min_size: 1024, types: DEFAULT_TYPES.iter().map(|&kind| kind.to_string()).collect() }
+    }
+
+    pub fn min_size(mut self, min_size: usize) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Replaces the types compressed
+    pub fn types<I, T>(mut self, types: I) -> Compression
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.types = types.into_iter().map(|kind| kind.into().to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Whether a body with this Content-Type is compressed, ignoring its parameters
+    pub fn compresses(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        self.types.iter().any(|kind| match kind.strip_suffix("/*") {
+            Some(top) => essence.split_once('/').is_some_and(|(other, _)| other == top),
+            None => *kind == essence,
+        })
+    }
+
+    /// Compresses response with the coding accept_encoding, a request's Accept-Encoding,
+    /// prefers, if it's worth compressing. Bodies over STREAM_SIZE to an HTTP/1.1 client
+    /// are compressed as they're written.
+    ///
+    /// A response that could have been compressed says Vary: Accept-Encoding whether it was
+    /// or not, so caches keep the two apart, and a strong ETag on a compressed one is made
+    /// weak, as the bytes are no longer the ones it was made for.
+    pub fn apply(&self, accept_encoding: Option<&str>, version: Version, response: &mut Response) {
+        let headers = &response.headers;
+        let skip = matches!(response.status, 100..=199 | 204 | 206 | 304)
+            || response.upgrade.is_some()
+            || response.compress.is_some()
+            || headers.contains("Content-Encoding")
+            || headers.contains("Content-Range")
+            || headers.has_token("Cache-Control", "no-transform")
+            || response.body.len() < self.min_size
+            || !headers.get("Content-Type").is_some_and(|content_type| self.compresses(content_type));
+        if skip {
+            return;
+        }
+        if !response.headers.has_token("Vary", "Accept-Encoding") {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+        let Some(encoding) = accept_encoding.and_then(negotiate) else { return };
+        if let Some(etag) = response.headers.get("ETag")
+            && !etag.starts_with("W/")
+        {
+            let weak = format!("W/{etag}");
+            response.headers.insert("ETag", weak);
+        }
+        // An HTTP/1.0 client can't take a chunked body, so needs its length up front
+        if version == Version::Http11 && response.body.len() > STREAM_SIZE {
+            response.compress = Some(encoding);
+        } else {
+            response.body = encoding.encode(&response.body);
+            response.headers.insert("Content-Encoding", encoding.name());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes() {
+        let page = "<li>An item in a long list</li>\n".repeat(1000);
+        for encoding in [Encoding::Gzip, Encoding::Deflate] {
+            let encoded = encoding.encode(page.as_bytes());
+            assert!(encoded.len() < page.len() / 10);
+            assert_eq!(Some(page.as_bytes().to_vec()), encoding.decode(&encoded));
+
+            // A piece at a time, as for a chunked response
+            let mut encoder = Encoder::new(encoding);
+            let mut streamed = Vec::new();
+            for piece in page.as_bytes().chunks(7000) {
+                streamed.extend(encoder.write(piece));
+            }
+            streamed.extend(encoder.finish(b""));
+            assert_eq!(Some(page.as_bytes().to_vec()), encoding.decode(&streamed));
+
+            let mut corrupt = encoded.clone();
+            *corrupt.last_mut().unwrap() ^= 1;
+            assert_eq!(None, encoding.decode(&corrupt));
+        }
+        assert_eq!(0xcbf43926, crc32(0, b"123456789"));
+        assert_eq!(0x091e01de, adler32(1, b"123456789"));
+
+        // "hello\n" from Python's gzip module, with a file name in the header
+        let gzip = [
+            0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, b'h', b'i', 0x00, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+            0xe7, 0x02, 0x00, 0x20, 0x30, 0x3a, 0x36, 0x06, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(Some(b"hello\n".to_vec()), Encoding::Gzip.decode(&gzip));
+    }
+
+    #[test]
+    fn negotiates_codings() {
+        assert_eq!(Some(Encoding::Gzip), negotiate("gzip, deflate, br"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("deflate, gzip"));
+        assert_eq!(Some(Encoding::Deflate), negotiate("gzip;q=0.5, deflate"));
+        assert_eq!(Some(Encoding::Deflate), negotiate("gzip;q=0, *"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("x-gzip"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("*;q=0.1"));
+        assert_eq!(Some(Encoding::Gzip), negotiate("GZIP ; Q=1.000"));
+        assert_eq!(None, negotiate("identity"));
+        assert_eq!(None, negotiate("br, *;q=0"));
+        assert_eq!(None, negotiate(""));
+        // A malformed weight drops the item rather than the whole field
+        assert_eq!(Some(Encoding::Deflate), negotiate("gzip;q=2, deflate"));
+    }
+
+    #[test]
+    fn compresses_what_is_worth_it() {
+        let compression = Compression::new().min_size(100);
+        let html = "<p>Hello!</p>".repeat(100);
+        let apply = |response: Response, accept: Option<&str>| {
+            let mut response = response;
+            compression.apply(accept, Version::Http11, &mut response);
+            response
+        };
+        let page = || Response::new(200).header("Content-Type", "text/html; charset=utf-8").header("ETag", "\"1\"").body(html.clone());
+
+        let gzipped = apply(page(), Some("gzip"));
+        assert_eq!(Some("gzip"), gzipped.headers.get("Content-Encoding"));
+        assert_eq!((Some("Accept-Encoding"), Some("W/\"1\"")), (gzipped.headers.get("Vary"), gzipped.headers.get("ETag")));
+        assert_eq!(Some(html.as_bytes().to_vec()), Encoding::Gzip.decode(&gzipped.body));
+
+        // Still varies on Accept-Encoding when it wasn't compressed
+        let plain = apply(page(), None);
+        assert_eq!((None, Some("Accept-Encoding")), (plain.headers.get("Content-Encoding"), plain.headers.get("Vary")));
+        assert_eq!(html.as_bytes(), plain.body);
+
+        let small = apply(Response::new(200).header("Content-Type", "text/plain").body("tiny"), Some("gzip"));
+        let image = apply(Response::new(200).header("Content-Type", "image/png").body(html.clone()), Some("gzip"));
+        let partial = Response { status: 206, ..page().header("Content-Range", "bytes 0-9/100") };
+        let partial = apply(partial, Some("gzip"));
+        for response in [small, image, partial] {
+            assert_eq!((None, None), (response.headers.get("Content-Encoding"), response.headers.get("Vary")));
+        }
+
+        // Large bodies are left to be compressed as they're written, except for HTTP/1.0
+        let large = Response::new(200).header("Content-Type", "application/json").body(vec![b' '; STREAM_SIZE + 1]);
+        let streamed = apply(large.clone(), Some("deflate"));
+        assert_eq!((Some(Encoding::Deflate), STREAM_SIZE + 1), (streamed.compress, streamed.body.len()));
+        let mut old = large;
+        compression.apply(Some("deflate"), Version::Http10, &mut old);
+        assert_eq!((None, Some("deflate")), (old.compress, old.headers.get("Content-Encoding")));
+
+        assert!(Compression::new().types(["text/html"]).compresses("TEXT/HTML; charset=utf-8"));
+        assert!(!Compression::new().types(["text/html"]).compresses("text/plain"));
+    }
+}
@@ -0,0 +1,996 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::compress::{self, Compression};
+use crate::http::Limits;
+use crate::log::LogFormat;
+use crate::server::ConnectionOptions;
+
+// The server's settings, from a TOML file and then command line flags, which win.
+//
+// There is no TOML crate, so the file is read with a parser for the part of TOML a config
+// like this needs: [tables], bare keys, "basic" and 'literal' strings, integers, booleans
+// and arrays of those, which may span lines, and # comments.
+//
+//     [server]
+//     bind = ["127.0.0.1", "::1"]
+//     port = 7878
+//
+//     [timeouts]
+//     idle = "5s"
+//
+// Every setting is checked when it's read, and the whole config once it's all read, and
+// errors say where the bad value came from: a line of the file or a flag.
+
+/// The config file read when no --config flag is given, if it exists
+pub const DEFAULT_FILE: &str = "hello.toml";
+
+/// What --help prints
+pub const USAGE: &str = "\
+Usage: hello [--config FILE] [OPTIONS]
+
+Settings come from FILE, or hello.toml if it exists, and then from the options,
+which override the file.
+
+Options:
+    --config FILE             Read settings from FILE
+    --bind ADDRESS            Listen on ADDRESS; repeat to listen on several [127.0.0.1]
+    --port PORT               Listen on PORT [7878]
+    --root DIR                Serve the files under DIR at /static/ [public]
+    --index FILE              Template for the page served at / [hello.html]
+    --error-page STATUS=FILE  Template for STATUS responses, e.g. 404=404.html
+    --dev BOOL                Reread templates when their files change [false]
+    --listing BOOL            List the files in directories under the root [false]
+    --workers N               Threads kept running [4]
+    --max-workers N           Threads started when connections pile up [16]
+    --queue N                 Connections waiting for a thread before 503s [256]
+    --idle-timeout TIME       Time a connection may sit between requests [5s]
+    --request-timeout TIME    Time a client has to send a whole request [10s]
+    --write-timeout TIME      Time a response may wait on a client to read it [10s]
+    --grace TIME              Time requests get to finish at shutdown [10s]
+    --max-requests N          Requests served on a connection before closing it [100]
+    --access-log FILE         Write an access log to FILE
+    --log-format FORMAT       common or combined [combined]
+    --log-max-size SIZE       Rotate the access log at SIZE, e.g. 10MiB [10MiB]
+    --log-keep N              Rotated access logs kept [5]
+    --compress BOOL           Send gzip or deflate bodies to clients that accept them [true]
+    --compress-min-size SIZE  Smallest body compressed [1KiB]
+    --compress-types TYPES    Comma-separated types compressed, like text/* [text/*,
+                              application/javascript, application/json,
+                              application/xml, application/wasm, image/svg+xml]
+    --help                    Print this
+
+TIME is a number of seconds or has a unit, as in 500ms, 10s or 2m.
+SIZE is a number of bytes or has a unit, as in 512KiB or 10MiB.
+";
+
+/// Why a config couldn't be read, and where the bad setting came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// A file and line, a file, or a flag
+    pub origin: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.origin, self.message)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Everything main needs to start a server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Addresses to listen on, all with port
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    /// Directory served at /static/
+    pub root: PathBuf,
+    /// Template for the page served at /
+    pub index: PathBuf,
+    /// Templates for error responses by status, see ErrorPages
+    pub error_pages: BTreeMap<u16, PathBuf>,
+    /// Development mode, where templates are read again when they change
+    pub dev: bool,
+    /// Whether a directory under the root without an index.html gets a listing of its files
+    pub listing: bool,
+    pub workers: usize,
+    pub max_workers: usize,
+    pub queue: usize,
+    pub idle_timeout: Duration,
+    pub request_timeout: Duration,
+    pub write_timeout: Duration,
+    /// How long requests being handled get to finish at shutdown
+    pub grace: Duration,
+    pub max_requests: usize,
+    pub access_log: Option<PathBuf>,
+    pub log_format: LogFormat,
+    pub log_max_size: u64,
+    pub log_keep: usize,
+    pub compress: bool,
+    pub compress_min_size: u64,
+    /// Media types compressed, see Compression::types
+    pub compress_types: Vec<String>,
+    // Where each setting that isn't a default came from, by key, for validate's errors
+    origins: HashMap<&'static str, String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let options = ConnectionOptions::default();
+        Config {
+            bind: vec![Ipv4Addr::LOCALHOST.into()],
+            port: 7878,
+            root: PathBuf::from("public"),
+            index: PathBuf::from("hello.html"),
+            error_pages: BTreeMap::from([(404, PathBuf::from("404.html"))]),
+            dev: false,
+            listing: false,
+            workers: 4,
+            max_workers: 16,
+            queue: 256,
+            idle_timeout: options.idle_timeout,
+            request_timeout: options.request_timeout,
+            write_timeout: options.write_timeout,
+            grace: Duration::from_secs(10),
+            max_requests: options.max_requests,
+            access_log: None,
+            log_format: LogFormat::Combined,
+            log_max_size: 10 * 1024 * 1024,
+            log_keep: 5,
+            compress: true,
+            compress_min_size: 1024,
+            compress_types: compress::DEFAULT_TYPES.iter().map(|&kind| kind.to_string()).collect(),
+            origins: HashMap::new(),
+        }
+    }
+}
+
+// The longest any timeout can be
+const MAX_TIMEOUT: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+// Every setting, by its key in the file and its flag
+const SETTINGS: [(&str, &str); 23] = [
+    ("server.bind", "--bind"),
+    ("server.port", "--port"),
+    ("server.root", "--root"),
+    ("server.index", "--index"),
+    ("server.max_requests", "--max-requests"),
+    ("server.dev", "--dev"),
+    ("server.listing", "--listing"),
+    ("error_pages", "--error-page"),
+    ("pool.workers", "--workers"),
+    ("pool.max_workers", "--max-workers"),
+    ("pool.queue", "--queue"),
+    ("timeouts.idle", "--idle-timeout"),
+    ("timeouts.request", "--request-timeout"),
+    ("timeouts.write", "--write-timeout"),
+    ("timeouts.grace", "--grace"),
+    ("log.access", "--access-log"),
+    ("log.format", "--log-format"),
+    ("log.max_size", "--log-max-size"),
+    ("log.keep", "--log-keep"),
+    ("compression.enabled", "--compress"),
+    ("compression.min_size", "--compress-min-size"),
+    ("compression.types", "--compress-types"),
+    ("config", "--config"),
+];
+
+// Settings whose flags take a path, which is never read as a number
+const PATHS: [&str; 3] = ["server.root", "server.index", "log.access"];
+
+impl Config {
+    /// Reads the file at path over the defaults, and validates the result.
+    /// Relative paths in the file are relative to the file's directory.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        config.read_file(path.as_ref())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The config for a command line, without the program name: the file given with
+    /// --config, or DEFAULT_FILE if there is one, then the other flags over it. Flags may
+    /// be written --flag value or --flag=value, and relative paths in them are relative
+    /// to the current directory.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), value.to_string()),
+                _ if arg.starts_with("--") => {
+                    let value = args.next().ok_or_else(|| flag_error(&arg, "needs a value"))?;
+                    (arg, value)
+                }
+                _ => return Err(flag_error(&arg, "unexpected argument, see --help")),
+            };
+            let key = SETTINGS
+                .iter()
+                .find(|(_, known)| *known == flag)
+                .map(|&(key, _)| key)
+                .ok_or_else(|| flag_error(&flag, "unknown option, see --help"))?;
+            flags.push((key, flag, value));
+        }
+
+        let mut config = Config::default();
+        match flags.iter().rfind(|(key, ..)| *key == "config") {
+            Some((_, _, file)) => config.read_file(Path::new(file))?,
+            None if Path::new(DEFAULT_FILE).exists() => config.read_file(Path::new(DEFAULT_FILE))?,
+            None => {}
+        }
+        let mut binds = Vec::new();
+        for (key, flag, raw) in flags {
+            let mut status = None;
+            let value = match key {
+                "config" => continue,
+                // Every --bind adds to the ones before, and together they replace the file's
+                "server.bind" => {
+                    binds.push(Value::String(raw));
+                    Value::Array(binds.clone())
+                }
+                "error_pages" => {
+                    let (code, file) = raw.split_once('=').ok_or_else(|| flag_error(&flag, "expected STATUS=FILE"))?;
+                    status = Some(code.to_string());
+                    Value::String(file.to_string())
+                }
+                _ if PATHS.contains(&key) => Value::String(raw),
+                _ if raw == "true" || raw == "false" => Value::Boolean(raw == "true"),
+                _ => raw.parse().map_or(Value::String(raw), Value::Integer),
+            };
+            config.set(key, status.as_deref(), &value, Path::new("")).map_err(|message| flag_error(&flag, &message))?;
+            config.origins.insert(key, flag);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let file = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|err| ConfigError { origin: file.clone(), message: err.to_string() })?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        let entries = parse(&text).map_err(|(line, message)| ConfigError { origin: format!("{file}:{line}"), message })?;
+        for entry in entries {
+            let origin = format!("{file}:{}", entry.line);
+            let (key, status) = match entry.table.as_str() {
+                "error_pages" => ("error_pages", Some(entry.key.as_str())),
+                table => {
+                    let name = if table.is_empty() { entry.key.clone() } else { format!("{table}.{}", entry.key) };
+                    // "config" is only a flag
+                    let key = SETTINGS
+                        .iter()
+                        .map(|&(key, _)| key)
+                        .find(|&key| key == name && key != "config")
+                        .ok_or_else(|| ConfigError { origin: origin.clone(), message: format!("unknown setting {name}") })?;
+                    (key, None)
+                }
+            };
+            self.set(key, status, &entry.value, base)
+                .map_err(|message| ConfigError { origin: origin.clone(), message: format!("{}: {message}", entry.key) })?;
+            self.origins.insert(key, origin);
+        }
+        Ok(())
+    }
+
+    // Sets the setting at key, or for error_pages the page for status, with relative paths
+    // taken from base
+    fn set(&mut self, key: &str, status: Option<&str>, value: &Value, base: &Path) -> Result<(), String> {
+        match key {
+            "server.bind" => {
+                self.bind = match value {
+                    Value::Array(values) => values.iter().map(address).collect::<Result<_, _>>()?,
+                    value => vec![address(value)?],
+                }
+            }
+            "server.port" => {
+                let port = integer(value)?;
+                self.port = u16::try_from(port).map_err(|_| format!("{port} isn't a port, which is 0 to 65535"))?;
+            }
+            "server.root" => self.root = base.join(string(value)?),
+            "server.index" => self.index = base.join(string(value)?),
+            "server.max_requests" => self.max_requests = count(value)?,
+            "server.dev" => self.dev = boolean(value)?,
+            "server.listing" => self.listing = boolean(value)?,
+            "error_pages" => {
+                let status = status.unwrap_or_default();
+                let code = status
+                    .parse()
+                    .ok()
+                    .filter(|code| (400..=599).contains(code))
+                    .ok_or_else(|| format!("{status} isn't an error status, which is 400 to 599"))?;
+                self.error_pages.insert(code, base.join(string(value)?));
+            }
+            "pool.workers" => self.workers = count(value)?,
+            "pool.max_workers" => self.max_workers = count(value)?,
+            "pool.queue" => self.queue = count(value)?,
+            "timeouts.idle" => self.idle_timeout = duration(value)?,
+            "timeouts.request" => self.request_timeout = duration(value)?,
+            "timeouts.write" => self.write_timeout = duration(value)?,
+            "timeouts.grace" => self.grace = duration(value)?,
+            "log.access" => self.access_log = Some(base.join(string(value)?)),
+            "log.format" => {
+                self.log_format = match string(value)? {
+                    "common" => LogFormat::Common,
+                    "combined" => LogFormat::Combined,
+                    format => return Err(format!("unknown format {format:?}, expected \"common\" or \"combined\"")),
+                }
+            }
+            "log.max_size" => self.log_max_size = size(value)?,
+            "log.keep" => self.log_keep = count(value)?,
+            "compression.enabled" => self.compress = boolean(value)?,
+            "compression.min_size" => self.compress_min_size = size(value)?,
+            // An array in the file, and a comma-separated list in the flag
+            "compression.types" => {
+                let types = match value {
+                    Value::Array(values) => values.iter().map(string).collect::<Result<Vec<_>, _>>()?,
+                    value => string(value)?.split(',').map(str::trim).filter(|kind| !kind.is_empty()).collect(),
+                };
+                if let Some(kind) = types.iter().find(|kind| !kind.split_once('/').is_some_and(|(a, b)| !a.is_empty() && !b.is_empty())) {
+                    return Err(format!("{kind:?} isn't a media type, like \"text/html\" or \"text/*\""));
+                }
+                self.compress_types = types.into_iter().map(str::to_string).collect();
+            }
+            _ => unreachable!("{key} isn't in SETTINGS"),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings make sense together and the files they name exist
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let fail = |key: &'static str, message: String| {
+            let origin = self.origins.get(key).cloned().unwrap_or_else(|| format!("default {key}"));
+            Err(ConfigError { origin, message })
+        };
+        if self.bind.is_empty() {
+            return fail("server.bind", "no address to listen on".to_string());
+        }
+        if !self.root.is_dir() {
+            return fail("server.root", format!("{} isn't a directory", self.root.display()));
+        }
+        if !self.index.is_file() {
+            return fail("server.index", format!("{} isn't a file", self.index.display()));
+        }
+        for page in self.error_pages.values() {
+            if !page.is_file() {
+                return fail("error_pages", format!("{} isn't a file", page.display()));
+            }
+        }
+        if self.workers == 0 {
+            return fail("pool.workers", "there must be at least 1 worker".to_string());
+        }
+        if self.max_workers < self.workers {
+            return fail(
+                "pool.max_workers",
+                format!("{} is fewer than the {} workers kept running", self.max_workers, self.workers),
+            );
+        }
+        if self.queue == 0 {
+            return fail("pool.queue", "the queue must have room for at least 1 connection".to_string());
+        }
+        let timeouts = [
+            ("timeouts.idle", self.idle_timeout),
+            ("timeouts.request", self.request_timeout),
+            ("timeouts.write", self.write_timeout),
+            ("timeouts.grace", self.grace),
+        ];
+        for (key, timeout) in timeouts {
+            if timeout.is_zero() && key != "timeouts.grace" {
+                return fail(key, "a timeout can't be 0".to_string());
+            }
+            // Deadlines are the time now plus a timeout, which can't be any time at all
+            if timeout > MAX_TIMEOUT {
+                return fail(key, format!("{}s is longer than a year", timeout.as_secs()));
+            }
+        }
+        if self.max_requests == 0 {
+            return fail("server.max_requests", "a connection must be allowed at least 1 request".to_string());
+        }
+        if self.access_log.is_some() && self.log_max_size == 0 {
+            return fail("log.max_size", "the access log can't rotate at 0 bytes".to_string());
+        }
+        Ok(())
+    }
+
+    /// Where to listen: every bind address with port
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.bind.iter().map(|&ip| SocketAddr::new(ip, self.port)).collect()
+    }
+
+    /// What to compress, None if compression is turned off
+    pub fn compression(&self) -> Option<Compression> {
+        let compression = Compression::new().min_size(self.compress_min_size as usize).types(&self.compress_types);
+        self.compress.then_some(compression)
+    }
+
+    pub fn connection_options(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            limits: Limits::default(),
+            idle_timeout: self.idle_timeout,
+            request_timeout: self.request_timeout,
+            write_timeout: self.write_timeout,
+            max_requests: self.max_requests,
+        }
+    }
+}
+
+fn flag_error(flag: &str, message: &str) -> ConfigError {
+    ConfigError { origin: flag.to_string(), message: message.to_string() }
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(string) => Ok(string),
+        value => Err(format!("expected a string, not {value}")),
+    }
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(integer) => Ok(*integer),
+        value => Err(format!("expected a whole number, not {value}")),
+    }
+}
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(boolean) => Ok(*boolean),
+        value => Err(format!("expected true or false, not {value}")),
+    }
+}
+
+fn count(value: &Value) -> Result<usize, String> {
+    let integer = integer(value)?;
+    usize::try_from(integer).map_err(|_| format!("{integer} is negative"))
+}
+
+fn address(value: &Value) -> Result<IpAddr, String> {
+    let address = string(value)?;
+    address.parse().map_err(|_| format!("{address:?} isn't an IP address, like \"127.0.0.1\" or \"::\""))
+}
+
+// Seconds, or a string with a unit: "500ms", "10s", "2m" or "1h"
+fn duration(value: &Value) -> Result<Duration, String> {
+    if let Value::Integer(_) = value {
+        return Ok(Duration::from_secs(count(value)? as u64));
+    }
+    let text = string(value)?;
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let invalid = || format!("{text:?} isn't a time, like \"500ms\", \"10s\" or \"2m\"");
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = |scale: u64| number.checked_mul(scale).map(Duration::from_secs).ok_or_else(invalid);
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" | "" => seconds(1),
+        "m" => seconds(60),
+        "h" => seconds(3600),
+        _ => Err(invalid()),
+    }
+}
+
+// Bytes, or a string with a unit: "512KiB", "10MiB" or "1GiB"
+fn size(value: &Value) -> Result<u64, String> {
+    if let Value::Integer(_) = value {
+        return Ok(count(value)? as u64);
+    }
+    let text = string(value)?;
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let invalid = || format!("{text:?} isn't a size, like \"512KiB\" or \"10MiB\"");
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let scale: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1024,
+        "MiB" => 1024 * 1024,
+        "GiB" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(scale).ok_or_else(invalid)
+}
+
+/// A value in a config file
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(string) => write!(f, "the string {string:?}"),
+            Value::Integer(integer) => write!(f, "the number {integer}"),
+            Value::Boolean(boolean) => write!(f, "{boolean}"),
+            Value::Array(_) => write!(f, "an array"),
+        }
+    }
+}
+
+// key = value, in [table] from line
+#[derive(Debug, PartialEq)]
+struct Entry {
+    line: usize,
+    table: String,
+    key: String,
+    value: Value,
+}
+
+// Reads the TOML subset described at the top, failing with a line number and message
+fn parse(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut parser = Parser { chars: text.chars().collect(), at: 0, line: 1 };
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut tables = vec![String::new()];
+    let mut table = String::new();
+    loop {
+        parser.skip_blank(true);
+        match parser.peek() {
+            None => return Ok(entries),
+            Some('[') => {
+                parser.at += 1;
+                parser.skip_blank(false);
+                let name = parser.key()?;
+                parser.skip_blank(false);
+                parser.expect(']')?;
+                parser.end_of_line()?;
+                if tables.contains(&name) {
+                    return Err((parser.line, format!("[{name}] appears twice")));
+                }
+                tables.push(name.clone());
+                table = name;
+            }
+            Some(_) => {
+                let line = parser.line;
+                let key = parser.key()?;
+                parser.skip_blank(false);
+                parser.expect('=')?;
+                parser.skip_blank(false);
+                let value = parser.value()?;
+                parser.end_of_line()?;
+                if entries.iter().any(|entry| entry.table == table && entry.key == key) {
+                    return Err((line, format!("{key} is set twice")));
+                }
+                entries.push(Entry { line, table: table.clone(), key, value });
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    at: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).copied()
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, (usize, String)> {
+        Err((self.line, message.into()))
+    }
+
+    // Skips spaces, tabs and comments, and newlines too if newlines is set
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.at += 1,
+                '\n' if newlines => {
+                    self.at += 1;
+                    self.line += 1;
+                }
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.at += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), (usize, String)> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.at += 1;
+                Ok(())
+            }
+            Some(c) => self.error(format!("expected {expected:?}, found {c:?}")),
+            None => self.error(format!("expected {expected:?}, found the end of the file")),
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), (usize, String)> {
+        self.skip_blank(false);
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some(c) => self.error(format!("expected the end of the line, found {c:?}")),
+        }
+    }
+
+    // A bare key: letters, digits, _ and -
+    fn key(&mut self) -> Result<String, (usize, String)> {
+        let start = self.at;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            self.at += 1;
+        }
+        if self.at == start {
+            return match self.peek() {
+                Some(c) => self.error(format!("expected a key, found {c:?}")),
+                None => self.error("expected a key, found the end of the file"),
+            };
+        }
+        Ok(self.chars[start..self.at].iter().collect())
+    }
+
+    fn value(&mut self) -> Result<Value, (usize, String)> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some(c) if c.is_ascii_alphanumeric() || c == '+' || c == '-' => {
+                let start = self.at;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || "+-_.:".contains(c)) {
+                    self.at += 1;
+                }
+                let word: String = self.chars[start..self.at].iter().collect();
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => integer_literal(&word)
+                        .map(Value::Integer)
+                        .ok_or((self.line, format!("{word} isn't a value this config understands; strings need quotes"))),
+                }
+            }
+            Some(c) => self.error(format!("expected a value, found {c:?}")),
+            None => self.error("expected a value, found the end of the file"),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, (usize, String)> {
+        self.at += 1;
+        let mut string = String::new();
+        loop {
+            match self.peek() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('"') => {
+                    self.at += 1;
+                    return Ok(string);
+                }
+                Some('\\') => {
+                    self.at += 1;
+                    let escaped = match self.peek() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some(c @ ('u' | 'U')) => {
+                            let digits = if c == 'u' { 4 } else { 8 };
+                            let hex: String = self.chars.iter().skip(self.at + 1).take(digits).collect();
+                            self.at += digits;
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .filter(|_| hex.len() == digits)
+                                .and_then(char::from_u32)
+                                .ok_or((self.line, format!("\\{c}{hex} isn't a character")))?
+                        }
+                        Some(c) => return self.error(format!("unknown escape \\{c}")),
+                        None => return self.error("unterminated string"),
+                    };
+                    self.at += 1;
+                    string.push(escaped);
+                }
+                Some(c) => {
+                    self.at += 1;
+                    string.push(c);
+                }
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, (usize, String)> {
+        self.at += 1;
+        let start = self.at;
+        loop {
+            match self.peek() {
+                None | Some('\n') => return self.error("unterminated string"),
+                Some('\'') => {
+                    self.at += 1;
+                    return Ok(self.chars[start..self.at - 1].iter().collect());
+                }
+                Some(_) => self.at += 1,
+            }
+        }
+    }
+
+    // [a, b, c], over as many lines as it likes, with an optional trailing comma
+    fn array(&mut self) -> Result<Value, (usize, String)> {
+        self.at += 1;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank(true);
+            if self.peek() == Some(']') {
+                self.at += 1;
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank(true);
+            match self.peek() {
+                Some(',') => self.at += 1,
+                Some(']') => {}
+                Some(c) => return self.error(format!("expected ',' or ']' in the array, found {c:?}")),
+                None => return self.error("unterminated array"),
+            }
+        }
+    }
+}
+
+// An integer the way TOML writes them: an optional sign, and _ only between digits
+fn integer_literal(word: &str) -> Option<i64> {
+    let digits = word.strip_prefix(['+', '-']).unwrap_or(word);
+    let valid = !digits.is_empty()
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '_')
+        && !digits.starts_with('_')
+        && !digits.ends_with('_')
+        && !digits.contains("__")
+        && (digits.len() == 1 || !digits.starts_with('0'));
+    if !valid {
+        return None;
+    }
+    word.replace('_', "").parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn value(text: &str) -> Result<Value, (usize, String)> {
+        parse(&format!("key = {text}")).map(|mut entries| entries.remove(0).value)
+    }
+
+    #[test]
+    fn parses_the_toml_subset() {
+        let text = "\
+# A comment
+top = 1
+
+[server]
+bind = [
+    \"127.0.0.1\", # loopback
+    '::1',
+]
+port = 8_080 # trailing comment
+quoted = \"tab\\there \\\"quoted\\\" \\u00e9\"
+on = true
+
+[error_pages]
+404 = 'C:\\pages\\404.html'
+";
+        let entries = parse(text).unwrap();
+        let found: Vec<(usize, &str, &str, &Value)> =
+            entries.iter().map(|entry| (entry.line, &entry.table[..], &entry.key[..], &entry.value)).collect();
+        assert_eq!(
+            vec![
+                (2, "", "top", &Value::Integer(1)),
+                (
+                    5,
+                    "server",
+                    "bind",
+                    &Value::Array(vec![Value::String("127.0.0.1".to_string()), Value::String("::1".to_string())])
+                ),
+                (9, "server", "port", &Value::Integer(8080)),
+                (10, "server", "quoted", &Value::String("tab\there \"quoted\" é".to_string())),
+                (11, "server", "on", &Value::Boolean(true)),
+                (14, "error_pages", "404", &Value::String("C:\\pages\\404.html".to_string())),
+            ],
+            found
+        );
+
+        assert_eq!(Ok(Value::Integer(-5)), value("-5"));
+        assert_eq!(Ok(Value::Array(vec![])), value("[]"));
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_their_line() {
+        let error = |text: &str| parse(text).unwrap_err();
+        assert_eq!((1, "unterminated string".to_string()), error("key = \"open"));
+        assert_eq!(3, error("a = 1\n\nb = 0x10").0);
+        assert_eq!(2, error("a = 1\na = 2").0);
+        assert_eq!(1, error("a = 1 b").0);
+        assert_eq!(3, error("[t]\n[u]\n[t]").0);
+        assert_eq!(1, error("key = unquoted").0);
+        assert_eq!(2, error("key = [1,\n2 3]").0);
+        assert!(error("= 1").1.contains("expected a key"));
+        assert!(value("01").is_err());
+        assert!(value("1__0").is_err());
+    }
+
+    #[test]
+    fn parses_times_and_sizes() {
+        let text = |text: &str| Value::String(text.to_string());
+        assert_eq!(Ok(Duration::from_secs(7)), duration(&Value::Integer(7)));
+        assert_eq!(Ok(Duration::from_millis(500)), duration(&text("500ms")));
+        assert_eq!(Ok(Duration::from_secs(120)), duration(&text("2m")));
+        assert!(duration(&text("soon")).is_err());
+        assert!(duration(&Value::Integer(-1)).is_err());
+        assert_eq!(Ok(10 * 1024 * 1024), size(&text("10MiB")));
+        assert_eq!(Ok(100), size(&Value::Integer(100)));
+        assert!(size(&text("10MB")).is_err());
+    }
+
+    // A directory with the files a config names, removed when dropped
+    struct Site(PathBuf);
+
+    impl Site {
+        fn new(name: &str) -> Site {
+            let dir = env::temp_dir().join(format!("hello-config-{name}-{}", process::id()));
+            fs::create_dir_all(dir.join("public")).unwrap();
+            for file in ["hello.html", "404.html", "500.html"] {
+                fs::write(dir.join(file), "<p>{{status}}</p>").unwrap();
+            }
+            Site(dir)
+        }
+
+        fn config(&self, text: &str) -> PathBuf {
+            let path = self.0.join("hello.toml");
+            fs::write(&path, text).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn loads_a_file_and_applies_flags_over_it() {
+        let site = Site::new("flags");
+        let path = site.config(
+            "[server]
+bind = [\"127.0.0.1\", \"::1\"]
+port = 8080
+root = \"public\"
+index = \"hello.html\"
+
+[error_pages]
+500 = \"500.html\"
+
+[pool]
+workers = 2
+max_workers = 8
+
+[timeouts]
+idle = \"500ms\"
+grace = 3
+
+[log]
+access = \"access.log\"
+format = \"common\"
+max_size = \"1MiB\"
+
+[compression]
+min_size = \"2KiB\"
+types = [\"text/html\", \"application/json\"]
+",
+        );
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(vec![IpAddr::from([127, 0, 0, 1]), "::1".parse().unwrap()], config.bind);
+        assert_eq!(site.0.join("public"), config.root);
+        assert_eq!(Some(&site.0.join("500.html")), config.error_pages.get(&500));
+        // Defaults the file doesn't override are kept
+        assert_eq!(Some(&PathBuf::from("404.html")), config.error_pages.get(&404));
+        assert_eq!((2, 8, 256), (config.workers, config.max_workers, config.queue));
+        assert_eq!(Duration::from_millis(500), config.connection_options().idle_timeout);
+        assert_eq!(Duration::from_secs(3), config.grace);
+        assert_eq!(Some(site.0.join("access.log")), config.access_log);
+        assert_eq!((LogFormat::Common, 1024 * 1024), (config.log_format, config.log_max_size));
+        assert_eq!(Some(Compression::new().min_size(2048).types(["text/html", "application/json"])), config.compression());
+
+        let config = Config::from_args(args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--port=9090",
+            "--bind",
+            "0.0.0.0",
+            "--bind",
+            "::",
+            "--workers",
+            "3",
+            "--error-page",
+            &format!("404={}", site.0.join("404.html").display()),
+            "--log-format",
+            "combined",
+            "--compress-types",
+            "text/*, image/svg+xml",
+            "--dev",
+            "true",
+            "--listing=true",
+        ]))
+        .unwrap();
+        assert_eq!(
+            vec!["0.0.0.0:9090".parse::<SocketAddr>().unwrap(), "[::]:9090".parse().unwrap()],
+            config.addresses()
+        );
+        assert_eq!((3, 8), (config.workers, config.max_workers));
+        assert_eq!(Some(&site.0.join("404.html")), config.error_pages.get(&404));
+        assert_eq!(LogFormat::Combined, config.log_format);
+        assert_eq!(vec!["text/*", "image/svg+xml"], config.compress_types);
+        assert!(config.dev && config.listing);
+        assert!(!Config::default().listing);
+        assert_eq!(None, Config::from_args(args(&["--config", path.to_str().unwrap(), "--compress", "false"])).unwrap().compression());
+    }
+
+    #[test]
+    fn errors_say_where_the_setting_came_from() {
+        let site = Site::new("errors");
+        let file = |text: &str| {
+            let path = site.config(text);
+            let error = Config::load(&path).unwrap_err();
+            (error.origin.strip_prefix(path.to_str().unwrap()).unwrap().to_string(), error.message)
+        };
+        let root = "[server]\nroot = \"public\"\nindex = \"hello.html\"\n[error_pages]\n404 = \"404.html\"\n";
+
+        assert_eq!(
+            (":2".to_string(), "port: 70000 isn't a port, which is 0 to 65535".to_string()),
+            file("[server]\nport = 70000")
+        );
+        assert_eq!((":2".to_string(), "unknown setting server.prot".to_string()), file("[server]\nprot = 80"));
+        assert_eq!((":2".to_string(), "unknown setting threads.count".to_string()), file("[threads]\ncount = 1"));
+        assert_eq!(":2", file("[pool]\nworkers = \"four\"").0);
+        assert_eq!(":2", file("[timeouts]\nidle = \"soon\"").0);
+        assert_eq!(":2", file("[error_pages]\n200 = \"hello.html\"").0);
+        assert_eq!(":2", file("[compression]\nenabled = \"yes\"").0);
+        assert_eq!(
+            (":2".to_string(), "types: \"html\" isn't a media type, like \"text/html\" or \"text/*\"".to_string()),
+            file("[compression]\ntypes = [\"html\"]")
+        );
+        assert_eq!(":6", file(&format!("{root}[server]\n")).0);
+
+        // Problems found once everything is read point at the line that caused them
+        let (origin, message) = file(&format!("{root}[pool]\nworkers = 8\nmax_workers = 4\n"));
+        assert_eq!((":8", "4 is fewer than the 8 workers kept running"), (&origin[..], &message[..]));
+        let (origin, message) = file("[server]\n\nroot = \"missing\"\n");
+        assert_eq!(":3", origin);
+        assert!(message.ends_with("missing isn't a directory"), "{message}");
+
+        // And flags at the flag
+        let flag = |flags: &[&str]| Config::from_args(args(flags)).unwrap_err();
+        let config = site.config(root);
+        let config = config.to_str().unwrap();
+        assert_eq!(flag_error("--workers", "there must be at least 1 worker"), flag(&["--config", config, "--workers", "0"]));
+        assert_eq!("--port", flag(&["--config", config, "--port", "http"]).origin);
+        assert_eq!("--bind", flag(&["--config", config, "--bind", "localhost"]).origin);
+        assert_eq!(
+            flag_error("--grace", "\"400000000000000000m\" isn't a time, like \"500ms\", \"10s\" or \"2m\""),
+            flag(&["--config", config, "--grace", "400000000000000000m"])
+        );
+        assert_eq!(
+            flag_error("--idle-timeout", "9223372036854775807s is longer than a year"),
+            flag(&["--config", config, "--idle-timeout", "9223372036854775807"])
+        );
+        assert_eq!("--verbose", flag(&["--verbose", "1"]).origin);
+        assert_eq!(flag_error("--port", "needs a value"), flag(&["--port"]));
+        assert_eq!("public", flag(&["public"]).origin);
+        let missing = site.0.join("missing.toml");
+        assert_eq!(missing.display().to_string(), flag(&["--config", missing.to_str().unwrap()]).origin);
+    }
+}
@@ -0,0 +1,663 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+// DEFLATE (RFC 1951), the compressed format inside both gzip and zlib, for compressing
+// responses without a compression crate.
+//
+// Compressing finds repeated strings with LZ77, looking back up to 32 KiB through hash
+// chains of 3 byte prefixes, then codes each block with Huffman codes made for it, the
+// fixed codes, or not at all, whichever comes out smallest. Matching is greedy and only
+// follows a chain so far, so it's quicker than zlib's default level and compresses a
+// little less.
+//
+// Decompressing handles every kind of block, so the output of other compressors can be
+// read too.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// Links of a hash chain followed looking for a longer match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+// Input coded as one block, small enough to be stored as one if it doesn't compress
+const BLOCK: usize = 32 * 1024;
+const END_OF_BLOCK: usize = 256;
+
+const LENGTH_BASE: [u16; 29] =
+    [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] =
+    [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// The order a dynamic block's header gives the code length code's lengths in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Compresses a stream a piece at a time. Each piece's output can be sent on its own, and
+/// later pieces may still refer back to earlier ones.
+pub struct Deflater {
+    // The last WINDOW bytes of input, which matches can reach back into
+    history: Vec<u8>,
+    output: BitWriter,
+}
+
+impl Default for Deflater {
+    fn default() -> Deflater {
+        Deflater::new()
+    }
+}
+
+impl Deflater {
+    pub fn new() -> Deflater {
+        Deflater { history: Vec::new(), output: BitWriter::default() }
+    }
+
+    /// Compresses data and returns the output so far. It ends on a byte boundary with an
+    /// empty stored block (what zlib calls a sync flush), so it can be decoded without
+    /// waiting for what comes next.
+    pub fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        if !data.is_empty() {
+            self.blocks(data, false);
+            self.output.write(0, 3);
+            self.output.align();
+            self.output.bytes.extend_from_slice(&[0, 0, 0xff, 0xff]);
+        }
+        std::mem::take(&mut self.output.bytes)
+    }
+
+    /// Compresses the rest of the data and ends the stream
+    pub fn finish(mut self, data: &[u8]) -> Vec<u8> {
+        self.blocks(data, true);
+        self.output.align();
+        self.output.bytes
+    }
+
+    fn blocks(&mut self, data: &[u8], last: bool) {
+        if data.is_empty() && last {
+            write_block(&mut self.output, &[], &[], true);
+        }
+        let count = data.len().div_ceil(BLOCK);
+        for (index, block) in data.chunks(BLOCK).enumerate() {
+            let mut window = std::mem::take(&mut self.history);
+            let start = window.len();
+            window.extend_from_slice(block);
+            let tokens = tokens(&window, start);
+            write_block(&mut self.output, &tokens, block, last && index == count - 1);
+            window.drain(..window.len().saturating_sub(WINDOW));
+            self.history = window;
+        }
+    }
+}
+
+// Bits packed into bytes starting from the least significant bit, as DEFLATE wants
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Pads with zeros to the next byte boundary
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+            self.bits = 0;
+            self.count = 0;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Literal(u8),
+    // A copy of length bytes from distance back
+    Match { length: u16, distance: u16 },
+}
+
+fn length_symbol(length: u16) -> usize {
+    LENGTH_BASE.partition_point(|&base| base <= length) - 1
+}
+
+fn distance_symbol(distance: u16) -> usize {
+    DISTANCE_BASE.partition_point(|&base| base <= distance) - 1
+}
+
+// Positions of the input by the hash of the 3 bytes there: the latest with each hash,
+// and for every position the one before it with the same hash
+struct Chains {
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl Chains {
+    const NONE: usize = usize::MAX;
+
+    fn hash(data: &[u8], at: usize) -> usize {
+        let prefix = u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0]);
+        (prefix.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], at: usize) {
+        if at + MIN_MATCH <= data.len() {
+            let hash = Chains::hash(data, at);
+            self.previous[at] = self.head[hash];
+            self.head[hash] = at;
+        }
+    }
+
+    // The length and distance of the longest match for data[at..], if it's long enough
+    fn longest_match(&self, data: &[u8], at: usize) -> Option<(usize, usize)> {
+        let longest = MAX_MATCH.min(data.len() - at);
+        if longest < MIN_MATCH {
+            return None;
+        }
+        let (mut length, mut distance) = (0, 0);
+        let mut candidate = self.head[Chains::hash(data, at)];
+        let mut links = 0;
+        while candidate != Chains::NONE && at - candidate <= WINDOW && links < MAX_CHAIN {
+            // Only worth comparing if it could beat the best so far
+            if data[candidate + length] == data[at + length] {
+                let common =
+                    data[candidate..candidate + longest].iter().zip(&data[at..at + longest]).take_while(|(a, b)| a == b).count();
+                if common > length {
+                    (length, distance) = (common, at - candidate);
+                    if common == longest {
+                        break;
+                    }
+                }
+            }
+            candidate = self.previous[candidate];
+            links += 1;
+        }
+        (length >= MIN_MATCH).then_some((length, distance))
+    }
+}
+
+// Splits data[start..] into literals and matches, which may reach back before start
+fn tokens(data: &[u8], start: usize) -> Vec<Token> {
+    let mut chains = Chains { head: vec![Chains::NONE; 1 << HASH_BITS], previous: vec![Chains::NONE; data.len()] };
+    for at in 0..start {
+        chains.insert(data, at);
+    }
+    let mut tokens = Vec::new();
+    let mut at = start;
+    while at < data.len() {
+        match chains.longest_match(data, at) {
+            Some((length, distance)) => {
+                tokens.push(Token::Match { length: length as u16, distance: distance as u16 });
+                for at in at..at + length {
+                    chains.insert(data, at);
+                }
+                at += length;
+            }
+            None => {
+                tokens.push(Token::Literal(data[at]));
+                chains.insert(data, at);
+                at += 1;
+            }
+        }
+    }
+    tokens
+}
+
+// Huffman code lengths for symbols used freqs times, none longer than limit. A symbol
+// that isn't used gets no code.
+fn code_lengths(freqs: &[u32], limit: usize) -> Vec<u8> {
+    let mut lengths = vec![0; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|&symbol| freqs[symbol] > 0).collect();
+    match used.len() {
+        0 => return lengths,
+        // A code still needs a bit, even with only one symbol
+        1 => {
+            lengths[used[0]] = 1;
+            return lengths;
+        }
+        _ => {}
+    }
+
+    // Huffman's algorithm: join the two lightest nodes until one is left. Leaves are
+    // 0..used.len(), and each joined node comes after the nodes it joins.
+    let mut parent = vec![0; used.len() * 2 - 1];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> =
+        used.iter().enumerate().map(|(leaf, &symbol)| Reverse((freqs[symbol] as u64, leaf))).collect();
+    let mut next = used.len();
+    while let (Some(Reverse((a_weight, a))), Some(Reverse((b_weight, b)))) = (heap.pop(), heap.pop()) {
+        (parent[a], parent[b]) = (next, next);
+        heap.push(Reverse((a_weight + b_weight, next)));
+        next += 1;
+    }
+    let mut depth = vec![0; next];
+    for node in (0..next - 1).rev() {
+        depth[node] = depth[parent[node]] + 1;
+    }
+
+    // Too long codes are cut to limit, which makes too many codes for the lengths, then
+    // codes at limit are taken away and shorter ones split until they fit again
+    let mut counts = vec![0usize; limit + 1];
+    for &leaf_depth in &depth[..used.len()] {
+        counts[leaf_depth.min(limit)] += 1;
+    }
+    let mut total: usize = (1..=limit).map(|length| counts[length] << (limit - length)).sum();
+    while total > 1 << limit {
+        counts[limit] -= 1;
+        if let Some(length) = (1..limit).rev().find(|&length| counts[length] > 0) {
+            counts[length] -= 1;
+            counts[length + 1] += 2;
+        }
+        total -= 1;
+    }
+
+    // The most used symbols get the shortest codes
+    let mut order = used;
+    order.sort_by_key(|&symbol| Reverse(freqs[symbol]));
+    let sizes = (1..=limit).flat_map(|length| std::iter::repeat_n(length as u8, counts[length]));
+    for (symbol, length) in order.into_iter().zip(sizes) {
+        lengths[symbol] = length;
+    }
+    lengths
+}
+
+// The canonical code for each length (RFC 1951 3.2.2), bit-reversed to be written
+// least significant bit first
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for &length in lengths {
+        counts[length as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next = [0u16; 16];
+    let mut code = 0;
+    for length in 1..16 {
+        code = (code + counts[length - 1]) << 1;
+        next[length] = code;
+    }
+    lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return 0;
+            }
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            code.reverse_bits() >> (16 - length)
+        })
+        .collect()
+}
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let literals = (0..288).map(|symbol| match symbol {
+        0..=143 => 8,
+        144..=255 => 9,
+        256..=279 => 7,
+        _ => 8,
+    });
+    (literals.collect(), vec![5; 30])
+}
+
+// The code lengths of a dynamic block's two codes, run-length coded as (symbol, extra bits)
+fn run_lengths(lengths: &[u8]) -> Vec<(u8, u32)> {
+    let mut runs = Vec::new();
+    let mut at = 0;
+    while at < lengths.len() {
+        let length = lengths[at];
+        let run = lengths[at..].iter().take_while(|&&other| other == length).count();
+        if length == 0 && run >= 3 {
+            let take = run.min(138);
+            runs.push(if take >= 11 { (18, take as u32 - 11) } else { (17, take as u32 - 3) });
+            at += take;
+        } else if length != 0 && run >= 4 {
+            // The length once, then repeats of it 3 to 6 at a time
+            runs.push((length, 0));
+            let take = (run - 1).min(6);
+            runs.push((16, take as u32 - 3));
+            at += 1 + take;
+        } else {
+            runs.push((length, 0));
+            at += 1;
+        }
+    }
+    runs
+}
+
+fn extra_bits(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+// Writes tokens, which are raw compressed, as one block in whichever form is smallest
+fn write_block(output: &mut BitWriter, tokens: &[Token], raw: &[u8], last: bool) {
+    let mut literal_freqs = [0u32; 286];
+    let mut distance_freqs = [0u32; 30];
+    // Extra bits are the same whichever codes are used
+    let mut extra = 0;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_freqs[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                let (length, distance) = (length_symbol(length), distance_symbol(distance));
+                literal_freqs[257 + length] += 1;
+                distance_freqs[distance] += 1;
+                extra += LENGTH_EXTRA[length] as usize + DISTANCE_EXTRA[distance] as usize;
+            }
+        }
+    }
+    literal_freqs[END_OF_BLOCK] = 1;
+    let cost = |literals: &[u8], distances: &[u8]| {
+        let literal_bits: usize = literal_freqs.iter().zip(literals).map(|(&freq, &length)| freq as usize * length as usize).sum();
+        let distance_bits: usize =
+            distance_freqs.iter().zip(distances).map(|(&freq, &length)| freq as usize * length as usize).sum();
+        literal_bits + distance_bits + extra
+    };
+
+    let literal_lengths = code_lengths(&literal_freqs, 15);
+    let distance_lengths = code_lengths(&distance_freqs, 15);
+    let literal_count = 257.max(literal_lengths.iter().rposition(|&length| length > 0).map_or(0, |last| last + 1));
+    let distance_count = 1.max(distance_lengths.iter().rposition(|&length| length > 0).map_or(0, |last| last + 1));
+    let lengths: Vec<u8> =
+        literal_lengths[..literal_count].iter().chain(&distance_lengths[..distance_count]).copied().collect();
+    let runs = run_lengths(&lengths);
+    let mut run_freqs = [0u32; 19];
+    for &(symbol, _) in &runs {
+        run_freqs[symbol as usize] += 1;
+    }
+    let run_lengths = code_lengths(&run_freqs, 7);
+    let run_count = 4.max(CODE_LENGTH_ORDER.iter().rposition(|&symbol| run_lengths[symbol] > 0).map_or(0, |last| last + 1));
+    let header_bits: usize = 14
+        + 3 * run_count
+        + runs.iter().map(|&(symbol, _)| run_lengths[symbol as usize] as usize + extra_bits(symbol) as usize).sum::<usize>();
+
+    let (fixed_literals, fixed_distances) = fixed_lengths();
+    let dynamic = header_bits + cost(&literal_lengths, &distance_lengths);
+    let fixed = cost(&fixed_literals, &fixed_distances);
+    // Up to 7 bits of padding, then the length and its complement
+    let stored = 7 + 32 + 8 * raw.len();
+
+    output.write(u32::from(last), 1);
+    if stored < dynamic.min(fixed) {
+        output.write(0, 2);
+        output.align();
+        let length = raw.len() as u16;
+        output.bytes.extend_from_slice(&length.to_le_bytes());
+        output.bytes.extend_from_slice(&(!length).to_le_bytes());
+        output.bytes.extend_from_slice(raw);
+    } else if fixed <= dynamic {
+        output.write(1, 2);
+        write_tokens(output, tokens, &fixed_literals, &fixed_distances);
+    } else {
+        output.write(2, 2);
+        output.write(literal_count as u32 - 257, 5);
+        output.write(distance_count as u32 - 1, 5);
+        output.write(run_count as u32 - 4, 4);
+        for &symbol in &CODE_LENGTH_ORDER[..run_count] {
+            output.write(run_lengths[symbol] as u32, 3);
+        }
+        let run_codes = canonical_codes(&run_lengths);
+        for (symbol, value) in runs {
+            output.write(run_codes[symbol as usize] as u32, run_lengths[symbol as usize] as u32);
+            output.write(value, extra_bits(symbol));
+        }
+        write_tokens(output, tokens, &literal_lengths, &distance_lengths);
+    }
+}
+
+fn write_tokens(output: &mut BitWriter, tokens: &[Token], literal_lengths: &[u8], distance_lengths: &[u8]) {
+    let (literal_codes, distance_codes) = (canonical_codes(literal_lengths), canonical_codes(distance_lengths));
+    let symbol = |output: &mut BitWriter, symbol: usize| {
+        output.write(literal_codes[symbol] as u32, literal_lengths[symbol] as u32)
+    };
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => symbol(output, byte as usize),
+            Token::Match { length, distance } => {
+                let code = length_symbol(length);
+                symbol(output, 257 + code);
+                output.write((length - LENGTH_BASE[code]) as u32, LENGTH_EXTRA[code] as u32);
+                let code = distance_symbol(distance);
+                output.write(distance_codes[code] as u32, distance_lengths[code] as u32);
+                output.write((distance - DISTANCE_BASE[code]) as u32, DISTANCE_EXTRA[code] as u32);
+            }
+        }
+    }
+    symbol(output, END_OF_BLOCK);
+}
+
+/// Decompresses a DEFLATE stream from the start of data. Returns the output and how many
+/// bytes of data the stream took up, or None if it isn't valid.
+pub fn inflate(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut input = BitReader { data, position: 0, bits: 0, count: 0 };
+    let mut output = Vec::new();
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => {
+                input.align();
+                let header = data.get(input.position..input.position + 4)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if !length != u16::from_le_bytes([header[2], header[3]]) {
+                    return None;
+                }
+                let start = input.position + 4;
+                output.extend_from_slice(data.get(start..start + length as usize)?);
+                input.position = start + length as usize;
+            }
+            1 => {
+                let (literals, distances) = fixed_lengths();
+                inflate_block(&mut input, &mut output, &Decoder::new(&literals)?, &Decoder::new(&distances)?)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_header(&mut input)?;
+                inflate_block(&mut input, &mut output, &literals, &distances)?;
+            }
+            _ => return None,
+        }
+        if last {
+            return Some((output, input.position));
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    // The next byte to load
+    position: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        while self.count < count {
+            self.bits |= (*self.data.get(self.position)? as u32) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u64 << count) - 1) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Some(value)
+    }
+
+    // Skips the rest of the byte being read, which bytes are only ever loaded up to
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+// Decodes a canonical Huffman code a bit at a time, by counting the codes of each length
+struct Decoder {
+    counts: [u16; 16],
+    // Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Decoder {
+    // None if there are more codes than the lengths allow
+    fn new(lengths: &[u8]) -> Option<Decoder> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return None;
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Some(Decoder { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Option<usize> {
+        // The first code of each length, and the index of its symbol
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= input.bits(1)? as i32;
+            if code - first < count as i32 {
+                return self.symbols.get((index + code - first) as usize).map(|&symbol| symbol as usize);
+            }
+            index += count as i32;
+            first = (first + count as i32) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+fn read_dynamic_header(input: &mut BitReader) -> Option<(Decoder, Decoder)> {
+    let literal_count = input.bits(5)? as usize + 257;
+    let distance_count = input.bits(5)? as usize + 1;
+    let run_count = input.bits(4)? as usize + 4;
+    let mut run_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..run_count] {
+        run_lengths[symbol] = input.bits(3)? as u8;
+    }
+    let runs = Decoder::new(&run_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match runs.decode(input)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last()?, 3 + input.bits(2)?),
+            17 => (0, 3 + input.bits(3)?),
+            _ => (0, 11 + input.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() != literal_count + distance_count || lengths[END_OF_BLOCK] == 0 {
+        return None;
+    }
+    let (literals, distances) = lengths.split_at(literal_count);
+    Some((Decoder::new(literals)?, Decoder::new(distances)?))
+}
+
+fn inflate_block(input: &mut BitReader, output: &mut Vec<u8>, literals: &Decoder, distances: &Decoder) -> Option<()> {
+    loop {
+        match literals.decode(input)? {
+            byte @ 0..=255 => output.push(byte as u8),
+            END_OF_BLOCK => return Some(()),
+            symbol => {
+                let code = symbol - 257;
+                let length = *LENGTH_BASE.get(code)? as usize + input.bits(LENGTH_EXTRA[code] as u32)? as usize;
+                let code = distances.decode(input)?;
+                let distance = *DISTANCE_BASE.get(code)? as usize + input.bits(DISTANCE_EXTRA[code] as u32)? as usize;
+                let start = output.len().checked_sub(distance)?;
+                // The copy may overlap what it's copying, as a run of one repeated byte does
+                for at in start..start + length {
+                    output.push(output[at]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        Deflater::new().finish(data)
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "It was the best of times, it was the worst of times, it was the age of wisdom. ".repeat(500);
+        let noise: Vec<u8> = (0u32..100_000).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let inputs: [&[u8]; 5] = [b"", b"a", &[0; 100_000], text.as_bytes(), &noise];
+        for input in inputs {
+            let compressed = deflate(input);
+            assert_eq!(Some((input.to_vec(), compressed.len())), inflate(&compressed));
+        }
+        assert!(deflate(text.as_bytes()).len() < text.len() / 20);
+        // Stored rather than grown much by coding
+        assert!(deflate(&noise).len() < noise.len() + 100);
+    }
+
+    #[test]
+    fn streams_in_pieces() {
+        let text = "<p>Hello, world!</p>\n".repeat(5000);
+        let mut deflater = Deflater::new();
+        let mut compressed = Vec::new();
+        for piece in text.as_bytes().chunks(10_000) {
+            let output = deflater.write(piece);
+            // Everything so far can be decoded, save for the end of the stream
+            assert!(output.ends_with(&[0, 0, 0xff, 0xff]));
+            compressed.extend(output);
+        }
+        compressed.extend(deflater.finish(b""));
+        assert_eq!(text.as_bytes(), inflate(&compressed).unwrap().0);
+    }
+
+    #[test]
+    fn reads_other_compressors() {
+        // zlib.compress(b"hello hello hello hello", 9)[2:-4], with fixed codes
+        let fixed = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01];
+        assert_eq!(b"hello hello hello hello".to_vec(), inflate(&fixed).unwrap().0);
+        // A stored block
+        assert_eq!(b"abc".to_vec(), inflate(&[0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c']).unwrap().0);
+        assert_eq!(None, inflate(&[0x01, 0x03, 0x00, 0x00, 0x00, b'a', b'b', b'c']));
+        assert_eq!(None, inflate(&[0x07]));
+        assert_eq!(None, inflate(&fixed[..5]));
+    }
+
+    #[test]
+    fn limits_code_lengths() {
+        // Fibonacci frequencies give the deepest Huffman trees
+        let mut freqs = vec![1u32, 1];
+        while freqs.len() < 30 {
+            freqs.push(freqs[freqs.len() - 1] + freqs[freqs.len() - 2]);
+        }
+        let lengths = code_lengths(&freqs, 15);
+        assert_eq!(Some(&15), lengths.iter().max());
+        let kraft: f64 = lengths.iter().map(|&length| 0.5f64.powi(length as i32)).sum();
+        assert_eq!(1.0, kraft);
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, prelude::*, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::http::{self, Method, Request, Response};
+use crate::router::Handler;
+
+/// Serves the files under a root directory.
+///
+/// The file is the request's "path" parameter when it has one, so it can be mounted with
+/// router.get("/static/*path", StaticFiles::new("public")), and the whole path otherwise.
+/// Responses carry ETag and Last-Modified headers, answer conditional requests with
+/// 304 Not Modified, and support single byte ranges.
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Option<String>,
+    listing: bool,
+}
+
+impl StaticFiles {
+    /// Serves root, with index.html as the index file and directory listings turned off
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles { root: root.into(), index: Some("index.html".to_string()), listing: false }
+    }
+
+    /// The file served for a directory, None to never serve one
+    pub fn index(mut self, index: Option<&str>) -> StaticFiles {
+        self.index = index.map(String::from);
+        self
+    }
+
+    /// Whether a directory without an index file is shown as a list of links
+    pub fn listing(mut self, listing: bool) -> StaticFiles {
+        self.listing = listing;
+        self
+    }
+
+    // The file that relative names under the root.
+    // Every ".." is refused outright, and the canonical path has to stay inside the canonical
+    // root, so neither the path nor a symlink can reach files outside of it.
+    fn resolve(&self, relative: &str) -> Result<PathBuf, Response> {
+        let mut path = PathBuf::new();
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            if segment == "." || segment == ".." || segment.contains(['\\', '\0', ':']) {
+                return Err(error(403));
+            }
+            path.push(segment);
+        }
+        let root = fs::canonicalize(&self.root).map_err(|err| io_error(&err))?;
+        let path = fs::canonicalize(root.join(path)).map_err(|err| io_error(&err))?;
+        if !path.starts_with(&root) {
+            return Err(error(403));
+        }
+        Ok(path)
+    }
+
+    fn serve_dir(&self, request: &Request, dir: &Path) -> Response {
+        // Relative links in the page only work if the URL ends in a slash
+        if !request.path.ends_with('/') {
+            let mut location = request.path.split('/').map(http::percent_encode).collect::<Vec<_>>().join("/");
+            location.push('/');
+            if !request.query.is_empty() {
+                location = format!("{location}?{}", request.query);
+            }
+            return Response::new(301).header("Location", location);
+        }
+        if let Some(index) = &self.index {
+            let file = dir.join(index);
+            if let Ok(metadata) = fs::metadata(&file)
+                && metadata.is_file()
+            {
+                return serve_file(request, &file, &metadata);
+            }
+        }
+        if !self.listing {
+            return error(404);
+        }
+        match listing(&request.path, dir) {
+            Ok(page) => Response::new(200).header("Content-Type", "text/html; charset=utf-8").body(page),
+            Err(err) => io_error(&err),
+        }
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &Request) -> Response {
+        let relative = request.param("path").unwrap_or(&request.path);
+        let path = match self.resolve(relative) {
+            Ok(path) => path,
+            Err(response) => return response,
+        };
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => self.serve_dir(request, &path),
+            Ok(metadata) => serve_file(request, &path, &metadata),
+            Err(err) => io_error(&err),
+        }
+    }
+}
+
+fn serve_file(request: &Request, path: &Path, metadata: &Metadata) -> Response {
+    let length = metadata.len();
+    let modified = metadata.modified().ok();
+    // The size and modification time change whenever the file does, in practice
+    let etag = format!(
+        "\"{length:x}-{:x}\"",
+        modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map_or(0, |since| since.as_nanos())
+    );
+    let mut response = Response::new(200)
+        .header("Content-Type", mime_type(path))
+        .header("Accept-Ranges", "bytes")
+        .header("ETag", etag.clone());
+    if let Some(modified) = modified {
+        response = response.header("Last-Modified", http::http_date(modified));
+    }
+
+    if not_modified(request, &etag, modified) {
+        response.status = 304;
+        return response;
+    }
+
+    let range = request
+        .headers
+        .get("Range")
+        .filter(|_| request.method == Method::Get || request.method == Method::Head)
+        // If-Range asks for the range only if the file is still the one the client has part of
+        .filter(|_| request.headers.get("If-Range").is_none_or(|tag| tag == etag))
+        .and_then(|range| parse_range(range, length));
+    let body = match range {
+        None => fs::read(path),
+        Some(Err(())) => {
+            return error(416).header("Content-Range", format!("bytes */{length}"));
+        }
+        Some(Ok(range)) => {
+            response.status = 206;
+            response = response.header("Content-Range", format!("bytes {}-{}/{length}", range.start, range.end - 1));
+            read_range(path, range)
+        }
+    };
+    match body {
+        Ok(body) => response.body(body),
+        Err(err) => io_error(&err),
+    }
+}
+
+// Whether If-None-Match or If-Modified-Since say the client's copy is current.
+// If-None-Match wins when both are sent (RFC 9110 13.2.2).
+fn not_modified(request: &Request, etag: &str, modified: Option<std::time::SystemTime>) -> bool {
+    if request.method != Method::Get && request.method != Method::Head {
+        return false;
+    }
+    if let Some(tags) = request.headers.get("If-None-Match") {
+        // Compared weakly, so W/"x" matches "x"
+        return tags
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    match (request.headers.get("If-Modified-Since").and_then(http::parse_http_date), modified) {
+        // Dates only have whole seconds, so the file counts as unchanged within the same second
+        (Some(since), Some(modified)) => modified
+            .duration_since(UNIX_EPOCH)
+            .is_ok_and(|modified| since.duration_since(UNIX_EPOCH).is_ok_and(|since| modified.as_secs() <= since.as_secs())),
+        _ => false,
+    }
+}
+
+// The byte range a Range header asks for in a file of length bytes.
+// None means the header should be ignored and the whole file sent, which is allowed for
+// anything it can't make sense of, including several ranges at once.
+// Err means the range is past the end of the file, which is a 416.
+fn parse_range(header: &str, length: u64) -> Option<Result<Range<u64>, ()>> {
+    let spec = header.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let number = |value: &str| -> Option<u64> {
+        (!value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit())).then(|| value.parse().ok())?
+    };
+    let range = match (start, end) {
+        // The last n bytes
+        ("", suffix) => {
+            let suffix = number(suffix)?;
+            if suffix == 0 || length == 0 {
+                return Some(Err(()));
+            }
+            length.saturating_sub(suffix)..length
+        }
+        (start, "") => number(start)?..length,
+        (start, end) => {
+            let (start, end) = (number(start)?, number(end)?);
+            if end < start {
+                return None;
+            }
+            start..(end + 1).min(length)
+        }
+    };
+    if range.start >= length {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+fn read_range(path: &Path, range: Range<u64>) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut body = Vec::with_capacity((range.end - range.start) as usize);
+    file.take(range.end - range.start).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+// An HTML page linking to everything in dir, directories first
+fn listing(path: &str, dir: &Path) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        entries.push((!entry.file_type()?.is_dir(), name));
+    }
+    entries.sort();
+
+    let title = escape_html(path);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+    if path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (is_file, name) in entries {
+        let slash = if is_file { "" } else { "/" };
+        page.push_str(&format!(
+            "<li><a href=\"{}{slash}\">{}{slash}</a></li>\n",
+            http::percent_encode(&name),
+            escape_html(&name)
+        ));
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    Ok(page)
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The Content-Type for a file, from its extension
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
+
+fn error(status: u16) -> Response {
+    Response::new(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("{status} {}\n", http::reason(status)))
+}
+
+fn io_error(err: &io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => error(404),
+        io::ErrorKind::PermissionDenied => error(403),
+        _ => error(500),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Limits;
+    use std::env;
+
+    // A fresh directory for one test, removed again when it is dropped
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(name: &str) -> Root {
+            let root = env::temp_dir().join(format!("hello-files-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("public/docs")).unwrap();
+            fs::write(root.join("public/hello.html"), "<h1>Hello!</h1>").unwrap();
+            fs::write(root.join("public/pixel.png"), [0x89, b'P', b'N', b'G', 0, 0xff, 0x0a, 0x0d]).unwrap();
+            fs::write(root.join("public/docs/a & b.txt"), "0123456789").unwrap();
+            fs::write(root.join("secret.txt"), "outside the root").unwrap();
+            Root(root)
+        }
+
+        fn files(&self) -> StaticFiles {
+            StaticFiles::new(self.0.join("public"))
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn get(files: &StaticFiles, path: &str, headers: &str) -> Response {
+        let raw = format!("GET {path} HTTP/1.1\r\nHost: x\r\n{headers}\r\n");
+        let request = http::read_request(&mut raw.as_bytes(), &Limits::default()).unwrap().unwrap();
+        files.handle(&request)
+    }
+
+    #[test]
+    fn serves_files_with_types() {
+        let root = Root::new("types");
+        let files = root.files();
+
+        let page = get(&files, "/hello.html", "");
+        assert_eq!(200, page.status);
+        assert_eq!(Some("text/html; charset=utf-8"), page.headers.get("Content-Type"));
+        assert_eq!(b"<h1>Hello!</h1>".to_vec(), page.body);
+
+        let image = get(&files, "/pixel.png", "");
+        assert_eq!(Some("image/png"), image.headers.get("Content-Type"));
+        assert_eq!(8, image.body.len());
+        assert_eq!(b"0123456789".to_vec(), get(&files, "/docs/a%20&%20b.txt", "").body);
+        assert_eq!(404, get(&files, "/missing.html", "").status);
+    }
+
+    #[test]
+    fn refuses_to_leave_the_root() {
+        let root = Root::new("traversal");
+        let files = root.files();
+
+        assert_eq!(403, get(&files, "/../secret.txt", "").status);
+        assert_eq!(403, get(&files, "/docs/%2e%2e/%2e%2e/secret.txt", "").status);
+        assert_eq!(403, get(&files, "/docs/..%2F..%2Fsecret.txt", "").status);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.0.join("secret.txt"), root.0.join("public/link.txt")).unwrap();
+            assert_eq!(403, get(&files, "/link.txt", "").status);
+        }
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let root = Root::new("conditional");
+        let files = root.files();
+
+        let first = get(&files, "/hello.html", "");
+        let etag = first.headers.get("ETag").unwrap().to_string();
+        let modified = first.headers.get("Last-Modified").unwrap().to_string();
+
+        let cached = get(&files, "/hello.html", &format!("If-None-Match: \"other\", W/{etag}\r\n"));
+        assert_eq!(304, cached.status);
+        assert!(cached.body.is_empty());
+        let mut out = Vec::new();
+        cached.write_to(&mut out).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("Content-Length"));
+
+        assert_eq!(304, get(&files, "/hello.html", &format!("If-Modified-Since: {modified}\r\n")).status);
+        assert_eq!(200, get(&files, "/hello.html", "If-None-Match: \"other\"\r\n").status);
+        assert_eq!(200, get(&files, "/hello.html", "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n").status);
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let root = Root::new("ranges");
+        let files = root.files();
+        let range = |header: &str| get(&files, "/docs/a%20&%20b.txt", &format!("Range: {header}\r\n"));
+
+        let partial = range("bytes=2-4");
+        assert_eq!(206, partial.status);
+        assert_eq!(b"234".to_vec(), partial.body);
+        assert_eq!(Some("bytes 2-4/10"), partial.headers.get("Content-Range"));
+        assert_eq!(b"789".to_vec(), range("bytes=-3").body);
+        assert_eq!(b"89".to_vec(), range("bytes=8-").body);
+        assert_eq!(b"89".to_vec(), range("bytes=8-100").body);
+        assert_eq!(416, range("bytes=10-").status);
+        assert_eq!(Some("bytes */10"), range("bytes=10-").headers.get("Content-Range"));
+        assert_eq!(200, range("bytes=0-1,4-5").status);
+        assert_eq!(200, range("lines=1-2").status);
+
+        let stale = get(&files, "/docs/a%20&%20b.txt", "Range: bytes=0-0\r\nIf-Range: \"old\"\r\n");
+        assert_eq!((200, 10), (stale.status, stale.body.len()));
+    }
+
+    #[test]
+    fn directories() {
+        let root = Root::new("dirs");
+        fs::write(root.0.join("public/index.html"), "index").unwrap();
+
+        let redirect = get(&root.files(), "/docs", "");
+        assert_eq!((301, Some("/docs/")), (redirect.status, redirect.headers.get("Location")));
+        assert_eq!(b"index".to_vec(), get(&root.files(), "/", "").body);
+        assert_eq!(404, get(&root.files(), "/docs/", "").status);
+
+        let page = get(&root.files().listing(true), "/docs/", "");
+        let page = String::from_utf8(page.body).unwrap();
+        assert!(page.contains("<a href=\"../\">"));
+        assert!(page.contains("<a href=\"a%20%26%20b.txt\">a &amp; b.txt</a>"));
+    }
+}
@@ -0,0 +1,954 @@
+use std::{
+    error, fmt,
+    io::{self, prelude::*},
+    net::TcpStream,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::compress::{Encoder, Encoding};
+
+// A small HTTP/1.1 parser (RFC 9112) for the server in main.rs.
+// Everything is read through a BufRead with a limit on each part of the request, so a client
+// can't make the server buffer an unbounded request line, header section or body.
+
+/// Size limits for a single request
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Longest request line, in bytes
+    pub request_line: usize,
+    /// Most header fields, including chunked trailers
+    pub headers: usize,
+    /// Longest header section, in bytes
+    pub header_bytes: usize,
+    /// Largest body, in bytes after any chunked encoding is removed
+    pub body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { request_line: 8 * 1024, headers: 100, header_bytes: 16 * 1024, body: 1024 * 1024 }
+    }
+}
+
+/// Why a request couldn't be read. Every variant but Io has a status to send back.
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    BadRequest(&'static str),
+    UriTooLong,
+    HeadersTooLarge,
+    BodyTooLarge,
+    NotImplemented(&'static str),
+    VersionNotSupported,
+}
+
+impl ParseError {
+    /// The status code for a response to the request, None if the connection itself failed
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ParseError::Io(_) => None,
+            ParseError::BadRequest(_) => Some(400),
+            ParseError::UriTooLong => Some(414),
+            ParseError::HeadersTooLarge => Some(431),
+            ParseError::BodyTooLarge => Some(413),
+            ParseError::NotImplemented(_) => Some(501),
+            ParseError::VersionNotSupported => Some(505),
+        }
+    }
+
+    /// A plain text error response, None if the connection failed and nothing can be sent
+    pub fn response(&self) -> Option<Response> {
+        let status = self.status()?;
+        Some(
+            Response::new(status)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .header("Connection", "close")
+                .body(format!("{status} {}: {self}\n", reason(status))),
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(err) => write!(f, "{err}"),
+            ParseError::BadRequest(why) => f.write_str(why),
+            ParseError::UriTooLong => f.write_str("request line too long"),
+            ParseError::HeadersTooLarge => f.write_str("header section too large"),
+            ParseError::BodyTooLarge => f.write_str("body too large"),
+            ParseError::NotImplemented(what) => write!(f, "{what} is not supported"),
+            ParseError::VersionNotSupported => f.write_str("only HTTP/1.0 and HTTP/1.1 are supported"),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
+
+/// The request method. Methods this server has no use for are kept as Other.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Trace,
+    Connect,
+    Other(String),
+}
+
+impl Method {
+    /// Methods are case-sensitive, so "get" is an unknown method rather than GET
+    pub fn parse(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        })
+    }
+}
+
+/// Header fields in the order they were received.
+/// Names are compared ignoring ASCII case and a name can appear more than once.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// The first value of the field called name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Every value of the field called name
+    pub fn get_all<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether a comma-separated field such as Connection lists token, ignoring case
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Replaces every field called name with a single one
+    pub fn insert(&mut self, name: &str, value: impl Into<String>) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Adds a field, keeping any others with the same name
+    pub fn append(&mut self, name: &str, value: impl Into<String>) {
+        self.fields.push((name.to_string(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// A parsed request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as it was sent
+    pub target: String,
+    /// The percent-decoded path of the target, always starting with '/' ("*" for OPTIONS *)
+    pub path: String,
+    /// The query string after '?', still encoded; see query_pairs
+    pub query: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Path parameters like :id, filled in by the Router that matched the request
+    pub params: Vec<(String, String)>,
+}
+
+impl Request {
+    /// The path parameter called name, e.g. "42" for "id" when /users/:id matched /users/42
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The path of the target before percent-decoding, which the Router splits into segments
+    /// so that an encoded '/' stays inside its segment
+    pub fn raw_path(&self) -> &str {
+        split_target(&self.target).map_or(&self.path, |(path, _)| path)
+    }
+
+    /// The decoded name=value pairs of the query string, with '+' meaning a space
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_form(name), decode_form(value))
+            })
+            .collect()
+    }
+
+    /// The first value of the query parameter called name
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_pairs()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Reads one request from reader.
+/// Returns Ok(None) if the connection was closed before a request started, which is how a
+/// client ends an idle connection, and an error for anything malformed or over the limits.
+pub fn read_request<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Option<Request>, ParseError> {
+    // A server should ignore at least one empty line before the request line (RFC 9112 2.2)
+    let mut line = Vec::new();
+    for _ in 0..4 {
+        match read_line(reader, limits.request_line, &mut line) {
+            Ok(true) if line.is_empty() => continue,
+            Ok(true) => break,
+            Ok(false) => return Ok(None),
+            Err(ParseError::HeadersTooLarge) => return Err(ParseError::UriTooLong),
+            Err(err) => return Err(err),
+        }
+    }
+    if line.is_empty() {
+        return Err(ParseError::BadRequest("missing request line"));
+    }
+    let line = std::str::from_utf8(&line).map_err(|_| ParseError::BadRequest("request line is not UTF-8"))?;
+
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::BadRequest("request line must be METHOD TARGET VERSION"));
+    };
+    if !is_token(method) {
+        return Err(ParseError::BadRequest("invalid method"));
+    }
+    let method = Method::parse(method);
+    let version = parse_version(version)?;
+    let (path, query) = parse_target(&method, target)?;
+
+    let headers = read_headers(reader, limits.headers, limits.header_bytes)?;
+    if version == Version::Http11 && headers.get_all("Host").count() != 1 {
+        return Err(ParseError::BadRequest("HTTP/1.1 requests need exactly one Host header"));
+    }
+    let body = read_body(reader, &headers, limits)?;
+
+    Ok(Some(Request {
+        method,
+        target: target.to_string(),
+        path,
+        query,
+        version,
+        headers,
+        body,
+        params: Vec::new(),
+    }))
+}
+
+// Reads a line ending in "\n" into line, without the line ending and an optional '\r'.
+// Returns false at the end of the stream if nothing was read. Fails with HeadersTooLarge if
+// the line is longer than limit, without reading past the limit.
+fn read_line<R: BufRead>(reader: &mut R, limit: usize, line: &mut Vec<u8>) -> Result<bool, ParseError> {
+    line.clear();
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            if line.is_empty() {
+                return Ok(false);
+            }
+            return Err(ParseError::BadRequest("connection closed in the middle of a line"));
+        }
+        let (used, done) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        if line.len() + used > limit + 2 {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        line.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if done {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.len() > limit {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            return Ok(true);
+        }
+    }
+}
+
+fn parse_version(version: &str) -> Result<Version, ParseError> {
+    match version {
+        "HTTP/1.1" => Ok(Version::Http11),
+        "HTTP/1.0" => Ok(Version::Http10),
+        _ => match version.strip_prefix("HTTP/").map(str::as_bytes) {
+            Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                Err(ParseError::VersionNotSupported)
+            }
+            _ => Err(ParseError::BadRequest("invalid HTTP version")),
+        },
+    }
+}
+
+// Splits the target into its decoded path and raw query.
+// Besides the usual "/path?query" this accepts the absolute form "http://host/path" and "*".
+fn parse_target(method: &Method, target: &str) -> Result<(String, String), ParseError> {
+    if target == "*" && *method == Method::Options {
+        return Ok(("*".to_string(), String::new()));
+    }
+    if target.bytes().any(|byte| byte <= b' ' || byte == 0x7f) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+    let (path, query) = split_target(target).ok_or(ParseError::BadRequest("invalid request target"))?;
+    let path = percent_decode(path).ok_or(ParseError::BadRequest("invalid percent-encoding in path"))?;
+    Ok((path, query.to_string()))
+}
+
+// Splits an origin or absolute form target into its path and query, both still encoded
+fn split_target(target: &str) -> Option<(&str, &str)> {
+    let origin = if target.starts_with('/') {
+        target
+    } else {
+        let rest = target.strip_prefix("http://").or_else(|| target.strip_prefix("https://"))?;
+        // Everything up to the first '/' or '?' is the authority
+        match rest.find(['/', '?']) {
+            Some(start) if rest[start..].starts_with('/') => &rest[start..],
+            Some(start) => return Some(("/", without_fragment(&rest[start + 1..]))),
+            None => "/",
+        }
+    };
+    let origin = without_fragment(origin);
+    Some(origin.split_once('?').unwrap_or((origin, "")))
+}
+
+fn without_fragment(target: &str) -> &str {
+    target.split_once('#').map_or(target, |(before, _)| before)
+}
+
+// Reads header fields up to the empty line that ends them.
+// Also used for the trailer fields after a chunked body.
+fn read_headers<R: BufRead>(reader: &mut R, max_fields: usize, max_bytes: usize) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+    let mut line = Vec::new();
+    let mut total = 0;
+    loop {
+        if !read_line(reader, max_bytes.saturating_sub(total), &mut line)? {
+            return Err(ParseError::BadRequest("connection closed in the middle of the headers"));
+        }
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        total += line.len() + 2;
+        if headers.len() == max_fields {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        // Obsolete line folding is rejected rather than unfolded (RFC 9112 5.2)
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(ParseError::BadRequest("folded header lines are not allowed"));
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| ParseError::BadRequest("header is not UTF-8"))?;
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::BadRequest("header line without a colon"));
+        };
+        // No whitespace is allowed between the name and the colon (RFC 9112 5.1)
+        if !is_token(name) {
+            return Err(ParseError::BadRequest("invalid header name"));
+        }
+        let value = value.trim_matches([' ', '\t']);
+        if value.chars().any(|c| c.is_control() && c != '\t') {
+            return Err(ParseError::BadRequest("invalid header value"));
+        }
+        headers.append(name, value);
+    }
+}
+
+// Reads the body described by Transfer-Encoding or Content-Length, if there is one
+fn read_body<R: BufRead>(reader: &mut R, headers: &Headers, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        // Both at once is how request smuggling works, so it is refused (RFC 9112 6.1)
+        if headers.contains("Content-Length") {
+            return Err(ParseError::BadRequest("both Transfer-Encoding and Content-Length"));
+        }
+        let codings: Vec<&str> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty())
+            .collect();
+        return match codings.as_slice() {
+            [coding] if coding.eq_ignore_ascii_case("chunked") => read_chunked(reader, limits),
+            [.., last] if last.eq_ignore_ascii_case("chunked") => {
+                Err(ParseError::NotImplemented("transfer coding other than chunked"))
+            }
+            _ => Err(ParseError::BadRequest("chunked must be the final transfer coding")),
+        };
+    }
+
+    let mut length = None;
+    for value in headers.get_all("Content-Length").flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(ParseError::BadRequest("invalid Content-Length"));
+        }
+        // Too many digits for a usize is certainly over the limit
+        let value: usize = value.parse().map_err(|_| ParseError::BodyTooLarge)?;
+        if length.is_some_and(|length| length != value) {
+            return Err(ParseError::BadRequest("conflicting Content-Length values"));
+        }
+        length = Some(value);
+    }
+    let length = length.unwrap_or(0);
+    if length > limits.body {
+        return Err(ParseError::BodyTooLarge);
+    }
+    let mut body = vec![0; length];
+    read_exact(reader, &mut body)?;
+    Ok(body)
+}
+
+// A chunked body: each chunk is its size in hex, a line ending, the data and another line
+// ending, until a chunk of size 0, which is followed by optional trailer fields.
+fn read_chunked<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        if !read_line(reader, 1024, &mut line)? {
+            return Err(ParseError::BadRequest("connection closed in the middle of the body"));
+        }
+        // Chunk extensions after ';' are allowed and ignored
+        let size = line.split(|byte| *byte == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size).unwrap_or_default().trim_end_matches([' ', '\t']);
+        if size.is_empty() || size.len() > 16 || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(ParseError::BadRequest("invalid chunk size"));
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::BodyTooLarge)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.body - body.len() {
+            return Err(ParseError::BodyTooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        read_exact(reader, &mut body[start..])?;
+        // The data must end right where its size says, anything but the CRLF after it is a
+        // malformed chunk rather than an overlong line
+        match read_line(reader, 0, &mut line) {
+            Ok(true) => {}
+            Ok(false) | Err(ParseError::HeadersTooLarge) => {
+                return Err(ParseError::BadRequest("chunk data is longer than its size"));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    // Trailer fields aren't used for anything, but they still count towards the header limits
+    read_headers(reader, limits.headers, limits.header_bytes)?;
+    Ok(body)
+}
+
+fn read_exact<R: BufRead>(reader: &mut R, buf: &mut [u8]) -> Result<(), ParseError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => ParseError::BadRequest("body is shorter than its length"),
+        _ => ParseError::Io(err),
+    })
+}
+
+// A token is what methods and header names are made of (RFC 9110 5.6.2)
+fn is_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Decodes %XX escapes. None if an escape is malformed or the result isn't UTF-8.
+pub fn percent_decode(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Encodes everything but unreserved chars (RFC 3986 2.3), so the result is safe as one path segment
+pub fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+// Query strings are lenient: '+' is a space and a bad escape is kept as it was
+fn decode_form(encoded: &str) -> String {
+    let spaced = encoded.replace('+', " ");
+    percent_decode(&spaced).unwrap_or(spaced)
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+pub(crate) const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A time in the format of Date and Last-Modified headers, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn http_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let days = (seconds / 86_400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let time = seconds % 86_400;
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        // 1 January 1970 was a Thursday
+        WEEKDAYS[((days + 4) % 7) as usize],
+        MONTHS[month as usize - 1],
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Parses the format http_date writes. The obsolete RFC 850 and asctime formats aren't accepted,
+/// which only means a conditional request is answered in full.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.split(' ');
+    let (Some(_weekday), Some(day), Some(month), Some(year), Some(time), Some("GMT"), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return None;
+    };
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) =
+        (time.next(), time.next(), time.next(), time.next())
+    else {
+        return None;
+    };
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + hours * 3600 + minutes * 60 + seconds))
+}
+
+// Converts between days since 1970-01-01 and a (year, month, day) date in the proleptic
+// Gregorian calendar, using Howard Hinnant's algorithms with years starting in March
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The standard reason phrase for a status code
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Takes over a connection once the 101 Switching Protocols response carrying it has been
+/// written. It gets the stream, and whatever the client sent after the request that the
+/// server had already read.
+#[derive(Clone)]
+pub struct Upgrade(Arc<dyn Fn(TcpStream, Vec<u8>) + Send + Sync>);
+
+impl Upgrade {
+    pub fn run(&self, stream: TcpStream, buffered: Vec<u8>) {
+        (self.0)(stream, buffered)
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+impl PartialEq for Upgrade {
+    fn eq(&self, other: &Upgrade) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// A response, built up with the header and body methods and then sent with write_to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// What the connection switches to after a 101 response, see Response::upgrade
+    pub upgrade: Option<Upgrade>,
+    /// Compresses the body with this coding as it's written, sending it chunked since its
+    /// compressed length isn't known until then. Only for HTTP/1.1 clients; see Compression.
+    pub compress: Option<Encoding>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response { status, headers: Headers::new(), body: Vec::new(), upgrade: None, compress: None }
+    }
+
+    /// Hands the connection to upgrade once this response is written, for a 101 Switching
+    /// Protocols response. The server stops reading requests from it.
+    pub fn upgrade<F>(mut self, upgrade: F) -> Response
+    where
+        F: Fn(TcpStream, Vec<u8>) + Send + Sync + 'static,
+    {
+        self.upgrade = Some(Upgrade(Arc::new(upgrade)));
+        self
+    }
+
+    /// Sets a header, replacing any earlier value
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Writes the status line, headers and body, returning how many bytes of body that was:
+    /// for a compressed body its compressed length, without the chunks' framing.
+    /// Content-Length is always the length of the body, so it is never wrong for binary data.
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<usize> {
+        self.write_head(out)?;
+        let written = match self.compress {
+            Some(encoding) => {
+                // Each piece goes out as soon as it's compressed, as a chunk of its own
+                let mut encoder = Encoder::new(encoding);
+                let mut written = 0;
+                // Every piece but the last, which ends the stream
+                let last = self.body.len().saturating_sub(1) / STREAM_PIECE * STREAM_PIECE;
+                for piece in self.body[..last].chunks(STREAM_PIECE) {
+                    written += write_chunk(out, &encoder.write(piece))?;
+                }
+                written += write_chunk(out, &encoder.finish(&self.body[last..]))?;
+                out.write_all(b"0\r\n\r\n")?;
+                written
+            }
+            None => {
+                out.write_all(&self.body)?;
+                self.body.len()
+            }
+        };
+        out.flush()?;
+        Ok(written)
+    }
+
+    /// Writes everything but the body, which is the answer to a HEAD request.
+    /// Content-Length is still the length of the body that a GET would get.
+    pub fn write_head_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.write_head(out)?;
+        out.flush()
+    }
+
+    fn write_head<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in self.headers.iter().filter(|(name, _)| !name.eq_ignore_ascii_case("Content-Length")) {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if let Some(encoding) = self.compress {
+            // The length isn't known until the body has been compressed
+            head.push_str(&format!("Content-Encoding: {}\r\nTransfer-Encoding: chunked\r\n", encoding.name()));
+        } else if !(self.status < 200 || self.status == 204 || self.status == 304) {
+            // Those never have a body, and a Content-Length of 0 would be wrong for a 304
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        out.write_all(head.as_bytes())
+    }
+}
+
+// How much of a body is compressed at a time when it's streamed
+const STREAM_PIECE: usize = 32 * 1024;
+
+// One chunk of a chunked body: its size in hex, a line ending, the data and another line
+// ending. Nothing is written for no data, as a chunk of size 0 ends the body.
+// Returns the length of the data.
+fn write_chunk<W: Write>(out: &mut W, data: &[u8]) -> io::Result<usize> {
+    if data.is_empty() {
+        return Ok(0);
+    }
+    write!(out, "{:x}\r\n", data.len())?;
+    out.write_all(data)?;
+    out.write_all(b"\r\n")?;
+    Ok(data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> Result<Option<Request>, ParseError> {
+        read_request(&mut &raw[..], &Limits::default())
+    }
+
+    fn status(raw: &[u8]) -> Option<u16> {
+        parse(raw).unwrap_err().status()
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let request = parse(b"GET /users/caf%C3%A9?name=J+Doe&x=%41 HTTP/1.1\r\nHost: localhost\r\nAccept:  text/html \r\nX-Multi: a\r\nx-multi: b\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(Method::Get, request.method);
+        assert_eq!("/users/café", request.path);
+        assert_eq!("name=J+Doe&x=%41", request.query);
+        assert_eq!(Some("J Doe".to_string()), request.query_param("name"));
+        assert_eq!(Some("A".to_string()), request.query_param("x"));
+        assert_eq!(Version::Http11, request.version);
+        assert_eq!(Some("text/html"), request.headers.get("accept"));
+        assert_eq!(vec!["a", "b"], request.headers.get_all("X-MULTI").collect::<Vec<_>>());
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn empty_stream_is_not_a_request() {
+        assert!(parse(b"").unwrap().is_none());
+        assert_eq!(Some(400), status(b"\r\n\r\n\r\n\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost: x\r\n"));
+    }
+
+    #[test]
+    fn reads_bodies() {
+        let request = parse(b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloEXTRA").unwrap().unwrap();
+        assert_eq!(b"hello".to_vec(), request.body);
+
+        let request = parse(
+            b"POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: yes\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(b"hello, world".to_vec(), request.body);
+
+        assert_eq!(Some(400), status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nshort"));
+        assert_eq!(Some(400), status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"));
+        assert_eq!(
+            Some(400),
+            status(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n")
+        );
+        assert_eq!(Some(400), status(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"));
+        // Chunk data running past its size is a bad request, not a header that is too large
+        assert_eq!(
+            Some(400),
+            status(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhello\r\n0\r\n\r\n")
+        );
+        assert_eq!(Some(501), status(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(Some(400), status(b"GET /\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET  / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"G(T / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET nope HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET /%zz HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost : x\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTP/1.1\r\nHost: x\r\nno colon\r\n\r\n"));
+        assert_eq!(Some(505), status(b"GET / HTTP/2.0\r\n\r\n"));
+        assert_eq!(Some(400), status(b"GET / HTTQ/1.1\r\n\r\n"));
+
+        let response = parse(b"GET /\r\n\r\n").unwrap_err().response().unwrap();
+        assert_eq!(400, response.status);
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits { request_line: 32, headers: 2, header_bytes: 64, body: 8 };
+        let parse = |raw: &[u8]| read_request(&mut &raw[..], &limits).unwrap_err().status();
+
+        assert_eq!(Some(414), parse(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40)).as_bytes()));
+        assert_eq!(Some(431), parse(b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\nB: 2\r\n\r\n"));
+        assert_eq!(Some(431), parse(format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", "x".repeat(80)).as_bytes()));
+        assert_eq!(Some(413), parse(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 9\r\n\r\n123456789"));
+        assert_eq!(
+            Some(413),
+            parse(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n12345\r\n5\r\n12345\r\n0\r\n\r\n")
+        );
+        assert_eq!(Some(413), parse(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999999999999999\r\n\r\n"));
+    }
+
+    #[test]
+    fn absolute_form_and_http_1_0() {
+        let request = parse(b"GET http://example.com/a/b?c=d HTTP/1.0\r\n\r\n").unwrap().unwrap();
+        assert_eq!("/a/b", request.path);
+        assert_eq!("c=d", request.query);
+        assert_eq!(Version::Http10, request.version);
+    }
+
+    #[test]
+    fn formats_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", http_date(time));
+        assert_eq!(Some(time), parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!("Thu, 29 Feb 2024 00:00:00 GMT", http_date(UNIX_EPOCH + Duration::from_secs(1_709_164_800)));
+        assert_eq!(None, parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!("a%20b%2Fc%25d-e.txt", percent_encode("a b/c%d-e.txt"));
+    }
+
+    #[test]
+    fn writes_responses() {
+        let mut out = Vec::new();
+        Response::new(200)
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Length", "999")
+            .body(vec![0, 159, 146, 150])
+            .write_to(&mut out)
+            .unwrap();
+        let mut expected =
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: 4\r\n\r\n".to_vec();
+        expected.extend_from_slice(&[0, 159, 146, 150]);
+        assert_eq!(expected, out);
+    }
+}
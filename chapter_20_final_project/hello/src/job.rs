@@ -0,0 +1,318 @@
+use std::{
+    error, fmt,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use crate::scheduler::Refused;
+use crate::{panic_message, Job, Shared, ThreadPool};
+
+// Jobs that give back a value, and scoped jobs that can borrow from the caller's stack.
+// Both are ordinary jobs on the pool's existing workers: a submitted job sends its result
+// down a channel of its own, which its JobHandle waits on.
+
+/// Why a job didn't produce a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobError {
+    /// The job panicked with this message
+    Panicked(String),
+    /// The job was dropped without running, because the pool had been shut down
+    /// or had no room for it
+    Canceled,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(message) => write!(f, "job panicked: {message}"),
+            JobError::Canceled => f.write_str("job was canceled before it ran"),
+        }
+    }
+}
+
+impl error::Error for JobError {}
+
+/// The result of a job from ThreadPool::submit or Scope::submit
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job to finish
+    pub fn join(self) -> Result<T, JobError> {
+        self.receiver.recv().unwrap_or(Err(JobError::Canceled))
+    }
+
+    /// The result if the job has finished, without waiting.
+    /// The result is only given out once, by this or join_timeout.
+    pub fn try_join(&mut self) -> Option<Result<T, JobError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JobError::Canceled)),
+        }
+    }
+
+    /// Waits up to timeout for the job to finish, None if it is still running
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JobError>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => Some(Err(JobError::Canceled)),
+        }
+    }
+}
+
+// Wraps f so it sends its result, or its panic, to the returned handle.
+// A panic still counts towards the pool's panic_count.
+fn with_handle<'a, F, T>(f: F, shared: Arc<Shared>) -> (impl FnOnce() + Send + 'a, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'a,
+    T: Send + 'a,
+{
+    let (sender, receiver) = mpsc::channel();
+    let job = move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+            shared.panics.fetch_add(1, Ordering::SeqCst);
+            JobError::Panicked(panic_message(payload.as_ref()))
+        });
+        // The handle may have been dropped, and then nobody wants the result
+        let _ = sender.send(result);
+    };
+    (job, JobHandle { receiver })
+}
+
+impl ThreadPool {
+    /// Runs f on a worker and returns a handle to wait for its value.
+    /// A panic in f is given to the handle as an error rather than to the panic hook.
+    /// If the pool has been shut down, or its QueuePolicy turns the job away or drops it,
+    /// joining it returns Canceled.
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = with_handle(f, Arc::clone(&self.shared));
+        let _ = self.execute(job);
+        handle
+    }
+
+    /// Like std::thread::scope: jobs started through the Scope can borrow anything that
+    /// outlives the call, because scope only returns once all of them have finished.
+    /// If a job started with Scope::execute panicked, scope panics after they have all finished.
+    ///
+    /// Calling scope from a job on the same pool can deadlock when every worker ends up
+    /// waiting for jobs that have no free worker to run on.
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                running: Mutex::new(0),
+                finished: Condvar::new(),
+                panicked: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        // Even if f panics, the jobs may still be using its borrows, so wait for them first
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.panicked.load(Ordering::SeqCst) => panic!("a scoped job panicked"),
+            Ok(result) => result,
+        }
+    }
+}
+
+/// Starts jobs that can borrow from outside the ThreadPool::scope call.
+/// The lifetimes work like std::thread::Scope: 'env is what the jobs may borrow and 'scope
+/// is the scope itself.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    // Invariant, so neither lifetime can be shortened or lengthened
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    running: Mutex<usize>,
+    finished: Condvar,
+    panicked: AtomicBool,
+}
+
+// Counts a job as running until it is dropped, which happens after the job has run
+struct Running(Arc<ScopeState>);
+
+// A scoped job. Fields are dropped in order, so an f that is never run is still dropped
+// before its Running.
+struct Scoped<F> {
+    f: F,
+    _running: Running,
+}
+
+impl<F: FnOnce()> Scoped<F> {
+    fn run(self) {
+        (self.f)();
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().unwrap_or_else(PoisonError::into_inner);
+        *running -= 1;
+        if *running == 0 {
+            self.0.finished.notify_all();
+        }
+    }
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Runs f on a worker. A panic in f makes ThreadPool::scope panic once every job is done.
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let state = Arc::clone(&self.state);
+        self.spawn(move || {
+            if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+                state.panicked.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+
+    /// Runs f on a worker and returns a handle to wait for its value
+    pub fn submit<F, T>(&'scope self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let (job, handle) = with_handle(f, Arc::clone(&self.pool.shared));
+        self.spawn(job);
+        handle
+    }
+
+    // Sends f to the pool as an ordinary 'static job. It must not panic.
+    fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.running.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        let scoped = Scoped { f, _running: Running(Arc::clone(&self.state)) };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || scoped.run());
+        // Safety: ThreadPool::scope doesn't return until every job's Running has been
+        // dropped, and Running is only dropped after f has run, or been dropped unrun by
+        // QueuePolicy::DropOldest, so nothing f borrows can go away while the job exists.
+        let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        // The pool has been shut down or is full, so run it here rather than never
+        match self.pool.shared.scheduler.push(job) {
+            Ok(()) => self.pool.shared.grow(),
+            Err(Refused::Closed(job) | Refused::Full(job)) => job(),
+        }
+    }
+
+    fn wait(&self) {
+        let running = self.state.running.lock().unwrap_or_else(PoisonError::into_inner);
+        let _done = self
+            .state
+            .finished
+            .wait_while(running, |running| *running > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Instant};
+
+    #[test]
+    fn submitted_jobs_return_values() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<JobHandle<u64>> = (1..=10).map(|n| pool.submit(move || n * n)).collect();
+        let squares: Vec<u64> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(vec![1, 4, 9, 16, 25, 36, 49, 64, 81, 100], squares);
+
+        let failed = pool.submit(|| -> u8 { panic!("no value for {}", "you") });
+        assert_eq!(Err(JobError::Panicked("no value for you".to_string())), failed.join());
+        assert_eq!(1, pool.panic_count());
+    }
+
+    #[test]
+    fn try_and_timed_joins() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel::<()>();
+        let mut handle = pool.submit(move || {
+            receiver.recv().unwrap();
+            "done"
+        });
+
+        assert_eq!(None, handle.try_join());
+        let start = Instant::now();
+        assert_eq!(None, handle.join_timeout(Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        sender.send(()).unwrap();
+        assert_eq!(Some(Ok("done")), handle.join_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn canceled_after_shutdown() {
+        let mut pool = ThreadPool::new(1);
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(Err(JobError::Canceled), pool.submit(|| 1).join());
+    }
+
+    #[test]
+    fn scoped_jobs_borrow() {
+        let pool = ThreadPool::new(3);
+        let mut numbers = vec![1, 2, 3, 4, 5, 6];
+        let words = ["scoped", "jobs", "borrow"];
+        let total = Mutex::new(0);
+
+        let letters = pool.scope(|scope| {
+            for chunk in numbers.chunks_mut(2) {
+                scope.execute(|| {
+                    thread::sleep(Duration::from_millis(10));
+                    for n in chunk.iter_mut() {
+                        *n *= 2;
+                    }
+                    *total.lock().unwrap() += chunk.iter().sum::<i32>();
+                });
+            }
+            let letters = scope.submit(|| words.iter().map(|word| word.len()).sum::<usize>());
+            letters.join().unwrap()
+        });
+
+        assert_eq!(16, letters);
+        assert_eq!(vec![2, 4, 6, 8, 10, 12], numbers);
+        assert_eq!(42, total.into_inner().unwrap());
+    }
+
+    #[test]
+    fn scope_waits_even_when_jobs_panic() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.execute(|| panic!("scoped job failed"));
+                scope.execute(|| {
+                    thread::sleep(Duration::from_millis(50));
+                    finished.store(true, Ordering::SeqCst);
+                });
+            })
+        }));
+        assert!(result.is_err());
+        assert!(finished.load(Ordering::SeqCst));
+    }
+}
@@ -1,82 +1,831 @@
+pub mod compress;
+pub mod config;
+pub mod files;
+pub mod http;
+pub mod job;
+pub mod log;
+pub mod metrics;
+pub mod pages;
+pub mod router;
+mod base64;
+mod builder;
+mod deflate;
+mod scheduler;
+pub mod server;
+mod sha1;
+pub mod signal;
+pub mod template;
+pub mod websocket;
+
+pub use builder::Builder;
+pub use job::{JobError, JobHandle, Scope};
+
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    error, fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+use scheduler::{Popped, Refused, Scheduler};
+
 /// Single Worker struct
-/// Contains an id (usize) and a thread of type Option<thread::JoinHandle<()>>
+/// One of the max_threads places a worker thread can run in. Contains an id (usize), the
+/// handle of the thread running there, and whether a worker has the place.
+/// A worker that dies puts the handle of its replacement in the same place.
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+    taken: AtomicBool,
 }
 
-
-impl Worker {
-    /// Worker::new()
-    /// Takes an 
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(
-            move || loop {
-                let message = receiver.lock().unwrap().recv();
-                match message {
-                    Ok(job) => {
-                        println!("Worker {id} got a job; executing.");
-                        job();
+// Starts the thread for worker id, whose place must already be taken, and stores its handle
+fn spawn_worker(shared: &Arc<Shared>, id: usize) -> io::Result<()> {
+    // Held until the handle is stored, so a thread dying straight away can't store its
+    // replacement first and then have it overwritten
+    let mut stored = shared.workers[id].thread.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut builder = thread::Builder::new().name(format!("{}-{id}", shared.name_prefix));
+    if let Some(stack_size) = shared.stack_size {
+        builder = builder.stack_size(stack_size);
+    }
+    let sentinel = Sentinel { id, shared: Arc::clone(shared) };
+    let shared = Arc::clone(shared);
+    let thread = builder.spawn(
+        move || {
+            let _sentinel = sentinel;
+            shared.scheduler.register(id);
+            shared.event(&PoolEvent::Started { worker: id });
+            // Only workers above min_threads can retire, so the rest wait without a timeout
+            let idle = (shared.min_threads < shared.workers.len()).then_some(shared.keep_alive);
+            loop {
+                match shared.scheduler.pop(id, idle) {
+                    Popped::Job(job) => {
+                        shared.active.fetch_add(1, Ordering::SeqCst);
+                        // A panicking job mustn't take the worker down with it
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            shared.job_panicked(id, payload);
+                        }
+                        shared.active.fetch_sub(1, Ordering::SeqCst);
+                        shared.completed.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Popped::Idle => {
+                        let retired = shared.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                            (live > shared.min_threads).then(|| live - 1)
+                        });
+                        if retired.is_ok() {
+                            shared.event(&PoolEvent::Retired { worker: id });
+                            // Last, since Shared::grow can reuse the place as soon as it's free
+                            shared.workers[id].taken.store(false, Ordering::SeqCst);
+                            // A job queued while the place was still taken may have had no
+                            // worker started for it, so take the place back and run it
+                            if shared.scheduler.len() > 0 && shared.rejoin(id) {
+                                shared.event(&PoolEvent::Started { worker: id });
+                                continue;
+                            }
+                            return;
+                        }
                     }
-                    Err(_) => {
-                        println!("Worker {id} disconnected; shutting down.");
-                        break;
+                    Popped::Closed => {
+                        shared.event(&PoolEvent::Stopped { worker: id });
+                        return;
                     }
                 }
             }
-        );
-        Worker { id: id, thread: Some(thread) }
+        }
+    )?;
+    *stored = Some(thread);
+    Ok(())
+}
+
+// Lives on a worker's thread. If the thread dies from a panic that escaped catch_unwind,
+// such as one in the panic hook, dropping the sentinel starts a replacement so the pool
+// never silently shrinks.
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.shared.event(&PoolEvent::Died { worker: self.id });
+            self.shared.died.lock().unwrap_or_else(PoisonError::into_inner).push(self.id);
+            if let Err(err) = spawn_worker(&self.shared, self.id) {
+                self.shared.spawn_failed(self.id, err);
+            }
+        }
+    }
+}
+
+/// Something that happened to one of a ThreadPool's workers, for the hook set with
+/// Builder::on_event. Without a hook these are printed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoolEvent {
+    /// A worker thread started, when the pool was built or grew
+    Started { worker: usize },
+    /// A worker above min_threads was idle for the keep-alive, and stopped
+    Retired { worker: usize },
+    /// A worker stopped because the pool was shut down
+    Stopped { worker: usize },
+    /// A worker's thread died from a panic outside a job, and a new one is starting in its place
+    Died { worker: usize },
+    /// A thread for worker couldn't be started
+    SpawnFailed { worker: usize, error: String },
+}
+
+impl fmt::Display for PoolEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolEvent::Started { worker } => write!(f, "Worker {worker} started."),
+            PoolEvent::Retired { worker } => write!(f, "Worker {worker} idle; retiring."),
+            PoolEvent::Stopped { worker } => write!(f, "Worker {worker} disconnected; shutting down."),
+            PoolEvent::Died { worker } => write!(f, "Worker {worker} died; starting a new one."),
+            PoolEvent::SpawnFailed { worker, error } => write!(f, "Worker {worker} couldn't start: {error}"),
+        }
     }
 }
 
+type EventHook = Box<dyn Fn(&PoolEvent) + Send + Sync>;
+
 /// Job type alias of Box<dyn FnOnce + Send + 'static>
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-/// ThreadPool contains a vector of Workers
-/// and a sender of type Option<mpsc::Sender<Job>>
-pub struct ThreadPool {
+/// What the panic hook is told about a job that panicked
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobPanic {
+    /// The id of the worker that ran the job
+    pub worker: usize,
+    /// The panic message, if it was a string
+    pub message: String,
+}
+
+type PanicHook = Box<dyn Fn(&JobPanic) + Send + Sync>;
+
+// The state the workers share with the pool
+struct Shared {
+    scheduler: Scheduler,
+    // One place for each of max_threads
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>
+    // Places taken by a worker
+    live: AtomicUsize,
+    min_threads: usize,
+    keep_alive: Duration,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    events: Option<EventHook>,
+    policy: QueuePolicy,
+    active: AtomicUsize,
+    completed: AtomicUsize,
+    rejected: AtomicUsize,
+    dropped: AtomicUsize,
+    panics: AtomicUsize,
+    panic_hook: RwLock<Option<PanicHook>>,
+    // Ids of workers whose threads died and were replaced
+    died: Mutex<Vec<usize>>,
+}
+
+impl Shared {
+    fn event(&self, event: &PoolEvent) {
+        match (&self.events, event) {
+            (Some(hook), event) => hook(event),
+            (None, PoolEvent::Died { .. } | PoolEvent::SpawnFailed { .. }) => eprintln!("{event}"),
+            (None, event) => println!("{event}"),
+        }
+    }
+
+    // Gives up the place of a worker whose thread couldn't be started
+    fn spawn_failed(&self, worker: usize, err: io::Error) {
+        self.workers[worker].taken.store(false, Ordering::SeqCst);
+        self.live.fetch_sub(1, Ordering::SeqCst);
+        self.event(&PoolEvent::SpawnFailed { worker, error: err.to_string() });
+    }
+
+    // Starts another worker, up to max_threads, when more jobs are queued than there are
+    // idle workers to take them
+    fn grow(self: &Arc<Shared>) {
+        let live = self.live.load(Ordering::SeqCst);
+        if live >= self.workers.len() || self.scheduler.len() <= self.scheduler.idle() {
+            return;
+        }
+        // Someone else growing or a worker retiring meanwhile makes it the next push's call
+        if self.live.compare_exchange(live, live + 1, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return;
+        }
+        let free = self.workers.iter().find(|worker| {
+            worker.taken.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        });
+        let Some(worker) = free else {
+            // A retiring worker has given up its count but not its place yet
+            self.live.fetch_sub(1, Ordering::SeqCst);
+            return;
+        };
+        // The worker that retired from this place has nothing left to do but return
+        let previous = worker.thread.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(previous) = previous {
+            let _ = previous.join();
+        }
+        if let Err(err) = spawn_worker(self, worker.id) {
+            self.spawn_failed(worker.id, err);
+        }
+    }
+
+    // Takes back the place of a worker that has just retired from it, unless another worker
+    // has already been started there or the pool is at max_threads again
+    fn rejoin(&self, worker: usize) -> bool {
+        let place = &self.workers[worker].taken;
+        if place.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return false;
+        }
+        let counted = self.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+            (live < self.workers.len()).then(|| live + 1)
+        });
+        if counted.is_err() {
+            place.store(false, Ordering::SeqCst);
+        }
+        counted.is_ok()
+    }
+
+    fn job_panicked(&self, worker: usize, payload: Box<dyn Any + Send>) {
+        self.panics.fetch_add(1, Ordering::SeqCst);
+        let job_panic = JobPanic { worker, message: panic_message(payload.as_ref()) };
+        match &*self.panic_hook.read().unwrap_or_else(PoisonError::into_inner) {
+            Some(hook) => hook(&job_panic),
+            None => eprintln!("Worker {worker} caught a panicking job: {}", job_panic.message),
+        }
+    }
+}
+
+// The message panic! was given, which is either a &str or a formatted String
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => payload.downcast_ref::<&str>().map_or("Box<dyn Any>", |message| *message).to_string(),
+    }
+}
+
+/// ThreadPool holds the state it shares with its Workers,
+/// including the Scheduler their jobs are queued on
+pub struct ThreadPool {
+    shared: Arc<Shared>,
 }
 
 impl ThreadPool {
+    /// A pool of size workers whose queue can grow without limit
     pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::bounded(size, usize::MAX, QueuePolicy::Block)
+    }
+
+    /// A pool of size workers that holds at most capacity jobs waiting for a worker,
+    /// and deals with any more as policy says
+    pub fn bounded(size: usize, capacity: usize, policy: QueuePolicy) -> ThreadPool {
         assert!(size > 0);
+        assert!(capacity > 0);
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers: Vec<Worker> = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-        ThreadPool { workers: workers, sender: Some(sender)}
+        Builder::new()
+            .threads(size)
+            .capacity(capacity)
+            .policy(policy)
+            .build()
+            .unwrap_or_else(|err| panic!("couldn't start the thread pool: {err}"))
     }
 
-    pub fn execute<F> (&self, f: F)
+    /// A Builder for a pool that grows and shrinks between min_threads and max_threads
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Queues f to run on a worker. Fails once the pool has been shut down, or when the
+    /// queue is full and the QueuePolicy is Reject.
+    /// Called from a job on this pool, f goes on that worker's own queue, where idle
+    /// workers can steal it from.
+    pub fn execute<F> (&self, f: F) -> Result<(), ExecuteError>
     where F: FnOnce() + Send + 'static
     {
-        let job = Box::new(f);
-        self.sender
-            .as_ref()
-            .unwrap()
-            .send(job)
-            .unwrap();
+        let scheduler = &self.shared.scheduler;
+        let mut job: Job = Box::new(f);
+        loop {
+            job = match scheduler.push(job) {
+                Ok(()) => {
+                    self.shared.grow();
+                    return Ok(());
+                }
+                Err(Refused::Closed(_)) => return Err(ExecuteError::ShutDown),
+                Err(Refused::Full(job)) => job,
+            };
+            match self.shared.policy {
+                QueuePolicy::Reject => {
+                    self.shared.rejected.fetch_add(1, Ordering::SeqCst);
+                    return Err(ExecuteError::Full);
+                }
+                // A worker waiting for room could be waiting on itself
+                QueuePolicy::Block if !scheduler.on_worker() => {
+                    scheduler.push_wait(job).map_err(|_| ExecuteError::ShutDown)?;
+                    self.shared.grow();
+                    return Ok(());
+                }
+                QueuePolicy::DropOldest => {
+                    if scheduler.pop_oldest().is_some() {
+                        self.shared.dropped.fetch_add(1, Ordering::SeqCst);
+                    }
+                    // Try again, since another job may have taken the place meanwhile
+                }
+                QueuePolicy::Block | QueuePolicy::CallerRuns => {
+                    job();
+                    self.shared.completed.fetch_add(1, Ordering::SeqCst);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// What execute does with a job when the queue is full
+    pub fn policy(&self) -> QueuePolicy {
+        self.shared.policy
+    }
+
+    /// A snapshot of what the pool is doing
+    pub fn metrics(&self) -> PoolMetrics {
+        self.monitor().metrics()
+    }
+
+    /// Something to take metrics with where the pool itself can't go, such as a handler
+    /// running on the pool
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor { shared: Arc::clone(&self.shared) }
+    }
+
+    /// How many jobs have panicked so far
+    pub fn panic_count(&self) -> usize {
+        self.shared.panics.load(Ordering::SeqCst)
+    }
+
+    /// Calls hook on the worker's thread whenever a job panics, instead of printing the panic.
+    /// If the hook panics itself, the worker dies and is replaced.
+    pub fn set_panic_hook<F>(&self, hook: F)
+    where F: Fn(&JobPanic) + Send + Sync + 'static
+    {
+        *self.shared.panic_hook.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+    }
+}
+
+/// What ThreadPool::execute does with a job when the queue is already at capacity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    /// Wait for a worker to take a job off the queue. Called from one of the pool's own
+    /// workers, which might be the only one that could make room, it runs the job instead.
+    Block,
+    /// Fail with ExecuteError::Full, so a server can answer 503 Service Unavailable
+    Reject,
+    /// Drop the job that has been waiting longest to make room for this one
+    DropOldest,
+    /// Run the job on the calling thread, which slows down whoever is queuing jobs.
+    /// A panic in the job is the caller's.
+    CallerRuns,
+}
+
+/// Takes a ThreadPool's metrics; from ThreadPool::monitor
+#[derive(Clone)]
+pub struct PoolMonitor {
+    shared: Arc<Shared>,
+}
+
+impl PoolMonitor {
+    pub fn metrics(&self) -> PoolMetrics {
+        let shared = &self.shared;
+        PoolMetrics {
+            workers: shared.live.load(Ordering::SeqCst),
+            queued: shared.scheduler.len(),
+            active: shared.active.load(Ordering::SeqCst),
+            completed: shared.completed.load(Ordering::SeqCst),
+            rejected: shared.rejected.load(Ordering::SeqCst),
+            dropped: shared.dropped.load(Ordering::SeqCst),
+            panicked: shared.panics.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Counts from ThreadPool::metrics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolMetrics {
+    /// Worker threads running, between min_threads and max_threads
+    pub workers: usize,
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// Workers running a job
+    pub active: usize,
+    /// Jobs that have run, including ones that panicked and ones run by CallerRuns
+    pub completed: usize,
+    /// Jobs turned away by the Reject policy
+    pub rejected: usize,
+    /// Jobs dropped by the DropOldest policy
+    pub dropped: usize,
+    /// Jobs that panicked, as in ThreadPool::panic_count
+    pub panicked: usize,
+}
+
+/// Why ThreadPool::execute couldn't queue a job
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecuteError {
+    ShutDown,
+    /// The queue was at capacity and the policy is QueuePolicy::Reject
+    Full,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::ShutDown => f.write_str("the thread pool has been shut down"),
+            ExecuteError::Full => f.write_str("the thread pool's queue is full"),
+        }
+    }
+}
+
+impl error::Error for ExecuteError {}
+
+impl ThreadPool {
+    /// Stops taking jobs and waits up to timeout for the workers to finish the ones they have.
+    /// Workers still busy after that are left to finish in the background and reported in the
+    /// error, along with any that had died from a panic.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> Result<(), ShutdownError> {
+        self.shared.scheduler.close();
+        let deadline = Instant::now() + timeout;
+        let mut panicked = self.shared.died.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        panicked.sort();
+        panicked.dedup();
+        let mut error = ShutdownError { timed_out: Vec::new(), panicked };
+        for worker in &self.shared.workers {
+            // A worker that died has put its replacement in its place, which needs joining too
+            while let Some(thread) = take_thread(worker) {
+                // JoinHandle has no join with a timeout, so poll until the deadline
+                while !thread.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(5));
+                }
+                if !thread.is_finished() {
+                    // Dropping the handle detaches the thread
+                    error.timed_out.push(worker.id);
+                    break;
+                }
+                if thread.join().is_err() && !error.panicked.contains(&worker.id) {
+                    error.panicked.push(worker.id);
+                }
+            }
+        }
+        if error.timed_out.is_empty() && error.panicked.is_empty() {
+            Ok(())
+        } else {
+            Err(error)
+        }
     }
 }
 
+fn take_thread(worker: &Worker) -> Option<thread::JoinHandle<()>> {
+    worker.thread.lock().unwrap_or_else(PoisonError::into_inner).take()
+}
+
+/// The workers that didn't stop cleanly in ThreadPool::shutdown_timeout, by id
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownError {
+    pub timed_out: Vec<usize>,
+    pub panicked: Vec<usize>,
+}
+
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "workers still running: {:?}, workers that panicked: {:?}", self.timed_out, self.panicked)
+    }
+}
+
+impl error::Error for ShutdownError {}
+
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+        self.shared.scheduler.close();
+        // Workers already joined by shutdown_timeout have no thread left
+        for worker in &self.shared.workers {
+            while let Some(thread) = take_thread(worker) {
+                if thread.join().is_err() {
+                    eprintln!("Worker {} had panicked", worker.id);
+                }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn shutdown_waits_for_jobs() {
+        let mut pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        for job in 0..4 {
+            let sender = sender.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                sender.send(job).unwrap();
+            })
+            .unwrap();
+        }
+        assert_eq!(Ok(()), pool.shutdown_timeout(Duration::from_secs(5)));
+        assert_eq!(4, receiver.try_iter().count());
+    }
+
+    #[test]
+    fn shutdown_reports_stuck_and_dead_workers() {
+        let mut pool = ThreadPool::new(3);
+        // The hook panicking is what kills a worker, since jobs themselves are caught
+        pool.set_panic_hook(|_| panic!("hook failed"));
+        // Stuck until the shutdown has given up on it
+        let (release, stuck) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = stuck.recv();
+        })
+        .unwrap();
+        pool.execute(|| panic!("job failed")).unwrap();
+        // Let the panic happen before the deadline
+        while pool.panic_count() == 0 {
+            thread::yield_now();
+        }
+
+        let error = pool.shutdown_timeout(Duration::from_secs(1)).unwrap_err();
+        assert_eq!((1, 1), (error.timed_out.len(), error.panicked.len()));
+        assert_ne!(error.timed_out, error.panicked);
+        assert_eq!(Err(ExecuteError::ShutDown), pool.execute(|| {}));
+        drop(release);
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        let panics = sender.clone();
+        pool.set_panic_hook(move |job_panic| panics.send(Err(job_panic.clone())).unwrap());
+
+        pool.execute(|| panic!("first")).unwrap();
+        pool.execute(|| panic!("{} {}", "second", 2)).unwrap();
+        pool.execute(move || sender.send(Ok(())).unwrap()).unwrap();
+
+        let results: Vec<Result<(), JobPanic>> = receiver.iter().take(3).collect();
+        assert_eq!(
+            vec![
+                Err(JobPanic { worker: 0, message: "first".to_string() }),
+                Err(JobPanic { worker: 0, message: "second 2".to_string() }),
+                Ok(()),
+            ],
+            results
+        );
+        assert_eq!(2, pool.panic_count());
+    }
+
+    #[test]
+    fn replaces_dead_workers() {
+        let pool = ThreadPool::new(1);
+        pool.set_panic_hook(|_| panic!("hook failed"));
+        pool.execute(|| panic!("job failed")).unwrap();
+
+        // The only worker died in the hook, so this runs on its replacement
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().id()).unwrap()).unwrap();
+        let id = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_ne!(thread::current().id(), id);
+        assert_eq!(1, pool.panic_count());
+    }
+
+    #[test]
+    fn idle_workers_steal_jobs_queued_by_a_job() {
+        let pool = ThreadPool::new(4);
+        // Two jobs that each tell the other they've started and wait to hear the same back,
+        // which they only can if two workers run them at once
+        let (left, right) = (mpsc::channel(), mpsc::channel());
+        let pairs = [(left.0, right.1), (right.0, left.1)];
+        let met = AtomicUsize::new(0);
+        pool.scope(|scope| {
+            scope.execute(|| {
+                // These both go on this worker's own deque, so one has to be stolen
+                for (sender, receiver) in pairs {
+                    let met = &met;
+                    scope.execute(move || {
+                        let _ = sender.send(());
+                        if receiver.recv_timeout(Duration::from_secs(10)).is_ok() {
+                            met.fetch_add(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+            });
+        });
+        assert_eq!(2, met.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn many_producers() {
+        let pool = ThreadPool::new(3);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|producers| {
+            for producer in 0..4 {
+                let (pool, sender) = (&pool, sender.clone());
+                producers.spawn(move || {
+                    for job in 0..1000 {
+                        let sender = sender.clone();
+                        pool.execute(move || sender.send(producer * 1000 + job).unwrap()).unwrap();
+                    }
+                });
+            }
+        });
+        drop(sender);
+        let mut done: Vec<usize> = receiver.iter().collect();
+        done.sort();
+        assert_eq!((0..4000).collect::<Vec<usize>>(), done);
+    }
+
+    // A pool of one worker stuck in a job until the returned sender is used or dropped
+    fn stuck(capacity: usize, policy: QueuePolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::bounded(1, capacity, policy);
+        let (release, stuck) = mpsc::channel();
+        let (started, running) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = stuck.recv();
+        })
+        .unwrap();
+        running.recv().unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn reject_when_full() {
+        let (mut pool, release) = stuck(2, QueuePolicy::Reject);
+        pool.execute(|| {}).unwrap();
+        pool.execute(|| {}).unwrap();
+        assert_eq!(Err(ExecuteError::Full), pool.execute(|| {}));
+        assert_eq!(
+            PoolMetrics { workers: 1, queued: 2, active: 1, completed: 0, rejected: 1, dropped: 0, panicked: 0 },
+            pool.metrics()
+        );
+
+        drop(release);
+        pool.shutdown_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            PoolMetrics { workers: 1, queued: 0, active: 0, completed: 3, rejected: 1, dropped: 0, panicked: 0 },
+            pool.metrics()
+        );
+    }
+
+    #[test]
+    fn block_until_there_is_room() {
+        let (pool, release) = stuck(1, QueuePolicy::Block);
+        pool.execute(|| {}).unwrap();
+        let released = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(release);
+        });
+        let start = Instant::now();
+        pool.execute(|| {}).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        released.join().unwrap();
+    }
+
+    #[test]
+    fn drop_oldest_when_full() {
+        let (pool, release) = stuck(2, QueuePolicy::DropOldest);
+        let oldest = pool.submit(|| "oldest");
+        let middle = pool.submit(|| "middle");
+        let newest = pool.submit(|| "newest");
+        assert_eq!(1, pool.metrics().dropped);
+
+        drop(release);
+        assert_eq!(Err(JobError::Canceled), oldest.join());
+        assert_eq!(Ok("middle"), middle.join());
+        assert_eq!(Ok("newest"), newest.join());
+    }
+
+    #[test]
+    fn caller_runs_when_full() {
+        let (pool, release) = stuck(1, QueuePolicy::CallerRuns);
+        pool.execute(|| {}).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().id()).unwrap()).unwrap();
+        assert_eq!(thread::current().id(), receiver.recv().unwrap());
+        assert_eq!(1, pool.metrics().completed);
+        drop(release);
+    }
+
+    // A gate jobs wait at until it is opened
+    struct Gate {
+        open: Mutex<bool>,
+        opened: std::sync::Condvar,
+    }
+
+    impl Gate {
+        fn new() -> Arc<Gate> {
+            Arc::new(Gate { open: Mutex::new(false), opened: std::sync::Condvar::new() })
+        }
+
+        fn wait(&self) {
+            let _open = self.opened.wait_while(self.open.lock().unwrap(), |open| !*open).unwrap();
+        }
+
+        fn open(&self) {
+            *self.open.lock().unwrap() = true;
+            self.opened.notify_all();
+        }
+    }
+
+    #[test]
+    fn grows_under_load_and_shrinks_when_idle() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let pool = ThreadPool::builder()
+            .min_threads(1)
+            .max_threads(4)
+            .keep_alive(Duration::from_millis(50))
+            .name_prefix("test-pool")
+            .stack_size(256 * 1024)
+            .on_event(move |event| recorded.lock().unwrap().push(event.clone()))
+            .build()
+            .unwrap();
+        assert_eq!(1, pool.metrics().workers);
+
+        // Four jobs that all have to be running at once before any can finish
+        let gate = Gate::new();
+        let (started, running) = mpsc::channel();
+        for _ in 0..4 {
+            let (gate, started) = (Arc::clone(&gate), started.clone());
+            pool.execute(move || {
+                started.send(thread::current().name().unwrap().to_string()).unwrap();
+                gate.wait();
+            })
+            .unwrap();
+        }
+        let mut names: Vec<String> = (0..4).map(|_| running.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        names.sort();
+        assert_eq!(vec!["test-pool-0", "test-pool-1", "test-pool-2", "test-pool-3"], names);
+        assert_eq!(4, pool.metrics().workers);
+
+        gate.open();
+        let start = Instant::now();
+        while pool.metrics().workers > 1 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, pool.metrics().workers);
+        let events = events.lock().unwrap();
+        let count = |f: fn(&PoolEvent) -> bool| events.iter().filter(|event| f(event)).count();
+        assert_eq!(4, count(|event| matches!(event, PoolEvent::Started { .. })));
+        assert_eq!(3, count(|event| matches!(event, PoolEvent::Retired { .. })));
+    }
+
+    #[test]
+    fn grows_from_no_workers() {
+        let pool = ThreadPool::builder()
+            .min_threads(0)
+            .max_threads(2)
+            .keep_alive(Duration::from_millis(20))
+            .on_event(|_| {})
+            .build()
+            .unwrap();
+        assert_eq!(0, pool.metrics().workers);
+        assert_eq!(Ok(42), pool.submit(|| 42).join());
+        // Coming back after the worker retired starts another
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(0, pool.metrics().workers);
+        assert_eq!(Ok(7), pool.submit(|| 7).join());
+    }
+
+    #[test]
+    fn a_retiring_worker_doesnt_strand_jobs() {
+        // The only worker stops in its Retired event, after giving up its count but before its
+        // place, and a job is queued then, when there is no place to start another worker in
+        let (retiring, retired) = mpsc::channel();
+        let pool = ThreadPool::builder()
+            .min_threads(0)
+            .max_threads(1)
+            .keep_alive(Duration::from_millis(10))
+            .on_event(move |event| {
+                if let PoolEvent::Retired { .. } = event {
+                    let _ = retiring.send(());
+                    thread::sleep(Duration::from_millis(100));
+                }
+            })
+            .build()
+            .unwrap();
+        assert_eq!(Ok(1), pool.submit(|| 1).join());
+        retired.recv_timeout(Duration::from_secs(5)).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(2).unwrap()).unwrap();
+        assert_eq!(Ok(2), receiver.recv_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn builder_checks_its_settings() {
+        let invalid = |builder: Builder| builder.build().err().map(|err| err.kind());
+        assert_eq!(Some(io::ErrorKind::InvalidInput), invalid(Builder::new().max_threads(0)));
+        assert_eq!(Some(io::ErrorKind::InvalidInput), invalid(Builder::new().min_threads(3).max_threads(2)));
+        assert_eq!(Some(io::ErrorKind::InvalidInput), invalid(Builder::new().capacity(0)));
+    }
+}
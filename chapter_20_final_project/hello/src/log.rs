@@ -0,0 +1,279 @@
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::http::{self, Method, Version};
+
+// Access logs in the formats Apache and nginx write, so the usual log tools can read them,
+// with how long the request took appended in microseconds, like Apache's %D.
+//
+//   Common:   127.0.0.1 - - [19/Oct/2026:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326 1520
+//   Combined: the same, then "referer" "user agent" before the microseconds
+
+/// Which fields an AccessLog line has
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Client, time, request line, status and bytes
+    Common,
+    /// Common plus the Referer and User-Agent headers
+    Combined,
+}
+
+/// The request line of a logged request
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestLine {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+}
+
+/// One request and its response, as served on a connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessRecord {
+    pub client: Option<SocketAddr>,
+    /// When the request started to arrive
+    pub time: SystemTime,
+    /// None when the request was too malformed to read, or timed out before its end
+    pub request: Option<RequestLine>,
+    pub status: u16,
+    /// Bytes of body sent, not counting the head
+    pub bytes: usize,
+    /// From the request starting to arrive to the response being written
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessRecord {
+    /// The record as a line in format, without the newline
+    pub fn format(&self, format: LogFormat) -> String {
+        let mut line = String::new();
+        match self.client {
+            Some(client) => write!(line, "{} - - ", client.ip()),
+            None => write!(line, "- - - "),
+        }
+        .unwrap();
+        line.push_str(&log_date(self.time));
+        match &self.request {
+            Some(request) => {
+                let request_line = format!("{} {} {}", request.method, request.target, request.version);
+                write!(line, " \"{}\"", escape(&request_line)).unwrap();
+            }
+            None => line.push_str(" \"-\""),
+        }
+        write!(line, " {}", self.status).unwrap();
+        // CLF writes no body as - rather than 0
+        match self.bytes {
+            0 => line.push_str(" -"),
+            bytes => write!(line, " {bytes}").unwrap(),
+        }
+        if format == LogFormat::Combined {
+            for header in [&self.referer, &self.user_agent] {
+                write!(line, " \"{}\"", header.as_deref().map_or("-".to_string(), escape)).unwrap();
+            }
+        }
+        write!(line, " {}", self.duration.as_micros()).unwrap();
+        line
+    }
+}
+
+// A time the way CLF writes it, e.g. [10/Oct/2000:13:55:36 +0000], always in UTC
+fn log_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (year, month, day) = http::civil_from_days((seconds / 86_400) as i64);
+    let time = seconds % 86_400;
+    format!(
+        "[{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000]",
+        http::MONTHS[month as usize - 1],
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// Escapes what the client sent so it can't end a quoted field or forge a line,
+// the way Apache does: \" and \\, and \xhh for control characters and non-ASCII bytes
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for &byte in field.as_bytes() {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => write!(escaped, "\\x{byte:02x}").unwrap(),
+        }
+    }
+    escaped
+}
+
+struct Output {
+    file: File,
+    size: u64,
+}
+
+/// Appends AccessRecords to a file, one line each.
+/// When the file would grow past max_bytes it is renamed to path.1, an older path.1 to
+/// path.2 and so on up to path.keep, which is deleted, and a new file is started.
+pub struct AccessLog {
+    path: PathBuf,
+    format: LogFormat,
+    max_bytes: u64,
+    keep: usize,
+    output: Mutex<Output>,
+}
+
+impl AccessLog {
+    /// Appends to the file at path, creating it if needed, rotating it every 10 MiB and
+    /// keeping 5 old files
+    pub fn open(path: impl AsRef<Path>, format: LogFormat) -> io::Result<AccessLog> {
+        let path = path.as_ref().to_path_buf();
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(AccessLog {
+            path,
+            format,
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+            output: Mutex::new(Output { file, size }),
+        })
+    }
+
+    /// Rotates the file once it would grow past max_bytes, keeping keep old files.
+    /// With keep at 0 the old lines are simply deleted.
+    pub fn rotate_at(mut self, max_bytes: u64, keep: usize) -> AccessLog {
+        self.max_bytes = max_bytes;
+        self.keep = keep;
+        self
+    }
+
+    /// Writes record as one line, rotating first if it doesn't fit
+    pub fn write(&self, record: &AccessRecord) -> io::Result<()> {
+        let mut line = record.format(self.format);
+        line.push('\n');
+        let mut output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+        if output.size > 0 && output.size + line.len() as u64 > self.max_bytes {
+            self.rotate(&mut output)?;
+        }
+        // A single write, so lines from different connections never interleave
+        output.file.write_all(line.as_bytes())?;
+        output.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, output: &mut Output) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            rename_if_exists(&self.rotated(n), &self.rotated(n + 1))?;
+        }
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        output.file = append(&self.path)?;
+        output.size = 0;
+        Ok(())
+    }
+
+    // path.n
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            client: Some("127.0.0.1:50000".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            request: Some(RequestLine {
+                method: Method::Get,
+                target: "/apache_pb.gif".to_string(),
+                version: Version::Http10,
+            }),
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1520),
+            referer: Some("http://www.example.com/start.html".to_string()),
+            user_agent: Some("Mozilla/4.08 [en] (Win98; I ;Nav)".to_string()),
+        }
+    }
+
+    #[test]
+    fn formats_common_and_combined() {
+        let record = record();
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 1520",
+            record.format(LogFormat::Common)
+        );
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 \
+             \"http://www.example.com/start.html\" \"Mozilla/4.08 [en] (Win98; I ;Nav)\" 1520",
+            record.format(LogFormat::Combined)
+        );
+
+        let unreadable = AccessRecord {
+            request: None,
+            status: 400,
+            bytes: 0,
+            referer: None,
+            user_agent: Some("quote\" and\nnewline".to_string()),
+            ..record
+        };
+        assert_eq!(
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 - \"-\" \"quote\\\" and\\x0anewline\" 1520",
+            unreadable.format(LogFormat::Combined)
+        );
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = env::temp_dir().join(format!("hello-log-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let line = record().format(LogFormat::Common).len() as u64 + 1;
+
+        // Room for two lines per file, and two old files
+        let log = AccessLog::open(&path, LogFormat::Common).unwrap().rotate_at(line * 2, 2);
+        for _ in 0..7 {
+            log.write(&record()).unwrap();
+        }
+        let lines = |path: &Path| fs::read_to_string(path).map_or(0, |text| text.lines().count());
+        assert_eq!(1, lines(&path));
+        assert_eq!(2, lines(&dir.join("access.log.1")));
+        assert_eq!(2, lines(&dir.join("access.log.2")));
+        assert!(!dir.join("access.log.3").exists());
+
+        // Reopening carries on from the size already written
+        drop(log);
+        let log = AccessLog::open(&path, LogFormat::Common).unwrap().rotate_at(line * 2, 2);
+        log.write(&record()).unwrap();
+        log.write(&record()).unwrap();
+        assert_eq!(1, lines(&path));
+        assert_eq!(2, lines(&dir.join("access.log.1")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,80 +1,142 @@
 use std::{
-    fs,
-    io::{BufReader, prelude::*},
-    net::{TcpListener, TcpStream}
+    env,
+    path::PathBuf,
+    process,
+    sync::{Arc, OnceLock},
 };
 
-use hello::ThreadPool;
+use hello::config::{self, Config};
+use hello::files::StaticFiles;
+use hello::http::{Request, Response};
+use hello::log::AccessLog;
+use hello::pages::ErrorPages;
+use hello::router::Router;
+use hello::server::Server;
+use hello::signal;
+use hello::template::{Context, Templates};
+use hello::websocket::Echo;
+use hello::{QueuePolicy, ThreadPool};
+use macros_proc_macros::route;
+
+// What the handlers below serve, set once the config is read. #[route] handlers are plain
+// functions, so they can't capture it.
+struct Site {
+    templates: Arc<Templates>,
+    index: PathBuf,
+    errors: ErrorPages,
+}
+
+static SITE: OnceLock<Site> = OnceLock::new();
 
 fn main() {
-    // Create threadpool with 4 workers
-    let pool = ThreadPool::new(4);
-    // Create listener or else panic
-    let tcp_listener = TcpListener::bind("127.0.0.1:7878");
+    // Settings come from hello.toml or the file given with --config, then the other flags
+    if env::args().skip(1).any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", config::USAGE);
+        return;
+    }
+    let config = Config::from_args(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("Invalid configuration: {err}");
+        eprintln!("Run with --help to see the options.");
+        process::exit(1);
+    });
+    // Pages are compiled now, so a broken template stops the server from starting rather
+    // than failing requests, and in dev mode again whenever their files change
+    let templates = Arc::new(Templates::new().dev(config.dev));
+    let errors = templates
+        .check(&config.index)
+        .and_then(|()| ErrorPages::load(&templates, &config.error_pages))
+        .unwrap_or_else(|err| {
+            eprintln!("Invalid template {err}");
+            process::exit(1);
+        });
+    let _ = SITE.set(Site { templates, index: config.index.clone(), errors: errors.clone() });
+
+    // Files under the document root are served from /static/, and /echo is a WebSocket
+    // that sends back whatever it gets, which /static/echo.html talks to. Errors, the
+    // router's and the server's own, get the configured error pages.
+    let mut router = Router::new();
+    router
+        .register(INDEX_ROUTE)
+        .get("/static/*path", StaticFiles::new(&config.root).listing(config.listing))
+        .websocket("/echo", Echo)
+        .error_pages(errors);
 
-    match tcp_listener {
-        Ok(listener) => {
-            for stream in listener.incoming() { 
-            // Check if listener stream is valid, if not no execution is made
-            // If valid we use the execute method and pass a closure executing the handle_connection() function
-            
-                match stream  {
-                    Ok(res) => pool.execute(|| {handle_connection(res);}),
-                    Err(err) => {
-                        eprintln!("Failed listening with error : {}", err);
-                        eprintln!("Continuing...");
-                    }
-                }
+    // The pool keeps config.workers threads, growing to config.max_workers when connections
+    // pile up. Connections that would wait behind more than config.queue others get 503
+    // rather than piling up in memory.
+    let pool = ThreadPool::builder()
+        .min_threads(config.workers)
+        .max_threads(config.max_workers)
+        .name_prefix("http")
+        .capacity(config.queue)
+        .policy(QueuePolicy::Reject)
+        .build();
+    let pool = pool.unwrap_or_else(|err| {
+        eprintln!("Unable to start the thread pool: {err}");
+        process::exit(1);
+    });
+    let mut server = Server::bind_all(&config.addresses(), router, pool, config.connection_options())
+        .unwrap_or_else(|err| {
+            eprintln!("Unable to create listener: {err}");
+            process::exit(1);
+        });
+
+    if let Some(path) = &config.access_log {
+        match AccessLog::open(path, config.log_format) {
+            Ok(log) => server = server.access_log(log.rotate_at(config.log_max_size, config.log_keep)),
+            Err(err) => {
+                eprintln!("Unable to open the access log {}: {err}", path.display());
+                process::exit(1);
             }
-        },
-        Err(err) => {
-            eprintln!("Unable to create listener, ensure address and port are valid.");
-            eprintln!("{err}");
         }
     }
-    
-    println!("Shutting down");
-}
+    if let Some(compression) = config.compression() {
+        server = server.compression(compression);
+    }
 
-fn handle_connection(mut stream: TcpStream) {
-    // BufReader turns a a TcpStream into an iterable
-    let buf_reader = BufReader::new(&mut stream);
-    let http_request: Vec<String> = buf_reader
-        .lines() // Lines<BufReader<&mut TcpStream>>
-        .map(|result| 
-            {
-                result.unwrap_or_default() 
-            }
-        )//Iterator of strings
-        .take_while(|line| !line.is_empty()) // Iterator of strings up until empty string
-        .collect(); // finally turn into a vector of Strings
-    //println!("Request: {:#?}", http_request); // debug the http_request
+    // Built with the event-loop feature, connections are served from an epoll loop rather
+    // than taking a worker each for as long as they stay open
+    #[cfg(feature = "event-loop")]
+    let server = server.event_loop().unwrap_or_else(|err| {
+        eprintln!("Unable to start the event loop: {err}");
+        process::exit(1);
+    });
 
-    //let response = "HTTP/1.1 200 OK\r\n\r\n"; // Standard response
-    let request_line = http_request[0].as_str(); //First line with http request header
-    // Serve site if GET / else return 404
-    let (status, file_name) = 
-        if request_line == "GET / HTTP/1.1" {
-            ("HTTP/1.1 200 OK", "hello.html")
-        }
-        else {
-            ("HTTP/1.1 404 NOT FOUND", "404.html")
-        };
-    // Get String from html file, or return "" on fail
-    let contents = fs::read_to_string(file_name).unwrap_or_default();
+    for address in server.local_addrs() {
+        println!("Listening on http://{address}");
+    }
+
+    // Ctrl-C or SIGTERM stops accepting connections and lets the ones in flight finish
+    let handle = server.shutdown_handle();
+    signal::on_terminate(move || {
+        println!("Shutting down");
+        handle.shutdown();
+    });
 
-    // Get length of response
-    let length = contents.len();
+    if let Err(err) = server.run(config.grace) {
+        eprintln!("Shutdown wasn't clean: {err}");
+        process::exit(1);
+    }
+}
 
-    // Format response in HTTP standard
-    let response = format!(
-        "{status}\r\n\
-        Content-Length: {length}\r\n\r\n\
-        {contents}"
-    );
+fn site() -> &'static Site {
+    SITE.get().expect("main sets the site before serving")
+}
 
-    // Write the response in byte buffer and unwrap the result
-    stream
-        .write_all(response.as_bytes())
-        .unwrap();
-}
\ No newline at end of file
+#[route(GET, "/")]
+fn index(request: &Request) -> Response {
+    let site = site();
+    let link = |href: &str, title: &str| Context::new().set("href", href).set("title", title);
+    let links = vec![link("/static/", "Files"), link("/static/echo.html", "WebSocket echo"), link("/metrics", "Metrics")];
+    let context = Context::new().set("links", links);
+    match site.templates.render(&site.index, &context) {
+        Ok(page) => Response::new(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(page),
+        // A missing page is the server's fault, not an empty success
+        Err(err) => {
+            eprintln!("Failed rendering {err}");
+            site.errors.response(500, request)
+        }
+    }
+}
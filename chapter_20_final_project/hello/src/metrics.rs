@@ -0,0 +1,190 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use crate::http::{Method, Request, Response};
+use crate::log::AccessRecord;
+use crate::router::Handler;
+use crate::PoolMonitor;
+
+// Request metrics in the Prometheus text exposition format, version 0.0.4:
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+
+// Upper bounds of the request duration histogram's buckets, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counts requests as a Server serves them
+pub struct Metrics {
+    // By method and status
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    // Requests that took at most each of BUCKETS, not cumulative; the last is for longer ones
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    duration_micros: AtomicU64,
+    bytes: AtomicU64,
+    connections: AtomicUsize,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            buckets: Default::default(),
+            duration_micros: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record(&self, record: &AccessRecord) {
+        // Methods are up to the client, so unknown ones share a label rather than each
+        // making a new series
+        let method = match record.request.as_ref().map(|request| &request.method) {
+            Some(Method::Other(_)) => "OTHER",
+            Some(method) => method.as_str(),
+            None => "NONE",
+        };
+        let mut requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        *requests.entry((method.to_string(), record.status)).or_insert(0) += 1;
+        drop(requests);
+
+        let seconds = record.duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.duration_micros.fetch_add(record.duration.as_micros() as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(record.bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Everything counted so far, and pool's gauges if there is one, in the text format
+    pub fn render(&self, pool: Option<&PoolMonitor>) -> String {
+        let mut out = String::new();
+        metric(&mut out, "http_requests_total", "counter", "Requests answered, by method and status.");
+        for ((method, status), count) in self.requests.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            writeln!(out, "http_requests_total{{method=\"{method}\",status=\"{status}\"}} {count}").unwrap();
+        }
+
+        metric(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time from a request starting to arrive to its response being written.",
+        );
+        let mut cumulative = 0;
+        for (bucket, bound) in BUCKETS.iter().map(|bound| bound.to_string()).chain(["+Inf".to_string()]).enumerate() {
+            cumulative += self.buckets[bucket].load(Ordering::Relaxed);
+            writeln!(out, "http_request_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}").unwrap();
+        }
+        let sum = self.duration_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "http_request_duration_seconds_sum {sum}").unwrap();
+        writeln!(out, "http_request_duration_seconds_count {cumulative}").unwrap();
+
+        metric(&mut out, "http_response_body_bytes_total", "counter", "Bytes of response bodies sent.");
+        writeln!(out, "http_response_body_bytes_total {}", self.bytes.load(Ordering::Relaxed)).unwrap();
+        metric(&mut out, "http_connections_open", "gauge", "Connections accepted and not yet closed.");
+        writeln!(out, "http_connections_open {}", self.connections.load(Ordering::Relaxed)).unwrap();
+
+        if let Some(pool) = pool {
+            let pool = pool.metrics();
+            let gauges = [
+                ("threadpool_workers", "gauge", "Worker threads running.", pool.workers),
+                ("threadpool_active_workers", "gauge", "Workers running a job.", pool.active),
+                ("threadpool_queued_jobs", "gauge", "Jobs waiting for a worker.", pool.queued),
+                ("threadpool_jobs_completed_total", "counter", "Jobs that have run.", pool.completed),
+                ("threadpool_jobs_rejected_total", "counter", "Jobs turned away with the queue full.", pool.rejected),
+                ("threadpool_jobs_dropped_total", "counter", "Jobs dropped to make room in the queue.", pool.dropped),
+                ("threadpool_job_panics_total", "counter", "Jobs that panicked.", pool.panicked),
+            ];
+            for (name, kind, help, value) in gauges {
+                metric(&mut out, name, kind, help);
+                writeln!(out, "{name} {value}").unwrap();
+            }
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// Serves Metrics::render, for Prometheus to scrape
+pub struct MetricsEndpoint {
+    pub metrics: Arc<Metrics>,
+    pub pool: Option<PoolMonitor>,
+}
+
+impl Handler for MetricsEndpoint {
+    fn handle(&self, _: &Request) -> Response {
+        Response::new(200)
+            .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .body(self.metrics.render(self.pool.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Version;
+    use crate::log::RequestLine;
+    use std::time::{Duration, SystemTime};
+
+    fn record(method: Method, status: u16, millis: u64) -> AccessRecord {
+        AccessRecord {
+            client: None,
+            time: SystemTime::now(),
+            request: Some(RequestLine { method, target: "/".to_string(), version: Version::Http11 }),
+            status,
+            bytes: 100,
+            duration: Duration::from_millis(millis),
+            referer: None,
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn renders_counters_and_histogram() {
+        let metrics = Metrics::new();
+        metrics.record(&record(Method::Get, 200, 3));
+        metrics.record(&record(Method::Get, 200, 40));
+        metrics.record(&record(Method::Other("BREW".to_string()), 501, 20_000));
+        metrics.connection_opened();
+
+        let text = metrics.render(None);
+        for line in [
+            "# TYPE http_requests_total counter",
+            "http_requests_total{method=\"GET\",status=\"200\"} 2",
+            "http_requests_total{method=\"OTHER\",status=\"501\"} 1",
+            "http_request_duration_seconds_bucket{le=\"0.005\"} 1",
+            "http_request_duration_seconds_bucket{le=\"0.025\"} 1",
+            "http_request_duration_seconds_bucket{le=\"0.05\"} 2",
+            "http_request_duration_seconds_bucket{le=\"10\"} 2",
+            "http_request_duration_seconds_bucket{le=\"+Inf\"} 3",
+            "http_request_duration_seconds_sum 20.043",
+            "http_request_duration_seconds_count 3",
+            "http_response_body_bytes_total 300",
+            "http_connections_open 1",
+        ] {
+            assert!(text.lines().any(|rendered| rendered == line), "{line} missing from\n{text}");
+        }
+        assert!(!text.contains("threadpool_"));
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::http::{self, Request, Response};
+use crate::template::{Context, TemplateError, Templates};
+
+/// Error responses rendered from templates, one per status.
+/// A template is rendered with status, reason, method and path: the response's status,
+/// its reason phrase, and the request's method and path, which are empty when the request
+/// couldn't be read. Statuses without a template get a plain text body.
+///
+/// Given to Router::error_pages, they're used for the router's own 404 and 405 responses
+/// and every error response the Server makes itself.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    templates: Arc<Templates>,
+    pages: BTreeMap<u16, PathBuf>,
+}
+
+impl ErrorPages {
+    /// Pages kept in their own Templates, for templates given with insert
+    pub fn new() -> ErrorPages {
+        ErrorPages::default()
+    }
+
+    /// Pages from the template files for each status, found in templates.
+    /// Every page is compiled now, along with what it includes and extends, so a mistake
+    /// in one is found before it's needed. The error says which file and line it's in.
+    pub fn load(templates: &Arc<Templates>, files: &BTreeMap<u16, PathBuf>) -> Result<ErrorPages, TemplateError> {
+        for file in files.values() {
+            templates.check(file)?;
+        }
+        Ok(ErrorPages { templates: Arc::clone(templates), pages: files.clone() })
+    }
+
+    /// Compiles template as the page for status
+    pub fn insert(&mut self, status: u16, template: &str) -> Result<(), TemplateError> {
+        let path = PathBuf::from(format!("{status}.html"));
+        self.templates.insert(&path, template)?;
+        self.pages.insert(status, path);
+        Ok(())
+    }
+
+    /// The response with status to request
+    pub fn response(&self, status: u16, request: &Request) -> Response {
+        self.page(status, request.method.as_str(), &request.path)
+            .unwrap_or_else(|| plain(status, http::reason(status)))
+    }
+
+    /// The page for status, for a request with method and path, or None if status has no
+    /// template or it couldn't be rendered
+    pub fn page(&self, status: u16, method: &str, path: &str) -> Option<Response> {
+        let template = self.pages.get(&status)?;
+        let context = Context::new()
+            .set("status", status)
+            .set("reason", http::reason(status))
+            .set("method", method)
+            .set("path", path);
+        match self.templates.render(template, &context) {
+            Ok(page) => Some(
+                Response::new(status)
+                    .header("Content-Type", "text/html; charset=utf-8")
+                    .body(page),
+            ),
+            // In dev mode a page can break while the server runs, which mustn't lose the status
+            Err(err) => {
+                eprintln!("Failed rendering the {status} page: {err}");
+                None
+            }
+        }
+    }
+}
+
+fn plain(status: u16, reason: &str) -> Response {
+    Response::new(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("{status} {reason}\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Headers, Method, Version};
+
+    #[test]
+    fn fills_in_templates() {
+        let mut pages = ErrorPages::new();
+        pages.insert(404, "<h1>{{status}} {{reason}}</h1><p>No {{path}} here</p>").unwrap();
+        pages
+            .insert(405, "{% if method == \"POST\" %}Can't post to {{ path }}{% else %}{{ reason }}{% endif %}")
+            .unwrap();
+        assert!(pages.insert(500, "{% if status %}").is_err());
+        let request = Request {
+            method: Method::Get,
+            target: "/%3Cb%3E".to_string(),
+            path: "/<b>".to_string(),
+            query: String::new(),
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+            params: Vec::new(),
+        };
+
+        let response = pages.response(404, &request);
+        assert_eq!(404, response.status);
+        assert_eq!(b"<h1>404 Not Found</h1><p>No /&lt;b&gt; here</p>".to_vec(), response.body);
+
+        let response = pages.response(405, &request);
+        assert_eq!(b"Method Not Allowed".to_vec(), response.body);
+        let response = pages.response(500, &request);
+        assert_eq!(b"500 Internal Server Error\n".to_vec(), response.body);
+        assert_eq!(None, pages.page(500, "", ""));
+        assert_eq!(b"Method Not Allowed".to_vec(), pages.page(405, "", "").unwrap().body);
+    }
+}
@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use crate::http::{self, Method, Request, Response};
+use crate::pages::ErrorPages;
+use crate::websocket::{self, WebSocketHandler};
+
+/// Anything that turns a Request into a Response.
+/// Handlers are shared by every worker thread, so they must be Send + Sync.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+/// Plain functions and closures are handlers, including the ones #[route] is put on
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+// One segment of a path pattern
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    // :name matches any single segment
+    Param(String),
+    // * or *name matches the rest of the path, including nothing
+    Wildcard(Option<String>),
+}
+
+// A parsed path pattern such as /users/:id or /static/*path
+#[derive(Debug)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        assert!(pattern.starts_with('/'), "route pattern {pattern:?} must start with '/'");
+        let segments: Vec<Segment> = split(pattern)
+            .map(|segment| match segment.as_bytes()[0] {
+                b':' => Segment::Param(segment[1..].to_string()),
+                b'*' => Segment::Wildcard((segment.len() > 1).then(|| segment[1..].to_string())),
+                _ => Segment::Literal(segment.to_string()),
+            })
+            .collect();
+        let wildcard = segments.iter().position(|segment| matches!(segment, Segment::Wildcard(_)));
+        assert!(
+            wildcard.is_none_or(|index| index == segments.len() - 1),
+            "a wildcard must be the last segment of {pattern:?}"
+        );
+        Pattern { segments }
+    }
+
+    // The parameters captured from the decoded segments of a path, or None if it doesn't match
+    fn matches(&self, path: &[String]) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut parts = path.iter();
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    if let Some(name) = name {
+                        params.push((name.clone(), path[index.min(path.len())..].join("/")));
+                    }
+                    return Some(params);
+                }
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => params.push((name.clone(), parts.next()?.clone())),
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+
+    // How specific the pattern is, lower is more specific: a literal segment beats a parameter,
+    // which beats a wildcard, compared from the start of the path
+    fn rank(&self) -> Vec<u8> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(_) => 0,
+                Segment::Param(_) => 1,
+                Segment::Wildcard(_) => 2,
+            })
+            .collect()
+    }
+}
+
+// The non-empty segments of a path, so /users/ and /users are the same route
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+}
+
+/// Sends each request to the handler registered for its method and path.
+///
+/// Patterns are made of literal segments, :name parameters that match one segment, and a
+/// final * or *name wildcard that matches the rest of the path. When several patterns match,
+/// the most specific one wins, so /users/new is chosen over /users/:id for "/users/new".
+/// A path that matches only with other methods gets 405 Method Not Allowed, and a HEAD
+/// request is handled by the GET route when there is no HEAD route.
+pub struct Router {
+    routes: Vec<Route>,
+    // None for the 404 page from errors
+    not_found: Option<Box<dyn Handler>>,
+    errors: ErrorPages,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new(), not_found: None, errors: ErrorPages::new() }
+    }
+
+    /// Registers handler for method and pattern.
+    /// # Panics
+    /// If the pattern doesn't start with '/' or has a wildcard before its last segment.
+    pub fn add<H: Handler>(&mut self, method: Method, pattern: &str, handler: H) -> &mut Router {
+        self.routes.push(Route { method, pattern: Pattern::parse(pattern), handler: Box::new(handler) });
+        self
+    }
+
+    pub fn get<H: Handler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.add(Method::Get, pattern, handler)
+    }
+
+    pub fn post<H: Handler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.add(Method::Post, pattern, handler)
+    }
+
+    pub fn put<H: Handler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.add(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<H: Handler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.add(Method::Delete, pattern, handler)
+    }
+
+    /// Opens a WebSocket for GET requests to pattern and gives it to handler, answering
+    /// requests that aren't WebSocket handshakes with 400
+    pub fn websocket<H: WebSocketHandler>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        let handler: Arc<dyn WebSocketHandler> = Arc::new(handler);
+        self.get(pattern, move |request: &Request| websocket::upgrade(request, &handler))
+    }
+
+    /// Registers the (method, pattern, handler) constant that #[route(METHOD, "pattern")]
+    /// generates next to a function, e.g. router.register(INDEX_ROUTE)
+    pub fn register<H: Handler>(&mut self, (method, pattern, handler): (&str, &str, H)) -> &mut Router {
+        self.add(Method::parse(method), pattern, handler)
+    }
+
+    /// The handler for paths no route matches, the 404 error page by default
+    pub fn not_found<H: Handler>(&mut self, handler: H) -> &mut Router {
+        self.not_found = Some(Box::new(handler));
+        self
+    }
+
+    /// The pages for the router's 404 and 405 responses, which a Server serving the router
+    /// also uses for the errors it answers itself. Plain text by default.
+    pub fn error_pages(&mut self, pages: ErrorPages) -> &mut Router {
+        self.errors = pages;
+        self
+    }
+
+    pub(crate) fn errors(&self) -> &ErrorPages {
+        &self.errors
+    }
+
+    // The page for status, or plain text with body
+    fn error(&self, status: u16, request: &Request, body: &str) -> Response {
+        self.errors
+            .page(status, request.method.as_str(), &request.path)
+            .unwrap_or_else(|| plain(status, body))
+    }
+
+    /// Finds the route for request and runs its handler with the path parameters filled in
+    pub fn handle(&self, mut request: Request) -> Response {
+        // Split before decoding, so that %2F in a segment can't add segments and change the route
+        let Some(path) = split(request.raw_path()).map(http::percent_decode).collect::<Option<Vec<String>>>()
+        else {
+            return self.error(400, &request, "Bad Request\n");
+        };
+        let mut allowed: Vec<&Method> = Vec::new();
+        let mut best: Option<(&Route, Vec<(String, String)>)> = None;
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(&path) else {
+                continue;
+            };
+            if !allowed.contains(&&route.method) {
+                allowed.push(&route.method);
+            }
+            let usable = route.method == request.method
+                || (request.method == Method::Head && route.method == Method::Get);
+            // An exact HEAD route is preferred over falling back to GET
+            let better = match &best {
+                None => true,
+                Some((current, _)) => {
+                    (route.pattern.rank(), route.method != request.method)
+                        < (current.pattern.rank(), current.method != request.method)
+                }
+            };
+            if usable && better {
+                best = Some((route, params));
+            }
+        }
+
+        match best {
+            Some((route, params)) => {
+                request.params = params;
+                route.handler.handle(&request)
+            }
+            None if allowed.is_empty() => match &self.not_found {
+                Some(handler) => handler.handle(&request),
+                None => self.error(404, &request, "Not Found\n"),
+            },
+            None => {
+                if allowed.contains(&&Method::Get) && !allowed.contains(&&Method::Head) {
+                    allowed.push(&Method::Head);
+                }
+                let allow: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+                self.error(405, &request, "Method Not Allowed\n").header("Allow", allow.join(", "))
+            }
+        }
+    }
+}
+
+fn plain(status: u16, body: &str) -> Response {
+    Response::new(status).header("Content-Type", "text/plain; charset=utf-8").body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, Limits};
+
+    fn request(raw: &str) -> Request {
+        http::read_request(&mut raw.as_bytes(), &Limits::default()).unwrap().unwrap()
+    }
+
+    fn get(router: &Router, path: &str) -> Response {
+        router.handle(request(&format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n")))
+    }
+
+    fn text(response: Response) -> String {
+        String::from_utf8(response.body).unwrap()
+    }
+
+    #[test]
+    fn matches_params_and_wildcards() {
+        let mut router = Router::new();
+        router
+            .get("/", |_: &Request| plain(200, "index"))
+            .get("/users/:id", |request: &Request| plain(200, &format!("user {}", request.param("id").unwrap())))
+            .get("/users/new", |_: &Request| plain(200, "new user"))
+            .get("/users/:id/posts/:post", |request: &Request| {
+                plain(200, &format!("{} {}", request.param("id").unwrap(), request.param("post").unwrap()))
+            })
+            .get("/static/*path", |request: &Request| plain(200, request.param("path").unwrap()));
+
+        assert_eq!("index", text(get(&router, "/")));
+        assert_eq!("user 42", text(get(&router, "/users/42")));
+        assert_eq!("user 42", text(get(&router, "/users/42/")));
+        assert_eq!("new user", text(get(&router, "/users/new")));
+        assert_eq!("7 9", text(get(&router, "/users/7/posts/9")));
+        assert_eq!("css/site.css", text(get(&router, "/static/css/site.css")));
+        assert_eq!("", text(get(&router, "/static")));
+        assert_eq!(404, get(&router, "/users").status);
+        assert_eq!(404, get(&router, "/users/7/posts").status);
+
+        // Encoded slashes are decoded within their segment and don't change the route
+        assert_eq!("user a/b", text(get(&router, "/users/a%2Fb")));
+        assert_eq!("user 7/posts/9", text(get(&router, "/users/7%2Fposts%2F9")));
+        assert_eq!("new user", text(get(&router, "/users/%6Eew")));
+        assert_eq!("a%b c/d", text(get(&router, "/static/a%25b%20c/d")));
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let mut router = Router::new();
+        router
+            .get("/items", |_: &Request| plain(200, "list"))
+            .post("/items", |request: &Request| plain(201, &String::from_utf8_lossy(&request.body)));
+
+        let created = router.handle(request("POST /items HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabc"));
+        assert_eq!((201, "abc".to_string()), (created.status, text(created)));
+
+        let response = router.handle(request("DELETE /items HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(405, response.status);
+        assert_eq!(Some("GET, POST, HEAD"), response.headers.get("Allow"));
+
+        let head = router.handle(request("HEAD /items HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert_eq!(200, head.status);
+    }
+
+    #[test]
+    fn registers_route_constants() {
+        fn index(_: &Request) -> Response {
+            plain(200, "from a constant")
+        }
+        const INDEX_ROUTE: (&str, &str, fn(&Request) -> Response) = ("GET", "/", index);
+
+        let mut router = Router::new();
+        router.register(INDEX_ROUTE).not_found(|_: &Request| plain(404, "custom"));
+        assert_eq!("from a constant", text(get(&router, "/")));
+        assert_eq!("custom", text(get(&router, "/missing")));
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/*rest/more", |_: &Request| plain(200, ""));
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::Job;
+
+// Where the ThreadPool's jobs wait for a worker: a deque per worker plus a shared injector.
+//
+// Jobs queued from outside the pool go to the injector. A job queued by a job, from a
+// worker's own thread, goes on that worker's deque, where it is likely to find the data it
+// needs still in cache. A worker looks in its own deque first, then takes a batch from the
+// injector, and only then steals the older half of another worker's deque. Every deque is
+// first in, first out, so connections are served roughly in the order they came in.
+//
+// Every lock is held just long enough to move jobs in or out and never while waiting, which
+// was the problem with workers sharing a single Mutex<Receiver>: whoever held it was blocked
+// in recv() and every other worker queued up behind it.
+//
+// The scheduler can be given a capacity. A push into a full scheduler is refused, and what
+// happens then is up to the ThreadPool's QueuePolicy.
+
+// Most jobs a worker moves from the injector to its deque at once, so the others get some
+const INJECTOR_BATCH: usize = 16;
+
+// Gives every Scheduler an id, so a worker thread can tell whether it belongs to the one
+// being pushed to
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // The scheduler id and worker index of the worker running on this thread
+    static WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+pub(crate) struct Scheduler {
+    id: usize,
+    injector: Mutex<VecDeque<Job>>,
+    locals: Vec<Mutex<VecDeque<Job>>>,
+    // Jobs pushed and not yet taken, in any deque. A push reserves its place here before
+    // the job is in a deque, so this is never more than capacity.
+    queued: AtomicUsize,
+    capacity: usize,
+    closed: AtomicBool,
+    // Workers waiting on wake, and how many of them have been woken but not yet got going.
+    // sleep is only locked to pair a wait with a notify.
+    sleepers: AtomicUsize,
+    waking: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    // Pushers waiting for room, the same way workers wait for jobs
+    blocked: AtomicUsize,
+    room: Mutex<()>,
+    space: Condvar,
+}
+
+/// What a worker waiting in pop got
+pub(crate) enum Popped {
+    Job(Job),
+    /// Nothing came within the idle time
+    Idle,
+    /// The scheduler is closed and every job has been taken
+    Closed,
+}
+
+/// Why a job couldn't be pushed, with the job given back
+pub(crate) enum Refused {
+    Closed(Job),
+    Full(Job),
+}
+
+impl Scheduler {
+    /// A scheduler for workers workers holding at most capacity jobs
+    pub(crate) fn new(workers: usize, capacity: usize) -> Scheduler {
+        Scheduler {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            injector: Mutex::new(VecDeque::new()),
+            locals: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            queued: AtomicUsize::new(0),
+            capacity,
+            closed: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            waking: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            blocked: AtomicUsize::new(0),
+            room: Mutex::new(()),
+            space: Condvar::new(),
+        }
+    }
+
+    /// Marks the current thread as running worker, so its pushes go on its own deque
+    pub(crate) fn register(&self, worker: usize) {
+        WORKER.with(|current| current.set(Some((self.id, worker))));
+    }
+
+    /// Whether the current thread is one of this scheduler's workers
+    pub(crate) fn on_worker(&self) -> bool {
+        WORKER.with(Cell::get).is_some_and(|(id, _)| id == self.id)
+    }
+
+    /// Jobs waiting for a worker
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Workers asleep waiting for a job and not already being woken for one.
+    /// Workers looking for a job don't count, so this can be less than the workers free.
+    pub(crate) fn idle(&self) -> usize {
+        self.sleepers.load(Ordering::SeqCst).saturating_sub(self.waking.load(Ordering::SeqCst))
+    }
+
+    /// Queues job, or gives it back if the scheduler is full or has been closed
+    pub(crate) fn push(&self, job: Job) -> Result<(), Refused> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Refused::Closed(job));
+        }
+        if !self.reserve() {
+            return Err(Refused::Full(job));
+        }
+        self.insert(job);
+        Ok(())
+    }
+
+    /// Queues job, waiting for room if the scheduler is full.
+    /// Gives it back if the scheduler is closed first.
+    pub(crate) fn push_wait(&self, job: Job) -> Result<(), Job> {
+        let mut room = lock(&self.room);
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(job);
+            }
+            self.blocked.fetch_add(1, Ordering::SeqCst);
+            // Counted as blocked before looking, which pairs with the order in pop
+            if self.reserve() {
+                self.blocked.fetch_sub(1, Ordering::SeqCst);
+                drop(room);
+                self.insert(job);
+                return Ok(());
+            }
+            room = self.space.wait(room).unwrap_or_else(PoisonError::into_inner);
+            self.blocked.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Takes the job that was queued first from outside the pool, or failing that the
+    /// oldest one on any worker's deque
+    pub(crate) fn pop_oldest(&self) -> Option<Job> {
+        let job = lock(&self.injector)
+            .pop_front()
+            .or_else(|| self.locals.iter().find_map(|local| lock(local).pop_front()))?;
+        self.taken();
+        Some(job)
+    }
+
+    // Takes a place for a job, unless there is no room
+    fn reserve(&self) -> bool {
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| (queued < self.capacity).then_some(queued + 1))
+            .is_ok()
+    }
+
+    // Puts a job whose place has been reserved in a deque, and wakes a worker for it.
+    // Until it's in, a worker that sees queued > 0 and can't find it yields and looks again.
+    fn insert(&self, job: Job) {
+        let deque = match WORKER.with(Cell::get) {
+            Some((id, worker)) if id == self.id => &self.locals[worker],
+            _ => &self.injector,
+        };
+        lock(deque).push_back(job);
+        self.wake_one();
+    }
+
+    // Uncounts a job taken from a deque, and lets a blocked pusher have its place
+    fn taken(&self) -> usize {
+        let left = self.queued.fetch_sub(1, Ordering::SeqCst) - 1;
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _room = lock(&self.room);
+            self.space.notify_one();
+        }
+        left
+    }
+
+    // Wakes a sleeping worker, unless every sleeper is already waking up. A woken worker
+    // wakes the next one if it finds more jobs queued, so a burst of pushes costs one
+    // notify rather than one each.
+    fn wake_one(&self) {
+        if self.sleepers.load(Ordering::SeqCst) <= self.waking.load(Ordering::SeqCst) {
+            return;
+        }
+        let _sleep = lock(&self.sleep);
+        if self.sleepers.load(Ordering::SeqCst) > self.waking.load(Ordering::SeqCst) {
+            self.waking.fetch_add(1, Ordering::SeqCst);
+            self.wake.notify_one();
+        }
+    }
+
+    /// Waits for a job for worker, for up to idle without one if that is given
+    pub(crate) fn pop(&self, worker: usize, idle: Option<Duration>) -> Popped {
+        let deadline = idle.map(|idle| Instant::now() + idle);
+        loop {
+            if let Some(job) = self.find(worker) {
+                if self.taken() > 0 {
+                    self.wake_one();
+                }
+                return Popped::Job(job);
+            }
+            let left = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => Some(left),
+                    _ => return Popped::Idle,
+                },
+                None => None,
+            };
+            let sleep = lock(&self.sleep);
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            let queued = self.queued.load(Ordering::SeqCst);
+            let closed = self.closed.load(Ordering::SeqCst);
+            if queued == 0 && !closed {
+                // A push after the check above sees sleepers > 0 and has to take the lock
+                // to notify, which it can't do until wait has released it
+                let sleep = match left {
+                    Some(left) => self.wake.wait_timeout(sleep, left).unwrap_or_else(PoisonError::into_inner).0,
+                    None => self.wake.wait(sleep).unwrap_or_else(PoisonError::into_inner),
+                };
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                // Spurious wakeups and close's notify_all aren't counted in waking
+                let _ = self.waking.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |waking| waking.checked_sub(1));
+                drop(sleep);
+                continue;
+            }
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            drop(sleep);
+            if queued == 0 {
+                return Popped::Closed;
+            }
+            // A job is counted but not in its deque yet, or taken and not uncounted yet
+            thread::yield_now();
+        }
+    }
+
+    /// Stops taking jobs and wakes every worker, which finish what is queued and then stop,
+    /// and every blocked pusher, which gets its job back
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        drop(lock(&self.sleep));
+        self.wake.notify_all();
+        drop(lock(&self.room));
+        self.space.notify_all();
+    }
+
+    // Takes the next job for worker without waiting
+    fn find(&self, worker: usize) -> Option<Job> {
+        let local = &self.locals[worker];
+        if let Some(job) = lock(local).pop_front() {
+            return Some(job);
+        }
+        if let Some(job) = self.take_injected(local) {
+            return Some(job);
+        }
+        // Start with the next worker, so thieves don't all pile onto worker 0
+        let count = self.locals.len();
+        (1..count).find_map(|offset| self.steal(&self.locals[(worker + offset) % count], local))
+    }
+
+    // Takes a job from the injector and moves up to a batch more onto local
+    fn take_injected(&self, local: &Mutex<VecDeque<Job>>) -> Option<Job> {
+        let mut injector = lock(&self.injector);
+        let job = injector.pop_front()?;
+        let share = injector.len() / self.locals.len();
+        let batch: Vec<Job> = injector.drain(..share.min(INJECTOR_BATCH)).collect();
+        drop(injector);
+        lock(local).extend(batch);
+        Some(job)
+    }
+
+    // Takes the oldest job from victim, and moves the older half of what's left onto local
+    fn steal(&self, victim: &Mutex<VecDeque<Job>>, local: &Mutex<VecDeque<Job>>) -> Option<Job> {
+        // try_lock, since a busy victim is better skipped than waited for
+        let mut victim = victim.try_lock().ok()?;
+        let job = victim.pop_front()?;
+        let half = victim.len() / 2;
+        let stolen: Vec<Job> = victim.drain(..half).collect();
+        drop(victim);
+        lock(local).extend(stolen);
+        Some(job)
+    }
+}
+
+// A job panicking can't poison these locks, since jobs never run while one is held,
+// but a worker dying in the panic hook shouldn't take the queue down with it either
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+
+    fn job(sender: &mpsc::Sender<u32>, value: u32) -> Job {
+        let sender = sender.clone();
+        Box::new(move || sender.send(value).unwrap())
+    }
+
+    #[test]
+    fn workers_take_their_own_jobs_in_order() {
+        let scheduler = Scheduler::new(2, usize::MAX);
+        let (sender, receiver) = mpsc::channel();
+        scheduler.register(0);
+        for value in 1..=3 {
+            scheduler.push(job(&sender, value)).ok().unwrap();
+        }
+        while let Some(job) = scheduler.find(0) {
+            job();
+        }
+        assert_eq!(vec![1, 2, 3], receiver.try_iter().collect::<Vec<u32>>());
+        WORKER.with(|current| current.set(None));
+    }
+
+    #[test]
+    fn idle_workers_steal_the_oldest_jobs() {
+        let scheduler = Arc::new(Scheduler::new(2, usize::MAX));
+        let (sender, receiver) = mpsc::channel();
+        scheduler.register(0);
+        for value in 1..=5 {
+            scheduler.push(job(&sender, value)).ok().unwrap();
+        }
+        WORKER.with(|current| current.set(None));
+
+        // Worker 1 has nothing of its own and the injector is empty
+        let thief = Arc::clone(&scheduler);
+        thread::spawn(move || match thief.pop(1, None) {
+            Popped::Job(job) => job(),
+            _ => panic!("nothing to steal"),
+        })
+        .join()
+        .unwrap();
+        assert_eq!(Ok(1), receiver.try_recv());
+        // It took half of the rest too
+        assert_eq!(2, scheduler.locals[1].lock().unwrap().len());
+        assert_eq!(2, scheduler.locals[0].lock().unwrap().len());
+    }
+
+    #[test]
+    fn close_drains_then_stops() {
+        let scheduler = Arc::new(Scheduler::new(1, usize::MAX));
+        let (sender, receiver) = mpsc::channel();
+        scheduler.push(job(&sender, 1)).ok().unwrap();
+
+        let worker = Arc::clone(&scheduler);
+        let worker = thread::spawn(move || {
+            let mut ran = 0;
+            while let Popped::Job(job) = worker.pop(0, None) {
+                job();
+                ran += 1;
+            }
+            ran
+        });
+        // The worker ends up asleep with nothing queued until close wakes it
+        thread::sleep(Duration::from_millis(20));
+        scheduler.push(job(&sender, 2)).ok().unwrap();
+        scheduler.close();
+        assert!(scheduler.push(job(&sender, 3)).is_err());
+        assert_eq!(2, worker.join().unwrap());
+        assert_eq!(vec![1, 2], receiver.try_iter().collect::<Vec<u32>>());
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::HashMap,
+    io::{self, prelude::*, BufReader, BufWriter},
+    panic::{self, AssertUnwindSafe},
+    net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::compress::Compression;
+use crate::http::{self, Limits, Method, ParseError, Request, Response, Version};
+use crate::log::{AccessLog, AccessRecord, RequestLine};
+use crate::metrics::{Metrics, MetricsEndpoint};
+use crate::router::Router;
+use crate::{ExecuteError, QueuePolicy, ShutdownError, ThreadPool};
+
+#[cfg(all(feature = "event-loop", target_os = "linux"))]
+mod event_loop;
+#[cfg(all(feature = "event-loop", target_os = "linux"))]
+pub use event_loop::EventLoop;
+
+#[cfg(all(feature = "event-loop", not(target_os = "linux")))]
+compile_error!("the event-loop feature uses epoll, which only Linux has");
+
+// Serving a connection: persistent connections (RFC 9112 9.3) with pipelining, and the
+// timeouts that stop a client from keeping a ThreadPool worker busy forever.
+//
+// A per-read timeout isn't enough for that: a slowloris client sends one byte just before
+// each read would time out. So every request gets a deadline for the whole of it, and each
+// read only waits for whatever is left until then.
+
+/// Limits on a single connection
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    pub limits: Limits,
+    /// How long a connection may sit between requests before it is closed
+    pub idle_timeout: Duration,
+    /// How long a client has to send a whole request once its first byte arrived
+    pub request_timeout: Duration,
+    /// How long writing a response may block on a client that doesn't read it
+    pub write_timeout: Duration,
+    /// Requests served on one connection before it is closed, so workers get shared around
+    pub max_requests: usize,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            limits: Limits::default(),
+            idle_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            max_requests: 100,
+        }
+    }
+}
+
+// Reads from a stream, failing with TimedOut once deadline has passed
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Serves requests from stream until the client closes it, asks for it to be closed, goes
+/// idle or uses up max_requests. Pipelined requests are answered in the order they came,
+/// since whatever the BufReader read past the end of one request is the start of the next.
+pub fn serve_connection(stream: TcpStream, router: &Router, options: &ConnectionOptions) -> io::Result<()> {
+    serve(stream, router, None, options, &AtomicBool::new(false), &|_| {})
+}
+
+// serve_connection, which compresses responses with compression, stops taking requests
+// once stopping is set and tells report about every response
+fn serve(
+    stream: TcpStream,
+    router: &Router,
+    compression: Option<&Compression>,
+    options: &ConnectionOptions,
+    stopping: &AtomicBool,
+    report: &dyn Fn(&AccessRecord),
+) -> io::Result<()> {
+    stream.set_write_timeout(Some(options.write_timeout))?;
+    // Buffered so a response's head and body go out in one write, rather than the body
+    // waiting on Nagle's algorithm for the client's delayed ACK of the head
+    let mut writer = BufWriter::new(&stream);
+    let mut reader = BufReader::new(DeadlineReader { stream: &stream, deadline: Instant::now() });
+    let client = stream.peer_addr().ok();
+
+    for served in 1..=options.max_requests {
+        if stopping.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        // Wait for the start of the next request, which may already be buffered
+        reader.get_mut().deadline = Instant::now() + options.idle_timeout;
+        match reader.fill_buf() {
+            Ok([]) => return Ok(()),
+            Ok(_) => {}
+            Err(err) if timed_out(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        }
+
+        let started = Instant::now();
+        let mut record = new_record(client);
+        // bytes is how much body was written, which is less than its length when compressed
+        let report_response = |record: &mut AccessRecord, response: &Response, bytes: usize| {
+            record.status = response.status;
+            record.bytes = bytes;
+            record.duration = started.elapsed();
+            report(record);
+        };
+
+        reader.get_mut().deadline = started + options.request_timeout;
+        let request = match http::read_request(&mut reader, &options.limits) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(ParseError::Io(err)) if timed_out(&err) => {
+                let response = error_response(router, 408);
+                if let Ok(bytes) = response.write_to(&mut writer) {
+                    report_response(&mut record, &response, bytes);
+                }
+                return Ok(());
+            }
+            Err(ParseError::Io(err)) => return Err(err),
+            // The rest of the stream can't be trusted after a malformed request
+            Err(err) => {
+                if let Some(response) = parse_error_response(router, &err) {
+                    let bytes = response.write_to(&mut writer)?;
+                    report_response(&mut record, &response, bytes);
+                }
+                return Ok(());
+            }
+        };
+        describe(&mut record, &request);
+
+        let Answer { response, head, keep_alive } = answer(router, compression, request, options, served, stopping);
+        let bytes = if head {
+            response.write_head_to(&mut writer)?;
+            0
+        } else {
+            response.write_to(&mut writer)?
+        };
+        report_response(&mut record, &response, bytes);
+        if let Some(upgrade) = &response.upgrade {
+            // The connection is the upgrade's now, and waits on the client as long as it likes
+            stream.set_read_timeout(None)?;
+            upgrade.run(stream.try_clone()?, reader.buffer().to_vec());
+            return Ok(());
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// A record for a request from client that is starting to arrive
+fn new_record(client: Option<SocketAddr>) -> AccessRecord {
+    AccessRecord {
+        client,
+        time: SystemTime::now(),
+        request: None,
+        status: 0,
+        bytes: 0,
+        duration: Duration::ZERO,
+        referer: None,
+        user_agent: None,
+    }
+}
+
+// Fills in what record says about request once it has been read
+fn describe(record: &mut AccessRecord, request: &Request) {
+    record.request = Some(RequestLine {
+        method: request.method.clone(),
+        target: request.target.clone(),
+        version: request.version,
+    });
+    record.referer = request.headers.get("Referer").map(str::to_string);
+    record.user_agent = request.headers.get("User-Agent").map(str::to_string);
+}
+
+// The response to the served'th request on a connection, ready to write
+struct Answer {
+    response: Response,
+    // Only the head is written for a HEAD request
+    head: bool,
+    // Whether the connection stays open for another request afterwards
+    keep_alive: bool,
+}
+
+// Runs the handler for request, compresses the response if the client can take it, and
+// tells the client what happens to the connection next
+fn answer(
+    router: &Router,
+    compression: Option<&Compression>,
+    request: Request,
+    options: &ConnectionOptions,
+    served: usize,
+    stopping: &AtomicBool,
+) -> Answer {
+    let mut keep_alive = keep_alive(&request) && served < options.max_requests;
+    let head = request.method == Method::Head;
+    let version = request.version;
+    let accept_encoding = request.headers.get("Accept-Encoding").map(str::to_string);
+    let (method, path) = (request.method.clone(), request.path.clone());
+    // A panicking handler gets the client a 500 rather than a dropped connection
+    let mut response = match panic::catch_unwind(AssertUnwindSafe(|| router.handle(request))) {
+        Ok(response) => response,
+        Err(_) => {
+            keep_alive = false;
+            router
+                .errors()
+                .page(500, method.as_str(), &path)
+                .unwrap_or_else(|| plain_error(500))
+                .header("Connection", "close")
+        }
+    };
+    // A connection switching protocols neither stays open for requests nor closes
+    if response.upgrade.is_some() {
+        return Answer { response, head, keep_alive: false };
+    }
+    if let Some(compression) = compression {
+        compression.apply(accept_encoding.as_deref(), version, &mut response);
+    }
+    // A handler can end the connection by answering with Connection: close
+    keep_alive &= !response.headers.has_token("Connection", "close") && !stopping.load(Ordering::SeqCst);
+    set_connection(&mut response, version, keep_alive, options.idle_timeout, options.max_requests - served);
+    Answer { response, head, keep_alive }
+}
+
+// What the accept loop shares with its ShutdownHandles and connections
+struct State {
+    addresses: Vec<SocketAddr>,
+    stopping: AtomicBool,
+    // A clone of every open connection, so shutting down can end the idle ones
+    connections: Mutex<HashMap<u64, TcpStream>>,
+}
+
+/// A server accepting connections on one or more listeners and serving them on a ThreadPool.
+/// run blocks until a ShutdownHandle from shutdown_handle is used.
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    pool: ThreadPool,
+    router: Arc<Router>,
+    options: ConnectionOptions,
+    state: Arc<State>,
+    metrics: Arc<Metrics>,
+    access_log: Option<Arc<AccessLog>>,
+    compression: Option<Arc<Compression>>,
+}
+
+/// Stops a running Server; it can be cloned and sent to other threads
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<State>,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        router: Router,
+        workers: usize,
+        options: ConnectionOptions,
+    ) -> io::Result<Server> {
+        Server::bind_with_pool(address, router, ThreadPool::new(workers), options)
+    }
+
+    /// Like bind, serving connections on pool, for instance one made with ThreadPool::bounded.
+    /// A connection that pool rejects gets 503 Service Unavailable. A pool with
+    /// QueuePolicy::DropOldest is an error, since the connections it dropped would never be
+    /// answered at all.
+    ///
+    /// GET /metrics is added to router, serving request and pool metrics for Prometheus,
+    /// unless router already has a route of its own for it.
+    pub fn bind_with_pool<A: ToSocketAddrs>(
+        address: A,
+        router: Router,
+        pool: ThreadPool,
+        options: ConnectionOptions,
+    ) -> io::Result<Server> {
+        let listener = TcpListener::bind(address)?;
+        Server::with_listeners(vec![listener], router, pool, options)
+    }
+
+    /// Like bind_with_pool, listening on every one of addresses rather than the first that
+    /// can be bound. The error names the address that couldn't be.
+    pub fn bind_all(
+        addresses: &[SocketAddr],
+        router: Router,
+        pool: ThreadPool,
+        options: ConnectionOptions,
+    ) -> io::Result<Server> {
+        let listeners = addresses
+            .iter()
+            .map(|address| {
+                TcpListener::bind(address)
+                    .map_err(|err| io::Error::new(err.kind(), format!("couldn't listen on {address}: {err}")))
+            })
+            .collect::<io::Result<Vec<TcpListener>>>()?;
+        Server::with_listeners(listeners, router, pool, options)
+    }
+
+    fn with_listeners(
+        listeners: Vec<TcpListener>,
+        mut router: Router,
+        pool: ThreadPool,
+        options: ConnectionOptions,
+    ) -> io::Result<Server> {
+        if listeners.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"));
+        }
+        if pool.policy() == QueuePolicy::DropOldest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a pool that drops the oldest jobs would leave their connections unanswered, use QueuePolicy::Reject",
+            ));
+        }
+        let metrics = Arc::new(Metrics::new());
+        // Routes that match equally well go to the one added first
+        router.get("/metrics", MetricsEndpoint { metrics: Arc::clone(&metrics), pool: Some(pool.monitor()) });
+        let state = Arc::new(State {
+            addresses: listeners.iter().map(TcpListener::local_addr).collect::<io::Result<_>>()?,
+            stopping: AtomicBool::new(false),
+            connections: Mutex::new(HashMap::new()),
+        });
+        let router = Arc::new(router);
+        Ok(Server { listeners, pool, router, options, state, metrics, access_log: None, compression: None })
+    }
+
+    /// Writes a line to log for every response
+    pub fn access_log(mut self, log: AccessLog) -> Server {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Compresses the responses compression picks for clients that accept gzip or deflate
+    pub fn compression(mut self, compression: Compression) -> Server {
+        self.compression = Some(Arc::new(compression));
+        self
+    }
+
+    /// The counts served on /metrics
+    pub fn metrics(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    // Counts every response it's given in the metrics and writes it to the access log
+    fn reporter(&self) -> impl Fn(&AccessRecord) + Send + Sync + 'static {
+        let metrics = Arc::clone(&self.metrics);
+        let access_log = self.access_log.clone();
+        move |record| {
+            metrics.record(record);
+            if let Some(log) = &access_log
+                && let Err(err) = log.write(record)
+            {
+                eprintln!("Couldn't write the access log: {err}");
+            }
+        }
+    }
+
+    // Logs the response to a connection turned away before any of its request was read
+    fn report_rejected(&self, connection: &TcpStream, response: &Response, bytes: usize) {
+        let record = AccessRecord { status: response.status, bytes, ..new_record(connection.peer_addr().ok()) };
+        self.metrics.record(&record);
+        if let Some(log) = &self.access_log {
+            let _ = log.write(&record);
+        }
+    }
+
+    /// The address the server listens on, useful after binding port 0.
+    /// The first one, if it listens on several.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.state.addresses[0]
+    }
+
+    /// Every address the server listens on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.state.addresses
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle { state: Arc::clone(&self.state) }
+    }
+
+    /// Accepts and serves connections until shutdown, then drains: idle connections are
+    /// closed, requests already being handled get up to grace to finish and be answered,
+    /// and the workers are joined. The error lists workers that were still busy or had died.
+    pub fn run(mut self, grace: Duration) -> Result<(), ShutdownError> {
+        // Ids for connections, unique across listeners
+        let next_id = AtomicU64::new(0);
+        // Every listener but the first gets a thread of its own to accept on
+        let (first, rest) = self.listeners.split_first().expect("a server has a listener");
+        thread::scope(|scope| {
+            for listener in rest {
+                let (server, next_id) = (&self, &next_id);
+                scope.spawn(move || server.accept(listener, next_id));
+            }
+            self.accept(first, &next_id);
+        });
+
+        // Closing the read side wakes connections waiting for a request, which then see the
+        // end of the stream, while ones in the middle of a handler can still write their response
+        for connection in self.state.connections.lock().unwrap().values() {
+            let _ = connection.shutdown(net::Shutdown::Read);
+        }
+        self.pool.shutdown_timeout(grace)
+    }
+
+    // Accepts connections on listener and queues them on the pool until shutdown
+    fn accept(&self, listener: &TcpListener, next_id: &AtomicU64) {
+        for stream in listener.incoming() {
+            if self.state.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("Failed listening with error : {err}");
+                    continue;
+                }
+            };
+            let id = next_id.fetch_add(1, Ordering::Relaxed);
+            if let Ok(clone) = stream.try_clone() {
+                self.state.connections.lock().unwrap().insert(id, clone);
+            }
+            self.metrics.connection_opened();
+            let router = Arc::clone(&self.router);
+            let compression = self.compression.clone();
+            let state = Arc::clone(&self.state);
+            let metrics = Arc::clone(&self.metrics);
+            let report = self.reporter();
+            let options = self.options;
+            let queued = self.pool.execute(move || {
+                if let Err(err) = serve(stream, &router, compression.as_deref(), &options, &state.stopping, &report) {
+                    eprintln!("Connection failed: {err}");
+                }
+                state.connections.lock().unwrap().remove(&id);
+                metrics.connection_closed();
+            });
+            // The job, and with it the stream, was dropped, but the clone is still open
+            if let Err(err) = queued {
+                self.metrics.connection_closed();
+                let connection = self.state.connections.lock().unwrap().remove(&id);
+                match (err, connection) {
+                    (ExecuteError::Full, Some(mut connection)) => {
+                        // The response is small enough for the socket's send buffer,
+                        // so this won't hold up the accept loop
+                        let _ = connection.set_write_timeout(Some(self.options.write_timeout));
+                        let response = error_response(&self.router, 503).header("Retry-After", "1");
+                        if let Ok(bytes) = response.write_to(&mut connection) {
+                            self.report_rejected(&connection, &response, bytes);
+                        }
+                    }
+                    (err, _) => eprintln!("Couldn't serve connection: {err}"),
+                }
+            }
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// Makes run stop accepting connections and drain the ones it has
+    pub fn shutdown(&self) {
+        if self.state.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // accept() blocks until a connection comes in, so make one on every listener
+        for &address in &self.state.addresses {
+            let mut address = address;
+            if address.ip().is_unspecified() {
+                address.set_ip(match address {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(address);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.state.stopping.load(Ordering::SeqCst)
+    }
+}
+
+/// Whether the client wants the connection kept open after request:
+/// by default for HTTP/1.1, and only when asked with Connection: keep-alive for HTTP/1.0
+pub fn keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+// Tells the client whether the connection stays open, and if so for how long and how many more requests
+fn set_connection(response: &mut Response, version: Version, keep_alive: bool, idle: Duration, remaining: usize) {
+    if !keep_alive {
+        response.headers.insert("Connection", "close");
+        return;
+    }
+    if version == Version::Http10 {
+        response.headers.insert("Connection", "keep-alive");
+    }
+    response.headers.insert("Keep-Alive", format!("timeout={}, max={remaining}", idle.as_secs()));
+}
+
+fn timed_out(err: &io::Error) -> bool {
+    // Depending on the platform a read timeout is either of these
+    matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+// The response to an error the server runs into before a request could be read or
+// handled, from router's error pages. The connection is closed after it.
+fn error_response(router: &Router, status: u16) -> Response {
+    router.errors().page(status, "", "").unwrap_or_else(|| plain_error(status)).header("Connection", "close")
+}
+
+// The response to a malformed request, from router's error pages, or plain text saying what
+// was wrong with it. None if the connection failed and nothing can be sent.
+fn parse_error_response(router: &Router, err: &ParseError) -> Option<Response> {
+    match router.errors().page(err.status()?, "", "") {
+        Some(page) => Some(page.header("Connection", "close")),
+        None => err.response(),
+    }
+}
+
+fn plain_error(status: u16) -> Response {
+    Response::new(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("{status} {}\n", http::reason(status)))
+}
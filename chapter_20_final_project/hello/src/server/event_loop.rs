@@ -0,0 +1,649 @@
+use std::{
+    collections::HashMap,
+    ffi::c_int,
+    fs::File,
+    io::{self, prelude::*},
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{atomic::Ordering, Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use super::{answer, describe, error_response, new_record, parse_error_response, Answer, Server, ShutdownHandle, State};
+use crate::http::{self, ParseError, Request, Response, Upgrade};
+use crate::log::AccessRecord;
+use crate::metrics::Metrics;
+use crate::{ExecuteError, ShutdownError};
+
+// Serving connections from one thread that waits on epoll for whichever of them is ready,
+// rather than giving each connection a ThreadPool worker for as long as it stays open.
+// An idle keep-alive connection then costs a HashMap entry instead of a thread.
+//
+// Each connection is a small state machine: Reading until its buffered input parses as a
+// whole request, Handling while a worker runs the handler, and Writing until the response
+// is out, then back to Reading or closed. Handlers still run on the ThreadPool, since they
+// may block, and hand their answer back through a queue and an eventfd that wakes the loop.
+//
+// epoll is level-triggered here, so a connection that isn't read in full comes up again
+// on the next wait, and a request is parsed again from the start each time more of it
+// arrives. That's quadratic in the number of reads, which the Limits keep small.
+//
+// A connection whose response switches protocols, such as to a WebSocket, leaves the loop
+// for a worker of its own, as it would have under Server::run.
+
+mod sys {
+    use std::ffi::{c_int, c_uint};
+
+    pub const EPOLL_CLOEXEC: c_int = 0o2000000;
+    pub const EPOLL_CTL_ADD: c_int = 1;
+    pub const EPOLL_CTL_DEL: c_int = 2;
+    pub const EPOLL_CTL_MOD: c_int = 3;
+    pub const EPOLLIN: u32 = 0x001;
+    pub const EPOLLOUT: u32 = 0x004;
+    pub const EPOLLERR: u32 = 0x008;
+    pub const EPOLLHUP: u32 = 0x010;
+    pub const EPOLLRDHUP: u32 = 0x2000;
+    pub const EFD_CLOEXEC: c_int = 0o2000000;
+    pub const EFD_NONBLOCK: c_int = 0o4000;
+
+    // The kernel's struct epoll_event, which is packed on x86-64 only
+    #[derive(Clone, Copy)]
+    #[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+    #[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+    pub struct EpollEvent {
+        pub events: u32,
+        pub data: u64,
+    }
+
+    unsafe extern "C" {
+        pub fn epoll_create1(flags: c_int) -> c_int;
+        pub fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+        pub fn epoll_wait(epfd: c_int, events: *mut EpollEvent, maxevents: c_int, timeout: c_int) -> c_int;
+        pub fn eventfd(initval: c_uint, flags: c_int) -> c_int;
+    }
+}
+
+// What a connection waits for in each phase
+const READ: u32 = sys::EPOLLIN | sys::EPOLLRDHUP;
+const WRITE: u32 = sys::EPOLLOUT;
+// Nothing, though errors and hang-ups are always reported
+const NONE: u32 = 0;
+
+// Tokens: connections count up from 0, listeners count up from LISTENER
+const WAKER: u64 = u64::MAX;
+const LISTENER: u64 = 1 << 63;
+
+// The most read from one connection before seeing to the others
+const READ_CHUNK: usize = 64 * 1024;
+// The most events taken from one wait
+const EVENTS: usize = 1024;
+
+fn check(result: c_int) -> io::Result<c_int> {
+    if result == -1 { Err(io::Error::last_os_error()) } else { Ok(result) }
+}
+
+// An epoll instance, telling which of the file descriptors added to it are ready by the
+// token each was added with
+struct Poll {
+    epoll: OwnedFd,
+}
+
+impl Poll {
+    fn new() -> io::Result<Poll> {
+        let fd = check(unsafe { sys::epoll_create1(sys::EPOLL_CLOEXEC) })?;
+        // Safe because fd was just opened and nothing else owns it
+        Ok(Poll { epoll: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    fn control(&self, op: c_int, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        let mut event = sys::EpollEvent { events: interest, data: token };
+        check(unsafe { sys::epoll_ctl(self.epoll.as_raw_fd(), op, fd.as_raw_fd(), &mut event) })?;
+        Ok(())
+    }
+
+    fn add(&self, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(sys::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    fn modify(&self, fd: &impl AsRawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(sys::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    fn delete(&self, fd: &impl AsRawFd) -> io::Result<()> {
+        self.control(sys::EPOLL_CTL_DEL, fd, 0, NONE)
+    }
+
+    // Waits until something is ready or timeout, None for no timeout, has passed, and
+    // replaces events with what's ready
+    fn wait(&self, events: &mut Vec<sys::EpollEvent>, timeout: Option<Duration>) -> io::Result<()> {
+        // Rounded up, so the wait doesn't end just before a deadline and spin until it passes
+        let millis = timeout.map_or(-1, |timeout| timeout.as_nanos().div_ceil(1_000_000).min(c_int::MAX as u128) as c_int);
+        events.clear();
+        let ready = unsafe { sys::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.capacity() as c_int, millis) };
+        match check(ready) {
+            // Safe because the kernel filled in that many events
+            Ok(ready) => unsafe { events.set_len(ready as usize) },
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+}
+
+// An eventfd, which a worker writes to to wake the loop
+struct Waker {
+    file: File,
+}
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        let fd = check(unsafe { sys::eventfd(0, sys::EFD_CLOEXEC | sys::EFD_NONBLOCK) })?;
+        Ok(Waker { file: File::from(unsafe { OwnedFd::from_raw_fd(fd) }) })
+    }
+
+    fn wake(&self) {
+        // Only fails if the counter would overflow, and then it's readable anyway
+        let _ = (&self.file).write(&1u64.to_ne_bytes());
+    }
+
+    // Reading resets the counter, so the eventfd isn't ready until the next wake
+    fn reset(&self) {
+        let _ = (&self.file).read(&mut [0; 8]);
+    }
+}
+
+// Answers the workers have finished, by connection token, with their output for the loop
+// to write
+struct Finished {
+    answers: Mutex<Vec<(u64, Answer, Output)>>,
+    waker: Waker,
+}
+
+enum Phase {
+    // Waiting for a request, or the rest of one
+    Reading,
+    // A worker is running the handler
+    Handling,
+    // Writing output, from written on. The connection is closed afterwards unless keep_alive.
+    Writing { output: Vec<u8>, written: usize, keep_alive: bool },
+}
+
+struct Connection {
+    stream: TcpStream,
+    client: Option<SocketAddr>,
+    // Bytes read but not yet part of a request
+    input: Vec<u8>,
+    // The client has shut its side, so input is all there will be
+    eof: bool,
+    phase: Phase,
+    // What the connection is registered to wait for
+    interest: u32,
+    // Requests read from the connection so far
+    served: usize,
+    // When the connection is closed if it's still waiting for the client.
+    // None while a handler runs, which has no time limit.
+    deadline: Option<Instant>,
+    // When the current request started to arrive, None between requests
+    started: Option<Instant>,
+    record: AccessRecord,
+}
+
+/// A Server that serves its connections from an epoll event loop; see Server::event_loop
+pub struct EventLoop {
+    server: Server,
+    poll: Poll,
+    finished: Arc<Finished>,
+    report: Box<dyn Fn(&AccessRecord) + Send>,
+    connections: HashMap<u64, Connection>,
+    next_id: u64,
+    // Set once shutdown starts, to when the connections still open stop getting waited for
+    draining: Option<Instant>,
+}
+
+impl Server {
+    /// Serves with one thread waiting on epoll for whichever connection is ready, instead of
+    /// a ThreadPool worker for each open connection. Handlers still run on the pool, which
+    /// should reject rather than block when full, as a blocked loop serves nobody.
+    ///
+    /// Everything else is as with run: the same ConnectionOptions, access log, metrics and
+    /// ShutdownHandles. Only on Linux, with the event-loop feature.
+    pub fn event_loop(self) -> io::Result<EventLoop> {
+        let poll = Poll::new()?;
+        let finished = Arc::new(Finished { answers: Mutex::new(Vec::new()), waker: Waker::new()? });
+        poll.add(&finished.waker.file, WAKER, sys::EPOLLIN)?;
+        for (index, listener) in self.listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            poll.add(listener, LISTENER + index as u64, sys::EPOLLIN)?;
+        }
+        let report = Box::new(self.reporter());
+        Ok(EventLoop { server: self, poll, finished, report, connections: HashMap::new(), next_id: 0, draining: None })
+    }
+}
+
+impl EventLoop {
+    /// The address the server listens on; the first one if it listens on several
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    /// Every address the server listens on
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        self.server.local_addrs()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.server.shutdown_handle()
+    }
+
+    /// Like Server::run: serves connections until shutdown, then closes the ones waiting for
+    /// a request and gives the rest up to grace to be answered
+    pub fn run(mut self, grace: Duration) -> Result<(), ShutdownError> {
+        let mut events = Vec::with_capacity(EVENTS);
+        loop {
+            if self.draining.is_none() && self.server.state.stopping.load(Ordering::SeqCst) {
+                self.start_draining(grace);
+            }
+            if let Some(until) = self.draining
+                && (self.connections.is_empty() || Instant::now() >= until)
+            {
+                break;
+            }
+
+            if let Err(err) = self.poll.wait(&mut events, self.timeout()) {
+                eprintln!("Event loop failed: {err}");
+                self.server.state.stopping.store(true, Ordering::SeqCst);
+                continue;
+            }
+            for event in &events {
+                let (ready, token) = (event.events, event.data);
+                match token {
+                    WAKER => self.take_answers(),
+                    LISTENER.. => self.accept((token - LISTENER) as usize),
+                    id => self.ready(id, ready),
+                }
+            }
+            self.expire();
+        }
+
+        for (_, connection) in self.connections.drain() {
+            drop(connection);
+            self.server.metrics.connection_closed();
+        }
+        let left = self.draining.map_or(Duration::ZERO, |until| until.saturating_duration_since(Instant::now()));
+        self.server.pool.shutdown_timeout(left)
+    }
+
+    // Stops listening, closes the connections waiting for a request and lets the ones with
+    // a handler running or a response being written finish
+    fn start_draining(&mut self, grace: Duration) {
+        self.draining = Some(Instant::now() + grace);
+        // Closing the listeners also takes them out of the epoll set
+        self.server.listeners.clear();
+        let idle: Vec<u64> =
+            self.connections.iter().filter(|(_, connection)| matches!(connection.phase, Phase::Reading)).map(|(&id, _)| id).collect();
+        for id in idle {
+            self.close(id);
+        }
+        // Connections handed over to an upgrade end once their read side is closed
+        for connection in self.server.state.connections.lock().unwrap_or_else(PoisonError::into_inner).values() {
+            let _ = connection.shutdown(Shutdown::Read);
+        }
+    }
+
+    // Until the nearest deadline, or the end of the grace period when draining
+    fn timeout(&self) -> Option<Duration> {
+        let deadlines = self.connections.values().filter_map(|connection| connection.deadline);
+        let nearest = deadlines.chain(self.draining).min()?;
+        Some(nearest.saturating_duration_since(Instant::now()))
+    }
+
+    fn accept(&mut self, index: usize) {
+        let Some(listener) = self.server.listeners.get(index) else { return };
+        loop {
+            let (stream, client) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    eprintln!("Failed listening with error : {err}");
+                    return;
+                }
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            if let Err(err) = stream.set_nonblocking(true).and_then(|()| self.poll.add(&stream, id, READ)) {
+                eprintln!("Couldn't serve connection: {err}");
+                continue;
+            }
+            self.server.metrics.connection_opened();
+            let connection = Connection {
+                stream,
+                client: Some(client),
+                input: Vec::new(),
+                eof: false,
+                phase: Phase::Reading,
+                interest: READ,
+                served: 0,
+                deadline: Some(Instant::now() + self.server.options.idle_timeout),
+                started: None,
+                record: new_record(Some(client)),
+            };
+            self.connections.insert(id, connection);
+        }
+    }
+
+    // Reads or writes what it can on a connection epoll says is ready
+    fn ready(&mut self, id: u64, ready: u32) {
+        let Some(connection) = self.connections.get_mut(&id) else { return };
+        let hung_up = ready & sys::EPOLLERR != 0 || (ready & sys::EPOLLHUP != 0 && matches!(connection.phase, Phase::Handling));
+        if hung_up {
+            self.close(id);
+            return;
+        }
+        // A hang-up reads as the end of the stream
+        let readable = ready & (READ | sys::EPOLLHUP) != 0;
+        if matches!(connection.phase, Phase::Reading) && readable && fill(connection).is_err() {
+            self.close(id);
+            return;
+        }
+        self.advance(id);
+    }
+
+    fn take_answers(&mut self) {
+        self.finished.waker.reset();
+        let answers = std::mem::take(&mut *self.finished.answers.lock().unwrap_or_else(PoisonError::into_inner));
+        for (id, Answer { response, keep_alive, .. }, output) in answers {
+            if let Some(upgrade) = response.upgrade.clone() {
+                self.hand_over(id, output, &response, upgrade);
+                continue;
+            }
+            // The connection may have closed while its handler ran
+            if let Some(connection) = self.connections.get_mut(&id) {
+                respond_with(connection, output, &response, keep_alive, self.server.options.write_timeout);
+                self.advance(id);
+            }
+        }
+    }
+
+    // Takes a connection out of the loop and gives it a worker that writes response and
+    // then runs upgrade on it, blocking, for as long as the upgrade keeps it
+    fn hand_over(&mut self, id: u64, output: Output, response: &Response, upgrade: Upgrade) {
+        let write_timeout = self.server.options.write_timeout;
+        let Some(mut connection) = self.connections.remove(&id) else { return };
+        respond_with(&mut connection, output, response, false, write_timeout);
+        let started = connection.started.take().unwrap_or_else(Instant::now);
+        connection.record.duration = started.elapsed();
+        (self.report)(&connection.record);
+
+        let taken = self.poll.delete(&connection.stream).and_then(|()| connection.stream.set_nonblocking(false));
+        let clone = match taken.and_then(|()| connection.stream.try_clone()) {
+            Ok(clone) => clone,
+            Err(_) => return self.server.metrics.connection_closed(),
+        };
+        // Kept where shutting down can find it, as Server::run keeps its connections
+        self.server.state.connections.lock().unwrap_or_else(PoisonError::into_inner).insert(id, clone);
+        let Connection { mut stream, input, phase, .. } = connection;
+        let Phase::Writing { output, .. } = phase else { unreachable!("respond starts writing") };
+        let (state, metrics) = (Arc::clone(&self.server.state), Arc::clone(&self.server.metrics));
+        let job = move || {
+            let written = stream.set_write_timeout(Some(write_timeout)).and_then(|()| stream.write_all(&output));
+            if written.is_ok() {
+                upgrade.run(stream, input);
+            }
+            handed_back(&state, &metrics, id);
+        };
+        if self.server.pool.execute(job).is_err() {
+            handed_back(&self.server.state, &self.server.metrics, id);
+        }
+    }
+
+    // Closes connections that have waited too long for the client. A request that started
+    // to arrive gets 408 Request Timeout, as from Server::run.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.deadline.is_some_and(|deadline| deadline <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            let connection = self.connections.get_mut(&id).unwrap();
+            if matches!(connection.phase, Phase::Reading) && connection.started.is_some() {
+                respond(connection, &error_response(&self.server.router, 408), false, false, self.server.options.write_timeout);
+                self.advance(id);
+            } else {
+                self.close(id);
+            }
+        }
+    }
+
+    // Takes a connection as far through its phases as it can go without waiting, and
+    // registers what it waits for next, or closes it
+    fn advance(&mut self, id: u64) {
+        let options = self.server.options;
+        let Some(connection) = self.connections.get_mut(&id) else { return };
+        let interest = loop {
+            match &mut connection.phase {
+                Phase::Reading if connection.input.is_empty() => {
+                    if connection.eof || self.draining.is_some() {
+                        return self.close(id);
+                    }
+                    break READ;
+                }
+                Phase::Reading => {
+                    if connection.started.is_none() {
+                        let now = Instant::now();
+                        connection.started = Some(now);
+                        connection.deadline = Some(now + options.request_timeout);
+                        connection.record = new_record(connection.client);
+                    }
+                    let mut buffered = Buffered { bytes: &connection.input, eof: connection.eof };
+                    match http::read_request(&mut buffered, &options.limits) {
+                        Ok(Some(request)) => {
+                            let used = connection.input.len() - buffered.bytes.len();
+                            connection.input.drain(..used);
+                            connection.served += 1;
+                            connection.deadline = None;
+                            describe(&mut connection.record, &request);
+                            connection.phase = Phase::Handling;
+                            match dispatch(&self.server, &self.finished, id, request, connection.served) {
+                                Ok(()) => {}
+                                // Turned away like a connection the pool has no room for
+                                Err(ExecuteError::Full) => {
+                                    let response = error_response(&self.server.router, 503).header("Retry-After", "1");
+                                    respond(connection, &response, false, false, options.write_timeout);
+                                }
+                                Err(ExecuteError::ShutDown) => return self.close(id),
+                            }
+                        }
+                        Ok(None) => return self.close(id),
+                        Err(ParseError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => break READ,
+                        Err(ParseError::Io(_)) => return self.close(id),
+                        // The rest of the input can't be trusted after a malformed request
+                        Err(err) => {
+                            let response = parse_error_response(&self.server.router, &err)
+                                .expect("only Io errors have no response");
+                            respond(connection, &response, false, false, options.write_timeout);
+                        }
+                    }
+                }
+                Phase::Handling => break NONE,
+                Phase::Writing { output, written, keep_alive } => {
+                    while *written < output.len() {
+                        match connection.stream.write(&output[*written..]) {
+                            Ok(0) => return self.close(id),
+                            Ok(wrote) => {
+                                *written += wrote;
+                                connection.deadline = Some(Instant::now() + options.write_timeout);
+                            }
+                            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(_) => return self.close(id),
+                        }
+                    }
+                    if *written < output.len() {
+                        break WRITE;
+                    }
+
+                    let started = connection.started.take().unwrap_or_else(Instant::now);
+                    connection.record.duration = started.elapsed();
+                    (self.report)(&connection.record);
+                    if !*keep_alive || self.draining.is_some() {
+                        return self.close(id);
+                    }
+                    // Pipelined requests may already be in the input
+                    connection.phase = Phase::Reading;
+                    connection.deadline = Some(Instant::now() + options.idle_timeout);
+                }
+            }
+        };
+        if connection.interest != interest {
+            if self.poll.modify(&connection.stream, id, interest).is_err() {
+                return self.close(id);
+            }
+            connection.interest = interest;
+        }
+    }
+
+    fn close(&mut self, id: u64) {
+        // Closing the socket also takes it out of the epoll set
+        if self.connections.remove(&id).is_some() {
+            self.server.metrics.connection_closed();
+        }
+    }
+}
+
+// Reads what the client has sent, up to READ_CHUNK
+fn fill(connection: &mut Connection) -> io::Result<()> {
+    let mut chunk = [0; 16 * 1024];
+    let mut total = 0;
+    while total < READ_CHUNK {
+        match connection.stream.read(&mut chunk) {
+            Ok(0) => {
+                connection.eof = true;
+                return Ok(());
+            }
+            Ok(read) => {
+                connection.input.extend_from_slice(&chunk[..read]);
+                total += read;
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// Runs the handler for request on the pool, which hands the answer back to the loop
+fn dispatch(server: &Server, finished: &Arc<Finished>, id: u64, request: Request, served: usize) -> Result<(), ExecuteError> {
+    let router = Arc::clone(&server.router);
+    let compression = server.compression.clone();
+    let state = Arc::clone(&server.state);
+    let finished = Arc::clone(finished);
+    let options = server.options;
+    server.pool.execute(move || {
+        let answer = answer(&router, compression.as_deref(), request, &options, served, &state.stopping);
+        // Written out here rather than on the loop, so compressing the body doesn't hold it up
+        let output = serialize(&answer.response, answer.head);
+        finished.answers.lock().unwrap_or_else(PoisonError::into_inner).push((id, answer, output));
+        finished.waker.wake();
+    })
+}
+
+// Forgets a connection handed over to an upgrade once it's done with it
+fn handed_back(state: &State, metrics: &Metrics, id: u64) {
+    state.connections.lock().unwrap_or_else(PoisonError::into_inner).remove(&id);
+    metrics.connection_closed();
+}
+
+// Starts writing response on connection
+fn respond(connection: &mut Connection, response: &Response, head: bool, keep_alive: bool, write_timeout: Duration) {
+    respond_with(connection, serialize(response, head), response, keep_alive, write_timeout);
+}
+
+// A response as it goes out on the connection
+struct Output {
+    bytes: Vec<u8>,
+    // How many of those are body, which is less than its length when it's compressed
+    body: usize,
+}
+
+// What's written for response, only the head of it for a HEAD request
+fn serialize(response: &Response, head: bool) -> Output {
+    let mut bytes = Vec::new();
+    let body = if head {
+        response.write_head_to(&mut bytes).map(|()| 0)
+    } else {
+        response.write_to(&mut bytes)
+    };
+    Output { body: body.expect("writing to a Vec can't fail"), bytes }
+}
+
+// Starts writing output, which is response serialized, on connection
+fn respond_with(connection: &mut Connection, output: Output, response: &Response, keep_alive: bool, write_timeout: Duration) {
+    connection.record.status = response.status;
+    connection.record.bytes = output.body;
+    connection.deadline = Some(Instant::now() + write_timeout);
+    connection.phase = Phase::Writing { output: output.bytes, written: 0, keep_alive };
+}
+
+// The input read so far, as a BufRead for http::read_request that runs out with WouldBlock
+// rather than the end of the stream while the client may still send more
+struct Buffered<'a> {
+    bytes: &'a [u8],
+    eof: bool,
+}
+
+impl Read for Buffered<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for Buffered<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.bytes.is_empty() && !self.eof {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(self.bytes)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.bytes = &self.bytes[amount..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Limits;
+
+    #[test]
+    fn parses_requests_as_they_arrive() {
+        let request = b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n";
+        let limits = Limits::default();
+        // Every prefix of the request is incomplete rather than an error
+        for end in 0..request.len() - 21 {
+            let mut buffered = Buffered { bytes: &request[..end], eof: false };
+            match http::read_request(&mut buffered, &limits) {
+                Err(ParseError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => panic!("{end} bytes read as {result:?}"),
+            }
+        }
+
+        let mut buffered = Buffered { bytes: request, eof: false };
+        let parsed = http::read_request(&mut buffered, &limits).unwrap().unwrap();
+        assert_eq!((b"hello".to_vec(), "GET / HTTP/1.1\r\n".as_bytes()), (parsed.body, buffered.bytes));
+
+        // Once the client has closed its side, a partial request is malformed
+        let mut buffered = Buffered { bytes: &request[..30], eof: true };
+        assert!(http::read_request(&mut buffered, &limits).unwrap_err().status().is_some());
+        let mut buffered = Buffered { bytes: b"", eof: true };
+        assert!(http::read_request(&mut buffered, &limits).unwrap().is_none());
+    }
+}
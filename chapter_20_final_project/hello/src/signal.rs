@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// SIGINT and SIGTERM handling without any dependencies.
+// A signal handler may only do async-signal-safe things, so it just sets a flag, and a thread
+// watching the flag runs the real shutdown. A second signal exits at once, for when the
+// graceful shutdown itself hangs.
+
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::c_int;
+
+    pub const SIGINT: c_int = 2;
+    pub const SIGTERM: c_int = 15;
+
+    unsafe extern "C" {
+        // sighandler_t is a pointer-sized function pointer
+        pub fn signal(signum: c_int, handler: usize) -> usize;
+        pub fn _exit(status: c_int) -> !;
+    }
+
+    pub extern "C" fn handle(signum: c_int) {
+        if super::TERMINATING.swap(true, super::Ordering::SeqCst) {
+            // 128 + the signal number is what shells report for a process killed by it
+            unsafe { _exit(128 + signum) }
+        }
+    }
+}
+
+/// Runs on_terminate on a new thread when the process gets SIGINT (Ctrl-C) or SIGTERM.
+/// Only unix signals are supported; elsewhere this does nothing.
+pub fn on_terminate<F: FnOnce() + Send + 'static>(on_terminate: F) {
+    #[cfg(unix)]
+    {
+        let handler = sys::handle as extern "C" fn(std::ffi::c_int) as usize;
+        // Safe because the handler only touches an atomic and calls _exit
+        unsafe {
+            sys::signal(sys::SIGINT, handler);
+            sys::signal(sys::SIGTERM, handler);
+        }
+        std::thread::spawn(move || {
+            while !TERMINATING.load(Ordering::SeqCst) {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            on_terminate();
+        });
+    }
+    #[cfg(not(unix))]
+    drop(on_terminate);
+}
@@ -0,0 +1,936 @@
+// HTML templates. A template is text with tags in it:
+//
+//     {{ user.name }}                   a value, escaped for HTML; {{ x | raw }} isn't escaped
+//     {% if a == "b" %}..{% elif not c %}..{% else %}..{% endif %}
+//     {% for item in items %}..{% else %}shown when items is empty{% endfor %}
+//     {% include "header.html" %}       another template, rendered with the same values
+//     {% extends "layout.html" %}       fills in the layout's blocks with this template's
+//     {% block content %}..{% endblock %}
+//     {# a comment #}
+//
+// A dash on the inside of a tag, as in {%- or -%}, trims the whitespace before or after
+// it. Inside a for loop, loop.index counts from 1, and loop.first and loop.last are true
+// on the first and last items. Filters are raw, upper, lower and length.
+//
+// Templates are compiled once and kept by Templates, which in dev mode compiles them again
+// when their files change. Included and extended templates are found relative to the
+// directory of the template naming them.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, PoisonError, RwLock},
+    time::SystemTime,
+};
+
+use crate::files::escape_html;
+
+/// Why a template couldn't be compiled or rendered, and where
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    /// A template's path, with the line when there is one
+    pub origin: String,
+    pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.origin, self.message)
+    }
+}
+
+impl Error for TemplateError {}
+
+/// Something a template can show, test or loop over
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(i64),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// Whether {% if %} takes it as true: anything but false, 0 and empty text, lists and maps
+    pub fn truthy(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.is_empty(),
+            Value::Number(number) => *number != 0,
+            Value::Bool(boolean) => *boolean,
+            Value::List(list) => !list.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+        }
+    }
+}
+
+// How {{ }} shows a value, before escaping. Lists are shown comma-separated and maps not at all.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Text(text) => f.write_str(text),
+            Value::Number(number) => write!(f, "{number}"),
+            Value::Bool(boolean) => write!(f, "{boolean}"),
+            Value::List(list) => {
+                for (i, value) in list.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                Ok(())
+            }
+            Value::Map(_) => Ok(()),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Value {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Value {
+        Value::Text(text)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Value {
+        Value::Bool(boolean)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(number: i64) -> Value {
+        Value::Number(number)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(number: u16) -> Value {
+        Value::Number(number.into())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(number: usize) -> Value {
+        Value::Number(number.try_into().unwrap_or(i64::MAX))
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(list: Vec<T>) -> Value {
+        Value::List(list.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Context> for Value {
+    fn from(context: Context) -> Value {
+        Value::Map(context.values)
+    }
+}
+
+/// The values a template is rendered with, by name. Also builds the maps inside it:
+/// Context::new().set("href", "/").set("title", "Home") converts into a Value::Map.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    values: BTreeMap<String, Value>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    pub fn set(mut self, name: &str, value: impl Into<Value>) -> Context {
+        self.values.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    // A name, then the keys looked up in it, as in user.name
+    Path(Vec<String>),
+    Literal(Value),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Filter {
+    Raw,
+    Upper,
+    Lower,
+    Length,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Output { expr: Expr, filters: Vec<Filter> },
+    If { branches: Vec<(Expr, Vec<Node>)>, otherwise: Vec<Node> },
+    For { name: String, list: Expr, body: Vec<Node>, otherwise: Vec<Node> },
+    Include { name: String, line: usize },
+    // The body is in Template::blocks, where a template extending this one can replace it
+    Block(String),
+}
+
+/// A compiled template
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    path: PathBuf,
+    // The layout named by {% extends %}, and the line it's on
+    extends: Option<(String, usize)>,
+    nodes: Vec<Node>,
+    blocks: HashMap<String, Vec<Node>>,
+}
+
+impl Template {
+    /// Compiles source, read from path, which included and extended templates are found next to
+    pub fn compile(path: impl Into<PathBuf>, source: &str) -> Result<Template, TemplateError> {
+        let path = path.into();
+        let tokens = tokenize(source).map_err(|(line, message)| error_at(&path, line, message))?;
+        let mut parser = Parser { tokens, next: 0, blocks: HashMap::new(), extends: None };
+        let nodes = match parser.nodes(&[]) {
+            Ok((nodes, _)) => nodes,
+            Err((line, message)) => return Err(error_at(&path, line, message)),
+        };
+        Ok(Template { path, extends: parser.extends, nodes, blocks: parser.blocks })
+    }
+
+    // The path of a template this one names
+    fn resolve(&self, name: &str) -> PathBuf {
+        self.path.parent().unwrap_or(Path::new("")).join(name)
+    }
+
+    // Every template this one names, with the line naming it
+    fn dependencies(&self) -> Vec<(&str, usize)> {
+        fn walk<'a>(nodes: &'a [Node], found: &mut Vec<(&'a str, usize)>) {
+            for node in nodes {
+                match node {
+                    Node::Include { name, line } => found.push((name, *line)),
+                    Node::If { branches, otherwise } => {
+                        branches.iter().for_each(|(_, body)| walk(body, found));
+                        walk(otherwise, found);
+                    }
+                    Node::For { body, otherwise, .. } => {
+                        walk(body, found);
+                        walk(otherwise, found);
+                    }
+                    Node::Text(_) | Node::Output { .. } | Node::Block(_) => {}
+                }
+            }
+        }
+        let mut found: Vec<(&str, usize)> = self.extends.iter().map(|(name, line)| (name.as_str(), *line)).collect();
+        walk(&self.nodes, &mut found);
+        self.blocks.values().for_each(|body| walk(body, &mut found));
+        found
+    }
+}
+
+fn error_at(path: &Path, line: usize, message: String) -> TemplateError {
+    TemplateError { origin: format!("{}:{line}", path.display()), message }
+}
+
+// A template's text split at its tags, with the line each tag starts on
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Text(String),
+    Output(String, usize),
+    Tag(String, usize),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, (usize, String)> {
+    let mut tokens = Vec::new();
+    let (mut rest, mut line) = (source, 1);
+    let mut trim_next = false;
+    loop {
+        let Some(start) = ["{{", "{%", "{#"].iter().filter_map(|open| rest.find(open)).min() else {
+            let text = if trim_next { rest.trim_start() } else { rest };
+            if !text.is_empty() {
+                tokens.push(Token::Text(text.to_string()));
+            }
+            return Ok(tokens);
+        };
+        let close = match &rest[start..start + 2] {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let mut text = &rest[..start];
+        let inner_start = start + 2;
+        let Some(length) = rest[inner_start..].find(close) else {
+            let line = line + text.matches('\n').count();
+            return Err((line, format!("{} is never closed with {close}", &rest[start..inner_start])));
+        };
+        let mut inner = &rest[inner_start..inner_start + length];
+        if trim_next {
+            text = text.trim_start();
+        }
+        if let Some(trimmed) = inner.strip_prefix('-') {
+            text = text.trim_end();
+            inner = trimmed;
+        }
+        trim_next = false;
+        if let Some(trimmed) = inner.strip_suffix('-') {
+            trim_next = true;
+            inner = trimmed;
+        }
+        if !text.is_empty() {
+            tokens.push(Token::Text(text.to_string()));
+        }
+        let tag_line = line + rest[..start].matches('\n').count();
+        match close {
+            "}}" => tokens.push(Token::Output(inner.trim().to_string(), tag_line)),
+            "%}" => tokens.push(Token::Tag(inner.trim().to_string(), tag_line)),
+            _ => {}
+        }
+        let end = inner_start + length + 2;
+        line += rest[..end].matches('\n').count();
+        rest = &rest[end..];
+    }
+}
+
+// Errors while parsing are a line and a message, made into a TemplateError by Template::compile
+type ParseResult<T> = Result<T, (usize, String)>;
+
+// The tag that ended a run of nodes, as in {% elif x %}
+struct End {
+    keyword: String,
+    argument: String,
+    line: usize,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+    blocks: HashMap<String, Vec<Node>>,
+    extends: Option<(String, usize)>,
+}
+
+impl Parser {
+    // Nodes up to one of the tags in ends, which is returned with its argument and line,
+    // or to the end of the template
+    fn nodes(&mut self, ends: &[&str]) -> ParseResult<(Vec<Node>, Option<End>)> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.get(self.next).cloned() {
+            self.next += 1;
+            let (tag, line) = match token {
+                Token::Text(text) => {
+                    nodes.push(Node::Text(text));
+                    continue;
+                }
+                Token::Output(source, line) => {
+                    let (expr, filters) = output(&source).map_err(|message| (line, message))?;
+                    nodes.push(Node::Output { expr, filters });
+                    continue;
+                }
+                Token::Tag(tag, line) => (tag, line),
+            };
+            let (keyword, argument) = tag.split_once(char::is_whitespace).unwrap_or((&tag, ""));
+            let argument = argument.trim();
+            if ends.contains(&keyword) {
+                return Ok((nodes, Some(End { keyword: keyword.to_string(), argument: argument.to_string(), line })));
+            }
+            match keyword {
+                "if" => nodes.push(self.conditional(argument, line)?),
+                "for" => nodes.push(self.for_loop(argument, line)?),
+                "include" => nodes.push(Node::Include { name: string_literal(argument, line)?, line }),
+                "block" => {
+                    let name = argument.to_string();
+                    if !is_name(&name) {
+                        return Err((line, format!("{name:?} isn't a block name")));
+                    }
+                    let (body, _) = self.until(&["endblock"], "block", line)?;
+                    if self.blocks.insert(name.clone(), body).is_some() {
+                        return Err((line, format!("the block {name} is defined twice")));
+                    }
+                    nodes.push(Node::Block(name));
+                }
+                "extends" => {
+                    let before = self.tokens[..self.next - 1].iter().any(|token| match token {
+                        Token::Text(text) => !text.trim().is_empty(),
+                        _ => true,
+                    });
+                    if before || !ends.is_empty() {
+                        return Err((line, "extends must come before anything else".to_string()));
+                    }
+                    self.extends = Some((string_literal(argument, line)?, line));
+                }
+                _ => return Err((line, format!("unknown tag {keyword:?}"))),
+            }
+        }
+        Ok((nodes, None))
+    }
+
+    // Nodes up to one of ends, which must be there, for the tag opened on line
+    fn until(&mut self, ends: &[&str], tag: &str, line: usize) -> ParseResult<(Vec<Node>, End)> {
+        match self.nodes(ends) {
+            Ok((nodes, Some(end))) => Ok((nodes, end)),
+            Ok((_, None)) => Err((line, format!("{tag} is never closed with {}", ends.last().unwrap()))),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn conditional(&mut self, condition: &str, line: usize) -> ParseResult<Node> {
+        let mut branches = Vec::new();
+        let mut condition = expression(condition).map_err(|message| (line, message))?;
+        loop {
+            let (body, end) = self.until(&["elif", "else", "endif"], "if", line)?;
+            branches.push((condition, body));
+            match end.keyword.as_str() {
+                "elif" => condition = expression(&end.argument).map_err(|message| (end.line, message))?,
+                "else" => {
+                    let (otherwise, _) = self.until(&["endif"], "if", line)?;
+                    return Ok(Node::If { branches, otherwise });
+                }
+                _ => return Ok(Node::If { branches, otherwise: Vec::new() }),
+            }
+        }
+    }
+
+    fn for_loop(&mut self, argument: &str, line: usize) -> ParseResult<Node> {
+        let words: Vec<&str> = argument.split_whitespace().collect();
+        let [name, "in", list] = words[..] else {
+            return Err((line, "expected for NAME in LIST".to_string()));
+        };
+        if !is_name(name) || name == "loop" {
+            return Err((line, format!("{name:?} can't be a loop variable")));
+        }
+        let list = expression(list).map_err(|message| (line, message))?;
+        let (body, end) = self.until(&["else", "endfor"], "for", line)?;
+        let otherwise = match end.keyword.as_str() {
+            "else" => self.until(&["endfor"], "for", line)?.0,
+            _ => Vec::new(),
+        };
+        Ok(Node::For { name: name.to_string(), list, body, otherwise })
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn string_literal(argument: &str, line: usize) -> ParseResult<String> {
+    match expression(argument) {
+        Ok(Expr::Literal(Value::Text(text))) => Ok(text),
+        _ => Err((line, format!("expected a quoted template name, not {argument:?}"))),
+    }
+}
+
+// The expression and filters in {{ }}
+fn output(source: &str) -> Result<(Expr, Vec<Filter>), String> {
+    let mut parts = split_filters(source).into_iter();
+    let expr = expression(parts.next().unwrap_or_default())?;
+    let filters = parts
+        .map(|filter| match filter.trim() {
+            "raw" => Ok(Filter::Raw),
+            "upper" => Ok(Filter::Upper),
+            "lower" => Ok(Filter::Lower),
+            "length" => Ok(Filter::Length),
+            other => Err(format!("unknown filter {other:?}")),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((expr, filters))
+}
+
+// Splits at the |s outside quotes
+fn split_filters(source: &str) -> Vec<&str> {
+    let (mut parts, mut start, mut quote) = (Vec::new(), 0, None);
+    for (i, c) in source.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, '|') => {
+                parts.push(&source[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&source[start..]);
+    parts
+}
+
+fn expression(source: &str) -> Result<Expr, String> {
+    let words = words(source)?;
+    if words.is_empty() {
+        return Err("expected an expression".to_string());
+    }
+    let mut words = Words { words, next: 0 };
+    let expr = words.or()?;
+    match words.words.get(words.next) {
+        Some(word) => Err(format!("unexpected {word:?} in {:?}", source.trim())),
+        None => Ok(expr),
+    }
+}
+
+// Splits an expression into names, literals and operators. Quoted strings keep their quotes.
+fn words(source: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let Some((end, _)) = chars.find(|&(_, next)| next == c) else {
+                return Err(format!("{} is never closed", &source[start..]));
+            };
+            words.push(source[start..=end].to_string());
+        } else if c == '(' || c == ')' {
+            chars.next();
+            words.push(c.to_string());
+        } else if source[start..].starts_with("==") || source[start..].starts_with("!=") {
+            chars.nth(1);
+            words.push(source[start..start + 2].to_string());
+        } else {
+            let mut end = source.len();
+            while let Some(&(i, next)) = chars.peek() {
+                if next.is_whitespace() || "\"'()=!".contains(next) {
+                    end = i;
+                    break;
+                }
+                chars.next();
+            }
+            if end == start {
+                return Err(format!("unexpected {:?}", &source[start..]));
+            }
+            words.push(source[start..end].to_string());
+        }
+    }
+    Ok(words)
+}
+
+// Parses words, loosest first: or, then and, then not, then == and !=
+struct Words {
+    words: Vec<String>,
+    next: usize,
+}
+
+impl Words {
+    fn take(&mut self, word: &str) -> bool {
+        let found = self.words.get(self.next).is_some_and(|next| next == word);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.take("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.take("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.take("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        let left = self.operand()?;
+        if self.take("==") {
+            Ok(Expr::Equal(Box::new(left), Box::new(self.operand()?)))
+        } else if self.take("!=") {
+            Ok(Expr::NotEqual(Box::new(left), Box::new(self.operand()?)))
+        } else {
+            Ok(left)
+        }
+    }
+
+    fn operand(&mut self) -> Result<Expr, String> {
+        let Some(word) = self.words.get(self.next).cloned() else {
+            return Err("expected a value at the end".to_string());
+        };
+        self.next += 1;
+        if word == "(" {
+            let expr = self.or()?;
+            return match self.take(")") {
+                true => Ok(expr),
+                false => Err("( is never closed".to_string()),
+            };
+        }
+        if let Some(quote) = word.chars().next().filter(|c| *c == '"' || *c == '\'') {
+            return Ok(Expr::Literal(Value::Text(word.trim_matches(quote).to_string())));
+        }
+        if let Ok(number) = word.parse() {
+            return Ok(Expr::Literal(Value::Number(number)));
+        }
+        match word.as_str() {
+            "true" | "false" => Ok(Expr::Literal(Value::Bool(word == "true"))),
+            _ if word.split('.').all(is_name) => Ok(Expr::Path(word.split('.').map(String::from).collect())),
+            _ => Err(format!("unexpected {word:?}")),
+        }
+    }
+}
+
+// The values a template sees: the context, under the loop variables of the loops it's in
+struct Scope<'a> {
+    context: &'a Context,
+    locals: Vec<(String, Value)>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (name, keys) = path.split_first()?;
+        let local = self.locals.iter().rev().find(|(local, _)| local == name).map(|(_, value)| value);
+        let mut value = local.or_else(|| self.context.values.get(name))?;
+        for key in keys {
+            value = match value {
+                Value::Map(map) => map.get(key)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    fn eval<'b>(&'b self, expr: &'b Expr) -> Option<Cow<'b, Value>> {
+        let boolean = |value: bool| Some(Cow::Owned(Value::Bool(value)));
+        let truthy = |expr: &Expr| self.eval(expr).is_some_and(|value| value.truthy());
+        // Compared as they'd be shown, so 404 == "404", and a missing value is empty
+        let text = |expr: &Expr| self.eval(expr).map(|value| value.to_string()).unwrap_or_default();
+        match expr {
+            Expr::Path(path) => self.lookup(path).map(Cow::Borrowed),
+            Expr::Literal(value) => Some(Cow::Borrowed(value)),
+            Expr::Not(expr) => boolean(!truthy(expr)),
+            Expr::And(left, right) => boolean(truthy(left) && truthy(right)),
+            Expr::Or(left, right) => boolean(truthy(left) || truthy(right)),
+            Expr::Equal(left, right) => boolean(text(left) == text(right)),
+            Expr::NotEqual(left, right) => boolean(text(left) != text(right)),
+        }
+    }
+}
+
+// Templates naming each other deeper than this are taken to be going round in circles
+const MAX_DEPTH: usize = 32;
+
+/// Compiled templates by path, compiled the first time they're used.
+/// In dev mode a template whose file has changed since is compiled again.
+#[derive(Debug, Default)]
+pub struct Templates {
+    dev: bool,
+    cache: RwLock<HashMap<PathBuf, Cached>>,
+}
+
+#[derive(Debug)]
+struct Cached {
+    template: Arc<Template>,
+    // The file's modification time and length when it was read, or None for an inserted template
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl Templates {
+    pub fn new() -> Templates {
+        Templates::default()
+    }
+
+    /// Whether to check for changed files each time a template is used
+    pub fn dev(mut self, dev: bool) -> Templates {
+        self.dev = dev;
+        self
+    }
+
+    /// Compiles source as the template at path, in place of any file there
+    pub fn insert(&self, path: impl Into<PathBuf>, source: &str) -> Result<(), TemplateError> {
+        let template = Arc::new(Template::compile(path, source)?);
+        let cached = Cached { template: Arc::clone(&template), stamp: None };
+        self.cache.write().unwrap_or_else(PoisonError::into_inner).insert(template.path.clone(), cached);
+        Ok(())
+    }
+
+    /// The template at path, compiled from its file if it hasn't been already
+    pub fn get(&self, path: &Path) -> Result<Arc<Template>, TemplateError> {
+        let reading_error = |err: std::io::Error| TemplateError { origin: path.display().to_string(), message: err.to_string() };
+        let stamp = |metadata: &fs::Metadata| metadata.modified().ok().map(|modified| (modified, metadata.len()));
+        if let Some(cached) = self.cache.read().unwrap_or_else(PoisonError::into_inner).get(path) {
+            let fresh = !self.dev
+                || cached.stamp.is_none()
+                || fs::metadata(path).is_ok_and(|metadata| stamp(&metadata) == cached.stamp);
+            if fresh {
+                return Ok(Arc::clone(&cached.template));
+            }
+        }
+        let metadata = fs::metadata(path).map_err(reading_error)?;
+        let source = fs::read_to_string(path).map_err(reading_error)?;
+        let template = Arc::new(Template::compile(path, &source)?);
+        // Without a modification time there's no telling when to read it again
+        let stamp = stamp(&metadata).or(Some((SystemTime::UNIX_EPOCH, metadata.len())));
+        let cached = Cached { template: Arc::clone(&template), stamp };
+        self.cache.write().unwrap_or_else(PoisonError::into_inner).insert(path.to_path_buf(), cached);
+        Ok(template)
+    }
+
+    /// Compiles the template at path and every template it includes or extends, so
+    /// mistakes in them show up before anything is rendered
+    pub fn check(&self, path: &Path) -> Result<(), TemplateError> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![(path.to_path_buf(), None)];
+        while let Some((path, named_at)) = pending.pop() {
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            let template = self.get(&path).map_err(|err| match named_at {
+                Some(origin) => TemplateError { origin, message: format!("{}: {}", err.origin, err.message) },
+                None => err,
+            })?;
+            for (name, line) in template.dependencies() {
+                pending.push((template.resolve(name), Some(format!("{}:{line}", template.path.display()))));
+            }
+        }
+        Ok(())
+    }
+
+    /// The template at path rendered with context
+    pub fn render(&self, path: &Path, context: &Context) -> Result<String, TemplateError> {
+        let template = self.get(path)?;
+        let mut scope = Scope { context, locals: Vec::new() };
+        let mut out = String::new();
+        self.render_template(&template, &mut scope, &mut out, 0)?;
+        Ok(out)
+    }
+
+    // Renders template, by way of the layouts it extends
+    fn render_template(&self, template: &Arc<Template>, scope: &mut Scope, out: &mut String, depth: usize) -> Result<(), TemplateError> {
+        let mut chain = vec![Arc::clone(template)];
+        while let Some((name, line)) = chain.last().unwrap().extends.clone() {
+            let child = chain.last().unwrap();
+            let parent = self.named(child, &name, line, depth + chain.len())?;
+            chain.push(parent);
+        }
+        let layout = Arc::clone(chain.last().unwrap());
+        self.render_nodes(&layout.nodes, &layout, &chain, scope, out, depth + chain.len())
+    }
+
+    // The template named in from on line
+    fn named(&self, from: &Template, name: &str, line: usize, depth: usize) -> Result<Arc<Template>, TemplateError> {
+        if depth > MAX_DEPTH {
+            return Err(error_at(&from.path, line, "templates include or extend each other too deeply".to_string()));
+        }
+        self.get(&from.resolve(name))
+            .map_err(|err| error_at(&from.path, line, format!("{}: {}", err.origin, err.message)))
+    }
+
+    // owner is the template nodes come from, which the templates they include are found
+    // next to. chain is the template being rendered followed by the layouts it extends,
+    // which blocks are looked up in in that order.
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        owner: &Template,
+        chain: &[Arc<Template>],
+        scope: &mut Scope,
+        out: &mut String,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Output { expr, filters } => {
+                    let Some(mut value) = scope.eval(expr) else { continue };
+                    let mut raw = false;
+                    for filter in filters {
+                        value = match filter {
+                            Filter::Raw => {
+                                raw = true;
+                                value
+                            }
+                            Filter::Upper => Cow::Owned(Value::Text(value.to_string().to_uppercase())),
+                            Filter::Lower => Cow::Owned(Value::Text(value.to_string().to_lowercase())),
+                            Filter::Length => Cow::Owned(Value::from(match &*value {
+                                Value::Text(text) => text.chars().count(),
+                                Value::List(list) => list.len(),
+                                Value::Map(map) => map.len(),
+                                Value::Number(_) | Value::Bool(_) => 0,
+                            })),
+                        };
+                    }
+                    let text = value.to_string();
+                    out.push_str(&if raw { text } else { escape_html(&text) });
+                }
+                Node::If { branches, otherwise } => {
+                    let taken = branches.iter().find(|(condition, _)| scope.eval(condition).is_some_and(|value| value.truthy()));
+                    let body = taken.map_or(otherwise, |(_, body)| body);
+                    self.render_nodes(body, owner, chain, scope, out, depth)?;
+                }
+                Node::For { name, list, body, otherwise } => {
+                    let items = match scope.eval(list).as_deref() {
+                        Some(Value::List(items)) => items.clone(),
+                        _ => Vec::new(),
+                    };
+                    if items.is_empty() {
+                        self.render_nodes(otherwise, owner, chain, scope, out, depth)?;
+                    }
+                    let length = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let state = Context::new()
+                            .set("index", i + 1)
+                            .set("first", i == 0)
+                            .set("last", i + 1 == length)
+                            .set("length", length);
+                        scope.locals.push(("loop".to_string(), state.into()));
+                        scope.locals.push((name.clone(), item));
+                        let rendered = self.render_nodes(body, owner, chain, scope, out, depth);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        rendered?;
+                    }
+                }
+                Node::Include { name, line } => {
+                    let included = self.named(owner, name, *line, depth + 1)?;
+                    self.render_template(&included, scope, out, depth + 1)?;
+                }
+                Node::Block(name) => {
+                    let found = chain.iter().find_map(|template| Some((template, template.blocks.get(name)?)));
+                    if let Some((template, body)) = found {
+                        self.render_nodes(body, template, chain, scope, out, depth)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process, thread, time::Duration};
+
+    fn render(source: &str, context: &Context) -> String {
+        let templates = Templates::new();
+        templates.insert("test.html", source).unwrap();
+        templates.render(Path::new("test.html"), context).unwrap()
+    }
+
+    #[test]
+    fn fills_in_values_escaped() {
+        let context = Context::new()
+            .set("name", "<Ferris & co>")
+            .set("status", 404u16)
+            .set("user", Context::new().set("name", "crab"))
+            .set("tags", vec!["a", "b"]);
+        assert_eq!(
+            "Hi &lt;Ferris &amp; co&gt;, <Ferris & co>! 404 crab a, b  2 CRAB",
+            render(
+                "Hi {{ name }}, {{name|raw}}! {{ status }} {{ user.name }} {{ tags }} {{ missing.value }} {{ tags | length }} {{ user.name | upper }}",
+                &context
+            )
+        );
+        assert_eq!("a|b", render("{{ \"a|b\" }}", &context));
+        assert_eq!("[x]", render("[ {#- a comment -#} \n x \n {{- \"]\" }}", &context));
+    }
+
+    #[test]
+    fn branches_and_loops() {
+        let context = Context::new()
+            .set("status", 404u16)
+            .set("admin", false)
+            .set("items", vec![Context::new().set("name", "one"), Context::new().set("name", "two")])
+            .set("none", Vec::<Value>::new());
+        let source = "{% if status == 500 %}broken{% elif status != 404 or admin %}other{% else %}missing{% endif %}";
+        assert_eq!("missing", render(source, &context));
+        assert_eq!("yes", render("{% if not admin and (items or none) %}yes{% endif %}", &context));
+        assert_eq!(
+            "1/2 one, 2/2 two.",
+            render("{% for item in items %}{{ loop.index }}/{{ loop.length }} {{ item.name }}{% if not loop.last %}, {% endif %}{% endfor %}.", &context)
+        );
+        assert_eq!("empty", render("{% for item in none %}{{ item }}{% else %}empty{% endfor %}", &context));
+    }
+
+    #[test]
+    fn compile_errors_say_where() {
+        let error = |source: &str| Template::compile("page.html", source).unwrap_err().to_string();
+        assert_eq!("page.html:2: if is never closed with endif", error("\n{% if x %}\nhi"));
+        assert_eq!("page.html:1: unknown tag \"fi\"", error("{% if x %}{% fi %}"));
+        assert_eq!("page.html:3: {{ is never closed with }}", error("\n\n{{ x"));
+        assert_eq!("page.html:1: unknown filter \"shout\"", error("{{ x | shout }}"));
+        assert_eq!("page.html:2: extends must come before anything else", error("hi\n{% extends \"a.html\" %}"));
+        assert_eq!("page.html:1: the block a is defined twice", error("{% block a %}{% endblock %}{% block a %}{% endblock %}"));
+        assert_eq!("page.html:1: expected for NAME in LIST", error("{% for x of y %}{% endfor %}"));
+    }
+
+    // A directory of templates, removed again when the test is done
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Dir {
+            let dir = env::temp_dir().join(format!("hello-template-{name}-{}", process::id()));
+            fs::create_dir_all(dir.join("parts")).unwrap();
+            Dir(dir)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn includes_and_extends_files() {
+        let site = Dir::new("files");
+        let dir = &site.0;
+        fs::write(dir.join("base.html"), "<title>{% block title %}Site{% endblock %}</title>{% block body %}{% endblock %}").unwrap();
+        fs::write(dir.join("layout.html"), "{% extends \"base.html\" %}{% block body %}<main>{% block content %}{% endblock %}</main>{% include \"parts/footer.html\" %}{% endblock %}").unwrap();
+        fs::write(dir.join("parts/footer.html"), "<footer>{{ name }}</footer>").unwrap();
+        fs::write(dir.join("page.html"), "{% extends \"layout.html\" %}\nignored\n{% block content %}Hi {{ name }}{% endblock %}").unwrap();
+        fs::write(dir.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+        fs::write(dir.join("broken.html"), "{% include \"parts/missing.html\" %}").unwrap();
+
+        let templates = Templates::new();
+        let page = dir.join("page.html");
+        templates.check(&page).unwrap();
+        assert_eq!(
+            "<title>Site</title><main>Hi crab</main><footer>crab</footer>",
+            templates.render(&page, &Context::new().set("name", "crab")).unwrap()
+        );
+        let error = templates.render(&dir.join("loop.html"), &Context::new()).unwrap_err();
+        assert_eq!("templates include or extend each other too deeply", error.message);
+        assert!(templates.check(&dir.join("loop.html")).is_ok());
+        let error = templates.check(&dir.join("broken.html")).unwrap_err();
+        assert_eq!(format!("{}:1", dir.join("broken.html").display()), error.origin);
+
+        // Includes are found next to the template naming them, not the one being rendered
+        fs::create_dir_all(dir.join("pages")).unwrap();
+        fs::write(dir.join("pages/page.html"), "{% extends \"../layout.html\" %}{% block content %}Deep{% endblock %}").unwrap();
+        let deep = dir.join("pages/page.html");
+        templates.check(&deep).unwrap();
+        assert_eq!(
+            "<title>Site</title><main>Deep</main><footer>crab</footer>",
+            templates.render(&deep, &Context::new().set("name", "crab")).unwrap()
+        );
+        fs::write(dir.join("pages/broken.html"), "{% extends \"../broken.html\" %}").unwrap();
+        let error = templates.render(&dir.join("pages/broken.html"), &Context::new()).unwrap_err();
+        assert_eq!(format!("{}:1", dir.join("pages/../broken.html").display()), error.origin);
+
+        // Without dev mode a changed file isn't read again, and with it, it is
+        thread::sleep(Duration::from_millis(10));
+        fs::write(dir.join("parts/footer.html"), "<footer>{{ name | upper }} was here</footer>").unwrap();
+        let rendered = |templates: &Templates| templates.render(&page, &Context::new().set("name", "crab")).unwrap();
+        assert!(rendered(&templates).ends_with("<footer>crab</footer>"));
+        let dev = Templates::new().dev(true);
+        assert!(rendered(&dev).ends_with("<footer>CRAB was here</footer>"));
+        thread::sleep(Duration::from_millis(10));
+        fs::write(dir.join("parts/footer.html"), "<footer>gone</footer>").unwrap();
+        assert!(rendered(&dev).ends_with("<footer>gone</footer>"));
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    collections::hash_map::RandomState,
+    error, fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, prelude::*, BufReader, Chain, Cursor},
+    net::{Shutdown, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::base64;
+use crate::http::{Method, Request, Response, Version};
+use crate::sha1::sha1;
+
+// WebSockets (RFC 6455): the opening handshake that turns an HTTP/1.1 GET into a WebSocket
+// connection, the frames both ends send over it afterwards, and handlers for the messages
+// that are put together from them, registered on a Router with Router::websocket.
+//
+// The connection passes from the server to its handler with the 101 response (see
+// Response::upgrade) and holds a ThreadPool worker until it closes, like a keep-alive
+// connection under Server::run does. Frames are read whole, so a message is held in memory
+// until its last frame arrives, which max_message keeps in check.
+
+// Appended to the client's key before hashing it, the same for every server (RFC 6455 1.3)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message a WebSocket takes unless set_max_message says otherwise
+pub const DEFAULT_MAX_MESSAGE: usize = 1024 * 1024;
+
+// How long close waits for the other end to answer its Close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xa => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xa,
+        }
+    }
+
+    /// Close, Ping and Pong, which may come between the frames of a message
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// One frame. A message is a Text or Binary frame followed by any number of Continuation
+/// frames, and ends with the first of them that has fin set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// A frame that ends its message
+    pub fn new(opcode: Opcode, payload: impl Into<Vec<u8>>) -> Frame {
+        Frame { fin: true, opcode, payload: payload.into() }
+    }
+
+    /// Reads a frame and unmasks its payload. Also says whether it was masked, which a
+    /// client's frames must be and a server's mustn't.
+    pub fn read_from(reader: &mut impl Read, max_payload: usize) -> Result<(Frame, bool), WebSocketError> {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+        // The reserved bits are for extensions, and none are ever agreed on here
+        if head[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = Opcode::from_bits(head[0] & 0x0f).ok_or(WebSocketError::Protocol("unknown opcode"))?;
+        let masked = head[1] & 0x80 != 0;
+        let length = match head[1] & 0x7f {
+            126 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                reader.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if opcode.is_control() && (!fin || length > 125) {
+            return Err(WebSocketError::Protocol("control frames must be unfragmented and at most 125 bytes"));
+        }
+        if length > max_payload as u64 {
+            return Err(WebSocketError::TooBig);
+        }
+        let mut key = [0; 4];
+        if masked {
+            reader.read_exact(&mut key)?;
+        }
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+        if masked {
+            apply_mask(&mut payload, key);
+        }
+        Ok((Frame { fin, opcode, payload }, masked))
+    }
+
+    /// Writes the frame, with its payload masked with mask if there is one
+    pub fn write_to(&self, writer: &mut impl Write, mask: Option<[u8; 4]>) -> io::Result<()> {
+        let mut head = Vec::with_capacity(14);
+        head.push(u8::from(self.fin) << 7 | self.opcode.bits());
+        let masked = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            length @ 0..=125 => head.push(masked | length as u8),
+            length @ 126..=0xffff => {
+                head.push(masked | 126);
+                head.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                head.push(masked | 127);
+                head.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(key) => {
+                head.extend_from_slice(&key);
+                let mut payload = self.payload.clone();
+                apply_mask(&mut payload, key);
+                writer.write_all(&head)?;
+                writer.write_all(&payload)
+            }
+            None => {
+                writer.write_all(&head)?;
+                writer.write_all(&self.payload)
+            }
+        }
+    }
+}
+
+// Masking and unmasking are the same: each byte XORed with the key, over and over
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[index % 4];
+    }
+}
+
+/// A whole message, put back together from its frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    /// The other end broke the protocol
+    Protocol(&'static str),
+    /// A frame or message longer than the WebSocket's max_message
+    TooBig,
+    /// A Text message that isn't UTF-8
+    InvalidUtf8,
+}
+
+impl WebSocketError {
+    /// The status code to close the connection with because of this error (RFC 6455 7.4.1)
+    pub fn close_code(&self) -> u16 {
+        match self {
+            WebSocketError::Io(_) => 1011,
+            WebSocketError::Protocol(_) => 1002,
+            WebSocketError::TooBig => 1009,
+            WebSocketError::InvalidUtf8 => 1007,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketError::Io(err) => write!(f, "{err}"),
+            WebSocketError::Protocol(reason) => write!(f, "protocol error: {reason}"),
+            WebSocketError::TooBig => f.write_str("message too big"),
+            WebSocketError::InvalidUtf8 => f.write_str("text message isn't UTF-8"),
+        }
+    }
+}
+
+impl error::Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            WebSocketError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(err: io::Error) -> WebSocketError {
+        WebSocketError::Io(err)
+    }
+}
+
+/// One end of a WebSocket connection, sending and receiving whole messages. Pings are
+/// answered and Close frames returned by recv on their own.
+pub struct WebSocket {
+    // Whatever was read along with the handshake comes before the rest of the stream
+    reader: BufReader<Chain<Cursor<Vec<u8>>, TcpStream>>,
+    stream: TcpStream,
+    // Some for the client end, which masks what it sends with keys from here
+    masks: Option<RandomState>,
+    sent: u64,
+    max_message: usize,
+    // A Close frame has been sent, so nothing else may be
+    close_sent: bool,
+}
+
+impl WebSocket {
+    /// The server end of stream, once the handshake is done. buffered is anything read from
+    /// the stream after the handshake.
+    pub fn server(stream: TcpStream, buffered: Vec<u8>) -> io::Result<WebSocket> {
+        WebSocket::new(stream, buffered, None)
+    }
+
+    /// The client end of stream, once the handshake is done
+    pub fn client(stream: TcpStream, buffered: Vec<u8>) -> io::Result<WebSocket> {
+        WebSocket::new(stream, buffered, Some(RandomState::new()))
+    }
+
+    fn new(stream: TcpStream, buffered: Vec<u8>, masks: Option<RandomState>) -> io::Result<WebSocket> {
+        let reader = BufReader::new(Cursor::new(buffered).chain(stream.try_clone()?));
+        Ok(WebSocket { reader, stream, masks, sent: 0, max_message: DEFAULT_MAX_MESSAGE, close_sent: false })
+    }
+
+    /// Sets the largest message recv takes, beyond which it fails with TooBig
+    pub fn set_max_message(&mut self, max_message: usize) {
+        self.max_message = max_message;
+    }
+
+    /// The next message, or None once the connection has closed. Pings and Close frames
+    /// that arrive on the way are answered.
+    pub fn recv(&mut self) -> Result<Option<Message>, WebSocketError> {
+        let mut message: Option<(Opcode, Vec<u8>)> = None;
+        loop {
+            // The other end going away between messages ends the connection like a Close would
+            if message.is_none() && self.reader.fill_buf()?.is_empty() {
+                return Ok(None);
+            }
+            let (frame, masked) = Frame::read_from(&mut self.reader, self.max_message)?;
+            if masked != self.masks.is_none() {
+                return Err(WebSocketError::Protocol("only frames from the client are masked"));
+            }
+            match frame.opcode {
+                Opcode::Ping => {
+                    self.send_frame(&Frame::new(Opcode::Pong, frame.payload))?;
+                    continue;
+                }
+                Opcode::Pong => continue,
+                Opcode::Close => {
+                    // Answered with the status code it carried, unless it answers ours
+                    if !self.close_sent {
+                        let code = frame.payload.get(..2).unwrap_or_default();
+                        self.send_frame(&Frame::new(Opcode::Close, code))?;
+                    }
+                    return Ok(None);
+                }
+                Opcode::Text | Opcode::Binary if message.is_none() => message = Some((frame.opcode, frame.payload)),
+                Opcode::Text | Opcode::Binary => {
+                    return Err(WebSocketError::Protocol("a message started before the last one ended"));
+                }
+                Opcode::Continuation => match &mut message {
+                    Some((_, data)) if data.len() + frame.payload.len() <= self.max_message => {
+                        data.extend_from_slice(&frame.payload)
+                    }
+                    Some(_) => return Err(WebSocketError::TooBig),
+                    None => return Err(WebSocketError::Protocol("a continuation frame with no message to continue")),
+                },
+            }
+            if frame.fin {
+                return match message.take() {
+                    Some((Opcode::Text, data)) => {
+                        String::from_utf8(data).map(|text| Some(Message::Text(text))).map_err(|_| WebSocketError::InvalidUtf8)
+                    }
+                    Some((_, data)) => Ok(Some(Message::Binary(data))),
+                    None => unreachable!("a message was started above"),
+                };
+            }
+        }
+    }
+
+    /// Sends message as a single frame
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        let frame = match message {
+            Message::Text(text) => Frame::new(Opcode::Text, text.as_bytes()),
+            Message::Binary(data) => Frame::new(Opcode::Binary, data.as_slice()),
+        };
+        self.send_frame(&frame)
+    }
+
+    /// Sends any frame, such as one of a fragmented message, masked if this is the client end
+    pub fn send_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "the WebSocket is closing"));
+        }
+        self.close_sent = frame.opcode == Opcode::Close;
+        let mask = self.masks.as_ref().map(|masks| {
+            // Masks are meant to be unpredictable to whatever sits between the two ends
+            let mut hasher = masks.build_hasher();
+            hasher.write_u64(self.sent);
+            (hasher.finish() as u32).to_ne_bytes()
+        });
+        self.sent += 1;
+        // One write for the whole frame, rather than its head waiting on Nagle's algorithm
+        let mut output = Vec::with_capacity(frame.payload.len() + 14);
+        frame.write_to(&mut output, mask)?;
+        self.stream.write_all(&output)
+    }
+
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.send_frame(&Frame::new(Opcode::Ping, payload))
+    }
+
+    /// Closes the connection with a status code such as 1000 for a normal closure, waiting
+    /// a while for the other end to answer first
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if !self.close_sent {
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            self.send_frame(&Frame::new(Opcode::Close, payload))?;
+            self.stream.set_read_timeout(Some(CLOSE_TIMEOUT))?;
+            // Anything else still on its way is dropped, and an error or timeout ends the
+            // wait just as the answer would
+            while let Ok((frame, _)) = Frame::read_from(&mut self.reader, self.max_message) {
+                if frame.opcode == Opcode::Close {
+                    break;
+                }
+            }
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+        Ok(())
+    }
+}
+
+/// Talks to a client over a WebSocket for as long as the connection lasts. Like a
+/// Handler, it's shared by every worker thread.
+pub trait WebSocketHandler: Send + Sync + 'static {
+    /// Returning closes the connection with 1000 Normal Closure, or the error's close_code
+    fn handle(&self, request: &Request, socket: &mut WebSocket) -> Result<(), WebSocketError>;
+}
+
+impl<F> WebSocketHandler for F
+where
+    F: Fn(&Request, &mut WebSocket) -> Result<(), WebSocketError> + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request, socket: &mut WebSocket) -> Result<(), WebSocketError> {
+        self(request, socket)
+    }
+}
+
+/// Sends every message back to the client it came from
+pub struct Echo;
+
+impl WebSocketHandler for Echo {
+    fn handle(&self, _: &Request, socket: &mut WebSocket) -> Result<(), WebSocketError> {
+        while let Some(message) = socket.recv()? {
+            socket.send(&message)?;
+        }
+        Ok(())
+    }
+}
+
+/// The Sec-WebSocket-Accept that answers a client's Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// Answers an opening handshake (RFC 6455 4.2.2): 101 Switching Protocols, after which
+/// handler has the connection, or what's wrong with the request
+pub fn upgrade(request: &Request, handler: &Arc<dyn WebSocketHandler>) -> Response {
+    let key = match handshake_key(request) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (request, handler) = (request.clone(), Arc::clone(handler));
+    Response::new(101)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key(key))
+        .upgrade(move |stream, buffered| {
+            let Ok(mut socket) = WebSocket::server(stream, buffered) else { return };
+            let code = match handler.handle(&request, &mut socket) {
+                Ok(()) => 1000,
+                Err(err) => err.close_code(),
+            };
+            let _ = socket.close(code, "");
+        })
+}
+
+// The client's key if request is a WebSocket handshake that can be answered, otherwise
+// the response saying why not
+fn handshake_key(request: &Request) -> Result<&str, Response> {
+    let bad = |reason: &str| {
+        Response::new(400).header("Content-Type", "text/plain; charset=utf-8").body(format!("{reason}\n"))
+    };
+    if request.method != Method::Get || request.version != Version::Http11 {
+        return Err(bad("A WebSocket handshake is an HTTP/1.1 GET"));
+    }
+    if !request.headers.has_token("Upgrade", "websocket") || !request.headers.has_token("Connection", "Upgrade") {
+        return Err(bad("Expected Upgrade: websocket and Connection: Upgrade"));
+    }
+    // 13 is the only version there is, and the one to tell the client to use
+    if request.headers.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(Response::new(426).header("Sec-WebSocket-Version", "13").body("Unsupported WebSocket version\n"));
+    }
+    match request.headers.get("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if base64::decode(key).is_some_and(|nonce| nonce.len() == 16) => Ok(key),
+        _ => Err(bad("Sec-WebSocket-Key must be 16 bytes in base64")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{self, Limits};
+
+    fn round_trip(frame: &Frame, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        frame.write_to(&mut bytes, mask).unwrap();
+        let (read, masked) = Frame::read_from(&mut bytes.as_slice(), usize::MAX).unwrap();
+        assert_eq!((frame, mask.is_some()), (&read, masked));
+        bytes
+    }
+
+    #[test]
+    fn encodes_rfc_examples() {
+        // A single-frame unmasked text message, and the same masked (RFC 6455 5.7)
+        let hello = Frame::new(Opcode::Text, "Hello");
+        assert_eq!(vec![0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f], round_trip(&hello, None));
+        assert_eq!(
+            vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58],
+            round_trip(&hello, Some([0x37, 0xfa, 0x21, 0x3d]))
+        );
+
+        // Payloads that need the 16 and 64 bit lengths
+        let medium = round_trip(&Frame::new(Opcode::Binary, vec![7; 256]), None);
+        assert_eq!([0x82, 0x7e, 0x01, 0x00], medium[..4]);
+        let large = round_trip(&Frame::new(Opcode::Binary, vec![7; 65536]), Some([1, 2, 3, 4]));
+        assert_eq!([0x82, 0xff, 0, 0, 0, 0, 0, 1, 0, 0], large[..10]);
+
+        let fragment = Frame { fin: false, opcode: Opcode::Text, payload: b"Hel".to_vec() };
+        assert_eq!(vec![0x01, 0x03, 0x48, 0x65, 0x6c], round_trip(&fragment, None));
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let read = |bytes: &[u8], max| Frame::read_from(&mut &bytes[..], max).unwrap_err();
+        assert!(matches!(read(&[0xc1, 0x00], 10), WebSocketError::Protocol(_)));
+        assert!(matches!(read(&[0x83, 0x00], 10), WebSocketError::Protocol(_)));
+        // Fragmented and oversized control frames
+        assert!(matches!(read(&[0x09, 0x00], 10), WebSocketError::Protocol(_)));
+        assert!(matches!(read(&[0x89, 0x7e, 0x00, 0x7e], 1000), WebSocketError::Protocol(_)));
+        assert!(matches!(read(&[0x82, 0x0b], 10), WebSocketError::TooBig));
+        assert!(matches!(read(&[0x82, 0x05, b'a'], 10), WebSocketError::Io(_)));
+    }
+
+    #[test]
+    fn answers_handshakes() {
+        // The example from RFC 6455 1.3
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+
+        let handler: Arc<dyn WebSocketHandler> = Arc::new(Echo);
+        let handshake = |extra: &str| {
+            let raw = format!(
+                "GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n{extra}\r\n"
+            );
+            let request = http::read_request(&mut raw.as_bytes(), &Limits::default()).unwrap().unwrap();
+            upgrade(&request, &handler)
+        };
+        let accepted = handshake("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n");
+        assert_eq!(101, accepted.status);
+        assert_eq!(Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), accepted.headers.get("Sec-WebSocket-Accept"));
+        assert!(accepted.upgrade.is_some());
+
+        let old = handshake("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n");
+        assert_eq!((426, Some("13")), (old.status, old.headers.get("Sec-WebSocket-Version")));
+        assert_eq!(400, handshake("Sec-WebSocket-Key: c2hvcnQ=\r\nSec-WebSocket-Version: 13\r\n").status);
+        assert_eq!(400, handshake("Sec-WebSocket-Version: 13\r\n").status);
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    io::{self, prelude::*, BufReader},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use hello::http::{Request, Response};
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server};
+use hello::{QueuePolicy, ThreadPool};
+
+mod common;
+use common::read_reply;
+
+fn connect(address: std::net::SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+#[test]
+fn full_queue_gets_503() {
+    let mut router = Router::new();
+    router.get("/", |_: &Request| Response::new(200).body("ok"));
+    // One worker and room for one connection to wait for it
+    let pool = ThreadPool::bounded(1, 1, QueuePolicy::Reject);
+    let server = Server::bind_with_pool("127.0.0.1:0", router, pool, ConnectionOptions::default()).unwrap();
+    let address = server.local_addr();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(Duration::from_secs(5)));
+
+    // The first connection keeps the worker while it stays open
+    let (mut busy, mut busy_reader) = connect(address);
+    busy.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    assert_eq!(200, read_reply(&mut busy_reader).status);
+    // The second waits in the queue, leaving no room for the third
+    let (_waiting, _) = connect(address);
+    let (mut turned_away, mut reader) = connect(address);
+    turned_away.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    let reply = read_reply(&mut reader);
+    assert_eq!(503, reply.status);
+    assert_eq!(Some("1"), reply.header("Retry-After"));
+    // The server closes without reading the request, which the kernel may answer with a
+    // reset rather than a FIN once the response is out
+    let mut rest = Vec::new();
+    match reader.read_to_end(&mut rest) {
+        Ok(0) => {}
+        Err(err) if err.kind() == io::ErrorKind::ConnectionReset => {}
+        end => panic!("connection still open: {end:?}"),
+    }
+
+    drop(busy);
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn pools_that_drop_jobs_are_refused() {
+    // A dropped connection would get no answer at all, not even a 503
+    let pool = ThreadPool::bounded(1, 1, QueuePolicy::DropOldest);
+    let err = Server::bind_with_pool("127.0.0.1:0", Router::new(), pool, ConnectionOptions::default()).err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
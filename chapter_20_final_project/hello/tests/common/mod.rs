@@ -0,0 +1,80 @@
+// Not every test file uses every helper
+#![allow(dead_code)]
+
+use std::{
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+use hello::ThreadPool;
+use hello::http::{Request, Response};
+use hello::router::Router;
+use hello::server::{self, ConnectionOptions};
+
+// Starts a server on a free loopback port with workers threads, answering every GET with its path.
+// The accept loop runs on its own thread until the test process exits.
+pub fn start(workers: usize, options: ConnectionOptions) -> SocketAddr {
+    let mut router = Router::new();
+    router.get("/*path", |request: &Request| Response::new(200).body(request.path.clone()));
+    let router = Arc::new(router);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let pool = ThreadPool::new(workers);
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let router = Arc::clone(&router);
+            pool.execute(move || {
+                let _ = server::serve_connection(stream, &router, &options);
+            })
+            .unwrap();
+        }
+    });
+    address
+}
+
+// A response as the client sees it
+pub struct Reply {
+    pub status: u16,
+    pub head: String,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (field, value) = line.split_once(':')?;
+            field.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+// Reads one response off the connection, using its Content-Length to find the end of it
+pub fn read_reply(reader: &mut BufReader<TcpStream>) -> Reply {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(!line.is_empty(), "connection closed before the end of the response");
+        if line == "\r\n" {
+            break;
+        }
+        head.push_str(&line);
+    }
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut reply = Reply { status, head, body: String::new() };
+    let length: usize = reply.header("Content-Length").map_or(0, |length| length.parse().unwrap());
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    reply.body = String::from_utf8(body).unwrap();
+    reply
+}
+
+// Whether the server has closed the connection, with nothing more to read
+pub fn closed(reader: &mut BufReader<TcpStream>) -> bool {
+    let mut rest = Vec::new();
+    matches!(reader.read_to_end(&mut rest), Ok(0))
+}
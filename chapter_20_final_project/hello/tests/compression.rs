@@ -0,0 +1,177 @@
+use std::{
+    env, fs,
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    process, thread,
+    time::Duration,
+};
+
+use hello::compress::{Compression, Encoding};
+use hello::files::StaticFiles;
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server};
+
+// A response with a body that may not be text, read by Content-Length or in chunks
+struct Reply {
+    head: String,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (field, value) = line.split_once(':')?;
+            field.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(!line.is_empty(), "connection closed before the end of the response");
+    line
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Reply {
+    let mut head = String::new();
+    loop {
+        let line = read_line(reader);
+        if line == "\r\n" {
+            break;
+        }
+        head.push_str(&line);
+    }
+    let mut reply = Reply { head, body: Vec::new() };
+    if reply.header("Transfer-Encoding") == Some("chunked") {
+        loop {
+            let size = usize::from_str_radix(read_line(reader).trim_end(), 16).unwrap();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            assert_eq!(b"\r\n", &chunk[size..]);
+            if size == 0 {
+                break;
+            }
+            reply.body.extend_from_slice(&chunk[..size]);
+        }
+    } else {
+        let length: usize = reply.header("Content-Length").map_or(0, |length| length.parse().unwrap());
+        reply.body = vec![0; length];
+        reader.read_exact(&mut reply.body).unwrap();
+    }
+    reply
+}
+
+// A server compressing the files in a temporary directory: small.txt under the threshold,
+// page.html over it, big.txt over the size streamed in chunks, and a picture. Returns the
+// server, not yet running, with the page and big file contents.
+fn server(name: &str) -> (Server, Vec<u8>, Vec<u8>) {
+    let dir = env::temp_dir().join(format!("hello-compression-{name}-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let page = "<p>hello, compressed world</p>\n".repeat(200).into_bytes();
+    let big: Vec<u8> = (0..20_000).flat_map(|line| format!("line {line} of the big file\n").into_bytes()).collect();
+    fs::write(dir.join("small.txt"), "tiny").unwrap();
+    fs::write(dir.join("page.html"), &page).unwrap();
+    fs::write(dir.join("big.txt"), &big).unwrap();
+    fs::write(dir.join("picture.png"), &page).unwrap();
+
+    let mut router = Router::new();
+    router.get("/*path", StaticFiles::new(&dir));
+    let server = Server::bind("127.0.0.1:0", router, 2, ConnectionOptions::default())
+        .unwrap()
+        .compression(Compression::new());
+    (server, page, big)
+}
+
+fn start(name: &str) -> (SocketAddr, Vec<u8>, Vec<u8>) {
+    let (server, page, big) = server(name);
+    let address = server.local_addr();
+    thread::spawn(move || server.run(Duration::from_secs(5)).unwrap());
+    (address, page, big)
+}
+
+fn get(reader: &mut BufReader<TcpStream>, request: &str) -> Reply {
+    reader.get_mut().write_all(request.as_bytes()).unwrap();
+    read_reply(reader)
+}
+
+fn connect(address: SocketAddr) -> BufReader<TcpStream> {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    BufReader::new(stream)
+}
+
+#[test]
+fn compresses_for_clients_that_accept_it() {
+    compresses(start("accept"));
+}
+
+#[cfg(all(feature = "event-loop", target_os = "linux"))]
+#[test]
+fn compresses_on_the_event_loop() {
+    let (server, page, big) = server("event-loop");
+    let server = server.event_loop().unwrap();
+    let address = server.local_addr();
+    thread::spawn(move || server.run(Duration::from_secs(5)).unwrap());
+    compresses((address, page, big));
+}
+
+fn compresses((address, page, big): (SocketAddr, Vec<u8>, Vec<u8>)) {
+    let mut reader = connect(address);
+    // The body bytes received, which the server's metrics should count the same
+    let mut received = 0;
+
+    for (accept, encoding) in [("gzip", Encoding::Gzip), ("deflate", Encoding::Deflate), ("deflate;q=0.5, gzip", Encoding::Gzip)] {
+        let reply = get(&mut reader, &format!("GET /page.html HTTP/1.1\r\nHost: x\r\nAccept-Encoding: {accept}\r\n\r\n"));
+        assert_eq!(Some(encoding.name()), reply.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), reply.header("Vary"));
+        assert!(reply.header("ETag").unwrap().starts_with("W/"));
+        assert_eq!(Some(reply.body.len().to_string().as_str()), reply.header("Content-Length"));
+        assert!(reply.body.len() < page.len() / 4);
+        assert_eq!(Some(page.clone()), encoding.decode(&reply.body));
+        received += reply.body.len();
+
+        // Large bodies are compressed as they are sent, in chunks
+        let reply = get(&mut reader, &format!("GET /big.txt HTTP/1.1\r\nHost: x\r\nAccept-Encoding: {accept}\r\n\r\n"));
+        assert_eq!(Some(encoding.name()), reply.header("Content-Encoding"));
+        assert_eq!((Some("chunked"), None), (reply.header("Transfer-Encoding"), reply.header("Content-Length")));
+        assert_eq!(Some(big.clone()), encoding.decode(&reply.body));
+        received += reply.body.len();
+    }
+
+    // The connection is still good after all that
+    let reply = get(&mut reader, "GET /small.txt HTTP/1.1\r\nHost: x\r\n\r\n");
+    assert_eq!(b"tiny", &reply.body[..]);
+    received += reply.body.len();
+
+    // Streamed bodies count as what was sent, not their length before compression
+    let reply = get(&mut reader, "GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n");
+    let metrics = String::from_utf8(reply.body).unwrap();
+    assert!(metrics.contains(&format!("\nhttp_response_body_bytes_total {received}\n")), "{metrics}");
+}
+
+#[test]
+fn leaves_other_responses_alone() {
+    let (address, page, big) = start("alone");
+    let mut reader = connect(address);
+    let plain = |reply: &Reply| reply.header("Content-Encoding").is_none();
+
+    let reply = get(&mut reader, "GET /page.html HTTP/1.1\r\nHost: x\r\n\r\n");
+    assert!(plain(&reply));
+    assert_eq!(page, reply.body);
+    let reply = get(&mut reader, "GET /page.html HTTP/1.1\r\nHost: x\r\nAccept-Encoding: br, gzip;q=0\r\n\r\n");
+    assert!(plain(&reply));
+    let reply = get(&mut reader, "GET /small.txt HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n");
+    assert!(plain(&reply));
+    assert_eq!(b"tiny", &reply.body[..]);
+    let reply = get(&mut reader, "GET /picture.png HTTP/1.1\r\nHost: x\r\nAccept-Encoding: gzip\r\n\r\n");
+    assert!(plain(&reply));
+    assert_eq!(page, reply.body);
+
+    // HTTP/1.0 has no chunks, so a large body is compressed whole and sent with its length
+    let mut reader = connect(address);
+    let reply = get(&mut reader, "GET /big.txt HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n");
+    assert_eq!((Some("gzip"), None), (reply.header("Content-Encoding"), reply.header("Transfer-Encoding")));
+    assert_eq!(Some(reply.body.len().to_string().as_str()), reply.header("Content-Length"));
+    assert_eq!(Some(big), Encoding::Gzip.decode(&reply.body));
+}
@@ -0,0 +1,73 @@
+use std::{
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use hello::http::{Request, Response};
+use hello::pages::ErrorPages;
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server};
+
+mod common;
+use common::read_reply;
+
+// A server whose error pages say what went wrong, with a route for POST only and one
+// whose handler panics. Returns it not yet running.
+fn server() -> Server {
+    let mut pages = ErrorPages::new();
+    for status in [400, 405, 500] {
+        pages.insert(status, "<p>{{ status }} {{ reason }}{% if path %} for {{ method }} {{ path }}{% endif %}</p>").unwrap();
+    }
+    let mut router = Router::new();
+    router
+        .post("/form", |_: &Request| Response::new(200).body("posted"))
+        .get("/panic", |_: &Request| -> Response { panic!("handler failed") })
+        .error_pages(pages);
+    Server::bind("127.0.0.1:0", router, 2, ConnectionOptions::default()).unwrap()
+}
+
+fn get(address: SocketAddr, request: &str) -> common::Reply {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream);
+    reader.get_mut().write_all(request.as_bytes()).unwrap();
+    read_reply(&mut reader)
+}
+
+#[test]
+fn errors_get_their_pages() {
+    let server = server();
+    let address = server.local_addr();
+    thread::spawn(move || server.run(Duration::from_secs(5)).unwrap());
+    gets_pages(address);
+}
+
+#[cfg(all(feature = "event-loop", target_os = "linux"))]
+#[test]
+fn errors_get_their_pages_on_the_event_loop() {
+    let server = server().event_loop().unwrap();
+    let address = server.local_addr();
+    thread::spawn(move || server.run(Duration::from_secs(5)).unwrap());
+    gets_pages(address);
+}
+
+fn gets_pages(address: SocketAddr) {
+    // The router's 405, with the method and path of the request
+    let reply = get(address, "GET /form HTTP/1.1\r\nHost: x\r\n\r\n");
+    assert_eq!((405, Some("POST")), (reply.status, reply.header("Allow")));
+    assert_eq!("<p>405 Method Not Allowed for GET /form</p>", reply.body);
+
+    // The server's own errors, for a handler that panicked and a request it couldn't read
+    let reply = get(address, "GET /panic HTTP/1.1\r\nHost: x\r\n\r\n");
+    assert_eq!((500, Some("close")), (reply.status, reply.header("Connection")));
+    assert_eq!("<p>500 Internal Server Error for GET /panic</p>", reply.body);
+    let reply = get(address, "GET / HTTP/1.1\r\nHost : x\r\n\r\n");
+    assert_eq!((400, Some("text/html; charset=utf-8")), (reply.status, reply.header("Content-Type")));
+    assert_eq!("<p>400 Bad Request</p>", reply.body);
+
+    // Statuses without a page are plain text, as before
+    let reply = get(address, "GET /nowhere HTTP/1.1\r\nHost: x\r\n\r\n");
+    assert_eq!((404, "Not Found\n"), (reply.status, reply.body.as_str()));
+}
@@ -0,0 +1,180 @@
+// The event loop backend, only built with: cargo test --features event-loop
+#![cfg(all(feature = "event-loop", target_os = "linux"))]
+
+use std::{
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::http::{Request, Response};
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server, ShutdownHandle};
+use hello::websocket::{Echo, Message, WebSocket};
+use hello::{QueuePolicy, ThreadPool};
+
+mod common;
+use common::{closed, read_reply};
+
+// An event loop server answering GETs with their path, whose /slow route takes a while,
+// running on its own thread
+fn start(workers: usize, options: ConnectionOptions) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<()>) {
+    let mut router = Router::new();
+    router
+        .get("/slow", |_: &Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::new(200).body("done")
+        })
+        .get("/*path", |request: &Request| Response::new(200).body(request.path.clone()));
+    let server = Server::bind("127.0.0.1:0", router, workers, options).unwrap().event_loop().unwrap();
+    let (address, handle) = (server.local_addr(), server.shutdown_handle());
+    let running = thread::spawn(move || server.run(Duration::from_secs(5)).unwrap());
+    (address, handle, running)
+}
+
+fn connect(address: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+#[test]
+fn serves_keep_alive_and_pipelined_requests() {
+    let (address, ..) = start(2, ConnectionOptions::default());
+    let (mut stream, mut reader) = connect(address);
+    for (path, left) in [("/one", 99), ("/two", 98)] {
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let reply = read_reply(&mut reader);
+        assert_eq!((200, path), (reply.status, reply.body.as_str()));
+        assert_eq!(Some(format!("timeout=5, max={left}").as_str()), reply.header("Keep-Alive"));
+    }
+
+    // Sent in pieces, and several at once
+    let pieces = [
+        "GET /a HT",
+        "TP/1.1\r\nHost: x\r\n",
+        "\r\nHEAD /b HTTP/1.1\r\nHost: x\r\n\r\nGET /c HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+    ];
+    for piece in pieces {
+        stream.write_all(piece.as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!("/a", read_reply(&mut reader).body);
+    // The HEAD response has a Content-Length but no body
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        reader.read_line(&mut head).unwrap();
+    }
+    assert!(head.contains("Content-Length: 2\r\n"), "{head}");
+    let last = read_reply(&mut reader);
+    assert_eq!(("/c", Some("close")), (last.body.as_str(), last.header("Connection")));
+    assert!(closed(&mut reader));
+}
+
+#[test]
+fn idle_connections_dont_hold_workers() {
+    // One worker, and more idle connections than a thread per connection could serve
+    let (address, ..) = start(1, ConnectionOptions::default());
+    let idle: Vec<_> = (0..20).map(|_| connect(address)).collect();
+    let (mut stream, mut reader) = connect(address);
+    let start = Instant::now();
+    stream.write_all(b"GET /busy HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    assert_eq!("/busy", read_reply(&mut reader).body);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    drop(idle);
+}
+
+#[test]
+fn times_out_slow_and_idle_clients() {
+    let options = ConnectionOptions {
+        idle_timeout: Duration::from_millis(200),
+        request_timeout: Duration::from_millis(200),
+        ..ConnectionOptions::default()
+    };
+    let (address, ..) = start(1, options);
+
+    let (_idle, mut idle_reader) = connect(address);
+    let (mut slow, mut slow_reader) = connect(address);
+    slow.write_all(b"GET / HTTP/1.1\r\nHost:").unwrap();
+
+    assert_eq!(408, read_reply(&mut slow_reader).status);
+    assert!(closed(&mut slow_reader));
+    assert!(closed(&mut idle_reader));
+
+    // Malformed requests get their status and the connection closed
+    let (mut bad, mut bad_reader) = connect(address);
+    bad.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(400, read_reply(&mut bad_reader).status);
+    assert!(closed(&mut bad_reader));
+}
+
+#[test]
+fn drains_on_shutdown() {
+    let (address, handle, running) = start(2, ConnectionOptions::default());
+    let (mut busy, mut busy_reader) = connect(address);
+    let (mut idle, mut idle_reader) = connect(address);
+    idle.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    assert_eq!("/", read_reply(&mut idle_reader).body);
+    busy.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    handle.shutdown();
+    assert!(closed(&mut idle_reader));
+    let reply = read_reply(&mut busy_reader);
+    assert_eq!(("done", Some("close")), (reply.body.as_str(), reply.header("Connection")));
+    assert!(closed(&mut busy_reader));
+    running.join().unwrap();
+    assert!(TcpStream::connect(address).is_err());
+}
+
+#[test]
+fn full_pool_gets_503() {
+    let mut router = Router::new();
+    router.get("/slow", |_: &Request| {
+        thread::sleep(Duration::from_millis(300));
+        Response::new(200).body("done")
+    });
+    let pool = ThreadPool::bounded(1, 1, QueuePolicy::Reject);
+    let server = Server::bind_with_pool("127.0.0.1:0", router, pool, ConnectionOptions::default()).unwrap();
+    let server = server.event_loop().unwrap();
+    let address = server.local_addr();
+    thread::spawn(move || server.run(Duration::from_secs(5)));
+
+    // One request runs, one waits in the queue, and the third has nowhere to go
+    let mut connections: Vec<_> = (0..3).map(|_| connect(address)).collect();
+    for (stream, _) in &mut connections {
+        stream.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let statuses: Vec<u16> = connections.iter_mut().map(|(_, reader)| read_reply(reader).status).collect();
+    assert_eq!(vec![200, 200, 503], statuses);
+}
+
+#[test]
+fn hands_websockets_to_a_worker() {
+    let mut router = Router::new();
+    router.websocket("/echo", Echo);
+    let server = Server::bind("127.0.0.1:0", router, 2, ConnectionOptions::default()).unwrap().event_loop().unwrap();
+    let (address, handle) = (server.local_addr(), server.shutdown_handle());
+    let running = thread::spawn(move || server.run(Duration::from_secs(5)).unwrap());
+
+    let (mut stream, mut reader) = connect(address);
+    stream
+        .write_all(
+            b"GET /echo HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    assert_eq!(101, read_reply(&mut reader).status);
+    let mut socket = WebSocket::client(stream, reader.buffer().to_vec()).unwrap();
+    let message = Message::Text("over the loop".to_string());
+    socket.send(&message).unwrap();
+    assert_eq!(Some(message), socket.recv().unwrap());
+
+    // Still open when shutdown starts, and closed by it
+    handle.shutdown();
+    assert_eq!(None, socket.recv().unwrap());
+    running.join().unwrap();
+}
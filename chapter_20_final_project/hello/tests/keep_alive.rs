@@ -0,0 +1,109 @@
+use std::{
+    io::{prelude::*, BufReader},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::server::ConnectionOptions;
+
+mod common;
+use common::{closed, read_reply, start};
+
+fn connect(options: ConnectionOptions) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(start(2, options)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+#[test]
+fn serves_several_requests_on_one_connection() {
+    let (mut stream, mut reader) = connect(ConnectionOptions::default());
+    for path in ["/one", "/two", "/three"] {
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let reply = read_reply(&mut reader);
+        assert_eq!((200, path), (reply.status, reply.body.as_str()));
+        assert_eq!(None, reply.header("Connection"));
+    }
+}
+
+#[test]
+fn answers_pipelined_requests_in_order() {
+    let (mut stream, mut reader) = connect(ConnectionOptions::default());
+    stream
+        .write_all(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\nGET /c HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+        .unwrap();
+
+    assert_eq!("/a", read_reply(&mut reader).body);
+    assert_eq!("/b", read_reply(&mut reader).body);
+    let last = read_reply(&mut reader);
+    assert_eq!(("/c", Some("close")), (last.body.as_str(), last.header("Connection")));
+    assert!(closed(&mut reader));
+}
+
+#[test]
+fn http_1_0_closes_unless_asked_not_to() {
+    let (mut stream, mut reader) = connect(ConnectionOptions::default());
+    stream.write_all(b"GET /kept HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+    assert_eq!(Some("keep-alive"), read_reply(&mut reader).header("Connection"));
+
+    stream.write_all(b"GET /closed HTTP/1.0\r\n\r\n").unwrap();
+    assert_eq!(Some("close"), read_reply(&mut reader).header("Connection"));
+    assert!(closed(&mut reader));
+}
+
+#[test]
+fn limits_requests_per_connection() {
+    let (mut stream, mut reader) = connect(ConnectionOptions { max_requests: 2, ..ConnectionOptions::default() });
+    stream.write_all(b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    assert_eq!(Some("timeout=5, max=1"), read_reply(&mut reader).header("Keep-Alive"));
+    stream.write_all(b"GET /2 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    assert_eq!(Some("close"), read_reply(&mut reader).header("Connection"));
+    assert!(closed(&mut reader));
+}
+
+#[test]
+fn closes_idle_connections() {
+    let options = ConnectionOptions { idle_timeout: Duration::from_millis(200), ..ConnectionOptions::default() };
+    let (mut stream, mut reader) = connect(options);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    read_reply(&mut reader);
+
+    let start = Instant::now();
+    assert!(closed(&mut reader));
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn times_out_slowloris_clients() {
+    let options = ConnectionOptions { request_timeout: Duration::from_millis(300), ..ConnectionOptions::default() };
+    // One worker, so the slow client holds the only one until it is timed out
+    let address = start(1, options);
+    let mut slow = TcpStream::connect(address).unwrap();
+    slow.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // Each byte comes well within any per-read timeout, but the request never ends
+    let start = Instant::now();
+    slow.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    let dripping = thread::spawn(move || {
+        for _ in 0..10 {
+            thread::sleep(Duration::from_millis(100));
+            if slow.write_all(b"X").is_err() {
+                break;
+            }
+        }
+        slow
+    });
+
+    let mut patient = TcpStream::connect(address).unwrap();
+    patient.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    patient.write_all(b"GET /next HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(patient);
+    assert_eq!("/next", read_reply(&mut reader).body);
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let slow = dripping.join().unwrap();
+    let reply = read_reply(&mut BufReader::new(slow));
+    assert_eq!(408, reply.status);
+}
@@ -0,0 +1,66 @@
+use std::{
+    env, fs,
+    io::{prelude::*, BufReader},
+    net::TcpStream,
+    process, thread,
+    time::Duration,
+};
+
+use hello::http::{Request, Response};
+use hello::log::{AccessLog, LogFormat};
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server};
+
+mod common;
+use common::read_reply;
+
+#[test]
+fn logs_requests_and_serves_metrics() {
+    let dir = env::temp_dir().join(format!("hello-observability-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let mut router = Router::new();
+    router.get("/", |_: &Request| Response::new(200).body("hi"));
+    let server = Server::bind("127.0.0.1:0", router, 2, ConnectionOptions::default())
+        .unwrap()
+        .access_log(AccessLog::open(&path, LogFormat::Combined).unwrap());
+    let address = server.local_addr();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(Duration::from_secs(5)));
+
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    (&stream).write_all(b"GET / HTTP/1.1\r\nHost: x\r\nUser-Agent: test/1.0\r\n\r\n").unwrap();
+    assert_eq!(200, read_reply(&mut reader).status);
+    (&stream).write_all(b"GET /missing HTTP/1.1\r\nHost: x\r\nReferer: http://x/\r\n\r\n").unwrap();
+    assert_eq!(404, read_reply(&mut reader).status);
+
+    (&stream).write_all(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    let metrics = read_reply(&mut reader);
+    assert_eq!(Some("text/plain; version=0.0.4; charset=utf-8"), metrics.header("Content-Type"));
+    for line in [
+        "http_requests_total{method=\"GET\",status=\"200\"} 1",
+        "http_requests_total{method=\"GET\",status=\"404\"} 1",
+        "http_request_duration_seconds_count 2",
+        "http_connections_open 1",
+        "threadpool_workers 2",
+        "threadpool_active_workers 1",
+    ] {
+        assert!(metrics.body.lines().any(|rendered| rendered == line), "{line} missing from\n{}", metrics.body);
+    }
+
+    drop((reader, stream));
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+
+    let log = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(3, lines.len(), "{log}");
+    assert!(lines[0].starts_with("127.0.0.1 - - ["));
+    assert!(lines[0].contains("] \"GET / HTTP/1.1\" 200 2 \"-\" \"test/1.0\" "));
+    assert!(lines[1].contains("] \"GET /missing HTTP/1.1\" 404 10 \"http://x/\" \"-\" "));
+    assert!(lines[2].contains("] \"GET /metrics HTTP/1.1\" 200 "));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,115 @@
+use std::{
+    io::{prelude::*, BufReader},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::http::{Request, Response};
+use hello::ThreadPool;
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server};
+
+mod common;
+use common::{closed, read_reply};
+
+// A server whose /slow route takes a while
+fn bind(workers: usize) -> Server {
+    let mut router = Router::new();
+    router
+        .get("/slow", |_: &Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::new(200).body("done")
+        })
+        .get("/", |_: &Request| Response::new(200).body("fast"));
+    Server::bind("127.0.0.1:0", router, workers, ConnectionOptions::default()).unwrap()
+}
+
+fn connect(server: &Server) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+#[test]
+fn finishes_requests_in_flight() {
+    let server = bind(2);
+    let address = server.local_addr();
+    let handle = server.shutdown_handle();
+
+    let (mut busy, mut busy_reader) = connect(&server);
+    let (mut idle, mut idle_reader) = connect(&server);
+    let running = thread::spawn(move || server.run(Duration::from_secs(5)));
+
+    // One connection sits idle between requests, the other is in the middle of a slow one
+    idle.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    assert_eq!("fast", read_reply(&mut idle_reader).body);
+    busy.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    handle.shutdown();
+    assert!(handle.is_shutdown());
+
+    let reply = read_reply(&mut busy_reader);
+    assert_eq!(("done", Some("close")), (reply.body.as_str(), reply.header("Connection")));
+    assert!(closed(&mut busy_reader));
+    assert!(closed(&mut idle_reader));
+    assert_eq!(Ok(()), running.join().unwrap());
+    // Well under the 5 second idle timeout, so the idle connection didn't hold things up
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(TcpStream::connect(address).is_err());
+}
+
+#[test]
+fn reports_workers_that_miss_the_deadline() {
+    let server = bind(1);
+    let handle = server.shutdown_handle();
+    let (mut busy, _busy_reader) = connect(&server);
+    let running = thread::spawn(move || server.run(Duration::from_millis(50)));
+
+    busy.write_all(b"GET /slow HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    handle.shutdown();
+
+    let error = running.join().unwrap().unwrap_err();
+    assert_eq!((vec![0], vec![]), (error.timed_out, error.panicked));
+}
+
+#[test]
+fn serves_and_stops_every_listener() {
+    let mut router = Router::new();
+    router.get("/", |_: &Request| Response::new(200).body("fast"));
+    let addresses = ["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+    let server = Server::bind_all(&addresses, router, ThreadPool::new(2), ConnectionOptions::default()).unwrap();
+    let bound = server.local_addrs().to_vec();
+    assert_eq!(2, bound.len());
+    assert_ne!(bound[0].port(), bound[1].port());
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(Duration::from_secs(5)));
+
+    for &address in &bound {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").unwrap();
+        assert_eq!("fast", read_reply(&mut reader).body);
+    }
+
+    handle.shutdown();
+    assert_eq!(Ok(()), running.join().unwrap());
+    for address in bound {
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
+
+#[test]
+fn names_the_address_it_cannot_bind() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addresses = ["127.0.0.1:0".parse().unwrap(), taken.local_addr().unwrap()];
+    let error = Server::bind_all(&addresses, Router::new(), ThreadPool::new(1), ConnectionOptions::default())
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains(&taken.local_addr().unwrap().to_string()), "{error}");
+}
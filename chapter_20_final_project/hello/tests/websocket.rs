@@ -0,0 +1,124 @@
+use std::{
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use hello::http::Request;
+use hello::router::Router;
+use hello::server::{ConnectionOptions, Server, ShutdownHandle};
+use hello::websocket::{Echo, Frame, Message, Opcode, WebSocket, WebSocketError};
+
+mod common;
+use common::{closed, read_reply};
+
+// A server with an echo WebSocket at /echo and one at /greet/:name that says hello and
+// closes, running on its own thread
+fn start() -> (SocketAddr, ShutdownHandle, thread::JoinHandle<()>) {
+    let mut router = Router::new();
+    router.websocket("/echo", Echo).websocket("/greet/:name", |request: &Request, socket: &mut WebSocket| {
+        socket.send(&Message::Text(format!("hello {}", request.param("name").unwrap())))?;
+        Ok::<(), WebSocketError>(())
+    });
+    let server = Server::bind("127.0.0.1:0", router, 4, ConnectionOptions::default()).unwrap();
+    let (address, handle) = (server.local_addr(), server.shutdown_handle());
+    let running = thread::spawn(move || server.run(Duration::from_secs(5)).unwrap());
+    (address, handle, running)
+}
+
+// Makes the opening handshake for path and checks the server agreed
+fn handshake(address: SocketAddr, path: &str) -> (TcpStream, BufReader<TcpStream>) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+    let reply = read_reply(&mut reader);
+    assert_eq!(101, reply.status);
+    assert_eq!(Some("websocket"), reply.header("Upgrade"));
+    assert_eq!(Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), reply.header("Sec-WebSocket-Accept"));
+    (stream, reader)
+}
+
+fn open(address: SocketAddr, path: &str) -> WebSocket {
+    let (stream, reader) = handshake(address, path);
+    WebSocket::client(stream, reader.buffer().to_vec()).unwrap()
+}
+
+#[test]
+fn echoes_messages() {
+    let (address, ..) = start();
+    let mut socket = open(address, "/echo");
+    let text = Message::Text("hello".to_string());
+    socket.send(&text).unwrap();
+    assert_eq!(Some(text), socket.recv().unwrap());
+    let binary = Message::Binary((0..=255).collect());
+    socket.send(&binary).unwrap();
+    assert_eq!(Some(binary), socket.recv().unwrap());
+    let large = Message::Binary(vec![b'x'; 100_000]);
+    socket.send(&large).unwrap();
+    assert_eq!(Some(large), socket.recv().unwrap());
+
+    // A fragmented message with a ping in the middle of it, which is answered first
+    socket.send_frame(&Frame { fin: false, opcode: Opcode::Text, payload: b"frag".to_vec() }).unwrap();
+    socket.ping(b"are you there").unwrap();
+    socket.send_frame(&Frame { fin: false, opcode: Opcode::Continuation, payload: b"men".to_vec() }).unwrap();
+    socket.send_frame(&Frame::new(Opcode::Continuation, "ted")).unwrap();
+    assert_eq!(Some(Message::Text("fragmented".to_string())), socket.recv().unwrap());
+
+    socket.close(1000, "done").unwrap();
+}
+
+#[test]
+fn closes_with_status_codes() {
+    let (address, ..) = start();
+
+    // The server answers a Close with the same code, then closes the connection
+    let (mut stream, mut reader) = handshake(address, "/echo");
+    Frame::new(Opcode::Close, 1001u16.to_be_bytes()).write_to(&mut stream, Some([1, 2, 3, 4])).unwrap();
+    let (frame, masked) = Frame::read_from(&mut reader, 125).unwrap();
+    assert_eq!((Opcode::Close, 1001u16.to_be_bytes().to_vec(), false), (frame.opcode, frame.payload, masked));
+    assert!(closed(&mut reader));
+
+    // Frames from a client must be masked
+    let (mut stream, mut reader) = handshake(address, "/echo");
+    Frame::new(Opcode::Text, "hi").write_to(&mut stream, None).unwrap();
+    let (frame, _) = Frame::read_from(&mut reader, 125).unwrap();
+    assert_eq!((Opcode::Close, 1002u16.to_be_bytes().to_vec()), (frame.opcode, frame.payload));
+
+    // A handler that returns closes with 1000
+    let mut socket = open(address, "/greet/ferris");
+    assert_eq!(Some(Message::Text("hello ferris".to_string())), socket.recv().unwrap());
+    assert_eq!(None, socket.recv().unwrap());
+}
+
+#[test]
+fn refuses_bad_handshakes() {
+    let (address, ..) = start();
+    let handshake = |headers: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /echo HTTP/1.1\r\nHost: x\r\n{headers}\r\n").unwrap();
+        read_reply(&mut BufReader::new(stream))
+    };
+    assert_eq!(400, handshake("").status);
+    let key = "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+    let old = handshake(&format!("{key}Sec-WebSocket-Version: 8\r\n"));
+    assert_eq!((426, Some("13")), (old.status, old.header("Sec-WebSocket-Version")));
+}
+
+#[test]
+fn shutdown_closes_open_websockets() {
+    let (address, handle, running) = start();
+    let mut socket = open(address, "/echo");
+    socket.send(&Message::Text("still here".to_string())).unwrap();
+    assert!(socket.recv().unwrap().is_some());
+
+    handle.shutdown();
+    assert_eq!(None, socket.recv().unwrap());
+    running.join().unwrap();
+}